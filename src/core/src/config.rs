@@ -0,0 +1,124 @@
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fs, path::Path};
+
+use crate::executor::{ExecBackend, Sandbox};
+use crate::rules::{default_true, Rule, RuleConfig, RuleDefaults};
+
+pub const APP_NAME: &str = "hypnos";
+/// Default of `inhibit_duration`
+pub const DEFAULT_INHIBIT_SEC: u64 = 30;
+
+/// The part of the config file that doesn't depend on Wayland: the rules, the hooks and
+/// the command and inhibit settings. The other sections of the file are ignored.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct CoreConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// Run when the system is about to sleep, which is delayed until it exits
+    #[serde(default)]
+    pub before_sleep: Option<String>,
+    /// Run when the system is back from sleep
+    #[serde(default, alias = "after_wake")]
+    pub after_resume: Option<String>,
+    /// Run when logind asks to lock the session (`loginctl lock-session`)
+    #[serde(default)]
+    pub on_lock: Option<String>,
+    /// Run when logind reports the session unlocked
+    #[serde(default)]
+    pub on_unlock: Option<String>,
+    /// Seconds `inhibit` requests keep the session awake, 0 until released
+    #[serde(default = "default_inhibit_duration")]
+    pub inhibit_duration: u64,
+    /// Run when the power source switches to the battery
+    #[serde(default)]
+    pub on_battery: Option<String>,
+    /// Run when the power source switches to AC
+    #[serde(default)]
+    pub on_ac: Option<String>,
+    /// Run once the daemon armed its first rules
+    #[serde(default)]
+    pub on_start: Option<String>,
+    /// Run when the daemon exits on SIGTERM/SIGINT
+    #[serde(default, alias = "on_stop")]
+    pub on_exit: Option<String>,
+    /// Run commands through `sh -c` by default
+    #[serde(default)]
+    pub shell: bool,
+    /// Seconds after which commands get terminated
+    #[serde(default)]
+    pub command_timeout: Option<u64>,
+    /// Start commands directly, or as systemd transient units
+    #[serde(default)]
+    pub exec_backend: ExecBackend,
+    /// Run commands in bubblewrap
+    #[serde(default)]
+    pub sandbox: Option<Sandbox>,
+    /// Further attempts for commands failing to start
+    #[serde(default)]
+    pub retries: u32,
+    pub rules: HashMap<String, RuleConfig>,
+}
+
+fn default_inhibit_duration() -> u64 {
+    DEFAULT_INHIBIT_SEC
+}
+
+impl CoreConfig {
+    /// Read the rules and settings from a hypnos config file.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    /// What the rules fall back to when they don't set it themselves.
+    pub fn defaults(&self) -> RuleDefaults {
+        RuleDefaults {
+            shell: self.shell,
+            command_timeout: self.command_timeout,
+            exec_backend: self.exec_backend,
+            sandbox: self.sandbox.clone(),
+            retries: self.retries,
+            on_lock: self.on_lock.clone(),
+        }
+    }
+
+    /// The enabled rules, sorted by name, e.g. to feed an `Engine`.
+    pub fn rules(&self) -> Vec<Rule> {
+        let defaults = self.defaults();
+        let mut rules: Vec<Rule> = self
+            .rules
+            .iter()
+            .filter(|(_, rule)| rule.enabled)
+            .map(|(name, rule)| Rule::from_config(name.clone(), rule.clone(), &defaults))
+            .collect();
+        rules.sort_by(|a, b| a.name.cmp(&b.name));
+        rules
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_rules_and_ignores_the_other_sections() {
+        let config: CoreConfig = serde_json::from_str(
+            r#"{
+                "shell": true,
+                "after_wake": "notify-send back",
+                "gamepad": { "inhibit": true },
+                "rules": {
+                    "lock": { "timeout": 300, "actions": "@lock", "restore": null },
+                    "dim": { "timeout": 60, "actions": "dim", "restore": "undim" },
+                    "off": { "timeout": 600, "actions": "off", "restore": null, "enabled": false }
+                }
+            }"#,
+        )
+        .unwrap();
+        assert!(config.enabled);
+        assert_eq!(config.inhibit_duration, DEFAULT_INHIBIT_SEC);
+        assert_eq!(config.after_resume.as_deref(), Some("notify-send back"));
+        assert!(config.defaults().shell);
+        let names: Vec<String> = config.rules().into_iter().map(|rule| rule.name).collect();
+        assert_eq!(names, ["dim", "lock"]);
+    }
+}
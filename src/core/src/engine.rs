@@ -0,0 +1,253 @@
+//! Rule evaluation driven by the caller's own idle and power sources, without Wayland.
+
+use std::time::{Duration, Instant};
+
+use crate::executor::Action;
+use crate::rules::{Conditions, Rule};
+
+/// What an [`Engine`] is fed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Input {
+    /// The session has been idle this long
+    Idle(Duration),
+    /// The session has been active again this long
+    Active(Duration),
+    OnBattery(bool),
+    /// Percent
+    BatteryLevel(f64),
+    SessionLocked(bool),
+    /// A remote desktop client is connected
+    RemoteDesktop(bool),
+    /// Idling is held off, the rules ignoring inhibitors still fire
+    Inhibited(bool),
+    /// Hottest thermal zone, in °C
+    Temperature(f64),
+}
+
+/// A set of rules, turning idle, resume and power events into the actions to run.
+///
+/// ```ignore
+/// let mut engine = Engine::new(vec![
+///     Rule::new("lock").timeout(Duration::from_secs(300)).actions("@lock"),
+///     Rule::new("suspend").timeout(Duration::from_secs(900)).actions("@suspend").on_battery_only(),
+/// ]);
+/// engine.handle(Input::OnBattery(true));
+/// for action in engine.handle(Input::Idle(Duration::from_secs(900))) {
+///     executor::run(action);
+/// }
+/// ```
+#[derive(Debug, Default)]
+pub struct Engine {
+    rules: Vec<Rule>,
+    conditions: Conditions,
+}
+
+impl Engine {
+    pub fn new(rules: Vec<Rule>) -> Self {
+        Self {
+            rules,
+            conditions: Conditions::default(),
+        }
+    }
+
+    pub fn add_rule(&mut self, rule: Rule) {
+        self.rules.push(rule);
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    pub fn conditions(&self) -> &Conditions {
+        &self.conditions
+    }
+
+    /// Update the state with `input`, returns the actions it triggers in rule order.
+    pub fn handle(&mut self, input: Input) -> Vec<Action> {
+        match input {
+            Input::Idle(idle_for) => self.idle(idle_for),
+            Input::Active(active_for) => self.active(active_for),
+            Input::OnBattery(on_battery) => {
+                self.conditions.on_battery = Some(on_battery);
+                Vec::new()
+            }
+            Input::BatteryLevel(level) => {
+                self.conditions.battery_level = Some(level);
+                Vec::new()
+            }
+            Input::SessionLocked(locked) => {
+                self.conditions.session_locked = locked;
+                Vec::new()
+            }
+            Input::RemoteDesktop(connected) => {
+                self.conditions.remote_desktop = connected;
+                Vec::new()
+            }
+            Input::Inhibited(inhibited) => {
+                self.conditions.inhibited = inhibited;
+                Vec::new()
+            }
+            Input::Temperature(celsius) => {
+                self.conditions.temperature = Some(celsius);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Fire the rules whose timeout passed and that didn't fire yet.
+    fn idle(&mut self, idle_for: Duration) -> Vec<Action> {
+        let mut actions = Vec::new();
+        for rule in &mut self.rules {
+            let timeout = Duration::from_secs(rule.timeout.max(0) as u64);
+            if rule.is_idled() || idle_for < timeout {
+                continue;
+            }
+            let Some(commands) = rule.idle_actions(&self.conditions) else {
+                continue;
+            };
+            let now = Instant::now();
+            rule.idled_since = Some(now);
+            rule.last_fired = Some(now);
+            actions.push(rule.action(commands, "idled", self.conditions.on_battery));
+        }
+        actions
+    }
+
+    /// Restore the fired rules, once the activity outlasted their `resume_debounce`.
+    fn active(&mut self, active_for: Duration) -> Vec<Action> {
+        let mut actions = Vec::new();
+        for rule in &mut self.rules {
+            if !rule.is_idled() || rule.resume_debounce.is_some_and(|debounce| active_for < debounce) {
+                continue;
+            }
+            // Like the daemon: battery-only rules aren't restored on AC
+            let on_battery = self.conditions.on_battery;
+            let restore = rule.restore.clone().filter(|_| !rule.on_battery || on_battery.unwrap_or(false));
+            if let Some(restore) = restore {
+                actions.push(rule.action(restore, "resumed", on_battery));
+            }
+            rule.idled_since = None;
+        }
+        actions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    fn commands(actions: Vec<Action>) -> Vec<(String, &'static str)> {
+        actions.into_iter().map(|action| (action.command, action.context.event)).collect()
+    }
+
+    fn engine() -> Engine {
+        Engine::new(vec![
+            Rule::new("dim").timeout(secs(60)).actions("dim").restore("undim"),
+            Rule::new("lock").timeout(secs(300)).actions("@lock"),
+            Rule::new("suspend").timeout(secs(900)).actions("@suspend").on_battery_only(),
+        ])
+    }
+
+    #[test]
+    fn rules_fire_once_their_timeout_passed() {
+        let mut engine = engine();
+        assert!(engine.handle(Input::Idle(secs(59))).is_empty());
+        assert_eq!(commands(engine.handle(Input::Idle(secs(60)))), [("dim".to_string(), "idled")]);
+        // Already fired, only the next one
+        assert_eq!(commands(engine.handle(Input::Idle(secs(300)))), [("@lock".to_string(), "idled")]);
+        assert!(engine.handle(Input::Idle(secs(400))).is_empty());
+    }
+
+    #[test]
+    fn rules_past_their_timeout_fire_together_in_order() {
+        let mut engine = engine();
+        let fired = commands(engine.handle(Input::Idle(secs(600))));
+        assert_eq!(fired, [("dim".to_string(), "idled"), ("@lock".to_string(), "idled")]);
+    }
+
+    #[test]
+    fn resume_restores_and_rearms() {
+        let mut engine = engine();
+        engine.handle(Input::Idle(secs(300)));
+        assert_eq!(commands(engine.handle(Input::Active(secs(1)))), [("undim".to_string(), "resumed")]);
+        assert!(engine.rules().iter().all(|rule| !rule.is_idled()));
+        assert_eq!(commands(engine.handle(Input::Idle(secs(60)))), [("dim".to_string(), "idled")]);
+    }
+
+    #[test]
+    fn resume_debounce_delays_the_restore() {
+        let mut rule = Rule::new("dim").timeout(secs(60)).actions("dim").restore("undim");
+        rule.resume_debounce = Some(secs(5));
+        let mut engine = Engine::new(vec![rule]);
+        engine.handle(Input::Idle(secs(60)));
+        assert!(engine.handle(Input::Active(secs(2))).is_empty());
+        assert!(engine.rules()[0].is_idled());
+        assert_eq!(commands(engine.handle(Input::Active(secs(5)))), [("undim".to_string(), "resumed")]);
+    }
+
+    #[test]
+    fn battery_rules_only_fire_on_battery() {
+        let mut engine = engine();
+        engine.handle(Input::OnBattery(false));
+        let fired = commands(engine.handle(Input::Idle(secs(900))));
+        assert!(!fired.iter().any(|(command, _)| command == "@suspend"));
+
+        engine.handle(Input::Active(secs(1)));
+        engine.handle(Input::OnBattery(true));
+        let fired = commands(engine.handle(Input::Idle(secs(900))));
+        assert_eq!(fired.last(), Some(&("@suspend".to_string(), "idled")));
+    }
+
+    #[test]
+    fn low_battery_hibernates_instead() {
+        let mut engine = Engine::new(vec![Rule::new("suspend")
+            .timeout(secs(900))
+            .actions("@suspend")
+            .hibernate_below(10.0)]);
+        engine.handle(Input::OnBattery(true));
+        engine.handle(Input::BatteryLevel(5.0));
+        assert_eq!(
+            commands(engine.handle(Input::Idle(secs(900)))),
+            [("@suspend-then-hibernate".to_string(), "idled")]
+        );
+    }
+
+    #[test]
+    fn inhibited_rules_hold_off_unless_ignoring_inhibitors() {
+        let mut engine = Engine::new(vec![
+            Rule::new("lock").timeout(secs(300)).actions("@lock"),
+            Rule::new("off").timeout(secs(300)).actions("off").ignore_inhibitors(),
+        ]);
+        engine.handle(Input::Inhibited(true));
+        assert_eq!(commands(engine.handle(Input::Idle(secs(300)))), [("off".to_string(), "idled")]);
+
+        engine.handle(Input::Inhibited(false));
+        assert_eq!(commands(engine.handle(Input::Idle(secs(301)))), [("@lock".to_string(), "idled")]);
+    }
+
+    #[test]
+    fn lock_rules_skip_while_locked() {
+        let mut engine = engine();
+        engine.handle(Input::SessionLocked(true));
+        let fired = commands(engine.handle(Input::Idle(secs(300))));
+        assert_eq!(fired, [("dim".to_string(), "idled")]);
+    }
+
+    #[test]
+    fn battery_rules_are_not_restored_on_ac() {
+        let mut engine = Engine::new(vec![Rule::new("dim")
+            .timeout(secs(60))
+            .actions("dim")
+            .restore("undim")
+            .on_battery_only()]);
+        engine.handle(Input::OnBattery(true));
+        engine.handle(Input::Idle(secs(60)));
+        engine.handle(Input::OnBattery(false));
+        assert!(engine.handle(Input::Active(secs(1))).is_empty());
+        assert!(!engine.rules()[0].is_idled());
+    }
+}
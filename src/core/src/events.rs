@@ -0,0 +1,77 @@
+//! Daemon lifecycle events, broadcast to the integrations (MQTT, webhooks).
+
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+/// Events are dropped for subscribers lagging further behind
+const CAPACITY: usize = 64;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// A rule fired
+    Idle { rule: String },
+    /// Activity after a rule fired
+    Resume { rule: String },
+    Lock,
+    Unlock,
+    Suspend,
+    Wakeup,
+    /// A keyboard or pointing device was plugged in, `kind` being `keyboard` or `pointer`
+    InputAdded { device: String, kind: String, external: bool },
+    InputRemoved { device: String, kind: String, external: bool },
+}
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::Idle { .. } => "idle",
+            Event::Resume { .. } => "resume",
+            Event::Lock => "lock",
+            Event::Unlock => "unlock",
+            Event::Suspend => "suspend",
+            Event::Wakeup => "wakeup",
+            Event::InputAdded { .. } => "input_added",
+            Event::InputRemoved { .. } => "input_removed",
+        }
+    }
+
+    /// The rule concerned, for rule events
+    pub fn rule(&self) -> Option<&str> {
+        match self {
+            Event::Idle { rule } | Event::Resume { rule } => Some(rule),
+            _ => None,
+        }
+    }
+
+    /// The event as sent to integrations, with the current time.
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        #[derive(Serialize)]
+        struct Payload<'a> {
+            #[serde(flatten)]
+            event: &'a Event,
+            /// Seconds since the epoch
+            timestamp: u64,
+        }
+
+        serde_json::to_string(&Payload {
+            event: self,
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+        })
+    }
+}
+
+lazy_static! {
+    static ref EVENTS: broadcast::Sender<Event> = broadcast::channel(CAPACITY).0;
+}
+
+pub fn emit(event: Event) {
+    // Fails only when nobody listens
+    let _ = EVENTS.send(event);
+}
+
+pub fn subscribe() -> broadcast::Receiver<Event> {
+    EVENTS.subscribe()
+}
@@ -0,0 +1,521 @@
+//! Spawning of the external commands configured as actions and hooks.
+
+use tracing::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    process::{Child, Command},
+    sync::oneshot,
+    time::sleep,
+};
+
+use zbus::zvariant::Value;
+
+use crate::{history, notify, systemd, utils};
+
+/// A command to run, and how.
+#[derive(Debug, Clone)]
+pub struct Action {
+    pub command: String,
+    /// Run through `sh -c` instead of splitting on whitespace
+    pub shell: bool,
+    /// Only used for rule commands
+    pub concurrency: Concurrency,
+    /// Terminate the command when it runs longer than this
+    pub timeout: Option<Duration>,
+    pub backend: ExecBackend,
+    pub limits: ResourceLimits,
+    pub sandbox: Option<Sandbox>,
+    /// Further attempts when the command fails to start
+    pub retries: u32,
+    pub context: ActionContext,
+}
+
+/// Why an action runs, exported to commands as `HYPNOS_*` variables.
+#[derive(Debug, Clone, Default)]
+pub struct ActionContext {
+    /// `idled`, `resumed`, `lock`, `before_sleep`...
+    pub event: &'static str,
+    pub rule: Option<String>,
+    pub timeout_ms: Option<u64>,
+    pub on_battery: Option<bool>,
+    pub idle_duration: Option<Duration>,
+}
+
+/// What to do when a rule fires while its previous command is still running.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Concurrency {
+    /// Start another copy
+    #[default]
+    Parallel,
+    Skip,
+    /// Start once the previous one exited
+    Queue,
+    KillPrevious,
+}
+
+/// How commands are started.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExecBackend {
+    /// As children of hypnos
+    #[default]
+    Direct,
+    /// As transient units of the systemd user manager, in their own cgroup
+    Systemd,
+}
+
+/// I/O scheduling class, as in ionice(1).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IoClass {
+    /// Lowest priority of the best-effort class
+    BestEffort,
+    /// Only gets disk time when nobody else needs it
+    Idle,
+}
+
+impl IoClass {
+    /// Value of IOSchedulingClass, and of the class bits of ioprio_set(2)
+    fn class(self) -> i32 {
+        match self {
+            IoClass::BestEffort => 2,
+            IoClass::Idle => 3,
+        }
+    }
+}
+
+/// The `limits` of a rule, so heavy jobs started on idle don't starve the session.
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct ResourceLimits {
+    pub nice: Option<i32>,
+    pub ionice: Option<IoClass>,
+    /// Bytes, systemd backend only
+    pub memory_max: Option<u64>,
+    /// Percent of one CPU, systemd backend only
+    pub cpu_quota: Option<u32>,
+}
+
+impl ResourceLimits {
+    /// Lowest priority within a class
+    const IOPRIO_LEVEL: i32 = 7;
+    const IOPRIO_CLASS_SHIFT: i32 = 13;
+    const IOPRIO_WHO_PROCESS: i32 = 1;
+
+    /// Run in the forked child right before exec.
+    fn apply_to_self(&self) -> std::io::Result<()> {
+        // SAFETY: plain syscalls on the calling process
+        unsafe {
+            if let Some(nice) = self.nice {
+                if libc::setpriority(libc::PRIO_PROCESS, 0, nice) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            if let Some(ionice) = self.ionice {
+                let ioprio = (ionice.class() << Self::IOPRIO_CLASS_SHIFT) | Self::IOPRIO_LEVEL;
+                if libc::syscall(libc::SYS_ioprio_set, Self::IOPRIO_WHO_PROCESS, 0, ioprio) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn unit_properties(&self) -> Vec<(&'static str, Value<'static>)> {
+        let mut properties = Vec::new();
+        if let Some(nice) = self.nice {
+            properties.push(("Nice", Value::from(nice)));
+        }
+        if let Some(ionice) = self.ionice {
+            properties.push(("IOSchedulingClass", Value::from(ionice.class())));
+            properties.push(("IOSchedulingPriority", Value::from(Self::IOPRIO_LEVEL)));
+        }
+        if let Some(memory_max) = self.memory_max {
+            properties.push(("MemoryMax", Value::from(memory_max)));
+        }
+        if let Some(cpu_quota) = self.cpu_quota {
+            // CPUQuota=100% is one second of CPU time per second
+            properties.push(("CPUQuotaPerSecUSec", Value::from(cpu_quota as u64 * 10_000)));
+        }
+        properties
+    }
+}
+
+/// The `sandbox` of a rule: run its commands in bubblewrap, with the whole
+/// filesystem read-only except what is listed in `writable`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Sandbox {
+    /// Also allow writing to $HOME
+    #[serde(default)]
+    pub writable_home: bool,
+    #[serde(default)]
+    pub network: bool,
+    /// Paths bound read-write
+    #[serde(default)]
+    pub writable: Vec<String>,
+    /// Let the command talk to the compositor, through the Wayland socket
+    #[serde(default)]
+    pub wayland: bool,
+    /// Let the command talk to the session bus. It can then run anything outside of the
+    /// sandbox (`systemd-run --user`...), only for trusted commands
+    #[serde(default)]
+    pub session_bus: bool,
+}
+
+/// The socket of the session bus, from `DBUS_SESSION_BUS_ADDRESS` or where it usually is.
+fn session_bus_socket(runtime_dir: &str) -> String {
+    std::env::var("DBUS_SESSION_BUS_ADDRESS")
+        .ok()
+        .and_then(|address| {
+            address
+                .split(';')
+                .filter_map(|address| address.strip_prefix("unix:"))
+                .flat_map(|params| params.split(','))
+                .find_map(|param| param.strip_prefix("path=").map(str::to_string))
+        })
+        .unwrap_or_else(|| format!("{}/bus", runtime_dir))
+}
+
+impl Sandbox {
+    fn wrap(&self, argv: Vec<String>) -> Vec<String> {
+        let mut bwrap: Vec<String> = [
+            "bwrap", "--ro-bind", "/", "/", "--dev", "/dev", "--proc", "/proc", "--tmpfs", "/tmp",
+            "--unshare-pid", "--die-with-parent",
+        ]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+
+        // Hidden, a socket in there is as good as a way out of the sandbox (the bus, the
+        // systemd user instance...), only those asked for are bound back
+        if let Ok(runtime_dir) = std::env::var("XDG_RUNTIME_DIR") {
+            bwrap.extend(["--tmpfs".to_string(), runtime_dir.clone()]);
+            let mut sockets = Vec::new();
+            if self.wayland {
+                let display = std::env::var("WAYLAND_DISPLAY").unwrap_or_else(|_| "wayland-0".to_string());
+                if display.starts_with('/') {
+                    sockets.push(display);
+                } else {
+                    sockets.push(format!("{}/{}", runtime_dir, display));
+                }
+            }
+            if self.session_bus {
+                sockets.push(session_bus_socket(&runtime_dir));
+            }
+            for socket in sockets {
+                bwrap.extend(["--ro-bind".to_string(), socket.clone(), socket]);
+            }
+        }
+
+        let mut bind = |path: String| bwrap.extend(["--bind".to_string(), path.clone(), path]);
+        if self.writable_home {
+            if let Ok(home) = std::env::var("HOME") {
+                bind(home);
+            }
+        }
+        for path in &self.writable {
+            bind(path.clone());
+        }
+
+        if !self.network {
+            bwrap.push("--unshare-net".to_string());
+        }
+        bwrap.push("--".to_string());
+        bwrap.extend(argv);
+        bwrap
+    }
+}
+
+/// Delay before the first retry of a command that failed to start, doubled each time
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// How long a command gets to exit after SIGTERM before it is SIGKILLed
+const KILL_GRACE: Duration = Duration::from_secs(5);
+
+/// A rule command that is running, or waiting for the previous run to exit.
+#[derive(Default)]
+struct Slot {
+    lock: Arc<tokio::sync::Mutex<()>>,
+    /// Kills the current run
+    kill: Option<oneshot::Sender<()>>,
+}
+
+/// Only log the commands instead of running them
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+pub fn set_dry_run(dry_run: bool) {
+    DRY_RUN.store(dry_run, Ordering::SeqCst);
+}
+
+pub fn is_dry_run() -> bool {
+    DRY_RUN.load(Ordering::SeqCst)
+}
+
+lazy_static::lazy_static! {
+    /// Keyed by rule name and command
+    static ref RUNNING: Mutex<HashMap<String, Slot>> = Mutex::new(HashMap::new());
+}
+
+/// `HYPNOS_*` variables telling the command why it runs.
+fn context_env(context: &ActionContext) -> Vec<(&'static str, String)> {
+    let mut env = vec![("HYPNOS_EVENT", context.event.to_string())];
+    if let Some(rule) = &context.rule {
+        env.push(("HYPNOS_RULE_NAME", rule.clone()));
+    }
+    if let Some(timeout_ms) = context.timeout_ms {
+        env.push(("HYPNOS_TIMEOUT_MS", timeout_ms.to_string()));
+    }
+    if let Some(on_battery) = context.on_battery {
+        env.push(("HYPNOS_ON_BATTERY", on_battery.to_string()));
+    }
+    if let Some(idle_duration) = context.idle_duration {
+        env.push(("HYPNOS_IDLE_DURATION", idle_duration.as_secs().to_string()));
+    }
+    env
+}
+
+fn build_command(action: &Action) -> Command {
+    let argv = argv(action);
+    let mut command = Command::new(&argv[0]);
+    command.args(&argv[1..]);
+    command.envs(context_env(&action.context));
+
+    let limits = action.limits.clone();
+    if limits.memory_max.is_some() || limits.cpu_quota.is_some() {
+        warn!("memory_max and cpu_quota need the systemd exec_backend, ignoring them for '{}'", action.command);
+    }
+    if limits.nice.is_some() || limits.ionice.is_some() {
+        // SAFETY: only async-signal-safe syscalls between fork and exec
+        unsafe {
+            command.pre_exec(move || limits.apply_to_self());
+        }
+    }
+    command
+}
+
+/// The program and its arguments, according to the shell mode and sandbox.
+fn argv(action: &Action) -> Vec<String> {
+    let argv = if action.shell {
+        debug!("Executing (shell): {}", action.command);
+        vec!["sh".to_string(), "-c".to_string(), action.command.clone()]
+    } else {
+        debug!("Executing: {}", action.command);
+        let (cmd_prog, args) = utils::get_args(action.command.clone());
+        std::iter::once(cmd_prog).chain(args).collect()
+    };
+
+    match &action.sandbox {
+        Some(sandbox) => sandbox.wrap(argv),
+        None => argv,
+    }
+}
+
+/// Run `action` in the background.
+pub fn run(action: Action) {
+    tokio::spawn(run_and_wait(action));
+}
+
+/// Run `action` and wait for it to exit, honoring its rule's concurrency policy.
+/// Commands run as systemd units are only started. Returns how it failed, as
+/// recorded in the history, None when it succeeded, was skipped or only started.
+#[tracing::instrument(skip_all, fields(command = %action.command, rule = action.context.rule.as_deref()))]
+pub async fn run_and_wait(action: Action) -> Option<String> {
+    if is_dry_run() {
+        info!("Would run: {}", action.command);
+        history::record(history::Entry::new(&action.context, &action.command, "dry-run".to_string(), Duration::ZERO));
+        return None;
+    }
+    if action.backend == ExecBackend::Systemd {
+        return run_unit(&action).await;
+    }
+
+    let key = match (&action.context.rule, action.concurrency) {
+        (Some(rule), concurrency) if concurrency != Concurrency::Parallel => format!("{}:{}", rule, action.command),
+        _ => return execute(&action, None).await,
+    };
+
+    let (lock, previous) = {
+        let mut slots = RUNNING.lock().unwrap();
+        let slot = slots.entry(key.clone()).or_default();
+        let previous = match action.concurrency {
+            Concurrency::KillPrevious => slot.kill.take(),
+            _ => None,
+        };
+        (slot.lock.clone(), previous)
+    };
+
+    let _guard = match action.concurrency {
+        Concurrency::Skip => match lock.try_lock_owned() {
+            Ok(guard) => guard,
+            Err(_) => {
+                info!("'{}' is still running, skipping it", action.command);
+                return None;
+            }
+        },
+        Concurrency::KillPrevious => {
+            if let Some(kill) = previous {
+                info!("'{}' is still running, killing it", action.command);
+                let _ = kill.send(());
+            }
+            lock.lock_owned().await
+        }
+        Concurrency::Queue | Concurrency::Parallel => {
+            if lock.try_lock().is_err() {
+                debug!("'{}' is still running, queueing it", action.command);
+            }
+            lock.lock_owned().await
+        }
+    };
+
+    let (kill_tx, kill_rx) = oneshot::channel();
+    if let Some(slot) = RUNNING.lock().unwrap().get_mut(&key) {
+        slot.kill = Some(kill_tx);
+    }
+    execute(&action, Some(kill_rx)).await
+}
+
+/// Try `start` up to `action.retries` more times with exponential backoff,
+/// with a desktop notification when it still fails. The error is the status recorded.
+async fn with_retries<T, F, Fut>(action: &Action, mut start: F) -> Result<T, String>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = anyhow::Result<T>>,
+{
+    let mut delay = RETRY_DELAY;
+    let mut attempt = 0;
+    loop {
+        match start().await {
+            Ok(started) => return Ok(started),
+            Err(e) if attempt < action.retries => {
+                warn!("Failed to start '{}': {}, retrying in {:?}", action.command, e, delay);
+                sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(e) => {
+                error!("Failed to start '{}': {}", action.command, e);
+                let status = format!("failed to start: {}", e);
+                record(action, status.clone(), Duration::ZERO);
+                if action.retries > 0 {
+                    let body = format!("'{}' failed after {} attempts: {}", action.command, action.retries + 1, e);
+                    notify::warn("Action failed", &body);
+                }
+                return Err(status);
+            }
+        }
+    }
+}
+
+async fn execute(action: &Action, kill: Option<oneshot::Receiver<()>>) -> Option<String> {
+    let mut child = match with_retries(action, || async { Ok(build_command(action).spawn()?) }).await {
+        Ok(child) => child,
+        Err(status) => return Some(status),
+    };
+
+    let killed = async {
+        match kill {
+            Some(kill) => kill.await.is_ok(),
+            None => false,
+        }
+    };
+    let timed_out = async {
+        match action.timeout {
+            Some(timeout) => sleep(timeout).await,
+            None => std::future::pending().await,
+        }
+    };
+
+    let started = Instant::now();
+    // Along with whether it failed
+    let (outcome, failed) = tokio::select! {
+        status = child.wait() => match status {
+            Ok(status) => {
+                debug!("Command '{}' finished with {}", action.command, status);
+                (status.to_string(), !status.success())
+            }
+            Err(e) => {
+                error!("Command '{}' failed to wait: {}", action.command, e);
+                (format!("failed to wait: {}", e), true)
+            }
+        },
+        true = killed => {
+            terminate(&mut child, &action.command).await;
+            ("killed by a newer run".to_string(), false)
+        }
+        _ = timed_out => {
+            warn!("'{}' still running after {:?}, terminating it", action.command, action.timeout.unwrap_or_default());
+            terminate(&mut child, &action.command).await;
+            ("timed out".to_string(), true)
+        }
+    };
+    record(action, outcome.clone(), started.elapsed());
+    failed.then_some(outcome)
+}
+
+fn record(action: &Action, status: String, duration: Duration) {
+    history::record(history::Entry::new(&action.context, &action.command, status, duration));
+}
+
+async fn run_unit(action: &Action) -> Option<String> {
+    let argv = argv(action);
+
+    let mut env: Vec<String> = context_env(&action.context)
+        .into_iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect();
+    // The user manager does not always have the session's display
+    for key in ["WAYLAND_DISPLAY", "DISPLAY"] {
+        if let Ok(value) = std::env::var(key) {
+            env.push(format!("{}={}", key, value));
+        }
+    }
+
+    let start = || async {
+        let mut argv = argv.clone();
+        let path = utils::find_in_path(&argv[0]).ok_or_else(|| anyhow::anyhow!("{} not found in PATH", argv[0]))?;
+        argv[0] = path.to_string_lossy().into_owned();
+
+        let mut properties = action.limits.unit_properties();
+        if let Some(timeout) = action.timeout {
+            properties.push(("RuntimeMaxUSec", Value::from(timeout.as_micros() as u64)));
+        }
+        systemd::start_transient_unit(argv, env.clone(), properties).await
+    };
+
+    match with_retries(action, start).await {
+        Ok(unit) => {
+            debug!("Started '{}' as {}", action.command, unit);
+            record(action, format!("started as {}", unit), Duration::ZERO);
+            None
+        }
+        Err(status) => Some(status),
+    }
+}
+
+/// SIGTERM the child, then SIGKILL it if it doesn't exit within `KILL_GRACE`.
+async fn terminate(child: &mut Child, command: &str) {
+    if let Some(pid) = child.id() {
+        // SAFETY: the child is not reaped yet, so its pid can't have been reused
+        unsafe {
+            libc::kill(pid as libc::pid_t, libc::SIGTERM);
+        }
+        if let Ok(Ok(status)) = tokio::time::timeout(KILL_GRACE, child.wait()).await {
+            info!("'{}' terminated, {}", command, status);
+            return;
+        }
+    }
+
+    warn!("'{}' ignored SIGTERM, killing it", command);
+    if let Err(e) = child.kill().await {
+        error!("Failed to kill '{}': {}", command, e);
+    }
+}
@@ -0,0 +1,134 @@
+//! Append-only log of the executed actions, in $XDG_STATE_HOME/hypnos/, also broadcast
+//! as they're recorded.
+
+use lazy_static::lazy_static;
+use tracing::error;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::broadcast;
+use xdg::BaseDirectories;
+
+use crate::{config, utils};
+use crate::executor::ActionContext;
+
+const HISTORY_FILE: &str = "history.jsonl";
+/// The log is rotated to `history.jsonl.1` past this size
+const MAX_SIZE: u64 = 1024 * 1024;
+
+/// Entries are dropped for subscribers lagging further behind
+const CAPACITY: usize = 64;
+
+/// Serializes writers, the daemon logs from several tasks
+static WRITE_LOCK: Mutex<()> = Mutex::new(());
+
+lazy_static! {
+    static ref RECORDED: broadcast::Sender<Entry> = broadcast::channel(CAPACITY).0;
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    /// Seconds since the epoch
+    pub timestamp: u64,
+    pub rule: Option<String>,
+    /// What triggered the action (`idled`, `lock`...)
+    pub event: String,
+    pub command: String,
+    /// `exit status: 0`, `terminated`, `failed to start`...
+    pub status: String,
+    pub duration_ms: u64,
+}
+
+impl Entry {
+    pub fn new(context: &ActionContext, command: &str, status: String, duration: Duration) -> Self {
+        Self {
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+            rule: context.rule.clone(),
+            event: context.event.to_string(),
+            command: command.to_string(),
+            status,
+            duration_ms: duration.as_millis() as u64,
+        }
+    }
+}
+
+fn history_path() -> std::io::Result<PathBuf> {
+    BaseDirectories::with_prefix(config::APP_NAME).place_state_file(HISTORY_FILE)
+}
+
+/// Append `entry` to the log, rotating it when it grew too large.
+pub fn record(entry: Entry) {
+    if let Err(e) = append(&entry) {
+        error!("Failed to write the action history: {}", e);
+    }
+    // Fails only when nobody listens
+    let _ = RECORDED.send(entry);
+}
+
+/// The entries recorded from now on.
+pub fn subscribe() -> broadcast::Receiver<Entry> {
+    RECORDED.subscribe()
+}
+
+fn append(entry: &Entry) -> anyhow::Result<()> {
+    let _guard = WRITE_LOCK.lock().unwrap();
+    let path = history_path()?;
+
+    if fs::metadata(&path).is_ok_and(|metadata| metadata.len() > MAX_SIZE) {
+        fs::rename(&path, path.with_extension("jsonl.1"))?;
+    }
+
+    let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+    writeln!(file, "{}", serde_json::to_string(entry)?)?;
+    Ok(())
+}
+
+/// The last `count` entries, oldest first.
+fn read_last(count: usize) -> anyhow::Result<Vec<Entry>> {
+    let path = history_path()?;
+    let mut entries = Vec::new();
+    for path in [path.with_extension("jsonl.1"), path] {
+        let Ok(file) = fs::File::open(&path) else {
+            continue;
+        };
+        for line in BufReader::new(file).lines() {
+            // Skip lines cut short by a crash
+            if let Ok(entry) = serde_json::from_str(&line?) {
+                entries.push(entry);
+            }
+        }
+    }
+
+    let skip = entries.len().saturating_sub(count);
+    Ok(entries.split_off(skip))
+}
+
+pub fn print(count: usize, json: bool) -> anyhow::Result<()> {
+    let entries = read_last(count)?;
+
+    if json {
+        println!("{}", serde_json::to_string(&entries)?);
+        return Ok(());
+    }
+
+    if entries.is_empty() {
+        println!("No action executed yet");
+    }
+    for entry in entries {
+        println!(
+            "{}  {:<12} {:<14} {:>7}ms  {}  ({})",
+            utils::format_local_time(entry.timestamp, c"%Y-%m-%d %H:%M:%S"),
+            entry.event,
+            entry.rule.as_deref().unwrap_or("-"),
+            entry.duration_ms,
+            entry.command,
+            entry.status,
+        );
+    }
+    Ok(())
+}
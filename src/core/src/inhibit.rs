@@ -0,0 +1,168 @@
+//! Whether hypnos itself is holding off idling, and on whose behalf.
+//!
+//! Each inhibition is a cookie in a registry: they stack, and idling is held off
+//! until the last one is released or expired. An inhibition can be limited to some
+//! rules, see `Scope`, the others fire as usual.
+
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, sync::Mutex, time::Instant};
+
+pub type Cookie = u32;
+
+/// The rules an inhibition holds off.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    #[default]
+    All,
+    /// By name
+    Rules(Vec<String>),
+    /// Those putting the machine to sleep, e.g. `@suspend`
+    Sleep,
+}
+
+impl Scope {
+    /// Every rule when `rules` is empty.
+    pub fn rules(rules: &[String]) -> Self {
+        if rules.is_empty() {
+            Self::All
+        } else {
+            Self::Rules(rules.to_vec())
+        }
+    }
+
+    fn holds(&self, rule: &str, sleeps: bool) -> bool {
+        // A rule's warning goes with it
+        let rule = rule.strip_suffix(":warning").unwrap_or(rule);
+        match self {
+            Self::All => true,
+            Self::Rules(rules) => rules.iter().any(|name| name == rule),
+            Self::Sleep => sleeps,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    owner: String,
+    reason: String,
+    scope: Scope,
+    /// None until released
+    expires: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct Registry {
+    entries: BTreeMap<Cookie, Entry>,
+    next_cookie: Cookie,
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    entries: BTreeMap::new(),
+    next_cookie: 1,
+});
+
+/// An inhibition, as listed by `list()`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Inhibitor {
+    pub cookie: Cookie,
+    /// Who holds it, e.g. `gamepad` or a DBus client
+    pub owner: String,
+    pub reason: String,
+    /// Seconds before it expires, None until released
+    pub remaining: Option<u64>,
+    #[serde(default)]
+    pub scope: Scope,
+}
+
+pub fn is_inhibited() -> bool {
+    !REGISTRY.lock().unwrap().entries.is_empty()
+}
+
+/// Whether an inhibition holds every rule off, as the compositor's idle inhibitor does.
+pub fn inhibits_all() -> bool {
+    REGISTRY.lock().unwrap().entries.values().any(|entry| entry.scope == Scope::All)
+}
+
+/// Whether an inhibition holds `rule` off, `sleeps` when its actions put the machine to sleep.
+pub fn inhibits(rule: &str, sleeps: bool) -> bool {
+    REGISTRY.lock().unwrap().entries.values().any(|entry| entry.scope.holds(rule, sleeps))
+}
+
+/// Start a new inhibition, lasting until released or until `expires`.
+pub fn acquire(owner: &str, reason: &str, expires: Option<Instant>) -> Cookie {
+    acquire_for(owner, reason, Scope::All, expires)
+}
+
+/// Like `acquire`, only holding off the rules in `scope`.
+pub fn acquire_for(owner: &str, reason: &str, scope: Scope, expires: Option<Instant>) -> Cookie {
+    let mut registry = REGISTRY.lock().unwrap();
+    let cookie = registry.next_cookie;
+    registry.next_cookie = registry.next_cookie.wrapping_add(1).max(1);
+    registry.entries.insert(
+        cookie,
+        Entry {
+            owner: owner.to_string(),
+            reason: reason.to_string(),
+            scope,
+            expires,
+        },
+    );
+    cookie
+}
+
+/// Like `acquire`, reusing the inhibition `owner` already holds, if any. Its expiry is
+/// only ever pushed back.
+pub fn extend(owner: &str, reason: &str, expires: Option<Instant>) -> Cookie {
+    let mut registry = REGISTRY.lock().unwrap();
+    if let Some((cookie, entry)) = registry.entries.iter_mut().find(|(_, entry)| entry.owner == owner) {
+        entry.reason = reason.to_string();
+        entry.expires = match (entry.expires, expires) {
+            (Some(current), Some(expires)) => Some(current.max(expires)),
+            _ => None,
+        };
+        return *cookie;
+    }
+    drop(registry);
+    acquire(owner, reason, expires)
+}
+
+/// Returns whether the cookie was held.
+pub fn release(cookie: Cookie) -> bool {
+    REGISTRY.lock().unwrap().entries.remove(&cookie).is_some()
+}
+
+/// Release every inhibition of `owner`, returns how many there were.
+pub fn release_owner(owner: &str) -> usize {
+    let mut registry = REGISTRY.lock().unwrap();
+    let before = registry.entries.len();
+    registry.entries.retain(|_, entry| entry.owner != owner);
+    before - registry.entries.len()
+}
+
+/// Drop the inhibitions past their expiry.
+pub fn release_expired() {
+    let now = Instant::now();
+    REGISTRY
+        .lock()
+        .unwrap()
+        .entries
+        .retain(|_, entry| entry.expires.is_none_or(|expires| expires > now));
+}
+
+pub fn list() -> Vec<Inhibitor> {
+    let now = Instant::now();
+    REGISTRY
+        .lock()
+        .unwrap()
+        .entries
+        .iter()
+        .map(|(cookie, entry)| Inhibitor {
+            cookie: *cookie,
+            owner: entry.owner.clone(),
+            reason: entry.reason.clone(),
+            remaining: entry.expires.map(|expires| expires.saturating_duration_since(now).as_secs()),
+            scope: entry.scope.clone(),
+        })
+        .collect()
+}
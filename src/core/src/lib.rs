@@ -0,0 +1,14 @@
+//! The parts of hypnos that don't depend on Wayland: the rules and their conditions,
+//! the command executor, and the event bus. The `hypnos` binary is one frontend,
+//! others can feed the rules from their own idle sources.
+
+pub mod config;
+pub mod engine;
+pub mod events;
+pub mod executor;
+pub mod history;
+pub mod inhibit;
+pub mod notify;
+pub mod rules;
+mod systemd;
+pub mod utils;
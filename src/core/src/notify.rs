@@ -0,0 +1,172 @@
+//! Desktop notifications, through org.freedesktop.Notifications.
+
+use futures::stream::{self, StreamExt};
+use tracing::debug;
+use std::{collections::HashMap, time::Duration};
+use zbus::{proxy, zvariant::Value};
+
+use crate::{config, utils};
+
+#[proxy(
+    interface = "org.freedesktop.Notifications",
+    default_service = "org.freedesktop.Notifications",
+    default_path = "/org/freedesktop/Notifications"
+)]
+trait Notifications {
+    #[allow(clippy::too_many_arguments)]
+    fn notify(
+        &self,
+        app_name: &str,
+        replaces_id: u32,
+        app_icon: &str,
+        summary: &str,
+        body: &str,
+        actions: &[&str],
+        hints: HashMap<&str, Value<'_>>,
+        expire_timeout: i32,
+    ) -> zbus::Result<u32>;
+
+    #[zbus(signal)]
+    fn action_invoked(&self, id: u32, action_key: String) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    fn notification_closed(&self, id: u32, reason: u32) -> zbus::Result<()>;
+}
+
+/// Key of the action added by `--snooze`
+pub const SNOOZE_ACTION: &str = "snooze";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Urgency {
+    Low = 0,
+    Normal = 1,
+    Critical = 2,
+}
+
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub summary: String,
+    pub body: String,
+    pub urgency: Urgency,
+    /// Milliseconds, -1 lets the notification server decide
+    pub timeout: i32,
+    pub icon: String,
+    /// Buttons, as (key, label)
+    pub actions: Vec<(String, String)>,
+}
+
+impl Notification {
+    pub fn new(summary: &str, body: &str) -> Self {
+        Self {
+            summary: summary.to_string(),
+            body: body.to_string(),
+            urgency: Urgency::Normal,
+            timeout: -1,
+            icon: String::new(),
+            actions: Vec::new(),
+        }
+    }
+
+    /// Parse the arguments of the `@notify` built-in, which follow notify-send:
+    /// `[-u low|normal|critical] [-t ms] [-i icon] summary [body]`, plus `--snooze` to
+    /// offer snoozing the rules.
+    pub fn parse(args: &str) -> anyhow::Result<Self> {
+        let mut notification = Self::new("", "");
+        let mut positional = Vec::new();
+        let mut args = utils::split_quoted(args).into_iter();
+
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| anyhow::anyhow!("{} needs a value", arg));
+            match arg.as_str() {
+                "-u" | "--urgency" => {
+                    notification.urgency = match value()?.as_str() {
+                        "low" => Urgency::Low,
+                        "normal" => Urgency::Normal,
+                        "critical" => Urgency::Critical,
+                        other => anyhow::bail!("Unknown urgency '{}'", other),
+                    }
+                }
+                "-t" | "--expire-time" => notification.timeout = value()?.parse()?,
+                "-i" | "--icon" => notification.icon = value()?,
+                "--snooze" => notification
+                    .actions
+                    .push((SNOOZE_ACTION.to_string(), "Snooze 10 min".to_string())),
+                _ => positional.push(arg),
+            }
+        }
+
+        let mut positional = positional.into_iter();
+        notification.summary = positional.next().ok_or_else(|| anyhow::anyhow!("@notify needs a summary"))?;
+        notification.body = positional.collect::<Vec<_>>().join(" ");
+        Ok(notification)
+    }
+
+    /// Show the notification, returns its id.
+    pub async fn send(&self) -> anyhow::Result<u32> {
+        let conn = zbus::Connection::session().await?;
+        let proxy = NotificationsProxy::new(&conn).await?;
+        self.notify(&proxy).await
+    }
+
+    /// Show the notification and wait for it to go away, returns the key of the action
+    /// the user picked, if any.
+    pub async fn send_and_wait(&self) -> anyhow::Result<Option<String>> {
+        let conn = zbus::Connection::session().await?;
+        let proxy = NotificationsProxy::new(&conn).await?;
+        // Subscribed before sending, so a quick click isn't missed
+        let invoked = proxy.receive_action_invoked().await?.filter_map(|signal| async move {
+            let args = signal.args().ok()?;
+            Some((args.id, Some(args.action_key)))
+        });
+        let closed = proxy
+            .receive_notification_closed()
+            .await?
+            .filter_map(|signal| async move { Some((signal.args().ok()?.id, None)) });
+        let mut events = std::pin::pin!(stream::select(invoked, closed));
+
+        let id = self.notify(&proxy).await?;
+        while let Some((event_id, action)) = events.next().await {
+            if event_id == id {
+                return Ok(action);
+            }
+        }
+        Ok(None)
+    }
+
+    async fn notify(&self, proxy: &NotificationsProxy<'_>) -> anyhow::Result<u32> {
+        let hints = HashMap::from([("urgency", Value::from(self.urgency as u8))]);
+        let actions: Vec<&str> = self
+            .actions
+            .iter()
+            .flat_map(|(key, label)| [key.as_str(), label.as_str()])
+            .collect();
+        let id = proxy
+            .notify(config::APP_NAME, 0, &self.icon, &self.summary, &self.body, &actions, hints, self.timeout)
+            .await?;
+        Ok(id)
+    }
+}
+
+/// Warn the user about something going wrong in hypnos itself, in the background.
+pub fn warn(summary: &str, body: &str) {
+    let notification = Notification::new(summary, body);
+    tokio::spawn(async move {
+        if let Err(e) = notification.send().await {
+            debug!("Failed to send a notification: {}", e);
+        }
+    });
+}
+
+/// Like `warn`, but usable outside of the runtime (e.g. from the panic hook): the
+/// notification is sent from its own thread, waiting at most `timeout`.
+pub fn warn_blocking(summary: &str, body: &str, timeout: Duration) {
+    let notification = Notification::new(summary, body);
+    let sender = std::thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        runtime.block_on(tokio::time::timeout(timeout, notification.send()))??;
+        anyhow::Ok(())
+    });
+    if let Ok(Err(e)) = sender.join() {
+        debug!("Failed to send a notification: {}", e);
+    }
+}
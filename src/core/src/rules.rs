@@ -0,0 +1,461 @@
+//! Idle rules: their configuration, their state, and whether they may fire.
+
+use tracing::debug;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+use tokio::task::AbortHandle;
+
+use crate::executor::{Action, ActionContext, Concurrency, ExecBackend, ResourceLimits, Sandbox};
+
+/// A rule as written in the configuration.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RuleConfig {
+    pub timeout: i32,
+    pub actions: String,
+    pub restore: Option<String>,
+    #[serde(default)]
+    pub on_battery: Option<bool>,
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// Fire on input inactivity alone, even while idle inhibitors are active
+    #[serde(default)]
+    pub ignore_inhibitors: bool,
+    /// Don't fire while the session is already locked
+    #[serde(default)]
+    pub skip_when_locked: bool,
+    /// Don't fire while a remote desktop client is connected, e.g. for rules turning the screens off
+    #[serde(default)]
+    pub skip_when_remote: bool,
+    /// Count the timeout from when the session got locked instead of from the last input
+    #[serde(default)]
+    pub after_lock: bool,
+    /// Run the actions again for an output plugged in while idle, e.g. to turn it off too
+    #[serde(default)]
+    pub reapply_on_output: bool,
+    /// Battery percentage below which `@suspend` becomes `@suspend-then-hibernate`
+    #[serde(default)]
+    pub hibernate_below: Option<f64>,
+    /// Only fire when the hottest thermal zone is at least this hot, in °C
+    #[serde(default)]
+    pub min_temperature: Option<f64>,
+    /// Don't fire when the hottest thermal zone is hotter than this, in °C
+    #[serde(default)]
+    pub max_temperature: Option<f64>,
+    /// Overrides the global `shell`
+    #[serde(default)]
+    pub shell: Option<bool>,
+    #[serde(default)]
+    pub concurrency: Concurrency,
+    /// Overrides the global `command_timeout`
+    #[serde(default)]
+    pub command_timeout: Option<u64>,
+    /// Overrides the global `exec_backend`
+    #[serde(default)]
+    pub exec_backend: Option<ExecBackend>,
+    #[serde(default)]
+    pub limits: ResourceLimits,
+    /// Overrides the global `sandbox`
+    #[serde(default)]
+    pub sandbox: Option<Sandbox>,
+    /// Seconds before the rule may fire again
+    #[serde(default)]
+    pub min_interval: Option<u64>,
+    /// Seconds of activity before `restore` runs
+    #[serde(default)]
+    pub resume_debounce: Option<u64>,
+    /// Overrides the global `retries`
+    #[serde(default)]
+    pub retries: Option<u32>,
+    /// WASM plugins that may hold the rule off
+    #[serde(default)]
+    pub plugins: Vec<String>,
+    /// Rhai expression that has to hold for the rule to fire
+    #[serde(default)]
+    pub condition: Option<String>,
+    /// Seconds ahead of the rule `warn_command` runs
+    #[serde(default)]
+    pub warn_before: Option<u64>,
+    /// Warning that the rule is about to fire, e.g. a chime or `@notify`
+    #[serde(default)]
+    pub warn_command: Option<String>,
+}
+
+impl RuleConfig {
+    /// Whether the rule locks the session, then it's pointless while already locked.
+    fn skips_when_locked(&self, defaults: &RuleDefaults) -> bool {
+        self.skip_when_locked || self.actions == "@lock" || defaults.on_lock.as_ref() == Some(&self.actions)
+    }
+
+    /// The rule running `warn_command` `warn_before` seconds ahead of this one, held off
+    /// by the same conditions, when both are set.
+    pub fn warning(&self, defaults: &RuleDefaults) -> Option<RuleConfig> {
+        let (before, command) = (self.warn_before?, self.warn_command.clone()?);
+        Some(RuleConfig {
+            timeout: self.timeout.saturating_sub(before.try_into().unwrap_or(i32::MAX)).max(1),
+            actions: command,
+            restore: None,
+            skip_when_locked: self.skips_when_locked(defaults),
+            hibernate_below: None,
+            resume_debounce: None,
+            reapply_on_output: false,
+            warn_before: None,
+            warn_command: None,
+            ..self.clone()
+        })
+    }
+}
+
+/// I hate you, serde
+pub(crate) fn default_true() -> bool {
+    true
+}
+
+/// Global settings the rules fall back to.
+#[derive(Debug, Clone, Default)]
+pub struct RuleDefaults {
+    pub shell: bool,
+    /// Seconds
+    pub command_timeout: Option<u64>,
+    pub exec_backend: ExecBackend,
+    pub sandbox: Option<Sandbox>,
+    pub retries: u32,
+    /// The `on_lock` hook, rules running it are skipped while locked
+    pub on_lock: Option<String>,
+}
+
+/// What the rules' conditions are checked against when they fire.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Conditions {
+    pub on_battery: Option<bool>,
+    /// Percent
+    pub battery_level: Option<f64>,
+    pub session_locked: bool,
+    /// A VNC/RDP client is connected to the session
+    pub remote_desktop: bool,
+    /// hypnos itself is holding off idling, for this rule
+    pub inhibited: bool,
+    /// Hottest thermal zone, in °C
+    pub temperature: Option<f64>,
+}
+
+/// An armed rule.
+#[derive(Debug)]
+pub struct Rule {
+    pub name: String,
+    pub actions: String,
+    pub restore: Option<String>,
+    pub timeout: i32,
+    pub on_battery: bool,
+    pub ignore_inhibitors: bool,
+    /// Turn `@suspend` into `@suspend-then-hibernate` on battery below this level
+    pub hibernate_below: Option<f64>,
+    /// °C
+    pub min_temperature: Option<f64>,
+    /// °C
+    pub max_temperature: Option<f64>,
+    pub shell: bool,
+    pub concurrency: Concurrency,
+    pub command_timeout: Option<Duration>,
+    pub exec_backend: ExecBackend,
+    pub limits: ResourceLimits,
+    pub sandbox: Option<Sandbox>,
+    pub retries: u32,
+    /// Locks the screen, pointless while the session is already locked
+    pub skip_when_locked: bool,
+    /// Turns the screens off or the like, pointless for a remote user
+    pub skip_when_remote: bool,
+    /// Run the actions again for an output plugged in while idle
+    pub reapply_on_output: bool,
+    /// Set while the rule's action has run and no resume was seen yet.
+    pub idled_since: Option<Instant>,
+    /// Don't fire again sooner than this after the last run
+    pub min_interval: Option<Duration>,
+    pub last_fired: Option<Instant>,
+    /// Only restore once activity lasted this long
+    pub resume_debounce: Option<Duration>,
+    /// The delayed restore, while waiting for `resume_debounce`
+    pub pending_restore: Option<AbortHandle>,
+    /// WASM plugins that may hold the rule off, checked by the daemon
+    pub plugins: Vec<String>,
+    /// Rhai expression that has to hold for the rule to fire, checked by the daemon
+    pub condition: Option<String>,
+    /// Set on the rules running another's `warn_command`: how long before it fires
+    pub warning: Option<Duration>,
+}
+
+impl Rule {
+    /// An empty rule, set up with the builder methods below.
+    pub fn new(name: impl Into<String>) -> Self {
+        Self::from_config(
+            name.into(),
+            RuleConfig {
+                timeout: 0,
+                actions: String::new(),
+                restore: None,
+                on_battery: None,
+                enabled: true,
+                ignore_inhibitors: false,
+                skip_when_locked: false,
+                skip_when_remote: false,
+                after_lock: false,
+                reapply_on_output: false,
+                hibernate_below: None,
+                min_temperature: None,
+                max_temperature: None,
+                shell: None,
+                concurrency: Concurrency::default(),
+                command_timeout: None,
+                exec_backend: None,
+                limits: ResourceLimits::default(),
+                sandbox: None,
+                min_interval: None,
+                resume_debounce: None,
+                retries: None,
+                plugins: Vec::new(),
+                condition: None,
+                warn_before: None,
+                warn_command: None,
+            },
+            &RuleDefaults::default(),
+        )
+    }
+
+    /// Idle time before the rule fires, rounded down to the second.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout.as_secs().try_into().unwrap_or(i32::MAX);
+        self
+    }
+
+    /// `@lock` makes the rule skip when locked, as in the configuration.
+    pub fn actions(mut self, actions: impl Into<String>) -> Self {
+        self.actions = actions.into();
+        self.skip_when_locked |= self.actions == "@lock";
+        self
+    }
+
+    pub fn restore(mut self, restore: impl Into<String>) -> Self {
+        self.restore = Some(restore.into());
+        self
+    }
+
+    pub fn on_battery_only(mut self) -> Self {
+        self.on_battery = true;
+        self
+    }
+
+    pub fn ignore_inhibitors(mut self) -> Self {
+        self.ignore_inhibitors = true;
+        self
+    }
+
+    pub fn skip_when_locked(mut self) -> Self {
+        self.skip_when_locked = true;
+        self
+    }
+
+    pub fn skip_when_remote(mut self) -> Self {
+        self.skip_when_remote = true;
+        self
+    }
+
+    /// Run the actions again for an output plugged in while idle.
+    pub fn reapply_on_output(mut self) -> Self {
+        self.reapply_on_output = true;
+        self
+    }
+
+    /// Battery percentage below which `@suspend` becomes `@suspend-then-hibernate`.
+    pub fn hibernate_below(mut self, level: f64) -> Self {
+        self.hibernate_below = Some(level);
+        self
+    }
+
+    /// Only fire at this temperature or above, in °C.
+    pub fn min_temperature(mut self, celsius: f64) -> Self {
+        self.min_temperature = Some(celsius);
+        self
+    }
+
+    /// Don't fire above this temperature, in °C.
+    pub fn max_temperature(mut self, celsius: f64) -> Self {
+        self.max_temperature = Some(celsius);
+        self
+    }
+
+    pub fn shell(mut self, shell: bool) -> Self {
+        self.shell = shell;
+        self
+    }
+
+    pub fn concurrency(mut self, concurrency: Concurrency) -> Self {
+        self.concurrency = concurrency;
+        self
+    }
+
+    pub fn command_timeout(mut self, timeout: Duration) -> Self {
+        self.command_timeout = Some(timeout);
+        self
+    }
+
+    pub fn exec_backend(mut self, backend: ExecBackend) -> Self {
+        self.exec_backend = backend;
+        self
+    }
+
+    pub fn limits(mut self, limits: ResourceLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn sandbox(mut self, sandbox: Sandbox) -> Self {
+        self.sandbox = Some(sandbox);
+        self
+    }
+
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    pub fn min_interval(mut self, interval: Duration) -> Self {
+        self.min_interval = Some(interval);
+        self
+    }
+
+    pub fn resume_debounce(mut self, debounce: Duration) -> Self {
+        self.resume_debounce = Some(debounce);
+        self
+    }
+
+    pub fn plugin(mut self, name: impl Into<String>) -> Self {
+        self.plugins.push(name.into());
+        self
+    }
+
+    pub fn condition(mut self, condition: impl Into<String>) -> Self {
+        self.condition = Some(condition.into());
+        self
+    }
+
+    pub fn from_config(name: String, config: RuleConfig, defaults: &RuleDefaults) -> Self {
+        let skip_when_locked = config.skips_when_locked(defaults);
+
+        Self {
+            name,
+            timeout: config.timeout,
+            actions: config.actions,
+            restore: config.restore,
+            on_battery: config.on_battery.unwrap_or(false),
+            ignore_inhibitors: config.ignore_inhibitors,
+            hibernate_below: config.hibernate_below,
+            min_temperature: config.min_temperature,
+            max_temperature: config.max_temperature,
+            skip_when_locked,
+            skip_when_remote: config.skip_when_remote,
+            reapply_on_output: config.reapply_on_output,
+            shell: config.shell.unwrap_or(defaults.shell),
+            concurrency: config.concurrency,
+            command_timeout: config.command_timeout.or(defaults.command_timeout).map(Duration::from_secs),
+            exec_backend: config.exec_backend.unwrap_or(defaults.exec_backend),
+            limits: config.limits,
+            sandbox: config.sandbox.or_else(|| defaults.sandbox.clone()),
+            retries: config.retries.unwrap_or(defaults.retries),
+            idled_since: None,
+            min_interval: config.min_interval.map(Duration::from_secs),
+            last_fired: None,
+            resume_debounce: config.resume_debounce.map(Duration::from_secs),
+            pending_restore: None,
+            plugins: config.plugins,
+            condition: config.condition,
+            warning: None,
+        }
+    }
+
+    pub fn is_idled(&self) -> bool {
+        self.idled_since.is_some()
+    }
+
+    /// Whether the rule puts the machine to sleep, with `@suspend` and the like.
+    pub fn sleeps(&self) -> bool {
+        let actions = self.actions.trim_start();
+        ["@suspend", "@hibernate", "@hybrid-sleep"].iter().any(|builtin| actions.starts_with(builtin))
+    }
+
+    /// The commands to run now that the session went idle, None when a condition holds
+    /// the rule back.
+    pub fn idle_actions(&self, conditions: &Conditions) -> Option<String> {
+        if self.on_battery && !conditions.on_battery.unwrap_or(false) {
+            debug!("Skipping idle command '{}' because on AC power", self.actions);
+            return None;
+        }
+
+        // Our own inhibitor is not always honored by the compositor (the surface
+        // is never mapped), so check it here too unless the rule opts out
+        if !self.ignore_inhibitors && conditions.inhibited {
+            debug!("Skipping idle command '{}' because hypnos is inhibiting idle", self.actions);
+            return None;
+        }
+
+        if self.skip_when_locked && conditions.session_locked {
+            debug!("Skipping idle command '{}' because the session is already locked", self.actions);
+            return None;
+        }
+
+        if self.skip_when_remote && conditions.remote_desktop {
+            debug!("Skipping idle command '{}' because a remote desktop client is connected", self.actions);
+            return None;
+        }
+
+        // Without thermal zones, the machine is never hot
+        if let Some(min) = self.min_temperature {
+            if conditions.temperature.is_none_or(|temperature| temperature < min) {
+                debug!("Skipping idle command '{}' because it's cooler than {}°C", self.actions, min);
+                return None;
+            }
+        }
+        if let Some(max) = self.max_temperature {
+            if conditions.temperature.is_some_and(|temperature| temperature > max) {
+                debug!("Skipping idle command '{}' because it's hotter than {}°C", self.actions, max);
+                return None;
+            }
+        }
+
+        if let (Some(min_interval), Some(last_fired)) = (self.min_interval, self.last_fired) {
+            if last_fired.elapsed() < min_interval {
+                debug!("Skipping idle command '{}', it ran less than {:?} ago", self.actions, min_interval);
+                return None;
+            }
+        }
+
+        let mut actions = self.actions.clone();
+        if let (Some(threshold), Some(level), Some(true)) = (self.hibernate_below, conditions.battery_level, conditions.on_battery) {
+            if actions == "@suspend" && level < threshold {
+                debug!("Battery at {}%, below {}%, hibernating later", level, threshold);
+                actions = "@suspend-then-hibernate".to_string();
+            }
+        }
+        Some(actions)
+    }
+
+    /// Run `command` on behalf of this rule.
+    pub fn action(&self, command: String, event: &'static str, on_battery: Option<bool>) -> Action {
+        let timeout = Duration::from_secs(self.timeout.max(0) as u64);
+        Action {
+            command,
+            shell: self.shell,
+            concurrency: self.concurrency,
+            timeout: self.command_timeout,
+            backend: self.exec_backend,
+            limits: self.limits.clone(),
+            sandbox: self.sandbox.clone(),
+            retries: self.retries,
+            context: ActionContext {
+                event,
+                rule: Some(self.name.clone()),
+                timeout_ms: Some(timeout.as_millis() as u64),
+                on_battery,
+                idle_duration: Some(timeout + self.idled_since.map(|since| since.elapsed()).unwrap_or_default()),
+            },
+        }
+    }
+}
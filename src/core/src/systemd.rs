@@ -0,0 +1,46 @@
+//! Transient units of the user manager, for the `systemd` exec backend.
+
+use zbus::zvariant::{OwnedObjectPath, Value};
+use zbus::{proxy, Connection, Result};
+
+#[proxy(
+    interface = "org.freedesktop.systemd1.Manager",
+    default_service = "org.freedesktop.systemd1",
+    default_path = "/org/freedesktop/systemd1"
+)]
+trait SystemdManager {
+    // Signature: (ssa(sv)a(sa(sv))) -> o
+    fn start_transient_unit(
+        &self,
+        name: &str,
+        mode: &str,
+        properties: &[(&str, Value<'_>)],
+        aux: &[(&str, &[(&str, Value<'_>)])],
+    ) -> Result<OwnedObjectPath>;
+}
+
+/// Start `argv` as a transient service of the user manager, returns the unit name.
+/// `extra` holds additional unit properties (limits, timeouts...).
+pub async fn start_transient_unit(
+    argv: Vec<String>,
+    env: Vec<String>,
+    extra: Vec<(&'static str, Value<'static>)>,
+) -> anyhow::Result<String> {
+    let connection = Connection::session().await?;
+    let manager = SystemdManagerProxy::new(&connection).await?;
+    let name = format!("hypnos-action-{}.service", uuid::Uuid::new_v4().simple());
+
+    let description = format!("hypnos action: {}", argv.join(" "));
+    let exec_start = vec![(argv[0].clone(), argv, false)];
+    let mut properties = vec![
+        ("Description", Value::from(description)),
+        ("ExecStart", Value::from(exec_start)),
+        ("Environment", Value::from(env)),
+        // Don't keep failed units around, their logs stay in the journal
+        ("CollectMode", Value::from("inactive-or-failed")),
+    ];
+    properties.extend(extra);
+
+    manager.start_transient_unit(&name, "fail", &properties, &[]).await?;
+    Ok(name)
+}
@@ -0,0 +1,118 @@
+use std::{ffi::CStr, path::PathBuf, time::Duration};
+use xdg::BaseDirectories;
+
+use super::config;
+
+pub fn get_args(cmd: String) -> (String, Vec<String>) {
+    let mut args = cmd.split_whitespace();
+    let cmd = args.next().unwrap().to_string();
+    let args: Vec<String> = args.map(|s| s.to_string()).collect();
+    (cmd, args)
+}
+
+/// Parse a duration like `90`, `45s`, `30m`, `2h` or `1h30m`, bare numbers being seconds.
+pub fn parse_duration(input: &str) -> Result<Duration, String> {
+    let invalid = || format!("invalid duration '{}', expected e.g. 90s, 30m or 1h30m", input);
+    let mut seconds = 0;
+    let mut number = String::new();
+    for c in input.trim().chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            _ => return Err(invalid()),
+        };
+        let value: u64 = number.parse().map_err(|_| invalid())?;
+        seconds += value * unit;
+        number.clear();
+    }
+    if !number.is_empty() {
+        seconds += number.parse::<u64>().map_err(|_| invalid())?;
+    } else if seconds == 0 && input.trim().is_empty() {
+        return Err(invalid());
+    }
+    Ok(Duration::from_secs(seconds))
+}
+
+/// Split on whitespace, keeping "double" or 'single' quoted parts together.
+pub fn split_quoted(input: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quote: Option<char> = None;
+
+    for c in input.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => word.push(c),
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                in_word = true;
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            (None, c) => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    words
+}
+
+/// Resolve a program name the way the shell would, as systemd wants absolute paths.
+pub fn find_in_path(program: &str) -> Option<PathBuf> {
+    if program.contains('/') {
+        return Some(PathBuf::from(program));
+    }
+    std::env::var_os("PATH").and_then(|paths| {
+        std::env::split_paths(&paths)
+            .map(|dir| dir.join(program))
+            .find(|path| path.is_file())
+    })
+}
+
+pub fn xdg_config_path(filename: Option<String>) -> std::io::Result<PathBuf> {
+    let xdg_dirs = BaseDirectories::with_prefix(config::APP_NAME);
+
+    match filename {
+        Some(filename) => {
+            let config_path: PathBuf = xdg_dirs.place_config_file(filename)?;
+            Ok(config_path)
+        }
+        None => {
+            let config_path: PathBuf = xdg_dirs.get_config_home().unwrap();
+            Ok(config_path)
+        }
+    }
+}
+
+/// Format `timestamp` (seconds since the epoch) as local time, with strftime.
+pub fn format_local_time(timestamp: u64, format: &CStr) -> String {
+    let time = timestamp as libc::time_t;
+    let mut buf = [0u8; 64];
+    // SAFETY: localtime_r fills the tm we own, strftime writes at most buf.len() bytes
+    unsafe {
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&time, &mut tm).is_null() {
+            return timestamp.to_string();
+        }
+        let len = libc::strftime(buf.as_mut_ptr() as *mut libc::c_char, buf.len(), format.as_ptr(), &tm);
+        if len == 0 {
+            return timestamp.to_string();
+        }
+    }
+    CStr::from_bytes_until_nul(&buf).map(|s| s.to_string_lossy().into_owned()).unwrap_or_default()
+}
@@ -0,0 +1,72 @@
+//! Battery timeouts following the discharge rate: shorter while draining fast
+//! (gaming on battery), longer while the drain is light.
+
+use serde::{Deserialize, Serialize};
+
+/// Relative change of the factor below which the rules aren't re-armed, the rate
+/// moves a little on every UPower update
+const MIN_CHANGE: f64 = 0.1;
+
+/// The `adaptive_timeouts` section of the config.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AdaptiveTimeouts {
+    /// `[watts, factor]` points, interpolated in between and flat beyond the ends
+    #[serde(default = "default_curve")]
+    pub curve: Vec<(f64, f64)>,
+}
+
+fn default_curve() -> Vec<(f64, f64)> {
+    vec![(5.0, 1.5), (15.0, 1.0), (30.0, 0.5)]
+}
+
+impl Default for AdaptiveTimeouts {
+    fn default() -> Self {
+        Self { curve: default_curve() }
+    }
+}
+
+impl AdaptiveTimeouts {
+    /// The factor for a discharge rate of `watts`.
+    pub fn factor(&self, watts: f64) -> f64 {
+        let mut curve = self.curve.clone();
+        curve.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let (Some(first), Some(last)) = (curve.first(), curve.last()) else {
+            return 1.0;
+        };
+        if watts <= first.0 {
+            return first.1;
+        }
+        if watts >= last.0 {
+            return last.1;
+        }
+        for pair in curve.windows(2) {
+            let ((low_watts, low), (high_watts, high)) = (pair[0], pair[1]);
+            if watts <= high_watts && high_watts > low_watts {
+                return low + (high - low) * (watts - low_watts) / (high_watts - low_watts);
+            }
+        }
+        last.1
+    }
+}
+
+/// The factor the `on_battery` rules' timeouts get, None when they are left alone.
+pub fn scale(config: Option<&AdaptiveTimeouts>, on_battery: Option<bool>, energy_rate: Option<f64>) -> Option<f64> {
+    match (config, on_battery, energy_rate) {
+        // UPower reports 0 while it doesn't know the rate yet
+        (Some(config), Some(true), Some(rate)) if rate > 0.0 => Some(config.factor(rate).max(0.0)),
+        _ => None,
+    }
+}
+
+/// Whether going from the `applied` factor to `wanted` is worth re-arming the rules.
+pub fn changed(applied: Option<f64>, wanted: Option<f64>) -> bool {
+    match (applied, wanted) {
+        (Some(applied), Some(wanted)) => (wanted - applied).abs() > applied * MIN_CHANGE,
+        (applied, wanted) => applied.is_some() != wanted.is_some(),
+    }
+}
+
+/// `timeout` in seconds scaled by `factor`, at least a second.
+pub fn scaled(timeout: i32, factor: f64) -> i32 {
+    ((timeout as f64 * factor).round() as i32).max(1)
+}
@@ -0,0 +1,40 @@
+//! Battery conservation: below a battery level, every timeout gets shorter until the
+//! machine is charging again.
+
+use serde::{Deserialize, Serialize};
+
+/// The `battery_saver` section of the config.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct BatterySaver {
+    /// Percent
+    #[serde(default = "default_below")]
+    pub below: f64,
+    /// Of every rule's timeout
+    #[serde(default = "default_factor")]
+    pub factor: f64,
+}
+
+fn default_below() -> f64 {
+    20.0
+}
+
+fn default_factor() -> f64 {
+    0.5
+}
+
+impl Default for BatterySaver {
+    fn default() -> Self {
+        Self {
+            below: default_below(),
+            factor: default_factor(),
+        }
+    }
+}
+
+/// The factor of every rule's timeout, None while the battery is fine or charging.
+pub fn scale(config: Option<&BatterySaver>, on_battery: Option<bool>, level: Option<f64>) -> Option<f64> {
+    match (config, on_battery, level) {
+        (Some(config), Some(true), Some(level)) if level < config.below => Some(config.factor.max(0.0)),
+        _ => None,
+    }
+}
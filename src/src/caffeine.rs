@@ -0,0 +1,52 @@
+//! `hypnos caffeinate`: keeping the session awake for a while, like the caffeine
+//! applets, with a notification once it wears off.
+
+use std::{fmt, str::FromStr, time::Duration};
+
+use crate::utils;
+
+/// Owner of the inhibition in the registry
+pub const OWNER: &str = "caffeinate";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Caffeine {
+    /// Until turned off
+    Indefinitely,
+    For(Duration),
+    /// Until no window is fullscreen anymore, e.g. the end of a film
+    UntilFullscreenEnds,
+    Off,
+}
+
+impl FromStr for Caffeine {
+    type Err = String;
+
+    fn from_str(preset: &str) -> Result<Self, Self::Err> {
+        match preset {
+            "" | "on" => Ok(Self::Indefinitely),
+            "off" => Ok(Self::Off),
+            "until-fullscreen-ends" => Ok(Self::UntilFullscreenEnds),
+            duration => utils::parse_duration(duration).map(Self::For),
+        }
+    }
+}
+
+impl fmt::Display for Caffeine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Indefinitely => write!(f, "until turned off"),
+            Self::For(duration) => write!(f, "for {}m", duration.as_secs().div_ceil(60)),
+            Self::UntilFullscreenEnds => write!(f, "until no window is fullscreen"),
+            Self::Off => write!(f, "off"),
+        }
+    }
+}
+
+impl Caffeine {
+    pub fn duration(&self) -> Option<Duration> {
+        match self {
+            Self::For(duration) => Some(*duration),
+            _ => None,
+        }
+    }
+}
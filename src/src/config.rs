@@ -1,3 +1,12 @@
-pub const APP_NAME: &str = "hypnos";
+pub use hypnos_core::config::{APP_NAME, DEFAULT_INHIBIT_SEC};
 pub const CONFIG_FILE: &str = include_str!("../configs/config.json");
-pub const TIMEOUT_SEC: u64 = 30;
+/// What the notifications' snooze action postpones the rules by
+pub const SNOOZE_SEC: u64 = 600;
+/// How long `before_sleep` may delay suspend, logind caps it with InhibitDelayMaxSec
+pub const BEFORE_SLEEP_TIMEOUT_SEC: u64 = 5;
+/// How long the restore commands and `on_exit` may delay exiting
+pub const SHUTDOWN_TIMEOUT_SEC: u64 = 5;
+/// Longest wait between attempts to reconnect to a restarted compositor
+pub const RECONNECT_MAX_DELAY_SEC: u64 = 30;
+/// Exit status after a panic, e.g. for `RestartForceExitStatus=` in the unit
+pub const PANIC_EXIT_CODE: i32 = 70;
@@ -0,0 +1,155 @@
+//! `hypnos config`: showing the configuration as the daemon reads it, and editing it
+//! safely, the running daemon only ever sees a version that parses.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::Path,
+    process::Command,
+};
+
+use serde_json::Value;
+
+use crate::{init, load_json_config, load_profile_config, profile, utils};
+
+/// `$VISUAL`, then `$EDITOR`, then vi.
+fn editor() -> String {
+    std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .ok()
+        .filter(|editor| !editor.trim().is_empty())
+        .unwrap_or_else(|| "vi".to_string())
+}
+
+/// The rules of a config, as written.
+fn rules(content: &str) -> BTreeMap<String, Value> {
+    let Ok(Value::Object(mut config)) = serde_json::from_str::<Value>(content) else {
+        return BTreeMap::new();
+    };
+    match config.remove("rules") {
+        Some(Value::Object(rules)) => rules.into_iter().collect(),
+        _ => BTreeMap::new(),
+    }
+}
+
+/// Print what changes in the rules, key by key.
+fn print_rules_diff(before: &str, after: &str) {
+    let (before, after) = (rules(before), rules(after));
+    let mut changed = false;
+    for (name, rule) in &after {
+        match before.get(name) {
+            None => println!("+ {}: {}", name, rule),
+            Some(old) if old != rule => {
+                println!("~ {}:", name);
+                let (Some(old), Some(new)) = (old.as_object(), rule.as_object()) else {
+                    println!("    {} -> {}", old, rule);
+                    continue;
+                };
+                let keys: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+                for key in keys {
+                    match (old.get(key), new.get(key)) {
+                        (Some(a), Some(b)) if a != b => println!("    {}: {} -> {}", key, a, b),
+                        (Some(a), None) => println!("    {}: {} -> (default)", key, a),
+                        (None, Some(b)) => println!("    {}: (default) -> {}", key, b),
+                        _ => {}
+                    }
+                }
+            }
+            Some(_) => continue,
+        }
+        changed = true;
+    }
+    for name in before.keys().filter(|name| !after.contains_key(*name)) {
+        println!("- {}", name);
+        changed = true;
+    }
+    if !changed {
+        println!("The rules are unchanged");
+    }
+}
+
+/// Write then rename, so the daemon watching the file never reads half of it. A
+/// symlink, e.g. into a dotfiles repository, is written through instead.
+fn replace(path: &Path, content: &str) -> std::io::Result<()> {
+    if path.symlink_metadata().is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+        return fs::write(path, content);
+    }
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, content)?;
+    fs::rename(tmp, path)
+}
+
+/// Open the config in the editor, and only save it once it's valid and confirmed. The
+/// daemon reloads it as soon as it's saved.
+pub fn edit(config_name: String) -> anyhow::Result<()> {
+    let path = utils::xdg_config_path(Some(config_name))?;
+    let original = fs::read_to_string(&path).unwrap_or_default();
+    let file_name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    let draft = std::env::temp_dir().join(format!("hypnos-{}-{}", std::process::id(), file_name));
+    fs::write(&draft, &original)?;
+
+    let result = (|| -> anyhow::Result<()> {
+        loop {
+            let editor = editor();
+            let status = Command::new("sh")
+                .arg("-c")
+                .arg(format!("{} \"$1\"", editor))
+                .arg("sh")
+                .arg(&draft)
+                .status()?;
+            if !status.success() {
+                anyhow::bail!("{} exited with {}, the configuration is left as it was", editor, status);
+            }
+
+            let edited = fs::read_to_string(&draft)?;
+            if edited == original {
+                println!("No changes");
+                return Ok(());
+            }
+            match load_json_config(&draft) {
+                Ok(config) => {
+                    for problem in config.problems() {
+                        println!("Warning: {}", problem);
+                    }
+                    print_rules_diff(&original, &edited);
+                    if init::confirm("Apply?", true)? {
+                        replace(&path, &edited)?;
+                        println!("Saved {:?}, the running daemon reloads it", path);
+                        return Ok(());
+                    }
+                }
+                Err(e) => println!("Invalid configuration: {}", e),
+            }
+            if !init::confirm("Edit again?", true)? {
+                println!("The configuration is left as it was");
+                return Ok(());
+            }
+        }
+    })();
+    let _ = fs::remove_file(&draft);
+    result
+}
+
+/// Print the configuration as the daemon reads it: normalized JSON, with the defaults
+/// filled in and the active profile applied. The warnings and the file it comes from go
+/// to stderr.
+pub fn show(config_name: String) -> anyhow::Result<()> {
+    let path = utils::xdg_config_path(Some(config_name))?;
+    let active = profile::load();
+    let config = load_profile_config(&path, active.as_deref())
+        .map_err(|e| anyhow::anyhow!("Invalid configuration {:?}: {}", path, e))?;
+    match &active {
+        Some(active) if config.profiles.contains_key(active) => eprintln!("From {:?}, with the {} profile", path, active),
+        _ => eprintln!("From {:?}", path),
+    }
+    for problem in config.problems() {
+        eprintln!("Warning: {}", problem);
+    }
+    let mut config = serde_json::to_value(&config)?;
+    // Not for the terminal's scrollback
+    if let Some(password) = config.pointer_mut("/mqtt/password").filter(|password| !password.is_null()) {
+        *password = Value::from("********");
+    }
+    println!("{}", serde_json::to_string_pretty(&config)?);
+    Ok(())
+}
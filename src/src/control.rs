@@ -0,0 +1,662 @@
+use futures::stream::StreamExt;
+use tracing::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+use lazy_static::lazy_static;
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
+use zbus::{interface, object_server::SignalEmitter, proxy};
+
+use crate::{executor, history, inhibit};
+use crate::events::{self, Event};
+use crate::caffeine::Caffeine;
+use crate::profile;
+use crate::rules::{self, IdleEvent};
+use crate::types::{NotificationListHandle, Request, SharedGlobals};
+use crate::wayland::{IdleNotification, OutputInfo};
+
+pub const BUS_NAME: &str = "org.axos.Hypnos";
+pub const OBJECT_PATH: &str = "/org/axos/Hypnos";
+/// How long the event loop may take to answer a ping
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    /// Marked changed whenever something shown in the status may have changed
+    static ref STATE_CHANGED: watch::Sender<()> = watch::channel(()).0;
+}
+
+/// Let `StateChanged` subscribers know, the status is compared before emitting.
+pub fn state_changed() {
+    STATE_CHANGED.send_replace(());
+}
+
+/// Woken by `state_changed`, e.g. to export the state elsewhere.
+pub fn subscribe() -> watch::Receiver<()> {
+    STATE_CHANGED.subscribe()
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Status {
+    pub paused: bool,
+    pub on_battery: Option<bool>,
+    pub inhibited: bool,
+    /// What hypnos holds off idling for
+    #[serde(default)]
+    pub inhibitors: Vec<inhibit::Inhibitor>,
+    #[serde(default)]
+    pub locked: bool,
+    #[serde(default)]
+    pub dry_run: bool,
+    /// `hypnos caffeinate` is on
+    #[serde(default)]
+    pub caffeinated: bool,
+    /// Another session is in the foreground
+    #[serde(default)]
+    pub session_inactive: bool,
+    /// A VNC/RDP client is connected
+    #[serde(default)]
+    pub remote_desktop: bool,
+    /// Factor of the `on_battery` rules' timeouts, from the discharge rate
+    #[serde(default)]
+    pub timeout_scale: Option<f64>,
+    /// Picked with `hypnos profile set`, None for the config as written
+    #[serde(default)]
+    pub profile: Option<String>,
+    /// Factor of every rule's timeout, from the battery saver
+    #[serde(default)]
+    pub saver_scale: Option<f64>,
+    /// Factor of every rule's timeout, from `hypnos scale`
+    #[serde(default)]
+    pub manual_scale: Option<f64>,
+    /// Watts drawn from the batteries
+    #[serde(default)]
+    pub energy_rate: Option<f64>,
+    /// The rule expected to fire next
+    #[serde(default)]
+    pub next_action: Option<rules::NextAction>,
+    pub rules: Vec<RuleStatus>,
+    pub outputs: Vec<OutputInfo>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RuleStatus {
+    pub name: String,
+    pub timeout: i32,
+    pub idled: bool,
+    #[serde(default)]
+    pub ignore_inhibitors: bool,
+    #[serde(default)]
+    pub after_lock: bool,
+}
+
+/// The daemon side of the control interface, served on the session bus.
+pub struct ControlInterface {
+    globals: SharedGlobals,
+    notification_list: NotificationListHandle,
+    tx: mpsc::Sender<Request>,
+    started: Instant,
+}
+
+impl ControlInterface {
+    fn snapshot(&self) -> Status {
+        let mut rules: Vec<RuleStatus> = self
+            .notification_list
+            .lock()
+            .unwrap()
+            .values()
+            .map(|rule| RuleStatus {
+                name: rule.name.clone(),
+                timeout: rule.timeout,
+                idled: rule.is_idled(),
+                ignore_inhibitors: rule.ignore_inhibitors,
+                after_lock: matches!(rule.notification, IdleNotification::Locked),
+            })
+            .collect();
+        rules.sort_by_key(|rule| rule.timeout);
+        let next_action = rules::next_action(&self.globals, &self.notification_list);
+
+        let globals = self.globals.lock().unwrap();
+        let mut outputs: Vec<(u32, OutputInfo)> = globals
+            .outputs
+            .iter()
+            .map(|(reg_name, output)| (*reg_name, output.info.clone()))
+            .collect();
+        outputs.sort_by_key(|(reg_name, _)| *reg_name);
+
+        Status {
+            paused: globals.is_paused,
+            on_battery: globals.on_battery,
+            inhibited: inhibit::is_inhibited(),
+            inhibitors: inhibit::list(),
+            locked: globals.session_locked(),
+            dry_run: executor::is_dry_run(),
+            caffeinated: globals.caffeine.is_some(),
+            session_inactive: globals.session_inactive,
+            remote_desktop: globals.remote_client,
+            timeout_scale: globals.timeout_scale,
+            energy_rate: globals.energy_rate,
+            manual_scale: globals.manual_scale,
+            saver_scale: globals.saver_scale,
+            profile: globals.profile.clone(),
+            next_action,
+            rules,
+            outputs: outputs.into_iter().map(|(_, info)| info).collect(),
+        }
+    }
+}
+
+#[interface(name = "org.axos.Hypnos1")]
+impl ControlInterface {
+    /// Current daemon state, serialized as JSON
+    fn status(&self) -> zbus::fdo::Result<String> {
+        serde_json::to_string(&self.snapshot()).map_err(|e| zbus::fdo::Error::Failed(e.to_string()))
+    }
+
+    /// Only log the commands instead of running them, or run them again
+    fn set_dry_run(&self, enabled: bool) {
+        info!("Dry run {}", if enabled { "enabled" } else { "disabled" });
+        executor::set_dry_run(enabled);
+        state_changed();
+    }
+
+    /// Fire the idle path of `rule` now, or its resume path with `resume`.
+    /// `force` bypasses its conditions.
+    fn simulate(&self, rule: &str, resume: bool, force: bool) -> zbus::fdo::Result<()> {
+        let uuid = self
+            .notification_list
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(_, handle)| handle.name == rule)
+            .map(|(uuid, _)| *uuid)
+            .ok_or_else(|| zbus::fdo::Error::InvalidArgs(format!("No armed rule named '{}'", rule)))?;
+
+        info!("Simulating the {} of '{}'", if resume { "resume" } else { "idle" }, rule);
+        let event = if resume { IdleEvent::Resumed } else { IdleEvent::Idled };
+        rules::idle_event(&self.globals, &self.notification_list, &self.tx, uuid, event, force);
+        state_changed();
+        Ok(())
+    }
+
+    /// Milliseconds since the last input.
+    fn idle_time(&self) -> u64 {
+        rules::session_idle_time(&self.globals, &self.notification_list).as_millis() as u64
+    }
+
+    /// The rule expected to fire next: its name, its actions and the seconds left if the
+    /// session stays idle. An empty name when no rule is pending.
+    #[zbus(property)]
+    fn next_action(&self) -> rules::NextAction {
+        rules::next_action(&self.globals, &self.notification_list).unwrap_or_default()
+    }
+
+    /// The version and uptime in seconds, once the event loop answered.
+    async fn ping(&self) -> zbus::fdo::Result<(String, u64)> {
+        let (reply, answered) = oneshot::channel();
+        let stuck = || zbus::fdo::Error::Failed("The event loop is not responding".to_string());
+        self.tx.send(Request::Ping(reply)).await.map_err(|_| stuck())?;
+        tokio::time::timeout(PING_TIMEOUT, answered).await.map_err(|_| stuck())?.map_err(|_| stuck())?;
+        Ok((env!("CARGO_PKG_VERSION").to_string(), self.started.elapsed().as_secs()))
+    }
+
+    /// Postpone the next firing of `rule` by `seconds`, of every rule that didn't fire
+    /// yet when `rule` is empty.
+    async fn snooze(&self, rule: &str, seconds: u64) -> zbus::fdo::Result<()> {
+        let rule = (!rule.is_empty()).then(|| rule.to_string());
+        if let Some(rule) = &rule {
+            let pending = self
+                .notification_list
+                .lock()
+                .unwrap()
+                .values()
+                .any(|handle| &handle.name == rule && !handle.is_idled());
+            if !pending {
+                return Err(zbus::fdo::Error::InvalidArgs(format!("No rule named '{}' waiting to fire", rule)));
+            }
+        }
+        let duration = Duration::from_secs(seconds);
+        let _ = self.tx.send(Request::Snooze { rule, duration }).await;
+        Ok(())
+    }
+
+    /// The profiles of the config, sorted.
+    fn list_profiles(&self) -> Vec<String> {
+        self.globals.lock().unwrap().profiles.clone()
+    }
+
+    /// The active profile, `default` for the config as written.
+    fn profile(&self) -> String {
+        self.globals.lock().unwrap().profile.clone().unwrap_or_else(|| profile::DEFAULT.to_string())
+    }
+
+    /// Switch to the profile `name`, `default` for the config as written.
+    async fn set_profile(&self, name: &str) -> zbus::fdo::Result<()> {
+        let profile = (name != profile::DEFAULT).then(|| name.to_string());
+        if let Some(profile) = &profile {
+            let profiles = self.globals.lock().unwrap().profiles.clone();
+            if !profiles.contains(profile) {
+                return Err(zbus::fdo::Error::InvalidArgs(format!(
+                    "No profile named '{}', expected {} or one of: {}",
+                    profile,
+                    profile::DEFAULT,
+                    profiles.join(", ")
+                )));
+            }
+        }
+        let _ = self.tx.send(Request::SetProfile(profile)).await;
+        Ok(())
+    }
+
+    /// Re-arm every rule with its timeout multiplied by `factor`, 1 to reset.
+    async fn scale(&self, factor: f64) -> zbus::fdo::Result<()> {
+        if !factor.is_finite() || factor <= 0.0 {
+            return Err(zbus::fdo::Error::InvalidArgs(format!("Invalid factor {}, expected a positive number", factor)));
+        }
+        let _ = self.tx.send(Request::Scale(factor)).await;
+        Ok(())
+    }
+
+    /// Keep the session awake: `on`, `off`, `until-fullscreen-ends` or a duration like `30m`.
+    async fn caffeinate(&self, preset: &str) -> zbus::fdo::Result<()> {
+        let caffeine: Caffeine = preset.parse().map_err(zbus::fdo::Error::InvalidArgs)?;
+        if caffeine == Caffeine::UntilFullscreenEnds {
+            let globals = self.globals.lock().unwrap();
+            if globals.toplevel_manager.is_none() {
+                return Err(zbus::fdo::Error::NotSupported(
+                    "The compositor doesn't tell about fullscreen windows (zwlr_foreign_toplevel_manager_v1)".to_string(),
+                ));
+            }
+            if globals.fullscreen_toplevels.is_empty() {
+                return Err(zbus::fdo::Error::Failed("No window is fullscreen".to_string()));
+            }
+        }
+        let _ = self.tx.send(Request::Caffeinate(caffeine)).await;
+        Ok(())
+    }
+
+    /// Emitted with the new status, as JSON, when it changed
+    #[zbus(signal)]
+    async fn state_changed(emitter: &SignalEmitter<'_>, status: &str) -> zbus::Result<()>;
+
+    /// A rule fired, or the session resumed after it did
+    #[zbus(signal)]
+    async fn idle_state_changed(emitter: &SignalEmitter<'_>, rule: &str, idle: bool) -> zbus::Result<()>;
+
+    /// A command ran: its rule (empty for hooks), what triggered it (`idled`, `lock`...)
+    /// and how it ended (`exit status: 0`, `terminated`...)
+    #[zbus(signal)]
+    async fn action_executed(
+        emitter: &SignalEmitter<'_>,
+        rule: &str,
+        event: &str,
+        command: &str,
+        status: &str,
+    ) -> zbus::Result<()>;
+
+    /// Whether idling is held off, and by what, as `owner: reason`
+    #[zbus(signal)]
+    async fn inhibit_changed(emitter: &SignalEmitter<'_>, inhibited: bool, inhibitors: &[String]) -> zbus::Result<()>;
+
+    /// The active profile, `default` for the config as written
+    #[zbus(signal)]
+    async fn profile_changed(emitter: &SignalEmitter<'_>, profile: &str) -> zbus::Result<()>;
+}
+
+#[proxy(
+    interface = "org.axos.Hypnos1",
+    default_service = "org.axos.Hypnos",
+    default_path = "/org/axos/Hypnos"
+)]
+trait Control {
+    fn status(&self) -> zbus::Result<String>;
+    fn set_dry_run(&self, enabled: bool) -> zbus::Result<()>;
+    fn simulate(&self, rule: &str, resume: bool, force: bool) -> zbus::Result<()>;
+    fn snooze(&self, rule: &str, seconds: u64) -> zbus::Result<()>;
+    fn ping(&self) -> zbus::Result<(String, u64)>;
+    fn idle_time(&self) -> zbus::Result<u64>;
+    fn caffeinate(&self, preset: &str) -> zbus::Result<()>;
+    fn scale(&self, factor: f64) -> zbus::Result<()>;
+    fn list_profiles(&self) -> zbus::Result<Vec<String>>;
+    fn profile(&self) -> zbus::Result<String>;
+    fn set_profile(&self, name: &str) -> zbus::Result<()>;
+    #[zbus(property)]
+    fn next_action(&self) -> zbus::Result<rules::NextAction>;
+
+    #[zbus(signal)]
+    fn state_changed(&self, status: String) -> zbus::Result<()>;
+    #[zbus(signal)]
+    fn idle_state_changed(&self, rule: String, idle: bool) -> zbus::Result<()>;
+    #[zbus(signal)]
+    fn action_executed(&self, rule: String, event: String, command: String, status: String) -> zbus::Result<()>;
+    #[zbus(signal)]
+    fn inhibit_changed(&self, inhibited: bool, inhibitors: Vec<String>) -> zbus::Result<()>;
+    #[zbus(signal)]
+    fn profile_changed(&self, profile: String) -> zbus::Result<()>;
+}
+
+/// Export the control interface. The returned connection must be kept alive.
+pub async fn serve(
+    globals: SharedGlobals,
+    notification_list: NotificationListHandle,
+    tx: mpsc::Sender<Request>,
+) -> anyhow::Result<zbus::Connection> {
+    let iface = ControlInterface {
+        globals,
+        notification_list,
+        tx,
+        started: Instant::now(),
+    };
+
+    let conn = zbus::connection::Builder::session()?
+        .name(BUS_NAME)?
+        .serve_at(OBJECT_PATH, iface)?
+        .build()
+        .await?;
+
+    debug!("Control interface available as {} on {}", BUS_NAME, OBJECT_PATH);
+    tokio::spawn(emit_changes(conn.clone()));
+    Ok(conn)
+}
+
+/// Emit the signals: `StateChanged` and the narrower ones derived from the status, and
+/// those of the rules' events and executed actions.
+async fn emit_changes(conn: zbus::Connection) -> anyhow::Result<()> {
+    let iface = conn.object_server().interface::<_, ControlInterface>(OBJECT_PATH).await?;
+    let emitter = iface.signal_emitter();
+    let mut changes = subscribe();
+    let mut events = events::subscribe();
+    let mut actions = history::subscribe();
+    let mut last = String::new();
+    let mut last_inhibitors = None;
+    let mut last_profile = None;
+    let mut last_next: Option<(String, u64)> = None;
+    loop {
+        tokio::select! {
+            changed = changes.changed() => {
+                changed?;
+                let snapshot = iface.get().await.snapshot();
+                let inhibitors: Vec<String> = snapshot
+                    .inhibitors
+                    .iter()
+                    .map(|inhibitor| format!("{}: {}", inhibitor.owner, inhibitor.reason))
+                    .collect();
+                if last_inhibitors.as_ref() != Some(&inhibitors) {
+                    ControlInterface::inhibit_changed(emitter, snapshot.inhibited, &inhibitors).await?;
+                    last_inhibitors = Some(inhibitors);
+                }
+                let profile = snapshot.profile.clone().unwrap_or_else(|| profile::DEFAULT.to_string());
+                if last_profile.as_ref() != Some(&profile) {
+                    ControlInterface::profile_changed(emitter, &profile).await?;
+                    last_profile = Some(profile);
+                }
+                // By when it fires, the seconds left alone change on every look
+                let next = snapshot.next_action.as_ref().map(|next| (next.rule.clone(), now_secs() + next.seconds));
+                let moved = match (&last_next, &next) {
+                    (Some((a, at)), Some((b, bt))) => a != b || at.abs_diff(*bt) > 1,
+                    (last, next) => last.is_some() != next.is_some(),
+                };
+                if moved {
+                    iface.get().await.next_action_changed(emitter).await?;
+                    last_next = next;
+                }
+                let status = serde_json::to_string(&snapshot)?;
+                if status != last {
+                    ControlInterface::state_changed(emitter, &status).await?;
+                    last = status;
+                }
+            }
+            event = events.recv() => match event {
+                Ok(Event::Idle { rule }) => ControlInterface::idle_state_changed(emitter, &rule, true).await?,
+                Ok(Event::Resume { rule }) => ControlInterface::idle_state_changed(emitter, &rule, false).await?,
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+            entry = actions.recv() => match entry {
+                Ok(entry) => {
+                    let rule = entry.rule.unwrap_or_default();
+                    ControlInterface::action_executed(emitter, &rule, &entry.event, &entry.command, &entry.status).await?;
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+        }
+    }
+}
+
+fn now_secs() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+async fn get_proxy() -> anyhow::Result<ControlProxy<'static>> {
+    let conn = zbus::Connection::session().await?;
+    match ControlProxy::new(&conn).await {
+        Ok(proxy) => Ok(proxy),
+        Err(e) => {
+            error!("Is the hypnos daemon running?");
+            Err(e.into())
+        }
+    }
+}
+
+pub async fn simulate(rule: &str, resume: bool, force: bool) -> anyhow::Result<()> {
+    get_proxy().await?.simulate(rule, resume, force).await?;
+    Ok(())
+}
+
+pub async fn snooze(duration: Duration, rule: Option<&str>) -> anyhow::Result<()> {
+    get_proxy().await?.snooze(rule.unwrap_or_default(), duration.as_secs()).await?;
+    Ok(())
+}
+
+pub async fn list_profiles() -> anyhow::Result<()> {
+    let proxy = get_proxy().await?;
+    let active = proxy.profile().await?;
+    for name in std::iter::once(profile::DEFAULT.to_string()).chain(proxy.list_profiles().await?) {
+        println!("{} {}", if name == active { "*" } else { " " }, name);
+    }
+    Ok(())
+}
+
+pub async fn get_profile() -> anyhow::Result<()> {
+    println!("{}", get_proxy().await?.profile().await?);
+    Ok(())
+}
+
+pub async fn set_profile(name: &str) -> anyhow::Result<()> {
+    get_proxy().await?.set_profile(name).await?;
+    Ok(())
+}
+
+pub async fn scale(factor: f64) -> anyhow::Result<()> {
+    get_proxy().await?.scale(factor).await?;
+    Ok(())
+}
+
+pub async fn caffeinate(preset: &str) -> anyhow::Result<()> {
+    get_proxy().await?.caffeinate(preset).await?;
+    Ok(())
+}
+
+pub async fn idle_time(ms: bool) -> anyhow::Result<()> {
+    let idle_ms = get_proxy().await?.idle_time().await?;
+    if ms {
+        println!("{}", idle_ms);
+    } else {
+        println!("{}", idle_ms / 1000);
+    }
+    Ok(())
+}
+
+pub async fn ping() -> anyhow::Result<()> {
+    let start = Instant::now();
+    let (version, uptime) = get_proxy().await?.ping().await?;
+    println!(
+        "hypnos {} is responsive ({} ms), up for {}h{:02}m",
+        version,
+        start.elapsed().as_millis(),
+        uptime / 3600,
+        uptime % 3600 / 60
+    );
+    Ok(())
+}
+
+pub async fn set_dry_run(enabled: bool) -> anyhow::Result<()> {
+    get_proxy().await?.set_dry_run(enabled).await?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusFormat {
+    Text,
+    Json,
+    Waybar,
+}
+
+pub async fn status(format: StatusFormat, follow: bool) -> anyhow::Result<()> {
+    let proxy = get_proxy().await?;
+    // Subscribe first, not to miss a change
+    let mut changes = proxy.receive_state_changed().await?;
+    print_status(&proxy.status().await?, format)?;
+
+    if !follow {
+        return Ok(());
+    }
+    while let Some(signal) = changes.next().await {
+        print_status(signal.args()?.status(), format)?;
+    }
+    Ok(())
+}
+
+fn print_status(raw: &str, format: StatusFormat) -> anyhow::Result<()> {
+    match format {
+        StatusFormat::Json => println!("{}", raw),
+        StatusFormat::Waybar => println!("{}", serde_json::to_string(&waybar(&serde_json::from_str(raw)?))?),
+        StatusFormat::Text => print_text(&serde_json::from_str(raw)?),
+    }
+    Ok(())
+}
+
+/// A line for a waybar `custom` module with `"return-type": "json"`.
+fn waybar(status: &Status) -> serde_json::Value {
+    let class = if status.paused {
+        "paused"
+    } else if status.caffeinated {
+        "caffeinated"
+    } else if status.inhibited {
+        "inhibited"
+    } else if status.locked {
+        "locked"
+    } else if status.rules.iter().any(|rule| rule.idled) {
+        "idle"
+    } else {
+        "active"
+    };
+
+    let mut tooltip = format!("Hypnos: {}", class);
+    for inhibitor in &status.inhibitors {
+        tooltip.push_str(&format!("\nInhibited by {}: {} ({})", inhibitor.owner, inhibitor.reason, remaining(inhibitor.remaining)));
+    }
+    if let Some(next) = &status.next_action {
+        tooltip.push_str(&format!("\n{} in {}", next.rule, countdown(next.seconds)));
+    }
+    for rule in &status.rules {
+        let state = if rule.idled { "idle" } else { "armed" };
+        tooltip.push_str(&format!("\n{} ({}s): {}", rule.name, rule.timeout, state));
+    }
+
+    serde_json::json!({
+        "text": class,
+        "alt": class,
+        "class": class,
+        "tooltip": tooltip,
+    })
+}
+
+/// Time left of an inhibition.
+fn remaining(seconds: Option<u64>) -> String {
+    match seconds {
+        Some(seconds) if seconds >= 60 => format!("{}m{:02}s left", seconds / 60, seconds % 60),
+        Some(seconds) => format!("{}s left", seconds),
+        None => "until released".to_string(),
+    }
+}
+
+/// `1:42`, `1:02:05` past the hour.
+fn countdown(seconds: u64) -> String {
+    match seconds {
+        3600.. => format!("{}:{:02}:{:02}", seconds / 3600, seconds % 3600 / 60, seconds % 60),
+        _ => format!("{}:{:02}", seconds / 60, seconds % 60),
+    }
+}
+
+fn print_text(status: &Status) {
+    let power = match status.on_battery {
+        Some(true) => "battery",
+        Some(false) => "AC",
+        None => "unknown",
+    };
+
+    let state = if status.paused {
+        "paused"
+    } else if status.session_inactive {
+        "paused (session inactive)"
+    } else {
+        "active"
+    };
+    println!("State:     {}", state);
+    println!("Power:     {}", power);
+    if let Some(profile) = &status.profile {
+        println!("Profile:   {}", profile);
+    }
+    println!("Inhibited: {}", if status.inhibited { "yes" } else { "no" });
+    for inhibitor in &status.inhibitors {
+        let rules = match &inhibitor.scope {
+            inhibit::Scope::All => String::new(),
+            inhibit::Scope::Rules(rules) => format!(", only {}", rules.join(", ")),
+            inhibit::Scope::Sleep => ", only sleeping".to_string(),
+        };
+        println!("  {:<16} {}  ({}{})", inhibitor.owner, inhibitor.reason, remaining(inhibitor.remaining), rules);
+    }
+    println!("Locked:    {}", if status.locked { "yes" } else { "no" });
+    if let Some(next) = &status.next_action {
+        println!("Next:      {} in {} if idle", next.rule, countdown(next.seconds));
+    }
+    if let (Some(scale), Some(rate)) = (status.timeout_scale, status.energy_rate) {
+        println!("Timeouts:  battery rules x{:.2} (discharging at {:.1} W)", scale, rate);
+    }
+    if let Some(scale) = status.saver_scale {
+        println!("Saver:     battery low, every timeout x{}", scale);
+    }
+    if let Some(scale) = status.manual_scale {
+        println!("Scale:     every timeout x{} (hypnos scale 1 to reset)", scale);
+    }
+    if status.remote_desktop {
+        println!("Remote:    client connected");
+    }
+    if status.dry_run {
+        println!("Dry run:   commands are only logged");
+    }
+
+    println!("Rules:");
+    for rule in &status.rules {
+        let state = if rule.idled { "idle" } else { "armed" };
+        let flags = match (rule.after_lock, rule.ignore_inhibitors) {
+            (true, _) => " (after lock)",
+            (false, true) => " (ignores inhibitors)",
+            (false, false) => "",
+        };
+        println!("  {:<16} {:>6}s  {}{}", rule.name, rule.timeout, state, flags);
+    }
+
+    println!("Outputs:");
+    for output in &status.outputs {
+        println!(
+            "  {:<10} {}x{}@{:.2}Hz scale {}  {}",
+            output.name.as_deref().unwrap_or("?"),
+            output.width,
+            output.height,
+            output.refresh as f64 / 1000.0,
+            output.scale,
+            output.description.as_deref().unwrap_or(""),
+        );
+    }
+}
@@ -0,0 +1,46 @@
+//! Countdown of the warning window to a FIFO, as read by wob or avizo: a percentage
+//! per line, from 100 when the warning fires down to 0 when its rule does.
+
+use tracing::debug;
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+use uuid::Uuid;
+
+use crate::types::NotificationListHandle;
+
+const TICK: Duration = Duration::from_secs(1);
+
+/// Count `window` down while the warning rule `uuid` stays idle.
+pub async fn run(pipe: PathBuf, window: Duration, list: NotificationListHandle, uuid: Uuid) {
+    // Without a reader, opening fails instead of blocking until one shows up
+    let mut fifo = match OpenOptions::new().write(true).custom_flags(libc::O_NONBLOCK).open(&pipe) {
+        Ok(fifo) => fifo,
+        Err(e) => {
+            debug!("Not counting down to {:?}: {}", pipe, e);
+            return;
+        }
+    };
+    let start = Instant::now();
+    let mut interval = tokio::time::interval(TICK);
+    loop {
+        interval.tick().await;
+        // Resumed, the rule won't fire
+        if !list.lock().unwrap().get(&uuid).is_some_and(|rule| rule.is_idled()) {
+            return;
+        }
+        let remaining = window.saturating_sub(start.elapsed());
+        let percent = (remaining.as_secs_f64() * 100.0 / window.as_secs_f64().max(1.0)).round();
+        if let Err(e) = writeln!(fifo, "{}", percent) {
+            debug!("Countdown to {:?} stopped: {}", pipe, e);
+            return;
+        }
+        if remaining.is_zero() {
+            return;
+        }
+    }
+}
@@ -1,8 +1,14 @@
-use super::types::Request;
+use super::inhibit::{self, Scope};
+use super::types::{Request, SharedGlobals};
 use futures::stream::StreamExt;
-use log::{debug, error};
+use tracing::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 use tokio::sync::mpsc;
-use zbus::proxy;
+use zbus::{fdo, message, names::BusName, proxy, zvariant::OwnedObjectPath, MatchRule, MessageStream};
 
 pub async fn upower_watcher(tx: mpsc::Sender<Request>) -> anyhow::Result<()> {
     let conn = zbus::Connection::system().await?;
@@ -12,6 +18,46 @@ pub async fn upower_watcher(tx: mpsc::Sender<Request>) -> anyhow::Result<()> {
     let mut power_stream = upw_proxy.receive_on_battery_changed().await;
     tx.send(Request::OnBattery(state)).await.unwrap();
 
+    let battery_proxy = UPowerDeviceInterfaceProxy::new(&conn).await?;
+    // Machines without a battery have a display device too, just never present
+    if battery_proxy.is_present().await.unwrap_or(false) {
+        let level = battery_proxy.percentage().await?;
+        let mut level_stream = battery_proxy.receive_percentage_changed().await;
+        tx.send(Request::BatteryLevel(level)).await.unwrap();
+
+        let rate = battery_proxy.energy_rate().await?;
+        let mut rate_stream = battery_proxy.receive_energy_rate_changed().await;
+        tx.send(Request::EnergyRate(rate)).await.unwrap();
+
+        let rate_tx = tx.clone();
+        tokio::spawn(async move {
+            while let Some(rate_changed) = rate_stream.next().await {
+                match rate_changed.get().await {
+                    Ok(rate) => {
+                        let _ = rate_tx.send(Request::EnergyRate(rate)).await;
+                    }
+                    Err(e) => {
+                        error!("Error, getting energy rate {}", e)
+                    }
+                }
+            }
+        });
+
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            while let Some(level_changed) = level_stream.next().await {
+                match level_changed.get().await {
+                    Ok(level) => {
+                        let _ = tx.send(Request::BatteryLevel(level)).await;
+                    }
+                    Err(e) => {
+                        error!("Error, getting battery percentage {}", e)
+                    }
+                }
+            }
+        });
+    }
+
     tokio::spawn(async move {
         while let Some(on_battery_changed) = power_stream.next().await {
             match on_battery_changed.get().await {
@@ -37,6 +83,22 @@ trait UPowerInterface {
     fn on_battery(&self) -> zbus::Result<bool>;
 }
 
+/// The composite battery UPower exposes for the whole system
+#[proxy(
+    interface = "org.freedesktop.UPower.Device",
+    default_service = "org.freedesktop.UPower",
+    default_path = "/org/freedesktop/UPower/devices/DisplayDevice"
+)]
+trait UPowerDeviceInterface {
+    #[zbus(property)]
+    fn is_present(&self) -> zbus::Result<bool>;
+    #[zbus(property)]
+    fn percentage(&self) -> zbus::Result<f64>;
+    /// Watts drawn from (or put into) the batteries
+    #[zbus(property)]
+    fn energy_rate(&self) -> zbus::Result<f64>;
+}
+
 #[proxy(
     interface = "org.freedesktop.login1.Manager",
     default_service = "org.freedesktop.login1",
@@ -45,27 +107,139 @@ trait UPowerInterface {
 trait LogindManagerInterface {
     #[zbus(signal)]
     fn prepare_for_sleep(&self, start: bool) -> fdo::Result<()>;
+    fn inhibit(&self, what: &str, who: &str, why: &str, mode: &str) -> zbus::Result<zbus::zvariant::OwnedFd>;
+    fn get_session(&self, session_id: &str) -> zbus::Result<OwnedObjectPath>;
+    fn suspend(&self, interactive: bool) -> zbus::Result<()>;
+    fn hibernate(&self, interactive: bool) -> zbus::Result<()>;
+    fn hybrid_sleep(&self, interactive: bool) -> zbus::Result<()>;
+    fn suspend_then_hibernate(&self, interactive: bool) -> zbus::Result<()>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SleepAction {
+    Suspend,
+    Hibernate,
+    HybridSleep,
+    SuspendThenHibernate,
+}
+
+/// Put the system to sleep through logind, no `systemctl` or polkit agent needed.
+pub async fn sleep(action: SleepAction) -> anyhow::Result<()> {
+    let conn = zbus::Connection::system().await?;
+    let manager_proxy = LogindManagerInterfaceProxy::new(&conn).await?;
+    match action {
+        SleepAction::Suspend => manager_proxy.suspend(false).await?,
+        SleepAction::Hibernate => manager_proxy.hibernate(false).await?,
+        SleepAction::HybridSleep => manager_proxy.hybrid_sleep(false).await?,
+        SleepAction::SuspendThenHibernate => manager_proxy.suspend_then_hibernate(false).await?,
+    }
+    Ok(())
+}
+
+/// Take a logind delay lock on sleep, held until the returned fd is dropped.
+pub async fn sleep_delay_inhibitor() -> anyhow::Result<zbus::zvariant::OwnedFd> {
+    let conn = zbus::Connection::system().await?;
+    let manager_proxy = LogindManagerInterfaceProxy::new(&conn).await?;
+    let fd = manager_proxy
+        .inhibit("sleep", "hypnos", "Run before_sleep (usually locking the screen)", "delay")
+        .await?;
+    Ok(fd)
 }
 
 #[proxy(
     interface = "org.freedesktop.login1.Session",
-    default_service = "org.freedesktop.login1",
-    default_path = "/org/freedesktop/login1"
+    default_service = "org.freedesktop.login1"
 )]
 trait LogindSessionInterface {
     #[zbus(signal)]
     fn lock(&self) -> fdo::Result<()>;
     #[zbus(signal)]
     fn unlock(&self) -> fdo::Result<()>;
+    #[zbus(property)]
+    fn idle_hint(&self) -> zbus::Result<bool>;
+    #[zbus(property)]
+    fn locked_hint(&self) -> zbus::Result<bool>;
+    /// Whether the session is in the foreground of its seat
+    #[zbus(property)]
+    fn active(&self) -> zbus::Result<bool>;
+    fn set_locked_hint(&self, locked: bool) -> zbus::Result<()>;
+    /// Realtime timestamp (usec) of the last IdleHint change
+    #[zbus(property)]
+    fn idle_since_hint(&self) -> zbus::Result<u64>;
+}
+
+/// Object path of our logind session. Signals are emitted on the real path only,
+/// so the `session/auto` alias can't be used to subscribe to them.
+async fn session_path(conn: &zbus::Connection) -> anyhow::Result<OwnedObjectPath> {
+    let manager_proxy = LogindManagerInterfaceProxy::new(conn).await?;
+    let path = manager_proxy.get_session("auto").await?;
+    debug!("logind session: {}", path.as_str());
+    Ok(path)
+}
+
+async fn session_proxy(conn: &zbus::Connection) -> anyhow::Result<LogindSessionInterfaceProxy<'static>> {
+    let path = session_path(conn).await?;
+    Ok(LogindSessionInterfaceProxy::builder(conn).path(path)?.build().await?)
+}
+
+/// Tell logind (and whoever watches it) whether our session is locked.
+pub async fn set_locked_hint(locked: bool) -> anyhow::Result<()> {
+    let conn = zbus::Connection::system().await?;
+    session_proxy(&conn).await?.set_locked_hint(locked).await?;
+    Ok(())
+}
+
+/// Convert logind's IdleHint/IdleSinceHint pair into the moment the session became idle.
+async fn idle_since(session_proxy: &LogindSessionInterfaceProxy<'_>, idle: bool) -> Option<Instant> {
+    if !idle {
+        return None;
+    }
+
+    let since_usec = session_proxy.idle_since_hint().await.unwrap_or(0);
+    let since = UNIX_EPOCH + Duration::from_micros(since_usec);
+    let elapsed = SystemTime::now().duration_since(since).unwrap_or_default();
+    Some(Instant::now().checked_sub(elapsed).unwrap_or_else(Instant::now))
+}
+
+/// Fallback idle source for sessions whose compositor has no idle protocol.
+pub async fn logind_idle_watcher(tx: mpsc::Sender<Request>) -> anyhow::Result<()> {
+    let conn = zbus::Connection::system().await?;
+    let session_proxy = session_proxy(&conn).await?;
+
+    let idle = session_proxy.idle_hint().await?;
+    let mut idle_stream = session_proxy.receive_idle_hint_changed().await;
+    let _ = tx.send(Request::IdleSince(idle_since(&session_proxy, idle).await)).await;
+
+    tokio::spawn(async move {
+        while let Some(idle_changed) = idle_stream.next().await {
+            match idle_changed.get().await {
+                Ok(idle) => {
+                    let since = idle_since(&session_proxy, idle).await;
+                    let _ = tx.send(Request::IdleSince(since)).await;
+                }
+                Err(e) => {
+                    error!("Error, getting IdleHint property {}", e)
+                }
+            }
+        }
+    });
+    Ok(())
 }
 
 pub async fn logind_watcher(tx: mpsc::Sender<Request>) -> anyhow::Result<()> {
     let conn = zbus::Connection::system().await?;
-    let session_proxy = LogindSessionInterfaceProxy::new(&conn).await?;
+    let session_proxy = session_proxy(&conn).await?;
     let manager_proxy = LogindManagerInterfaceProxy::new(&conn).await?;
 
+    let locked = session_proxy.locked_hint().await?;
+    let _ = tx.send(Request::LockedHint(locked)).await;
+    let active = session_proxy.active().await?;
+    let _ = tx.send(Request::SessionActive(active)).await;
+
     tokio::spawn(async move {
         let mut lock_stream = session_proxy.receive_lock().await.unwrap();
+        let mut locked_hint_stream = session_proxy.receive_locked_hint_changed().await;
+        let mut active_stream = session_proxy.receive_active_changed().await;
         let mut unlock_stream = session_proxy.receive_unlock().await.unwrap();
         let mut prepare_sleep_stream = manager_proxy.receive_prepare_for_sleep().await.unwrap();
 
@@ -79,6 +253,26 @@ pub async fn logind_watcher(tx: mpsc::Sender<Request>) -> anyhow::Result<()> {
                     debug!("Unlock signal received");
                     let _ = tx.send(Request::DbEvent("Unlock".to_string())).await;
                 },
+                Some(locked_changed) = locked_hint_stream.next() => {
+                    match locked_changed.get().await {
+                        Ok(locked) => {
+                            let _ = tx.send(Request::LockedHint(locked)).await;
+                        }
+                        Err(e) => {
+                            error!("Error, getting LockedHint property {}", e)
+                        }
+                    }
+                },
+                Some(active_changed) = active_stream.next() => {
+                    match active_changed.get().await {
+                        Ok(active) => {
+                            let _ = tx.send(Request::SessionActive(active)).await;
+                        }
+                        Err(e) => {
+                            error!("Error, getting Active property {}", e)
+                        }
+                    }
+                },
                 Some(signal) = prepare_sleep_stream.next() => {
                     debug!("Prepare for Sleep signal received");
                     match signal.args() {
@@ -99,3 +293,138 @@ pub async fn logind_watcher(tx: mpsc::Sender<Request>) -> anyhow::Result<()> {
     });
     Ok(())
 }
+
+#[derive(Debug, Default, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Bus {
+    #[default]
+    Session,
+    System,
+}
+
+/// A signal mapped to a command, from the `dbus_triggers` config section.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DbusTrigger {
+    #[serde(default)]
+    pub bus: Bus,
+    /// Bus name of the emitter, any when unset
+    #[serde(default)]
+    pub sender: Option<String>,
+    /// Object path of the emitter, any when unset
+    #[serde(default)]
+    pub path: Option<String>,
+    pub interface: String,
+    pub member: String,
+    /// String arguments the signal has to carry, by index
+    #[serde(default)]
+    pub args: BTreeMap<u8, String>,
+    pub command: String,
+}
+
+impl DbusTrigger {
+    fn match_rule(&self) -> zbus::Result<MatchRule<'_>> {
+        let mut builder = MatchRule::builder()
+            .msg_type(message::Type::Signal)
+            .interface(self.interface.as_str())?
+            .member(self.member.as_str())?;
+        if let Some(sender) = &self.sender {
+            builder = builder.sender(sender.as_str())?;
+        }
+        if let Some(path) = &self.path {
+            builder = builder.path(path.as_str())?;
+        }
+        for (index, value) in &self.args {
+            builder = builder.arg(*index, value.as_str())?;
+        }
+        Ok(builder.build())
+    }
+}
+
+/// Run the trigger's command whenever its signal is received, as a hook.
+pub async fn trigger_watcher(trigger: DbusTrigger, globals: SharedGlobals, tx: mpsc::Sender<Request>) -> anyhow::Result<()> {
+    let conn = match trigger.bus {
+        Bus::Session => zbus::Connection::session().await?,
+        Bus::System => zbus::Connection::system().await?,
+    };
+    let mut stream = MessageStream::for_match_rule(trigger.match_rule()?, &conn, None).await?;
+    debug!("Watching the {}.{} signal", trigger.interface, trigger.member);
+
+    tokio::spawn(async move {
+        // The connection has to outlive the stream
+        let _conn = conn;
+        while let Some(message) = stream.next().await {
+            if let Err(e) = message {
+                error!("Error, receiving the {}.{} signal {}", trigger.interface, trigger.member, e);
+                continue;
+            }
+            let action = {
+                let globals = globals.lock().unwrap();
+                globals.hook(&Some(trigger.command.clone()), "dbus")
+            };
+            if let Some(action) = action {
+                info!("{}.{} signal received, executing: {}", trigger.interface, trigger.member, action.command);
+                if tx.send(Request::RunCommand(action)).await.is_err() {
+                    break;
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// A bus name holding rules off while it's owned, from the `inhibit_while_names` config section.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct NameInhibit {
+    pub name: String,
+    #[serde(default)]
+    pub bus: Bus,
+    /// Names of the rules held off, every rule when empty
+    #[serde(default)]
+    pub rules: Vec<String>,
+}
+
+/// Inhibit the rules of `watch` while its name has an owner, e.g. an app while it runs.
+pub async fn name_inhibitor(watch: NameInhibit, tx: mpsc::Sender<Request>) -> anyhow::Result<()> {
+    let conn = match watch.bus {
+        Bus::Session => zbus::Connection::session().await?,
+        Bus::System => zbus::Connection::system().await?,
+    };
+    let dbus = fdo::DBusProxy::new(&conn).await?;
+    // Subscribed first, so an owner showing up meanwhile isn't missed
+    let mut changes = dbus.receive_name_owner_changed_with_args(&[(0, watch.name.as_str())]).await?;
+    let owned = dbus.name_has_owner(BusName::try_from(watch.name.as_str())?).await?;
+    debug!("Watching the owner of {}", watch.name);
+
+    tokio::spawn(async move {
+        let mut cookie = None;
+        let mut has_owner = owned;
+        loop {
+            let changed = match (has_owner, cookie) {
+                (true, None) => {
+                    info!("{} is on the bus, inhibiting idle", watch.name);
+                    let reason = format!("{} is on the bus", watch.name);
+                    cookie = Some(inhibit::acquire_for("dbus", &reason, Scope::rules(&watch.rules), None));
+                    true
+                }
+                (false, Some(held)) => {
+                    info!("{} left the bus", watch.name);
+                    inhibit::release(held);
+                    cookie = None;
+                    true
+                }
+                _ => false,
+            };
+            if changed && tx.send(Request::Uninhibit).await.is_err() {
+                break;
+            }
+
+            let Some(signal) = changes.next().await else {
+                break;
+            };
+            if let Ok(args) = signal.args() {
+                has_owner = args.new_owner().is_some();
+            }
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,99 @@
+//! Idle detection from the input devices themselves, for sessions whose compositor
+//! offers no idle protocol. Needs read access to `/dev/input/event*` (the `input` group).
+
+use evdev::{Device, KeyCode, RelativeAxisCode};
+use tracing::{debug, info, warn};
+use std::{
+    collections::HashSet,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc;
+
+use crate::joystick_handler::DeviceInfo;
+use crate::types::{Request, SharedGlobals};
+
+/// How often the last input is reported, rules are checked every 5 seconds anyway
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+/// How often `/dev/input` is scanned for devices plugged in meanwhile
+const RESCAN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Keyboards, mice, touchpads and touchscreens, not lid switches, power buttons or
+/// controllers, which have their own handling.
+fn is_user_input(device: &Device) -> bool {
+    let keyboard = device.supported_keys().is_some_and(|keys| keys.contains(KeyCode::KEY_A) || keys.contains(KeyCode::KEY_ENTER));
+    let touch = device.supported_keys().is_some_and(|keys| keys.contains(KeyCode::BTN_TOUCH));
+    let pointer = device.supported_relative_axes().is_some_and(|axes| axes.contains(RelativeAxisCode::REL_X));
+    keyboard || touch || pointer
+}
+
+/// Watch the input devices, reporting the last input as the moment the session became idle.
+pub async fn idle_watcher(globals: SharedGlobals, tx: mpsc::Sender<Request>) -> anyhow::Result<()> {
+    let last_input = Arc::new(Mutex::new(Instant::now()));
+    let watched: Arc<Mutex<HashSet<PathBuf>>> = Arc::default();
+
+    if scan(&globals, &last_input, &watched) == 0 {
+        anyhow::bail!("No readable input device in /dev/input, is the user in the input group?");
+    }
+
+    tokio::spawn({
+        let last_input = last_input.clone();
+        async move {
+            let mut rescan = tokio::time::interval(RESCAN_INTERVAL);
+            loop {
+                rescan.tick().await;
+                scan(&globals, &last_input, &watched);
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        let mut reported = None;
+        let mut interval = tokio::time::interval(REPORT_INTERVAL);
+        loop {
+            interval.tick().await;
+            let since = *last_input.lock().unwrap();
+            if reported != Some(since) {
+                reported = Some(since);
+                if tx.send(Request::IdleSince(Some(since))).await.is_err() {
+                    break;
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Start watching the devices not watched yet, returning how many are watched.
+/// The devices in `ignored_inputs` are watched too, the config may change meanwhile.
+fn scan(globals: &SharedGlobals, last_input: &Arc<Mutex<Instant>>, watched: &Arc<Mutex<HashSet<PathBuf>>>) -> usize {
+    for (path, device) in evdev::enumerate() {
+        if watched.lock().unwrap().contains(&path) || !is_user_input(&device) {
+            continue;
+        }
+        let name = device.name().unwrap_or("unknown").to_string();
+        let info = DeviceInfo::new(&device, &path);
+        let mut events = match device.into_event_stream() {
+            Ok(events) => events,
+            Err(e) => {
+                warn!("Can't watch input device '{}': {}", name, e);
+                continue;
+            }
+        };
+        info!("Watching input device '{}' ({})", name, path.display());
+        watched.lock().unwrap().insert(path.clone());
+
+        let (globals, last_input, watched) = (globals.clone(), last_input.clone(), watched.clone());
+        tokio::spawn(async move {
+            while let Ok(_event) = events.next_event().await {
+                if !globals.lock().unwrap().input_ignored(&info) {
+                    *last_input.lock().unwrap() = Instant::now();
+                }
+            }
+            debug!("Input device '{}' is gone", name);
+            watched.lock().unwrap().remove(&path);
+        });
+    }
+    watched.lock().unwrap().len()
+}
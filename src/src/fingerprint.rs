@@ -0,0 +1,103 @@
+use futures::stream::StreamExt;
+use tracing::{debug, info, warn};
+use tokio::sync::{mpsc, oneshot};
+use zbus::{proxy, zvariant::OwnedObjectPath};
+
+use crate::pam;
+use crate::types::Request;
+
+#[proxy(
+    interface = "net.reactivated.Fprint.Manager",
+    default_service = "net.reactivated.Fprint",
+    default_path = "/net/reactivated/Fprint/Manager"
+)]
+trait FprintManager {
+    fn get_default_device(&self) -> zbus::Result<OwnedObjectPath>;
+}
+
+#[proxy(
+    interface = "net.reactivated.Fprint.Device",
+    default_service = "net.reactivated.Fprint"
+)]
+trait FprintDevice {
+    fn claim(&self, username: &str) -> zbus::Result<()>;
+    fn release(&self) -> zbus::Result<()>;
+    fn verify_start(&self, finger_name: &str) -> zbus::Result<()>;
+    fn verify_stop(&self) -> zbus::Result<()>;
+    #[zbus(signal)]
+    fn verify_status(&self, result: &str, done: bool) -> zbus::Result<()>;
+}
+
+/// Verify fingerprints in the background until a match, `max_attempts` mismatches,
+/// or the returned sender is dropped. Results are reported as `Request::LockAuth`.
+pub fn start(tx: mpsc::Sender<Request>, max_attempts: u32) -> oneshot::Sender<()> {
+    let (cancel_tx, cancel_rx) = oneshot::channel();
+    tokio::spawn(async move {
+        if let Err(e) = verify(tx, max_attempts, cancel_rx).await {
+            warn!("Fingerprint unlock unavailable: {}", e);
+        }
+    });
+    cancel_tx
+}
+
+async fn verify(
+    tx: mpsc::Sender<Request>,
+    max_attempts: u32,
+    mut cancel: oneshot::Receiver<()>,
+) -> anyhow::Result<()> {
+    let user = pam::current_user().ok_or_else(|| anyhow::anyhow!("Cannot tell who the current user is"))?;
+
+    let conn = zbus::Connection::system().await?;
+    let manager = FprintManagerProxy::new(&conn).await?;
+    let device_path = manager.get_default_device().await?;
+    let device = FprintDeviceProxy::builder(&conn).path(device_path)?.build().await?;
+
+    device.claim(&user).await?;
+    let mut status_stream = device.receive_verify_status().await?;
+    device.verify_start("any").await?;
+    debug!("Waiting for a fingerprint");
+
+    let mut failures = 0;
+    // Whatever happens, the device has to be released below
+    let result: anyhow::Result<()> = async {
+        loop {
+            tokio::select! {
+                _ = &mut cancel => return Ok(()),
+                Some(signal) = status_stream.next() => {
+                    let args = signal.args()?;
+                    match *args.result() {
+                        "verify-match" => {
+                            info!("Fingerprint matched");
+                            let _ = tx.send(Request::LockAuth(true)).await;
+                            return Ok(());
+                        }
+                        "verify-no-match" => {
+                            failures += 1;
+                            let _ = tx.send(Request::LockAuth(false)).await;
+                            if failures >= max_attempts {
+                                info!("Too many fingerprint mismatches, only the password unlocks now");
+                                return Ok(());
+                            }
+                        }
+                        "verify-disconnected" | "verify-unknown-error" => {
+                            anyhow::bail!("fprintd reported {}", args.result());
+                        }
+                        // Retry conditions: swipe too short, finger not centered...
+                        other => debug!("Fingerprint status: {}", other),
+                    }
+
+                    // A finished verification has to be restarted for the next try
+                    if *args.done() {
+                        device.verify_stop().await?;
+                        device.verify_start("any").await?;
+                    }
+                }
+            }
+        }
+    }
+    .await;
+
+    let _ = device.verify_stop().await;
+    let _ = device.release().await;
+    result
+}
@@ -0,0 +1,238 @@
+//! `hypnos init`: a first-run wizard writing a config tailored to the compositor.
+
+use std::{
+    fs,
+    io::{self, BufRead, Write},
+};
+
+use serde_json::{json, Map, Value};
+
+use crate::{systemd, utils};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Compositor {
+    Hyprland,
+    Sway,
+    Niri,
+    Kde,
+    Other,
+}
+
+impl Compositor {
+    fn detect() -> Self {
+        if std::env::var_os("HYPRLAND_INSTANCE_SIGNATURE").is_some() {
+            return Self::Hyprland;
+        }
+        if std::env::var_os("SWAYSOCK").is_some() {
+            return Self::Sway;
+        }
+        if std::env::var_os("NIRI_SOCKET").is_some() {
+            return Self::Niri;
+        }
+        let desktop = std::env::var("XDG_CURRENT_DESKTOP").unwrap_or_default().to_lowercase();
+        match desktop.as_str() {
+            d if d.contains("hyprland") => Self::Hyprland,
+            d if d.contains("sway") => Self::Sway,
+            d if d.contains("niri") => Self::Niri,
+            d if d.contains("kde") => Self::Kde,
+            _ => Self::Other,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Hyprland => "Hyprland",
+            Self::Sway => "Sway",
+            Self::Niri => "niri",
+            Self::Kde => "KDE Plasma",
+            Self::Other => "an unknown compositor",
+        }
+    }
+
+    /// Commands turning the screens off and back on, the latter being optional
+    fn dpms(self) -> Option<(&'static str, Option<&'static str>)> {
+        match self {
+            Self::Hyprland => Some(("hyprctl dispatch dpms off", Some("hyprctl dispatch dpms on"))),
+            Self::Sway => Some(("swaymsg output * power off", Some("swaymsg output * power on"))),
+            // Any input turns them back on
+            Self::Niri => Some(("niri msg action power-off-monitors", None)),
+            Self::Kde | Self::Other => None,
+        }
+    }
+
+    /// The usual screen locker of the compositor
+    fn locker(self) -> Option<&'static str> {
+        match self {
+            Self::Hyprland => Some("hyprlock"),
+            Self::Sway | Self::Niri => Some("swaylock"),
+            Self::Kde | Self::Other => None,
+        }
+    }
+}
+
+/// Screen lockers offered when installed, run as `on_lock`
+const LOCKERS: &[&str] = &["hyprlock", "swaylock", "gtklock", "waylock"];
+
+fn read_line() -> anyhow::Result<String> {
+    let mut line = String::new();
+    if io::stdin().lock().read_line(&mut line)? == 0 {
+        anyhow::bail!("No answer, aborting");
+    }
+    Ok(line.trim().to_string())
+}
+
+pub fn confirm(question: &str, default: bool) -> anyhow::Result<bool> {
+    loop {
+        print!("{} [{}] ", question, if default { "Y/n" } else { "y/N" });
+        io::stdout().flush()?;
+        match read_line()?.to_lowercase().as_str() {
+            "" => return Ok(default),
+            "y" | "yes" => return Ok(true),
+            "n" | "no" => return Ok(false),
+            _ => println!("Please answer y or n"),
+        }
+    }
+}
+
+/// Index of the chosen option.
+fn choose(question: &str, options: &[String], default: usize) -> anyhow::Result<usize> {
+    println!("{}", question);
+    for (i, option) in options.iter().enumerate() {
+        println!("  {}) {}", i + 1, option);
+    }
+    loop {
+        print!("Choice [{}] ", default + 1);
+        io::stdout().flush()?;
+        let answer = read_line()?;
+        if answer.is_empty() {
+            return Ok(default);
+        }
+        match answer.parse::<usize>() {
+            Ok(choice) if (1..=options.len()).contains(&choice) => return Ok(choice - 1),
+            _ => println!("Please pick a number between 1 and {}", options.len()),
+        }
+    }
+}
+
+/// Minutes, 0 for never.
+fn minutes(question: &str, default: u64) -> anyhow::Result<u64> {
+    loop {
+        print!("{} [{}] ", question, default);
+        io::stdout().flush()?;
+        let answer = read_line()?;
+        if answer.is_empty() {
+            return Ok(default);
+        }
+        match answer.parse() {
+            Ok(minutes) => return Ok(minutes),
+            Err(_) => println!("Please enter a number of minutes"),
+        }
+    }
+}
+
+fn rule(timeout_min: u64, actions: &str) -> Map<String, Value> {
+    let mut rule = Map::new();
+    rule.insert("timeout".to_string(), json!(timeout_min * 60));
+    rule.insert("actions".to_string(), json!(actions));
+    rule
+}
+
+/// Ask about the wanted behavior, write the config `config_name` and optionally set
+/// the service up.
+pub async fn run(config_name: String) -> anyhow::Result<()> {
+    let compositor = Compositor::detect();
+    println!("Setting hypnos up for {}.\n", compositor.name());
+
+    let mut config = Map::new();
+    config.insert("enabled".to_string(), json!(true));
+    let mut rules = Map::new();
+
+    // Locking
+    let installed: Vec<&str> = LOCKERS.iter().copied().filter(|name| utils::find_in_path(name).is_some()).collect();
+    let mut options: Vec<String> = installed.iter().map(|name| name.to_string()).collect();
+    options.push("The built-in lock screen".to_string());
+    options.push("Don't lock".to_string());
+    let default = compositor
+        .locker()
+        .and_then(|locker| installed.iter().position(|name| *name == locker))
+        .unwrap_or(installed.len());
+    let choice = choose("Which screen locker should be used?", &options, default)?;
+    let locker = match choice {
+        i if i < installed.len() => Some(installed[i]),
+        i if i == installed.len() => Some("@lock"),
+        _ => None,
+    };
+
+    let mut lock_after = 0;
+    if let Some(locker) = locker {
+        lock_after = minutes("Lock the session after how many minutes idle? (0 for never)", 5)?;
+        // Through logind, so `loginctl lock-session` and other tools lock the same way
+        config.insert("on_lock".to_string(), json!(locker));
+        config.insert("before_sleep".to_string(), json!("loginctl lock-session"));
+        if lock_after > 0 {
+            rules.insert("lock".to_string(), Value::Object(rule(lock_after, "loginctl lock-session")));
+        }
+    }
+
+    // Dimming, a minute before locking
+    if lock_after > 1 && utils::find_in_path("brightnessctl").is_some() && confirm("Dim the screen a minute before locking?", true)? {
+        let mut dim = rule(lock_after - 1, "brightnessctl -s set 10");
+        dim.insert("restore".to_string(), json!("brightnessctl -r"));
+        rules.insert("dim".to_string(), Value::Object(dim));
+    }
+
+    // Screens off
+    match compositor.dpms() {
+        Some((off, on)) => {
+            let default = if lock_after > 0 { lock_after + 1 } else { 10 };
+            let screen_off = minutes("Turn the screens off after how many minutes idle? (0 for never)", default)?;
+            if screen_off > 0 {
+                let mut rule = rule(screen_off, off);
+                if let Some(on) = on {
+                    rule.insert("restore".to_string(), json!(on));
+                }
+                // A monitor plugged in meanwhile goes off too
+                rule.insert("reapply_on_output".to_string(), json!(true));
+                rules.insert("screen-off".to_string(), Value::Object(rule));
+            }
+        }
+        None => println!("Turning the screens off isn't known for {}, add a rule for it later if needed.", compositor.name()),
+    }
+
+    // Suspending
+    let options = ["Never", "On battery only", "Always"].map(String::from);
+    let suspend = choose("When should the system suspend while idle?", &options, 1)?;
+    if suspend > 0 {
+        let after = minutes("Suspend after how many minutes idle?", 15)?.max(1);
+        let mut rule = rule(after, "@suspend");
+        if suspend == 1 {
+            rule.insert("on_battery".to_string(), json!(true));
+        }
+        rules.insert("suspend".to_string(), Value::Object(rule));
+    }
+
+    config.insert("rules".to_string(), Value::Object(rules));
+
+    let config_path = utils::xdg_config_path(Some(config_name))?;
+    if config_path.exists() {
+        if !confirm(&format!("{} exists, replace it? A backup is kept", config_path.display()), false)? {
+            println!("Nothing written.");
+            return Ok(());
+        }
+        let backup = config_path.with_extension("json.bak");
+        fs::copy(&config_path, &backup)?;
+        println!("Previous config saved to {}", backup.display());
+    }
+    fs::write(&config_path, serde_json::to_string_pretty(&Value::Object(config))? + "\n")?;
+    println!("Config written to {}", config_path.display());
+
+    if confirm("Install and start the systemd user service?", true)? {
+        let target = systemd::Target::default();
+        systemd::install(target).await?;
+        systemd::enable(target).await?;
+        systemd::start(target).await?;
+    } else {
+        println!("Run `hypnos service install` and `hypnos service start` when ready, or `hypnos` in your session.");
+    }
+    Ok(())
+}
@@ -0,0 +1,183 @@
+//! Read the service logs through libsystemd's sd-journal API.
+
+use std::{
+    ffi::{c_char, c_int, c_void, CString},
+    ptr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::utils;
+
+#[repr(C)]
+struct SdJournal {
+    _private: [u8; 0],
+}
+
+const SD_JOURNAL_LOCAL_ONLY: c_int = 1;
+/// `sd_journal_wait` timeout, so the process stays interruptible
+const WAIT_USEC: u64 = 1_000_000;
+
+#[link(name = "systemd")]
+extern "C" {
+    fn sd_journal_open(ret: *mut *mut SdJournal, flags: c_int) -> c_int;
+    fn sd_journal_close(j: *mut SdJournal);
+    fn sd_journal_add_match(j: *mut SdJournal, data: *const c_void, size: usize) -> c_int;
+    fn sd_journal_add_disjunction(j: *mut SdJournal) -> c_int;
+    fn sd_journal_seek_tail(j: *mut SdJournal) -> c_int;
+    fn sd_journal_seek_realtime_usec(j: *mut SdJournal, usec: u64) -> c_int;
+    fn sd_journal_previous_skip(j: *mut SdJournal, skip: u64) -> c_int;
+    fn sd_journal_next(j: *mut SdJournal) -> c_int;
+    fn sd_journal_wait(j: *mut SdJournal, timeout_usec: u64) -> c_int;
+    fn sd_journal_get_realtime_usec(j: *mut SdJournal, ret: *mut u64) -> c_int;
+    fn sd_journal_get_data(j: *mut SdJournal, field: *const c_char, data: *mut *const c_void, length: *mut usize) -> c_int;
+}
+
+/// sd-journal functions return a negative errno on failure.
+fn check(ret: c_int) -> anyhow::Result<c_int> {
+    if ret < 0 {
+        return Err(std::io::Error::from_raw_os_error(-ret).into());
+    }
+    Ok(ret)
+}
+
+struct Journal(*mut SdJournal);
+
+impl Journal {
+    fn open() -> anyhow::Result<Self> {
+        let mut journal = ptr::null_mut();
+        // SAFETY: sd_journal_open sets `journal` on success
+        check(unsafe { sd_journal_open(&mut journal, SD_JOURNAL_LOCAL_ONLY) })?;
+        Ok(Self(journal))
+    }
+
+    fn add_match(&self, field: &str) -> anyhow::Result<()> {
+        // SAFETY: the data is copied by sd-journal
+        check(unsafe { sd_journal_add_match(self.0, field.as_ptr() as *const c_void, field.len()) })?;
+        Ok(())
+    }
+
+    fn add_disjunction(&self) -> anyhow::Result<()> {
+        check(unsafe { sd_journal_add_disjunction(self.0) })?;
+        Ok(())
+    }
+
+    /// Whether it moved to a new entry.
+    fn next(&self) -> anyhow::Result<bool> {
+        Ok(check(unsafe { sd_journal_next(self.0) })? > 0)
+    }
+
+    /// A field of the current entry.
+    fn field(&self, name: &str) -> Option<String> {
+        let name = CString::new(name).ok()?;
+        let mut data = ptr::null();
+        let mut length = 0;
+        // SAFETY: on success, `data` points to `length` bytes valid until the next move
+        let ret = unsafe { sd_journal_get_data(self.0, name.as_ptr(), &mut data, &mut length) };
+        if ret < 0 {
+            return None;
+        }
+        let data = unsafe { std::slice::from_raw_parts(data as *const u8, length) };
+        // The data is `FIELD=value`
+        let value = &data[name.as_bytes().len() + 1..];
+        Some(String::from_utf8_lossy(value).into_owned())
+    }
+
+    fn realtime_usec(&self) -> u64 {
+        let mut usec = 0;
+        unsafe { sd_journal_get_realtime_usec(self.0, &mut usec) };
+        usec
+    }
+
+    fn print_entry(&self) {
+        let timestamp = self.realtime_usec() / 1_000_000;
+        let identifier = self.field("SYSLOG_IDENTIFIER").or_else(|| self.field("_COMM")).unwrap_or_default();
+        let pid = self.field("_PID").map(|pid| format!("[{}]", pid)).unwrap_or_default();
+        println!(
+            "{} {}{}: {}",
+            utils::format_local_time(timestamp, c"%b %d %H:%M:%S"),
+            identifier,
+            pid,
+            self.field("MESSAGE").unwrap_or_default()
+        );
+    }
+}
+
+impl Drop for Journal {
+    fn drop(&mut self) {
+        unsafe { sd_journal_close(self.0) };
+    }
+}
+
+/// Parse `--since`: a relative `30s`, `10m`, `2h`, `1d`, or a local `YYYY-MM-DD[ HH:MM[:SS]]`.
+/// Returns microseconds since the epoch.
+pub fn parse_since(since: &str) -> anyhow::Result<u64> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    let units = [('s', 1), ('m', 60), ('h', 3600), ('d', 86400)];
+    for (suffix, secs) in units {
+        if let Some(Ok(count)) = since.strip_suffix(suffix).map(str::parse::<u64>) {
+            let ago = Duration::from_secs(count * secs);
+            return Ok(now.saturating_sub(ago).as_micros() as u64);
+        }
+    }
+
+    let input = CString::new(since)?;
+    for format in [c"%Y-%m-%d %H:%M:%S", c"%Y-%m-%d %H:%M", c"%Y-%m-%d"] {
+        // SAFETY: strptime fills the tm we own, mktime reads it
+        unsafe {
+            let mut tm: libc::tm = std::mem::zeroed();
+            let end = libc::strptime(input.as_ptr(), format.as_ptr(), &mut tm);
+            if end.is_null() || *end != 0 {
+                continue;
+            }
+            tm.tm_isdst = -1;
+            let time = libc::mktime(&mut tm);
+            if time >= 0 {
+                return Ok(time as u64 * 1_000_000);
+            }
+        }
+    }
+    anyhow::bail!("Invalid time '{}', expected e.g. 10m, 2h, 1d or 2024-01-31 08:00", since)
+}
+
+/// Print the journal entries of `unit`, a user unit unless `system`: the last `lines`,
+/// or those since `since` (microseconds since the epoch), then new ones with `follow`.
+pub fn print_logs(unit: &str, system: bool, lines: usize, since: Option<u64>, follow: bool) -> anyhow::Result<()> {
+    let journal = Journal::open()?;
+    if system {
+        // Like `journalctl --unit`
+        journal.add_match(&format!("_SYSTEMD_UNIT={}", unit))?;
+        journal.add_disjunction()?;
+        journal.add_match(&format!("UNIT={}", unit))?;
+    } else {
+        let uid = unsafe { libc::getuid() };
+        // Like `journalctl --user-unit`: the unit's own messages, or the manager's about it
+        journal.add_match(&format!("_SYSTEMD_USER_UNIT={}", unit))?;
+        journal.add_match(&format!("_UID={}", uid))?;
+        journal.add_disjunction()?;
+        journal.add_match(&format!("USER_UNIT={}", unit))?;
+        journal.add_match(&format!("_UID={}", uid))?;
+    }
+
+    match since {
+        Some(usec) => {
+            check(unsafe { sd_journal_seek_realtime_usec(journal.0, usec) })?;
+        }
+        None => {
+            check(unsafe { sd_journal_seek_tail(journal.0) })?;
+            // Lands on the first entry to print, if any
+            if check(unsafe { sd_journal_previous_skip(journal.0, lines as u64) })? > 0 {
+                journal.print_entry();
+            }
+        }
+    }
+
+    loop {
+        while journal.next()? {
+            journal.print_entry();
+        }
+        if !follow {
+            return Ok(());
+        }
+        check(unsafe { sd_journal_wait(journal.0, WAIT_USEC) })?;
+    }
+}
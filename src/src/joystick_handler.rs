@@ -1,40 +1,301 @@
-use evdev::{Device, EventSummary};
-use log::{debug, info};
-use std::path::Path;
+use evdev::{AbsoluteAxisCode, Device, EventSummary, KeyCode};
+use tracing::{debug, info};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
 use tokio::sync::mpsc;
 
-use crate::types::Request;
+use crate::types::{Request, SharedGlobals};
+
+/// Controller input is reported at most this often, sticks send events continuously
+const ACTIVITY_INTERVAL: Duration = Duration::from_secs(1);
+/// Attempts at opening a new event node, udev may not have granted access yet
+const OPEN_ATTEMPTS: u32 = 5;
+const OPEN_RETRY_DELAY: Duration = Duration::from_millis(200);
+
+/// The `gamepad` section of the config.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct GamepadConfig {
+    /// Hold an idle inhibitor while controllers are in use
+    #[serde(default = "default_inhibit")]
+    pub inhibit: bool,
+    /// Seconds without controller input before the inhibitor is released
+    #[serde(default = "default_quiet_period")]
+    pub quiet_period: u64,
+    /// Only these controllers count, all of them when empty
+    #[serde(default)]
+    pub include: Vec<DeviceMatch>,
+    /// Controllers that never count, e.g. a steering wheel
+    #[serde(default)]
+    pub exclude: Vec<DeviceMatch>,
+    /// Fraction of a stick's range around its rest position that is ignored
+    #[serde(default = "default_deadzone")]
+    pub deadzone: f64,
+    /// Fraction of its range a stick has to move to count again, so a drifting
+    /// stick doesn't keep the session awake
+    #[serde(default = "default_axis_noise")]
+    pub axis_noise: f64,
+    /// Button toggling presentation mode when held, e.g. `BTN_MODE` for the Guide button
+    #[serde(default)]
+    pub presentation_button: Option<String>,
+    /// Seconds `presentation_button` has to be held
+    #[serde(default = "default_presentation_hold")]
+    pub presentation_hold: f64,
+}
+
+fn default_inhibit() -> bool {
+    true
+}
+
+fn default_quiet_period() -> u64 {
+    30
+}
+
+fn default_deadzone() -> f64 {
+    0.2
+}
+
+fn default_axis_noise() -> f64 {
+    0.05
+}
+
+fn default_presentation_hold() -> f64 {
+    2.0
+}
+
+impl Default for GamepadConfig {
+    fn default() -> Self {
+        Self {
+            inhibit: default_inhibit(),
+            quiet_period: default_quiet_period(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            deadzone: default_deadzone(),
+            axis_noise: default_axis_noise(),
+            presentation_button: None,
+            presentation_hold: default_presentation_hold(),
+        }
+    }
+}
+
+impl GamepadConfig {
+    /// The presentation button, None when unset or unknown.
+    pub fn presentation_button(&self) -> Option<KeyCode> {
+        self.presentation_button.as_deref()?.parse().ok()
+    }
+
+    /// How long to hold the presentation button, the default when `presentation_hold`
+    /// is negative or too large.
+    pub fn presentation_hold(&self) -> Duration {
+        Duration::try_from_secs_f64(self.presentation_hold)
+            .unwrap_or_else(|_| Duration::from_secs_f64(default_presentation_hold()))
+    }
+
+    fn watches(&self, info: &DeviceInfo) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|device| device.matches(info)))
+            && !self.exclude.iter().any(|device| device.matches(info))
+    }
+}
+
+/// Devices picked in `include`, `exclude` or `ignored_inputs`, by every field set.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DeviceMatch {
+    /// Part of the device name, case-insensitive
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Hexadecimal USB vendor ID, as shown by lsusb
+    #[serde(default)]
+    pub vendor: Option<String>,
+    /// Hexadecimal USB product ID
+    #[serde(default)]
+    pub product: Option<String>,
+    /// The event node, or one of its `/dev/input/by-id` or `by-path` links
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+}
+
+impl DeviceMatch {
+    pub fn matches(&self, info: &DeviceInfo) -> bool {
+        fn id_matches(wanted: &Option<String>, id: u16) -> bool {
+            wanted.as_ref().is_none_or(|wanted| {
+                u16::from_str_radix(wanted.trim_start_matches("0x"), 16).is_ok_and(|wanted| wanted == id)
+            })
+        }
+        self.name
+            .as_ref()
+            .is_none_or(|name| info.name.to_lowercase().contains(&name.to_lowercase()))
+            && id_matches(&self.vendor, info.vendor)
+            && id_matches(&self.product, info.product)
+            && self.path.as_ref().is_none_or(|path| info.paths.contains(path))
+    }
+}
+
+/// What an input device can be matched on.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceInfo {
+    pub name: String,
+    pub vendor: u16,
+    pub product: u16,
+    /// The event node and the links to it
+    pub paths: Vec<PathBuf>,
+}
+
+impl DeviceInfo {
+    pub fn new(device: &Device, device_path: &Path) -> Self {
+        let mut paths = vec![device_path.to_path_buf()];
+        for dir in ["/dev/input/by-id", "/dev/input/by-path"] {
+            let Ok(entries) = fs::read_dir(dir) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                if fs::canonicalize(entry.path()).is_ok_and(|target| target == device_path) {
+                    paths.push(entry.path());
+                }
+            }
+        }
+        Self {
+            name: device.name().unwrap_or_default().to_string(),
+            vendor: device.input_id().vendor(),
+            product: device.input_id().product(),
+            paths,
+        }
+    }
+}
+
+/// A stick or trigger, to tell movements from noise.
+#[derive(Debug)]
+struct Axis {
+    rest: i32,
+    /// Distance from `rest` to the end of the range
+    reach: f64,
+    /// Position last counted as activity, None while in the deadzone
+    counted: Option<i32>,
+}
+
+impl Axis {
+    fn new(code: AbsoluteAxisCode, minimum: i32, maximum: i32) -> Self {
+        // Triggers rest at their minimum, sticks at their center
+        let is_trigger = matches!(
+            code,
+            AbsoluteAxisCode::ABS_Z
+                | AbsoluteAxisCode::ABS_RZ
+                | AbsoluteAxisCode::ABS_GAS
+                | AbsoluteAxisCode::ABS_BRAKE
+                | AbsoluteAxisCode::ABS_THROTTLE
+        );
+        let (rest, reach) = if is_trigger {
+            (minimum, (maximum - minimum) as f64)
+        } else {
+            (minimum + (maximum - minimum) / 2, (maximum - minimum) as f64 / 2.0)
+        };
+        Self { rest, reach, counted: None }
+    }
+
+    /// Whether moving to `value` is a deliberate movement.
+    fn moved(&mut self, value: i32, deadzone: f64, noise: f64) -> bool {
+        if self.reach <= 0.0 || ((value - self.rest).abs() as f64) / self.reach < deadzone {
+            self.counted = None;
+            return false;
+        }
+        if self.counted.is_some_and(|counted| ((value - counted).abs() as f64) / (2.0 * self.reach) < noise) {
+            return false;
+        }
+        self.counted = Some(value);
+        true
+    }
+}
 
 #[derive(Debug)]
 pub struct JoystickHandler {
     syspath: String,
     tx: mpsc::Sender<Request>,
+    globals: SharedGlobals,
 }
 
 impl JoystickHandler {
-    pub fn new(syspath: String, tx: mpsc::Sender<Request>) -> Self {
-        Self { syspath, tx }
+    pub fn new(syspath: String, tx: mpsc::Sender<Request>, globals: SharedGlobals) -> Self {
+        Self { syspath, tx, globals }
+    }
+
+    pub fn syspath(&self) -> &str {
+        &self.syspath
+    }
+
+    fn device_path(&self) -> PathBuf {
+        Path::new("/dev/input").join(&self.syspath)
+    }
+
+    /// Open the event node, waiting a bit for a freshly plugged (or paired) controller.
+    async fn open(&self) -> anyhow::Result<Device> {
+        let device_path = self.device_path();
+        let mut attempt = 1;
+        loop {
+            match Device::open(&device_path) {
+                Ok(device) => return Ok(device),
+                Err(e) if attempt < OPEN_ATTEMPTS && matches!(e.kind(), ErrorKind::PermissionDenied | ErrorKind::NotFound) => {
+                    attempt += 1;
+                    tokio::time::sleep(OPEN_RETRY_DELAY).await;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
     }
 
     pub async fn js_handler(&self) -> anyhow::Result<()> {
-        let device_path = Path::new("/dev/input").join(&self.syspath);
-        let device = Device::open(device_path)?;
-        //let absinfo = device.get_abs_state();
+        let device = self.open().await?;
+        info!("Watching controller '{}' ({})", device.name().unwrap_or("unknown"), self.syspath);
+        let info = DeviceInfo::new(&device, &self.device_path());
+        let mut axes: HashMap<AbsoluteAxisCode, Axis> = device
+            .get_absinfo()?
+            .map(|(code, absinfo)| (code, Axis::new(code, absinfo.minimum(), absinfo.maximum())))
+            .collect();
         let mut event_stream = device.into_event_stream()?;
+        let mut last_activity: Option<Instant> = None;
+        // When the presentation button, pressed, has been held long enough
+        let mut held_until: Option<tokio::time::Instant> = None;
 
         loop {
             tokio::select! {
+                _ = tokio::time::sleep_until(held_until.unwrap_or_else(tokio::time::Instant::now)), if held_until.is_some() => {
+                    held_until = None;
+                    if self.globals.lock().unwrap().gamepad.watches(&info) {
+                        let _ = self.tx.send(Request::TogglePresentation).await;
+                    }
+                }
                 ev = event_stream.next_event() => {
                     match ev {
                         Ok(ev) => {
                             match ev.destructure() {
-                                EventSummary::Key(_key, _, _) => {
-                                    //debug!("Key event: {:?}, value: {}", key, ev.value());
-                                    self.tx.send(Request::Inhibit).await.unwrap();
+                                EventSummary::Key(_, key, value) => {
+                                    // 1 is a press, 0 a release, 2 an autorepeat
+                                    if value != 2 {
+                                        let (button, hold) = {
+                                            let globals = self.globals.lock().unwrap();
+                                            (globals.gamepad.presentation_button(), globals.gamepad.presentation_hold())
+                                        };
+                                        if button == Some(key) {
+                                            held_until = (value == 1).then(|| tokio::time::Instant::now() + hold);
+                                        }
+                                    }
+                                    self.activity(&info, &mut last_activity).await;
+                                }
+                                // The d-pad is digital, unlike the sticks
+                                EventSummary::AbsoluteAxis(_, axis, _) if is_hat(axis) => {
+                                    self.activity(&info, &mut last_activity).await;
                                 }
-                                // Ignore axis and synchronization events for now. For Axis events
-                                // it's not currently clear how to get absinfo
-                                EventSummary::AbsoluteAxis(..) => {
+                                EventSummary::AbsoluteAxis(_, axis, value) => {
+                                    let (deadzone, noise) = {
+                                        let globals = self.globals.lock().unwrap();
+                                        (globals.gamepad.deadzone, globals.gamepad.axis_noise)
+                                    };
+                                    if axes.get_mut(&axis).is_some_and(|axis| axis.moved(value, deadzone, noise)) {
+                                        self.activity(&info, &mut last_activity).await;
+                                    }
                                 }
                                 EventSummary::Synchronization(..) =>  {}
                                 _ => {
@@ -51,4 +312,19 @@ impl JoystickHandler {
             }
         }
     }
+
+    async fn activity(&self, info: &DeviceInfo, last_activity: &mut Option<Instant>) {
+        if last_activity.is_some_and(|last| last.elapsed() < ACTIVITY_INTERVAL) {
+            return;
+        }
+        if !self.globals.lock().unwrap().gamepad.watches(info) {
+            return;
+        }
+        *last_activity = Some(Instant::now());
+        let _ = self.tx.send(Request::GamepadActivity).await;
+    }
+}
+
+fn is_hat(axis: AbsoluteAxisCode) -> bool {
+    (AbsoluteAxisCode::ABS_HAT0X.0..=AbsoluteAxisCode::ABS_HAT3Y.0).contains(&axis.0)
 }
@@ -0,0 +1,144 @@
+//! Timeouts learned from usage: a rule the user keeps cancelling right after it fired
+//! gets a longer timeout, within bounds. The extensions are kept in
+//! $XDG_STATE_HOME/hypnos/learned_timeouts.json.
+
+use tracing::{error, info};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::PathBuf,
+    time::Duration,
+};
+use xdg::BaseDirectories;
+
+use crate::config;
+
+const LEARNED_FILE: &str = "learned_timeouts.json";
+
+/// The `learn_timeouts` section of the config.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct LearnConfig {
+    /// Seconds after a rule fired within which a resume means its timeout is too short
+    #[serde(default = "default_window")]
+    pub window: u64,
+    /// Such resumes before the timeout gets extended
+    #[serde(default = "default_threshold")]
+    pub threshold: u32,
+    /// Seconds added at each extension
+    #[serde(default = "default_step")]
+    pub step: u64,
+    /// Most seconds a rule's timeout may be extended by
+    #[serde(default = "default_max_extra")]
+    pub max_extra: u64,
+    /// Extend the timeouts, instead of only logging suggestions
+    #[serde(default)]
+    pub apply: bool,
+}
+
+fn default_window() -> u64 {
+    10
+}
+
+fn default_threshold() -> u32 {
+    3
+}
+
+fn default_step() -> u64 {
+    60
+}
+
+fn default_max_extra() -> u64 {
+    600
+}
+
+impl Default for LearnConfig {
+    fn default() -> Self {
+        Self {
+            window: default_window(),
+            threshold: default_threshold(),
+            step: default_step(),
+            max_extra: default_max_extra(),
+            apply: false,
+        }
+    }
+}
+
+impl LearnConfig {
+    /// Whether a resume `fired_ago` after the rule fired is a sign its timeout is too short.
+    pub fn too_soon(&self, fired_ago: Duration) -> bool {
+        fired_ago <= Duration::from_secs(self.window)
+    }
+}
+
+/// The learned extensions, kept in the daemon's globals.
+#[derive(Debug)]
+pub struct Learner {
+    /// Seconds added to each rule's timeout
+    extra: BTreeMap<String, u64>,
+    /// Resumes right after firing, since the last extension
+    quick_resumes: HashMap<String, u32>,
+}
+
+impl Default for Learner {
+    fn default() -> Self {
+        Self {
+            extra: load().unwrap_or_default(),
+            quick_resumes: HashMap::new(),
+        }
+    }
+}
+
+impl Learner {
+    /// Seconds to add to `rule`'s timeout.
+    pub fn extra(&self, rule: &str) -> u64 {
+        self.extra.get(rule).copied().unwrap_or(0)
+    }
+
+    /// Count a resume right after `rule` fired, true once its timeout got extended and
+    /// the rules have to be re-armed.
+    pub fn quick_resume(&mut self, config: &LearnConfig, rule: &str) -> bool {
+        let count = self.quick_resumes.entry(rule.to_string()).or_default();
+        *count += 1;
+        if *count < config.threshold {
+            return false;
+        }
+        let count = std::mem::take(count);
+
+        let extra = self.extra(rule);
+        let extended = (extra + config.step).min(config.max_extra);
+        if extended <= extra {
+            info!("Rule '{}' resumed {} times right after firing, but its timeout is already extended by the {}s maximum", rule, count, config.max_extra);
+            return false;
+        }
+        if !config.apply {
+            info!("Rule '{}' resumed {} times within {}s of firing, consider raising its timeout by {}s", rule, count, config.window, extended - extra);
+            return false;
+        }
+
+        info!("Rule '{}' resumed {} times within {}s of firing, extending its timeout by {}s ({}s in total)", rule, count, config.window, extended - extra, extended);
+        self.extra.insert(rule.to_string(), extended);
+        if let Err(e) = save(&self.extra) {
+            error!("Failed to save the learned timeouts: {}", e);
+        }
+        true
+    }
+}
+
+fn learned_path() -> std::io::Result<PathBuf> {
+    BaseDirectories::with_prefix(config::APP_NAME).place_state_file(LEARNED_FILE)
+}
+
+fn load() -> anyhow::Result<BTreeMap<String, u64>> {
+    let content = fs::read_to_string(learned_path()?)?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+fn save(extra: &BTreeMap<String, u64>) -> anyhow::Result<()> {
+    let path = learned_path()?;
+    // Write then rename, so a crash can't leave a truncated file
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_string(extra)?)?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
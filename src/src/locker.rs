@@ -0,0 +1,462 @@
+use tracing::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    os::fd::{AsFd, FromRawFd, OwnedFd},
+};
+use tokio::sync::{mpsc, oneshot};
+use wayland_client::{
+    protocol::{wl_buffer, wl_compositor, wl_keyboard, wl_output, wl_shm, wl_shm_pool, wl_surface},
+    Connection, Dispatch, Proxy, QueueHandle, WEnum,
+};
+use wayland_protocols::ext::session_lock::v1::client::{
+    ext_session_lock_manager_v1, ext_session_lock_surface_v1, ext_session_lock_v1,
+};
+use xkbcommon::xkb;
+
+use crate::{fingerprint, pam};
+use crate::types::{Request, SharedGlobals, State};
+
+/// The `lock_screen` section of the config.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct LockScreenConfig {
+    /// Also unlock with an enrolled fingerprint, through fprintd
+    #[serde(default)]
+    pub fingerprint: bool,
+    /// Mismatches before only the password is accepted
+    #[serde(default = "default_fingerprint_attempts")]
+    pub fingerprint_attempts: u32,
+}
+
+fn default_fingerprint_attempts() -> u32 {
+    3
+}
+
+impl Default for LockScreenConfig {
+    fn default() -> Self {
+        Self {
+            fingerprint: false,
+            fingerprint_attempts: default_fingerprint_attempts(),
+        }
+    }
+}
+
+// evdev keycodes, as sent by wl_keyboard
+const KEY_ESC: u32 = 1;
+const KEY_BACKSPACE: u32 = 14;
+const KEY_ENTER: u32 = 28;
+const KEY_KPENTER: u32 = 96;
+
+/// What the lock screen shows, as a plain background color.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Indicator {
+    Idle,
+    Typing,
+    Checking,
+    Failed,
+}
+
+impl Indicator {
+    /// XRGB8888 background color
+    fn color(self) -> u32 {
+        match self {
+            Indicator::Idle => 0xFF1E1E2E,
+            Indicator::Typing => 0xFF2A2F45,
+            Indicator::Checking => 0xFF1E3A5F,
+            Indicator::Failed => 0xFF5F1E1E,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct LockSurface {
+    output: u32,
+    surface: wl_surface::WlSurface,
+    lock_surface: ext_session_lock_surface_v1::ExtSessionLockSurfaceV1,
+    buffer: Option<wl_buffer::WlBuffer>,
+    size: (u32, u32),
+}
+
+impl LockSurface {
+    fn draw(&mut self, shm: &wl_shm::WlShm, color: u32, qh: &QueueHandle<State>) -> anyhow::Result<()> {
+        let (width, height) = self.size;
+        if width == 0 || height == 0 {
+            // Not configured yet
+            return Ok(());
+        }
+
+        let stride = width as usize * 4;
+        let size = stride * height as usize;
+        let file = create_shm_file(size)?;
+        {
+            let row = color.to_le_bytes().repeat(width as usize);
+            let mut writer = BufWriter::new(&file);
+            for _ in 0..height {
+                writer.write_all(&row)?;
+            }
+            writer.flush()?;
+        }
+
+        let pool = shm.create_pool(file.as_fd(), size as i32, qh, ());
+        let buffer = pool.create_buffer(0, width as i32, height as i32, stride as i32, wl_shm::Format::Xrgb8888, qh, ());
+        pool.destroy();
+
+        self.surface.attach(Some(&buffer), 0, 0);
+        self.surface.damage(0, 0, width as i32, height as i32);
+        self.surface.commit();
+
+        if let Some(old) = self.buffer.replace(buffer) {
+            old.destroy();
+        }
+        Ok(())
+    }
+
+    fn destroy(self) {
+        self.lock_surface.destroy();
+        self.surface.destroy();
+        if let Some(buffer) = self.buffer {
+            buffer.destroy();
+        }
+    }
+}
+
+/// The compositor's keymap and the current modifiers, set from the `wl_keyboard` events.
+struct Keymap(xkb::State);
+
+// SAFETY: the xkb state is only touched through the globals mutex
+unsafe impl Send for Keymap {}
+
+impl std::fmt::Debug for Keymap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Keymap")
+    }
+}
+
+impl Keymap {
+    fn from_fd(fd: OwnedFd, size: u32) -> anyhow::Result<Self> {
+        let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
+        // SAFETY: the fd comes straight from the compositor's keymap event
+        let keymap = unsafe {
+            xkb::Keymap::new_from_fd(&context, fd, size as usize, xkb::KEYMAP_FORMAT_TEXT_V1, xkb::KEYMAP_COMPILE_NO_FLAGS)?
+        };
+        let Some(keymap) = keymap else {
+            anyhow::bail!("The compositor's keymap does not compile");
+        };
+        Ok(Self(xkb::State::new(&keymap)))
+    }
+
+    /// The text typed by an evdev keycode, under the current modifiers.
+    fn text(&self, key: u32) -> String {
+        // xkb keycodes are evdev keycodes offset by 8
+        self.0.key_get_utf8(xkb::Keycode::new(key + 8))
+    }
+}
+
+/// The built-in lock screen, alive from `lock()` until the password is accepted.
+#[derive(Debug)]
+pub struct LockScreen {
+    lock: ext_session_lock_v1::ExtSessionLockV1,
+    /// Set once the compositor confirmed the session is locked
+    locked: bool,
+    surfaces: Vec<LockSurface>,
+    keyboard: Option<wl_keyboard::WlKeyboard>,
+    /// None until the compositor sent its keymap, no text is typed before
+    keymap: Option<Keymap>,
+    password: String,
+    indicator: Indicator,
+    /// Dropping it stops the fingerprint verification
+    fingerprint: Option<oneshot::Sender<()>>,
+}
+
+impl LockScreen {
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    fn add_output(
+        &mut self,
+        compositor: &wl_compositor::WlCompositor,
+        reg_name: u32,
+        wl_output: &wl_output::WlOutput,
+        qh: &QueueHandle<State>,
+    ) {
+        let surface = compositor.create_surface(qh, ());
+        let lock_surface = self.lock.get_lock_surface(&surface, wl_output, qh, reg_name);
+        self.surfaces.push(LockSurface {
+            output: reg_name,
+            surface,
+            lock_surface,
+            buffer: None,
+            size: (0, 0),
+        });
+    }
+
+    fn redraw(&mut self, shm: &wl_shm::WlShm, qh: &QueueHandle<State>) {
+        let color = self.indicator.color();
+        for surface in self.surfaces.iter_mut() {
+            if let Err(e) = surface.draw(shm, color, qh) {
+                error!("Failed to draw the lock screen: {}", e);
+            }
+        }
+    }
+
+    /// Returns the password once Enter is pressed.
+    fn handle_key(&mut self, key: u32, pressed: bool) -> Option<String> {
+        match key {
+            _ if !pressed || self.indicator == Indicator::Checking => {}
+            KEY_ENTER | KEY_KPENTER => {
+                self.indicator = Indicator::Checking;
+                return Some(std::mem::take(&mut self.password));
+            }
+            KEY_BACKSPACE => {
+                self.password.pop();
+            }
+            KEY_ESC => self.password.clear(),
+            _ => {
+                if let Some(keymap) = &self.keymap {
+                    self.password.extend(keymap.text(key).chars().filter(|c| !c.is_control()));
+                }
+            }
+        }
+
+        if !self.password.is_empty() {
+            self.indicator = Indicator::Typing;
+        } else if self.indicator == Indicator::Typing {
+            self.indicator = Indicator::Idle;
+        }
+        None
+    }
+
+    fn destroy(self) {
+        if self.locked {
+            self.lock.unlock_and_destroy();
+        } else {
+            self.lock.destroy();
+        }
+        for surface in self.surfaces {
+            surface.destroy();
+        }
+        if let Some(keyboard) = self.keyboard {
+            if keyboard.version() >= 3 {
+                keyboard.release();
+            }
+        }
+    }
+}
+
+fn create_shm_file(size: usize) -> std::io::Result<File> {
+    // SAFETY: memfd_create hands us a new fd that nothing else owns
+    let fd = unsafe { libc::memfd_create(c"hypnos-lock".as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let file = File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+    file.set_len(size as u64)?;
+    Ok(file)
+}
+
+/// Lock the session with the built-in lock screen, a no-op when it is already up.
+pub fn lock(globals: &SharedGlobals, qh: &QueueHandle<State>, tx: &mpsc::Sender<Request>) -> anyhow::Result<()> {
+    let mut globals = globals.lock().unwrap();
+    if globals.lock_screen.is_some() {
+        debug!("Session is already locked");
+        return Ok(());
+    }
+
+    let Some(manager) = globals.session_lock_manager.clone() else {
+        anyhow::bail!("The compositor does not support ext-session-lock-v1");
+    };
+    let Some(compositor) = globals.compositor.clone() else {
+        anyhow::bail!("No wl_compositor available");
+    };
+    let Some(seat) = globals.seat.clone() else {
+        anyhow::bail!("No seat to read the password from");
+    };
+
+    info!("Locking the session");
+    let mut screen = LockScreen {
+        lock: manager.lock(qh, ()),
+        locked: false,
+        surfaces: Vec::new(),
+        keyboard: Some(seat.get_keyboard(qh, ())),
+        keymap: None,
+        password: String::new(),
+        indicator: Indicator::Idle,
+        fingerprint: None,
+    };
+    for (reg_name, output) in globals.outputs.iter() {
+        screen.add_output(&compositor, *reg_name, &output.wl_output, qh);
+    }
+
+    if globals.lock_config.fingerprint {
+        screen.fingerprint = Some(fingerprint::start(tx.clone(), globals.lock_config.fingerprint_attempts));
+    }
+
+    globals.lock_screen = Some(screen);
+    Ok(())
+}
+
+pub fn unlock(globals: &SharedGlobals) {
+    if let Some(screen) = globals.lock().unwrap().lock_screen.take() {
+        info!("Unlocking the session");
+        screen.destroy();
+    }
+}
+
+pub fn auth_failed(globals: &SharedGlobals, qh: &QueueHandle<State>) {
+    let mut globals = globals.lock().unwrap();
+    let shm = globals.shm.clone();
+    if let (Some(screen), Some(shm)) = (globals.lock_screen.as_mut(), shm) {
+        warn!("Wrong password");
+        screen.indicator = Indicator::Failed;
+        screen.redraw(&shm, qh);
+    }
+}
+
+/// Cover an output plugged in while locked.
+pub fn output_added(globals: &SharedGlobals, reg_name: u32, qh: &QueueHandle<State>) {
+    let mut globals = globals.lock().unwrap();
+    let (Some(compositor), Some(wl_output)) = (
+        globals.compositor.clone(),
+        globals.outputs.get(&reg_name).map(|output| output.wl_output.clone()),
+    ) else {
+        return;
+    };
+    if let Some(screen) = globals.lock_screen.as_mut() {
+        screen.add_output(&compositor, reg_name, &wl_output, qh);
+    }
+}
+
+pub fn output_removed(globals: &SharedGlobals, reg_name: u32) {
+    if let Some(screen) = globals.lock().unwrap().lock_screen.as_mut() {
+        if let Some(pos) = screen.surfaces.iter().position(|surface| surface.output == reg_name) {
+            screen.surfaces.remove(pos).destroy();
+        }
+    }
+}
+
+fn check_password(password: String, tx: mpsc::Sender<Request>) {
+    std::thread::spawn(move || {
+        let success = pam::current_user().is_some_and(|user| pam::authenticate(&user, &password));
+        let _ = tx.blocking_send(Request::LockAuth(success));
+    });
+}
+
+impl Dispatch<ext_session_lock_manager_v1::ExtSessionLockManagerV1, ()> for State {
+    fn event(_: &mut Self, _: &ext_session_lock_manager_v1::ExtSessionLockManagerV1, _: ext_session_lock_manager_v1::Event, _: &(), _: &Connection, _qh: &QueueHandle<Self>) {}
+}
+
+impl Dispatch<ext_session_lock_v1::ExtSessionLockV1, ()> for State {
+    fn event(
+        state: &mut Self,
+        _: &ext_session_lock_v1::ExtSessionLockV1,
+        event: ext_session_lock_v1::Event,
+        _: &(),
+        _: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        let mut globals = state.globals.lock().unwrap();
+        match event {
+            ext_session_lock_v1::Event::Locked => {
+                info!("Session locked");
+                if let Some(screen) = globals.lock_screen.as_mut() {
+                    screen.locked = true;
+                    let _ = state.tx.try_send(Request::SetLockedHint(true));
+                }
+            }
+            ext_session_lock_v1::Event::Finished => {
+                error!("The compositor refused or ended the session lock");
+                if let Some(mut screen) = globals.lock_screen.take() {
+                    // Only unlock_and_destroy may follow `locked`, unless `finished` was sent
+                    screen.locked = false;
+                    screen.destroy();
+                    let _ = state.tx.try_send(Request::SetLockedHint(false));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<ext_session_lock_surface_v1::ExtSessionLockSurfaceV1, u32> for State {
+    fn event(
+        state: &mut Self,
+        lock_surface: &ext_session_lock_surface_v1::ExtSessionLockSurfaceV1,
+        event: ext_session_lock_surface_v1::Event,
+        reg_name: &u32,
+        _: &Connection,
+        qh: &QueueHandle<Self>,
+    ) {
+        if let ext_session_lock_surface_v1::Event::Configure { serial, width, height } = event {
+            lock_surface.ack_configure(serial);
+
+            let mut globals = state.globals.lock().unwrap();
+            let shm = globals.shm.clone();
+            let (Some(screen), Some(shm)) = (globals.lock_screen.as_mut(), shm) else {
+                return;
+            };
+            let color = screen.indicator.color();
+            if let Some(surface) = screen.surfaces.iter_mut().find(|surface| surface.output == *reg_name) {
+                surface.size = (width, height);
+                if let Err(e) = surface.draw(&shm, color, qh) {
+                    error!("Failed to draw the lock screen: {}", e);
+                }
+            }
+        }
+    }
+}
+
+impl Dispatch<wl_keyboard::WlKeyboard, ()> for State {
+    fn event(
+        state: &mut Self,
+        _: &wl_keyboard::WlKeyboard,
+        event: wl_keyboard::Event,
+        _: &(),
+        _: &Connection,
+        qh: &QueueHandle<Self>,
+    ) {
+        let mut globals = state.globals.lock().unwrap();
+        let shm = globals.shm.clone();
+        let Some(screen) = globals.lock_screen.as_mut() else {
+            return;
+        };
+
+        match event {
+            wl_keyboard::Event::Keymap { format: WEnum::Value(wl_keyboard::KeymapFormat::XkbV1), fd, size } => {
+                match Keymap::from_fd(fd, size) {
+                    Ok(keymap) => screen.keymap = Some(keymap),
+                    Err(e) => error!("Failed to load the keymap: {}", e),
+                }
+            }
+            wl_keyboard::Event::Keymap { .. } => {
+                error!("The compositor sent a keymap that is not XKB, the password cannot be typed");
+            }
+            wl_keyboard::Event::Modifiers { mods_depressed, mods_latched, mods_locked, group, .. } => {
+                if let Some(keymap) = screen.keymap.as_mut() {
+                    keymap.0.update_mask(mods_depressed, mods_latched, mods_locked, 0, 0, group);
+                }
+            }
+            wl_keyboard::Event::Key { key, state: WEnum::Value(key_state), .. } => {
+                let indicator = screen.indicator;
+                if let Some(password) = screen.handle_key(key, key_state == wl_keyboard::KeyState::Pressed) {
+                    check_password(password, state.tx.clone());
+                }
+                if let (true, Some(shm)) = (screen.indicator != indicator, shm) {
+                    screen.redraw(&shm, qh);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<wl_shm::WlShm, ()> for State {
+    fn event(_: &mut Self, _: &wl_shm::WlShm, _: wl_shm::Event, _: &(), _: &Connection, _qh: &QueueHandle<Self>) {}
+}
+impl Dispatch<wl_shm_pool::WlShmPool, ()> for State {
+    fn event(_: &mut Self, _: &wl_shm_pool::WlShmPool, _: wl_shm_pool::Event, _: &(), _: &Connection, _qh: &QueueHandle<Self>) {}
+}
+impl Dispatch<wl_buffer::WlBuffer, ()> for State {
+    fn event(_: &mut Self, _: &wl_buffer::WlBuffer, _: wl_buffer::Event, _: &(), _: &Connection, _qh: &QueueHandle<Self>) {}
+}
@@ -0,0 +1,55 @@
+//! Tracing setup: plain lines, or one JSON object per line for log shippers (Loki,
+//! Vector...), and optionally a Chrome trace of the spans.
+
+use std::path::PathBuf;
+use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// Keep it until exit, the Chrome trace is only complete once it's dropped.
+pub struct Guard {
+    #[cfg(feature = "chrome-trace")]
+    _chrome: Option<tracing_chrome::FlushGuard>,
+}
+
+/// Set up the subscriber, `RUST_LOG` filtering per module, e.g. `info,hypnos::rules=debug`.
+pub fn init(format: LogFormat, chrome_trace: Option<PathBuf>) -> Guard {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let output = match format {
+        LogFormat::Text => fmt::layer().with_writer(std::io::stderr).boxed(),
+        // The message and fields (`rule`...) at the top level of each line
+        LogFormat::Json => fmt::layer()
+            .json()
+            .flatten_event(true)
+            .with_current_span(false)
+            .with_writer(std::io::stderr)
+            .boxed(),
+    };
+    let registry = tracing_subscriber::registry().with(output.with_filter(filter));
+
+    #[cfg(feature = "chrome-trace")]
+    {
+        let (chrome, guard) = match chrome_trace {
+            Some(path) => {
+                let (layer, guard) = tracing_chrome::ChromeLayerBuilder::new().file(path).include_args(true).build();
+                (Some(layer), Some(guard))
+            }
+            None => (None, None),
+        };
+        registry.with(chrome).init();
+        Guard { _chrome: guard }
+    }
+    #[cfg(not(feature = "chrome-trace"))]
+    {
+        registry.init();
+        if chrome_trace.is_some() {
+            tracing::warn!("hypnos was built without Chrome trace support, --chrome-trace is ignored");
+        }
+        Guard {}
+    }
+}
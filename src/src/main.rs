@@ -1,45 +1,82 @@
 use clap::{Parser, Subcommand};
-use env_logger::{Builder, Env};
 use inotify::{Inotify, WatchMask};
-use log::{debug, error, info};
-use serde::Deserialize;
+use tracing::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap, fs::{self, File}, io::Write, path::{Path, PathBuf}, sync::{
-        Arc, Mutex, atomic::{AtomicBool, Ordering}
-    }, time::Duration
+    collections::HashMap, fs::{self, File}, io::{self, Write}, os::fd::OwnedFd, path::{Path, PathBuf}, sync::{Arc, Mutex}, time::{Duration, Instant}
+};
+use futures::stream::StreamExt;
+use tokio::{
+    io::{unix::AsyncFd, Interest},
+    signal::unix::{signal, SignalKind},
+    sync::mpsc,
+    time::sleep,
 };
-use tokio::{process::Command, sync::mpsc, task::JoinHandle, time::sleep};
 use uuid::Uuid;
-use wayland::NotificationContext;
+use joystick_handler::GamepadConfig;
+use locker::LockScreenConfig;
+use wayland::{IdleNotification, NotificationContext, Seat};
 use wayland_client::{
-    Connection, EventQueue, QueueHandle, protocol::wl_surface::WlSurface
-};
-use wayland_protocols::{
-    wp::idle_inhibit::zv1::client::{
-        zwp_idle_inhibit_manager_v1, zwp_idle_inhibitor_v1::ZwpIdleInhibitorV1,
-    },
+    Connection, EventQueue, Proxy, QueueHandle, backend::WaylandError, protocol::wl_seat::WlSeat
 };
 
-use crate::types::{NotificationListHandle, SharedGlobals, WaylandGlobals};
+use crate::executor::ExecBackend;
+use crate::types::{IdleBackend, NotificationListHandle, RuleHandle, SharedGlobals, WaylandGlobals};
 
+mod adaptive;
+mod battery_saver;
+mod caffeine;
 mod config;
+mod config_cli;
+mod control;
+mod countdown;
 mod dbus;
+mod evdev_idle;
+mod fingerprint;
+mod init;
 mod joystick_handler;
+mod journal;
+mod learn;
+mod locker;
+mod logging;
+mod pam;
+mod profile;
+mod protocols;
+mod remote;
+mod rtc;
+mod rules;
+mod schedule;
+mod screensaver;
+mod state_file;
+mod stats;
 // mod sunset;
 mod types;
 mod udev_handler;
+mod udev_triggers;
 mod systemd;
-mod utils;
+mod thermal;
+mod timer;
+mod wake_locks;
 mod wayland;
+mod webhook;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+#[cfg(feature = "plugins")]
+mod plugins;
+#[cfg(feature = "scripting")]
+mod script;
+#[cfg(feature = "x11")]
+mod x11;
 
+use events::Event;
+use hypnos_core::config::CoreConfig;
+use hypnos_core::rules::Rule;
+use hypnos_core::{events, executor, history, inhibit, notify, utils};
+use executor::Action;
 use types::{Request, State};
+use caffeine::Caffeine;
 use udev_handler::UdevHandler;
 
-lazy_static::lazy_static! {
-    pub static ref INHIBIT_MANAGER: std::sync::Mutex<Option<zwp_idle_inhibit_manager_v1::ZwpIdleInhibitManagerV1>> = std::sync::Mutex::new(None);
-    pub static ref SURFACE: std::sync::Mutex<Option<WlSurface>> = std::sync::Mutex::new(None);
-}
-static IS_INHIBITED: AtomicBool = AtomicBool::new(false);
 
 fn ensure_config_file_exists(filename: &str) -> std::io::Result<()> {
     let config_path = utils::xdg_config_path(Some(filename.to_string()))?;
@@ -50,22 +87,107 @@ fn ensure_config_file_exists(filename: &str) -> std::io::Result<()> {
     Ok(())
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 struct AppConfig {
-    #[serde(default = "default_true")]
-    enabled: bool,
-    rules: HashMap<String, IdleRule>,
+    /// The rules, hooks and command settings, shared with hypnos-core
+    #[serde(flatten)]
+    core: CoreConfig,
+    /// Name of the seat to watch, the first one announced is used when unset
+    #[serde(default)]
+    seat: Option<String>,
+    #[serde(default)]
+    lock_screen: LockScreenConfig,
+    /// Controllers keeping the session awake
+    #[serde(default)]
+    gamepad: GamepadConfig,
+    #[serde(default)]
+    wake_schedule: Option<rtc::WakeSchedule>,
+    /// Commands run at fixed times of the day
+    #[serde(default)]
+    schedule: Vec<schedule::ScheduledCommand>,
+    /// Extend the timeouts of the rules resumed right after firing
+    #[serde(default)]
+    learn_timeouts: Option<learn::LearnConfig>,
+    /// Scale the `on_battery` rules' timeouts with the discharge rate
+    #[serde(default)]
+    adaptive_timeouts: Option<adaptive::AdaptiveTimeouts>,
+    /// Shorten every timeout on a low battery
+    #[serde(default)]
+    battery_saver: Option<battery_saver::BatterySaver>,
+    /// Input devices that don't count as activity, with the evdev backend, nor in the conditions
+    #[serde(default)]
+    ignored_inputs: Vec<joystick_handler::DeviceMatch>,
+    /// Commands run on DBus signals
+    #[serde(default)]
+    dbus_triggers: Vec<dbus::DbusTrigger>,
+    /// Commands run on udev events
+    #[serde(default)]
+    udev_triggers: Vec<udev_triggers::UdevTrigger>,
+    /// Notice VNC/RDP clients, for the rules' `skip_when_remote`
+    #[serde(default)]
+    remote_desktop: Option<remote::RemoteDesktopConfig>,
+    /// Serve org.freedesktop.ScreenSaver, for the apps inhibiting idle through it
+    #[serde(default)]
+    screensaver_server: bool,
+    /// systemd units (globs) holding the sleep rules off while running
+    #[serde(default)]
+    inhibit_while_units: Vec<String>,
+    /// Bus names holding rules off while owned
+    #[serde(default)]
+    inhibit_while_names: Vec<dbus::NameInhibit>,
+    /// Files in a directory holding idling off while present
+    #[serde(default)]
+    wake_locks: Option<wake_locks::WakeLockConfig>,
+    /// FIFO the warnings count down to, e.g. wob's
+    #[serde(default)]
+    countdown_pipe: Option<PathBuf>,
+    /// URLs to POST the events to
+    #[serde(default)]
+    webhooks: Vec<webhook::Webhook>,
+    /// Publish events to, and take commands from, an MQTT broker
+    #[cfg(feature = "mqtt")]
+    #[serde(default)]
+    mqtt: Option<mqtt::MqttConfig>,
+    /// Overrides of this config by name, merged over it while picked
+    #[serde(default)]
+    profiles: HashMap<String, serde_json::Value>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
-struct IdleRule {
-    timeout: i32,
-    actions: String,
-    restore: Option<String>,
-    #[serde(default)]
-    on_battery: Option<bool>,
-    #[serde(default = "default_true")]
-    enabled: bool,
+impl AppConfig {
+    /// What would be ignored, checked without a session.
+    fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        #[cfg(not(feature = "plugins"))]
+        if self.core.rules.values().any(|rule| !rule.plugins.is_empty()) {
+            problems.push("hypnos was built without plugin support, the rules' plugins are ignored".to_string());
+        }
+        #[cfg(not(feature = "scripting"))]
+        if self.core.rules.values().any(|rule| rule.condition.is_some()) {
+            problems.push("hypnos was built without scripting support, the rules' conditions are ignored".to_string());
+        }
+        if let (Some(name), None) = (&self.gamepad.presentation_button, self.gamepad.presentation_button()) {
+            problems.push(format!("Unknown presentation_button '{}', expected an evdev name like BTN_MODE", name));
+        }
+        if Duration::try_from_secs_f64(self.gamepad.presentation_hold).is_err() {
+            problems.push(format!(
+                "Invalid presentation_hold of {}s, using {:?}",
+                self.gamepad.presentation_hold,
+                self.gamepad.presentation_hold()
+            ));
+        }
+        let mut names: Vec<&String> = self.core.rules.keys().collect();
+        names.sort();
+        for name in names {
+            let rule = &self.core.rules[name];
+            if rule.warn_before.is_some() != rule.warn_command.is_some() {
+                problems.push(format!("Rule '{}' needs both warn_before and warn_command to warn", name));
+            }
+            if rule.timeout.checked_mul(1000).and_then(|ms| u32::try_from(ms).ok()).is_none() {
+                problems.push(format!("Rule '{}' has an invalid timeout of {}s, skipping it", name, rule.timeout));
+            }
+        }
+        problems
+    }
 }
 
 /// I hate you, serde
@@ -81,24 +203,250 @@ struct Args {
 
     #[arg(short, long, default_value = "config.json")]
     config: String,
+
+    #[arg(long, value_enum, help = "Idle detection backend, picked automatically when omitted")]
+    backend: Option<IdleBackend>,
+
+    #[arg(long, help = "Only log the commands the rules and hooks would run")]
+    dry_run: bool,
+
+    #[arg(long, value_enum, default_value_t = logging::LogFormat::Text, help = "Log as plain text, or as one JSON object per line")]
+    log_format: logging::LogFormat,
+
+    #[arg(long, help = "Write the spans (config reloads, rule firings, commands) as a Chrome trace to this file")]
+    chrome_trace: Option<PathBuf>,
 }
 
 #[derive(Subcommand, Debug)]
 enum Commands {
     #[command(about = "Run as daemon")]
     Daemon,
+    #[command(about = "Write a config for this session through a few questions")]
+    Init,
+    #[command(about = "Show or edit the configuration")]
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    #[command(about = "Manage the systemd service")]
+    Service {
+        #[arg(long, global = true, help = "Manage a system service, e.g. for a greeter or a kiosk")]
+        system: bool,
+        #[command(subcommand)]
+        command: ServiceCommand,
+    },
+    // Kept for compatibility, from before `hypnos service`
+    #[command(hide = true)]
+    Install { instance: Option<String> },
+    #[command(hide = true)]
+    Enable { instance: Option<String> },
+    #[command(hide = true)]
+    Disable { instance: Option<String> },
+    #[command(hide = true)]
+    Start { instance: Option<String> },
+    #[command(hide = true)]
+    Stop { instance: Option<String> },
+    #[command(hide = true)]
+    Restart { instance: Option<String> },
+    #[command(about = "Show the state of the running daemon")]
+    Status {
+        #[arg(long, help = "Print the raw JSON status")]
+        json: bool,
+        #[arg(long, conflicts_with = "json", help = "Print lines for a waybar custom module")]
+        waybar: bool,
+        #[arg(short, long, help = "Keep running and print the status again when it changes")]
+        follow: bool,
+    },
+    #[command(about = "List the Wayland globals of the compositor, marking those hypnos uses")]
+    Protocols {
+        #[arg(long, help = "Print the globals as JSON")]
+        json: bool,
+    },
+    #[command(about = "Fire a rule now, to try its commands without waiting for its timeout")]
+    Simulate {
+        #[arg(help = "Name of the rule")]
+        rule: String,
+        #[arg(long, help = "Run the resume path (restore) instead of the idle one")]
+        resume: bool,
+        #[arg(long, help = "Fire even if its conditions (on_battery, condition...) don't hold")]
+        force: bool,
+    },
+    #[command(about = "Postpone the rules once, e.g. while reading, they fire as usual afterwards")]
+    Snooze {
+        #[arg(value_parser = utils::parse_duration, help = "How long, e.g. 30m or 1h30m")]
+        duration: Duration,
+        #[arg(help = "Only this rule, instead of every rule that didn't fire yet")]
+        rule: Option<String>,
+    },
+    #[command(about = "Multiply every rule's timeout until reset with 1, e.g. during a meeting")]
+    Scale {
+        #[arg(value_parser = parse_scale, help = "Factor, e.g. 3 or 0.5")]
+        factor: f64,
+    },
+    #[command(about = "List, show or switch the profiles of the running daemon")]
+    Profile {
+        #[command(subcommand)]
+        command: ProfileCommand,
+    },
+    #[command(about = "Keep the session awake for a while, like a caffeine applet")]
+    Caffeinate {
+        #[arg(
+            default_value = "on",
+            help = "A duration like 30m or 2h, until-fullscreen-ends, on (until turned off) or off"
+        )]
+        preset: String,
+    },
+    #[command(about = "Print the time since the last input, like xprintidle")]
+    IdleTime {
+        #[arg(long, help = "In milliseconds, as xprintidle prints it, instead of seconds")]
+        ms: bool,
+    },
+    #[command(about = "Check that the daemon is running and responsive")]
+    Ping,
+    #[command(about = "Only log the commands instead of running them, or run them again")]
+    DryRun {
+        #[arg(value_parser = clap::builder::BoolishValueParser::new(), help = "on or off")]
+        enabled: bool,
+    },
+    #[command(about = "Show the last executed actions")]
+    History {
+        #[arg(short = 'n', long, default_value_t = 20, help = "Number of entries to show")]
+        count: usize,
+        #[arg(long, help = "Print the entries as JSON")]
+        json: bool,
+    },
+    #[command(about = "Show the daily idle/active time statistics")]
+    Stats {
+        #[arg(short = 'd', long, default_value_t = 7, help = "Number of days to show")]
+        days: usize,
+        #[arg(long, value_enum, default_value_t = stats::StatsFormat::Table, help = "Output format")]
+        format: stats::StatsFormat,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    #[command(about = "Print the configuration as the daemon reads it, with the defaults, as JSON")]
+    Show,
+    #[command(about = "Open the config in $EDITOR, and only save it once it's valid and the rule changes are confirmed")]
+    Edit,
+}
+
+#[derive(Subcommand, Debug)]
+enum ProfileCommand {
+    #[command(about = "List the profiles of the config, the active one marked with *")]
+    List,
+    #[command(about = "Print the active profile")]
+    Get,
+    #[command(about = "Switch to a profile, kept across restarts")]
+    Set {
+        #[arg(help = "Name of the profile, default for the config as written")]
+        name: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ServiceCommand {
     #[command(about = "Install the systemd service")]
-    Install,
+    Install {
+        #[arg(help = "Instance, hypnos@<instance>.service runs with <instance>.json")]
+        instance: Option<String>,
+        #[arg(long, requires = "system", help = "User the system service runs as, defaults to $SUDO_USER")]
+        user: Option<String>,
+        #[arg(long, requires = "system", help = "Wayland socket of the user's session, defaults to wayland-1")]
+        wayland_display: Option<String>,
+    },
+    #[command(about = "Stop, disable and remove the systemd service")]
+    Uninstall {
+        #[arg(help = "Instance, hypnos@<instance>.service runs with <instance>.json")]
+        instance: Option<String>,
+        #[arg(long, help = "Also remove the configuration file, and the history and statistics")]
+        purge: bool,
+    },
     #[command(about = "Enable the systemd service")]
-    Enable,
+    Enable {
+        #[arg(help = "Instance, hypnos@<instance>.service runs with <instance>.json")]
+        instance: Option<String>,
+    },
     #[command(about = "Disable the systemd service")]
-    Disable,
+    Disable {
+        #[arg(help = "Instance, hypnos@<instance>.service runs with <instance>.json")]
+        instance: Option<String>,
+    },
     #[command(about = "Start the systemd service")]
-    Start,
+    Start {
+        #[arg(help = "Instance, hypnos@<instance>.service runs with <instance>.json")]
+        instance: Option<String>,
+    },
     #[command(about = "Stop the systemd service")]
-    Stop,
+    Stop {
+        #[arg(help = "Instance, hypnos@<instance>.service runs with <instance>.json")]
+        instance: Option<String>,
+    },
     #[command(about = "Restart the systemd service")]
-    Restart,
+    Restart {
+        #[arg(help = "Instance, hypnos@<instance>.service runs with <instance>.json")]
+        instance: Option<String>,
+    },
+    #[command(about = "Show the state of the systemd service")]
+    Status {
+        #[arg(help = "Instance, hypnos@<instance>.service runs with <instance>.json")]
+        instance: Option<String>,
+        #[arg(long, help = "Print the status as JSON")]
+        json: bool,
+    },
+    #[command(about = "Show the logs of the systemd service")]
+    Logs {
+        #[arg(help = "Instance, hypnos@<instance>.service runs with <instance>.json")]
+        instance: Option<String>,
+        #[arg(short = 'n', long, default_value_t = 50, help = "Number of entries to show")]
+        lines: usize,
+        #[arg(long, help = "Show the entries since 10m, 2h, 1d... ago or a date (2024-01-31 08:00)")]
+        since: Option<String>,
+        #[arg(short, long, help = "Keep running and print new entries")]
+        follow: bool,
+    },
+}
+
+async fn service_command(command: ServiceCommand, system: bool) -> anyhow::Result<()> {
+    fn target(instance: &Option<String>, system: bool) -> systemd::Target<'_> {
+        systemd::Target {
+            instance: instance.as_deref(),
+            system,
+            ..Default::default()
+        }
+    }
+    match &command {
+        ServiceCommand::Install { instance, user, wayland_display } => {
+            systemd::install(systemd::Target {
+                user: user.as_deref(),
+                wayland_display: wayland_display.as_deref(),
+                ..target(instance, system)
+            })
+            .await
+        }
+        ServiceCommand::Uninstall { instance, purge } => systemd::uninstall(target(instance, system), *purge).await,
+        ServiceCommand::Enable { instance } => systemd::enable(target(instance, system)).await,
+        ServiceCommand::Disable { instance } => systemd::disable(target(instance, system)).await,
+        ServiceCommand::Start { instance } => systemd::start(target(instance, system)).await,
+        ServiceCommand::Stop { instance } => systemd::stop(target(instance, system)).await,
+        ServiceCommand::Restart { instance } => systemd::restart(target(instance, system)).await,
+        ServiceCommand::Status { instance, json } => systemd::status(target(instance, system), *json).await,
+        ServiceCommand::Logs { instance, lines, since, follow } => {
+            let since = since.as_deref().map(journal::parse_since).transpose()?;
+            let unit = systemd::unit_name(instance.as_deref());
+            let (lines, follow) = (*lines, *follow);
+            tokio::task::spawn_blocking(move || journal::print_logs(&unit, system, lines, since, follow)).await?
+        }
+    }
+}
+
+/// The `scale` factor, positive.
+fn parse_scale(factor: &str) -> Result<f64, String> {
+    match factor.parse::<f64>() {
+        Ok(factor) if factor.is_finite() && factor > 0.0 => Ok(factor),
+        _ => Err(format!("invalid factor '{}', expected a positive number like 3 or 0.5", factor)),
+    }
 }
 
 fn generate_uuid() -> uuid::Uuid {
@@ -111,100 +459,247 @@ fn load_json_config(path: &Path) -> anyhow::Result<AppConfig> {
     Ok(rules)
 }
 
+/// Like `load_json_config`, with the overrides of `profile` when the config has it.
+fn load_profile_config(path: &Path, profile: Option<&str>) -> anyhow::Result<AppConfig> {
+    let mut config: serde_json::Value = serde_json::from_str(&fs::read_to_string(path)?)?;
+    if let Some(profile) = profile {
+        profile::apply(&mut config, profile);
+    }
+    Ok(serde_json::from_value(config)?)
+}
+
+fn select_seat(seats: &HashMap<u32, Seat>, wanted: Option<&str>) -> Option<WlSeat> {
+    let seat = match wanted {
+        Some(wanted) => seats.values().find(|seat| seat.name.as_deref() == Some(wanted)),
+        None => seats.iter().min_by_key(|(reg_name, _)| **reg_name).map(|(_, seat)| seat),
+    };
+
+    // Only complain once every seat announced its name
+    if seat.is_none() && !seats.is_empty() && seats.values().all(|seat| seat.name.is_some()) {
+        if let Some(wanted) = wanted {
+            warn!("No seat named '{}' found", wanted);
+        }
+    }
+    seat.map(|seat| seat.wl_seat.clone())
+}
+
+#[tracing::instrument(skip_all)]
 pub fn apply_config(
     globals: &SharedGlobals,
-    qh: &QueueHandle<State>,
+    qh: Option<&QueueHandle<State>>,
     list: &NotificationListHandle,
     config_path: &Path,
 ) -> anyhow::Result<()> {
-    let config = match load_json_config(config_path) {
+    let profile = globals.lock().unwrap().profile.clone();
+    let config = match load_profile_config(config_path, profile.as_deref()) {
         Ok(r) => r,
         Err(e) => {
             error!("Failed to parse JSON config: {}", e);
+            notify::warn("Invalid configuration", &format!("{}\nThe previous configuration stays in effect.", e));
             return Ok(());
         }
     };
 
+    // What got ignored, for the notification
+    let mut problems = config.problems();
+    for message in &problems {
+        warn!("{}", message);
+    }
+    let mut problem = |message: String| {
+        if !problems.contains(&message) {
+            warn!("{}", message);
+            problems.push(message);
+        }
+    };
+
+    if let Some(profile) = profile.as_ref().filter(|profile| !config.profiles.contains_key(*profile)) {
+        problem(format!("No profile named '{}', using the configuration as written", profile));
+    }
+
+    #[cfg(feature = "plugins")]
+    plugins::load_all();
+
     {
         let mut globals_lock = globals.lock().unwrap();
-        globals_lock.is_paused = !config.enabled;
+        globals_lock.is_paused = !config.core.enabled;
+        globals_lock.lock_config = config.lock_screen.clone();
+        globals_lock.gamepad = config.gamepad.clone();
+        let mut profiles: Vec<String> = config.profiles.keys().cloned().collect();
+        profiles.sort();
+        globals_lock.profiles = profiles;
+        globals_lock.before_sleep = config.core.before_sleep.clone();
+        globals_lock.after_resume = config.core.after_resume.clone();
+        globals_lock.on_lock = config.core.on_lock.clone();
+        globals_lock.on_unlock = config.core.on_unlock.clone();
+        globals_lock.inhibit_duration = Some(Duration::from_secs(config.core.inhibit_duration));
+        globals_lock.on_battery_cmd = config.core.on_battery.clone();
+        globals_lock.on_ac_cmd = config.core.on_ac.clone();
+        globals_lock.on_start = config.core.on_start.clone();
+        globals_lock.on_exit = config.core.on_exit.clone();
+        globals_lock.wake_schedule = config.wake_schedule.clone();
+        globals_lock.schedule = config.schedule.clone();
+        globals_lock.shell = config.core.shell;
+        globals_lock.command_timeout = config.core.command_timeout.map(Duration::from_secs);
+        globals_lock.exec_backend = config.core.exec_backend;
+        globals_lock.sandbox = config.core.sandbox.clone();
+        globals_lock.retries = config.core.retries;
+        globals_lock.webhooks = config.webhooks.clone();
+        globals_lock.countdown_pipe = config.countdown_pipe.clone();
+        globals_lock.remote_desktop = config.remote_desktop.clone();
+        globals_lock.ignored_inputs = config.ignored_inputs.clone();
+        globals_lock.adaptive_timeouts = config.adaptive_timeouts.clone();
+        globals_lock.learn_timeouts = config.learn_timeouts.clone();
+        globals_lock.timeout_scale = adaptive::scale(
+            config.adaptive_timeouts.as_ref(),
+            globals_lock.on_battery,
+            globals_lock.energy_rate,
+        );
+        globals_lock.battery_saver = config.battery_saver.clone();
+        globals_lock.saver_scale = battery_saver::scale(
+            config.battery_saver.as_ref(),
+            globals_lock.on_battery,
+            globals_lock.battery_level,
+        );
+        globals_lock.rearm_pending = false;
 
-        let status = if config.enabled { "ENABLED" } else { "DISABLED" };
+        let status = if config.core.enabled { "ENABLED" } else { "DISABLED" };
         info!("Applying configuration: {}", status);
 
-        if globals_lock.notifier.is_none() || globals_lock.seat.is_none() {   
+        globals_lock.seat = select_seat(&globals_lock.seats, config.seat.as_deref());
+
+        let has_protocol = globals_lock.notifier.is_some() || globals_lock.kde_idle.is_some();
+        if globals_lock.backend == IdleBackend::Wayland && (!has_protocol || globals_lock.seat.is_none() || qh.is_none()) {
             debug!("Cannot apply config yet: notifier or seat missing");
             return Ok(());
         }
+        if let (IdleBackend::Wayland, Some(qh)) = (globals_lock.backend, qh) {
+            wayland::track_activity(&mut globals_lock, qh);
+        }
     }
 
     let globals_lock = globals.lock().unwrap();
-    let idle_notifier = globals_lock.notifier.as_ref().unwrap();
-    let wl_seat = globals_lock.seat.as_ref().unwrap();
+    let defaults = config.core.defaults();
 
     let mut map = list.lock().unwrap();
     
     // Cleanup
-    for (_, (_, _, _, notification)) in map.iter() {
-        notification.destroy();
+    for rule in map.values() {
+        rule.notification.destroy();
+        if let Some(pending) = &rule.pending_restore {
+            pending.abort();
+        }
     }
     map.clear();
 
-    for (name, rule) in config.rules {
-        let ctx = NotificationContext {
-            uuid: generate_uuid(),
-        };
-
+    let mut armed = Vec::new();
+    for (name, mut rule) in config.core.rules {
         if !rule.enabled {
             debug!("Skipping disabled rule: '{}'", name);
             continue;
         }
 
+        if config.learn_timeouts.as_ref().is_some_and(|learn| learn.apply) {
+            rule.timeout += globals_lock.learner.extra(&name) as i32;
+        }
+        if let (Some(true), Some(factor)) = (rule.on_battery, globals_lock.timeout_scale) {
+            rule.timeout = adaptive::scaled(rule.timeout, factor);
+        }
+        if let Some(factor) = globals_lock.saver_scale {
+            rule.timeout = adaptive::scaled(rule.timeout, factor);
+        }
+        if let Some(factor) = globals_lock.manual_scale {
+            rule.timeout = adaptive::scaled(rule.timeout, factor);
+        }
+        if let Some(snoozed) = globals_lock.snoozed.get(&name) {
+            rule.timeout += *snoozed as i32;
+        }
+        // Derived from the final timeout, so the warning follows it
+        if let Some(warning) = rule.warning(&defaults) {
+            let window = Duration::from_secs((rule.timeout - warning.timeout).max(0) as u64);
+            armed.push((format!("{}:warning", name), warning, Some(window)));
+        }
+        armed.push((name, rule, None));
+    }
+
+    for (name, rule, warning) in armed {
+        let ctx = NotificationContext {
+            uuid: generate_uuid(),
+        };
         debug!("Registering rule: {}s -> '{}' (on_battery: {:?})", rule.timeout, name, rule.on_battery);
 
-        let notification = idle_notifier.get_idle_notification(
-            (rule.timeout * 1000).try_into().unwrap(),
-            wl_seat,
-            qh,
-            ctx.clone(),
-        );
+        let Some(timeout_ms) = rule.timeout.checked_mul(1000).and_then(|ms| u32::try_from(ms).ok()) else {
+            problem(format!("Rule '{}' has an invalid timeout of {}s, skipping it", name, rule.timeout));
+            continue;
+        };
+        let notification = match (&globals_lock.notifier, &globals_lock.kde_idle, &globals_lock.seat, qh) {
+            _ if rule.after_lock => IdleNotification::Locked,
+            _ if globals_lock.backend != IdleBackend::Wayland => IdleNotification::Timer,
+            (Some(idle_notifier), _, Some(wl_seat), Some(qh)) => {
+                if rule.ignore_inhibitors && idle_notifier.version() >= 2 {
+                    IdleNotification::Ext(idle_notifier.get_input_idle_notification(timeout_ms, wl_seat, qh, ctx.clone()))
+                } else {
+                    if rule.ignore_inhibitors {
+                        warn!("Compositor does not support input idle notifications, rule '{}' will honor inhibitors", name);
+                    }
+                    IdleNotification::Ext(idle_notifier.get_idle_notification(timeout_ms, wl_seat, qh, ctx.clone()))
+                }
+            }
+            (None, Some(kde_idle), Some(wl_seat), Some(qh)) => {
+                if rule.ignore_inhibitors {
+                    warn!("org_kde_kwin_idle has no input idle notifications, rule '{}' will honor inhibitors", name);
+                }
+                IdleNotification::Kde(kde_idle.get_idle_timeout(wl_seat, timeout_ms, qh, ctx.clone()))
+            }
+            _ => unreachable!(),
+        };
 
         map.insert(
-            ctx.uuid, (
-                rule.actions,
-                rule.restore,
-                rule.on_battery.unwrap_or(false), 
-                notification
-            )
+            ctx.uuid,
+            RuleHandle {
+                rule: Rule {
+                    warning,
+                    ..Rule::from_config(name, rule, &defaults)
+                },
+                notification,
+            },
         );
     }
 
     info!("Configuration applied with {} rules", map.len());
+    drop(globals_lock);
+    let mut globals = globals.lock().unwrap();
+    globals.rules_armed = true;
+    // Re-arming (snooze, scaling...) applies the same config, only tell once
+    if problems != globals.config_problems {
+        if !problems.is_empty() {
+            notify::warn("Configuration applied with problems", &problems.join("\n"));
+        }
+        globals.config_problems = problems;
+    }
     Ok(())
 }
 
-async fn run_command(cmd: String) {
-    let (cmd_prog, args) = utils::get_args(cmd.clone());
-    debug!("Executing: {}", cmd);
-    
+/// Run a sleep hook and wait for it, reporting a failure: nobody watches the logs
+/// around a suspend, and a `before_sleep` that didn't lock leaves the session open.
+async fn run_hook(action: Action) {
+    let (command, event) = (action.command.clone(), action.context.event);
+    if let Some(failure) = executor::run_and_wait(action).await {
+        warn!("The {} hook '{}' failed: {}", event, command, failure);
+        notify::warn(&format!("The {} hook failed", event), &format!("'{}': {}", command, failure));
+    }
+}
+
+fn sleep_system(action: dbus::SleepAction) {
+    info!("Requesting {:?} from logind", action);
     tokio::spawn(async move {
-        match Command::new(&cmd_prog)
-            .args(args)
-            .spawn() 
-        {
-            Ok(mut child) => { 
-                match child.wait().await {
-                    Ok(status) => debug!("Command '{}' finished with {}", cmd_prog, status),
-                    Err(e) => error!("Command '{}' failed to wait: {}", cmd_prog, e),
-                }
-            }
-            Err(e) => error!("Failed to spawn '{}': {}", cmd_prog, e),
+        if let Err(e) = dbus::sleep(action).await {
+            error!("Failed to request {:?}: {}", action, e);
         }
     });
 }
 
 pub async fn filewatcher_run(config_path: &Path, tx: mpsc::Sender<Request>) -> anyhow::Result<()> {
-    let mut inotify = Inotify::init().expect("Error while initializing inotify");
+    let inotify = Inotify::init().expect("Error while initializing inotify");
 
     let parent = config_path.parent().unwrap_or_else(|| Path::new("."));
     let filename = config_path.file_name().expect("Failed to get file name").to_owned();
@@ -216,24 +711,79 @@ pub async fn filewatcher_run(config_path: &Path, tx: mpsc::Sender<Request>) -> a
         WatchMask::MODIFY | WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO | WatchMask::CREATE,
     ).expect("Failed to add watch");
 
-    let mut buffer = [0; 1024];
+    let mut events = inotify.into_event_stream([0; 1024])?;
+    while let Some(event) = events.next().await {
+        let event = event?;
+        if event.name.is_some_and(|n| n == filename) {
+            debug!("Config file changed (event: {:?}", event.mask);
+            let _ = tx.send(Request::ReloadConfig).await;
+        }
+    }
+    Ok(())
+}
+
+/// The Wayland side of the event loop: the queue and the `State` its events are
+/// dispatched to, read when the connection fd becomes readable.
+pub struct WaylandSession {
+    event_queue: EventQueue<State>,
+    state: State,
+    fd: AsyncFd<OwnedFd>,
+}
+
+impl WaylandSession {
+    /// Dispatch the events read so far and send out the queued requests.
+    fn dispatch(&mut self) -> anyhow::Result<()> {
+        self.event_queue.dispatch_pending(&mut self.state)?;
+        match self.event_queue.flush() {
+            // The socket is full, the rest goes out with the next flush
+            Err(WaylandError::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            result => Ok(result?),
+        }
+    }
 
-    tokio::task::spawn_blocking(move || loop {
-        let events = inotify.read_events_blocking(&mut buffer).expect("Failed to read inotify events");
-        for event in events {
-            if event.name.map(|n| n == filename).unwrap_or(false) {
-                debug!("Config file changed (event: {:?}", event.mask);
-                let _ = tx.blocking_send(Request::ReloadConfig);
+    /// Wait for events from the compositor and read them, forever without a connection.
+    async fn read(session: &mut Option<Self>) -> anyhow::Result<()> {
+        let Some(session) = session else {
+            return std::future::pending().await;
+        };
+        loop {
+            // None when events are already queued, the next dispatch handles them
+            let Some(guard) = session.event_queue.prepare_read() else {
+                return Ok(());
+            };
+            let mut ready = session.fd.readable().await?;
+            match guard.read() {
+                Ok(_) => return Ok(()),
+                Err(WaylandError::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => ready.clear_ready(),
+                Err(e) => return Err(e.into()),
             }
         }
-    });
-    Ok(())
+    }
+}
+
+/// Wait for the compositor to come back, with backoff, and hand the connection to
+/// the event loop.
+async fn reconnect(tx: mpsc::Sender<Request>) {
+    let mut delay = Duration::from_secs(1);
+    loop {
+        sleep(delay).await;
+        delay = (delay * 2).min(Duration::from_secs(config::RECONNECT_MAX_DELAY_SEC));
+
+        match Connection::connect_to_env() {
+            Ok(connection) => {
+                let _ = tx.send(Request::WaylandReconnected(connection)).await;
+                return;
+            }
+            Err(e) => debug!("Compositor not back yet: {}", e),
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct WaylandRunner {
-    connection: Connection,
-    qhandle: QueueHandle<State>,
+    /// None when running without a Wayland session
+    connection: Option<Connection>,
+    qhandle: Option<QueueHandle<State>>,
     tx: mpsc::Sender<Request>,
     notification_list: NotificationListHandle,
     config_path: PathBuf,
@@ -242,8 +792,8 @@ pub struct WaylandRunner {
 
 impl WaylandRunner {
     pub fn new(
-        connection: Connection,
-        qhandle: QueueHandle<State>,
+        connection: Option<Connection>,
+        qhandle: Option<QueueHandle<State>>,
         tx: mpsc::Sender<Request>,
         config_path: PathBuf,
         globals: SharedGlobals,
@@ -261,164 +811,1178 @@ impl WaylandRunner {
         }
     }
 
-    pub async fn wayland_run(
-        &self,
-        mut event_queue: EventQueue<State>,
-    ) -> anyhow::Result<JoinHandle<Result<(), anyhow::Error>>> {
+    pub async fn wayland_run(&self, mut event_queue: EventQueue<State>) -> anyhow::Result<WaylandSession> {
+        let (Some(connection), Some(qhandle)) = (&self.connection, &self.qhandle) else {
+            anyhow::bail!("No Wayland connection");
+        };
 
-        let display = self.connection.display();
-        display.get_registry(&self.qhandle, ());
+        let display = connection.display();
+        display.get_registry(qhandle, ());
 
         let mut state = State {
             globals: self.globals.clone(),
-            wl_seat: None,
-            idle_notifier: None,
-            qh: self.qhandle.clone(),
+            qh: qhandle.clone(),
             notification_list: self.notification_list.clone(),
             tx: self.tx.clone(),
-            outputs: HashMap::new(),
             config_path: self.config_path.clone(),
+            xdg_output_manager: None,
         };
 
-        Ok(tokio::task::spawn_blocking(move || loop {
-            event_queue.blocking_dispatch(&mut state)?;
-        }))
+        // Collect the globals before anything else needs them
+        event_queue.roundtrip(&mut state)?;
+
+        let fd = connection.backend().poll_fd().try_clone_to_owned()?;
+        Ok(WaylandSession {
+            event_queue,
+            state,
+            fd: AsyncFd::with_interest(fd, Interest::READABLE)?,
+        })
+    }
+
+    /// The compositor went away: drop everything tied to the old connection, and
+    /// reconnect in the background while the requests keep being handled.
+    fn connection_lost(&mut self) {
+        warn!("Lost the Wayland connection, reconnecting");
+        {
+            let mut map = self.notification_list.lock().unwrap();
+            for rule in map.values() {
+                if let Some(pending) = &rule.pending_restore {
+                    pending.abort();
+                }
+            }
+            map.clear();
+        }
+        {
+            let mut globals = self.globals.lock().unwrap();
+            globals.seats.clear();
+            globals.seat = None;
+            globals.outputs.clear();
+            globals.notifier = None;
+            globals.kde_idle = None;
+            globals.compositor = None;
+            globals.shm = None;
+            globals.session_lock_manager = None;
+            globals.lock_screen = None;
+            globals.idle_inhibit_manager = None;
+            globals.inhibit_surface = None;
+            globals.idle_inhibitor = None;
+        }
+        self.connection = None;
+        self.qhandle = None;
+        control::state_changed();
+        tokio::spawn(reconnect(self.tx.clone()));
+    }
+
+    /// Set the rules up again on the connection to the new compositor.
+    async fn reconnected(&mut self, connection: Connection) -> Option<WaylandSession> {
+        let event_queue = connection.new_event_queue();
+        self.qhandle = Some(event_queue.handle());
+        self.connection = Some(connection);
+        match self.wayland_run(event_queue).await {
+            Ok(session) => {
+                info!("Reconnected to the compositor");
+                let _ = apply_config(&self.globals, self.qhandle.as_ref(), &self.notification_list, &self.config_path);
+                Some(session)
+            }
+            Err(e) => {
+                debug!("Failed to set up the new Wayland connection: {}", e);
+                self.connection = None;
+                self.qhandle = None;
+                tokio::spawn(reconnect(self.tx.clone()));
+                None
+            }
+        }
     }
 
-    pub async fn process_command(&self, rx: &mut mpsc::Receiver<Request>) -> anyhow::Result<()> {
-        while let Some(event) = rx.recv().await {
+    /// The event loop: dispatches the Wayland events and handles the requests, so
+    /// both happen on this task.
+    pub async fn process_command(
+        &mut self,
+        rx: &mut mpsc::Receiver<Request>,
+        mut session: Option<WaylandSession>,
+    ) -> anyhow::Result<()> {
+        let mut started = false;
+        loop {
+            if let Some(wayland) = &mut session {
+                if let Err(e) = wayland.dispatch() {
+                    error!("Wayland connection error: {}", e);
+                    session = None;
+                    self.connection_lost();
+                    continue;
+                }
+            }
+
+            // The rules get armed once the compositor's globals are known, from a request
+            // or while dispatching
+            if !started && self.globals.lock().unwrap().rules_armed {
+                started = true;
+                let on_start = {
+                    let globals = self.globals.lock().unwrap();
+                    globals.hook(&globals.on_start, "start")
+                };
+                if let Some(action) = on_start {
+                    info!("Rules armed, executing: {}", action.command);
+                    self.run_action(action).await;
+                }
+            }
+
+            let event = tokio::select! {
+                event = rx.recv() => match event {
+                    Some(event) => event,
+                    None => return Ok(()),
+                },
+                result = WaylandSession::read(&mut session) => match result {
+                    Ok(()) => continue,
+                    Err(e) => {
+                        error!("Wayland connection error: {}", e);
+                        Request::WaylandLost
+                    }
+                },
+            };
             match event {
                 Request::ReloadConfig => {
                     debug!("Config reload requested");
                     let _ = apply_config(
                         &self.globals,
-                        &self.qhandle,
+                        self.qhandle.as_ref(),
                         &self.notification_list,
                         &self.config_path,
                     );
 
                     // Little flush to ensure requests are processed
-                    let _ = self.connection.flush();
-                }
-                Request::RunCommand(cmd) => {
-                    run_command(cmd).await;
+                    self.flush();
                 }
+                Request::RunCommand(action) => self.run_action(action).await,
                 Request::DbEvent(event_name) => {
                     debug!("DBus event received: {}", event_name);
+                    match event_name.as_str() {
+                        "PrepareSleep" => {
+                            events::emit(Event::Suspend);
+                            let runner = self.clone();
+                            tokio::spawn(async move { runner.prepare_for_sleep().await });
+                        }
+                        "Wakeup" => {
+                            events::emit(Event::Wakeup);
+                            self.take_sleep_inhibitor().await;
+                            self.check_scheduled_wake();
+                            let after_resume = {
+                                let globals = self.globals.lock().unwrap();
+                                globals.hook(&globals.after_resume, "after_resume")
+                            };
+                            if let Some(action) = after_resume {
+                                info!("Resumed from sleep, executing: {}", action.command);
+                                if action.command.starts_with('@') {
+                                    self.run_action(action).await;
+                                } else {
+                                    tokio::spawn(run_hook(action));
+                                }
+                            }
+                        }
+                        "Lock" => {
+                            let (on_lock, locked) = {
+                                let globals = self.globals.lock().unwrap();
+                                (globals.hook(&globals.on_lock, "lock"), globals.session_locked())
+                            };
+                            if locked {
+                                debug!("Session already locked, not running on_lock");
+                            } else if let Some(action) = on_lock {
+                                info!("Session lock requested, executing: {}", action.command);
+                                self.run_action(action).await;
+                            }
+                        }
+                        "Unlock" => {
+                            let on_unlock = {
+                                let globals = self.globals.lock().unwrap();
+                                globals.hook(&globals.on_unlock, "unlock")
+                            };
+                            self.set_locked_hint(false).await;
+                            if let Some(action) = on_unlock {
+                                info!("Session unlocked, executing: {}", action.command);
+                                self.run_action(action).await;
+                            }
+                        }
+                        _ => {}
+                    }
                 }
                 Request::OnBattery(state) => {
                     debug!("On Battery: {}", state);
-                    self.globals.lock().unwrap().on_battery = Some(state);
+                    // UPower and udev both report, and the first report isn't a switch
+                    let hook = {
+                        let mut globals = self.globals.lock().unwrap();
+                        let switched = globals.on_battery.is_some_and(|previous| previous != state);
+                        globals.on_battery = Some(state);
+                        match (switched, state) {
+                            (false, _) => None,
+                            (true, true) => globals.hook(&globals.on_battery_cmd, "on_battery"),
+                            (true, false) => globals.hook(&globals.on_ac_cmd, "on_ac"),
+                        }
+                    };
+                    self.rescale_timeouts();
+                    self.check_battery_saver();
+                    if let Some(action) = hook {
+                        info!("Switched to {}, executing: {}", if state { "battery" } else { "AC" }, action.command);
+                        self.run_action(action).await;
+                    }
+                }
+                Request::EnergyRate(rate) => {
+                    self.globals.lock().unwrap().energy_rate = Some(rate);
+                    self.rescale_timeouts();
+                }
+                Request::BatteryLevel(level) => {
+                    debug!("Battery level: {}%", level);
+                    self.globals.lock().unwrap().battery_level = Some(level);
+                    self.check_battery_saver();
+                }
+                Request::IdleSince(since) => {
+                    self.globals.lock().unwrap().idle_since = since;
+                }
+                Request::GamepadActivity => {
+                    let gamepad = self.globals.lock().unwrap().gamepad.clone();
+                    if gamepad.inhibit {
+                        self.inhibit_sleep("gamepad", "controller in use", Some(Duration::from_secs(gamepad.quiet_period)));
+                    }
+                }
+                Request::Inhibit { owner, reason, duration } => {
+                    let configured = self.globals.lock().unwrap().inhibit_duration;
+                    let duration = duration
+                        .or(configured)
+                        .unwrap_or(Duration::from_secs(config::DEFAULT_INHIBIT_SEC));
+                    if duration.is_zero() {
+                        info!("Inhibiting idle for {} until released", owner);
+                    }
+                    self.inhibit_sleep(&owner, &reason, Some(duration).filter(|duration| !duration.is_zero()));
+                }
+                Request::ReleaseInhibit { owner } => {
+                    if inhibit::release_owner(&owner) > 0 {
+                        info!("Released the inhibition of {}", owner);
+                    }
+                    self.sync_inhibitor();
+                }
+                Request::InputAdded(sysname, device) => {
+                    debug!("Input device added: {} ({}, external: {})", device.name, device.kind(), device.external);
+                    events::emit(Event::InputAdded {
+                        device: device.name.clone(),
+                        kind: device.kind().to_string(),
+                        external: device.external,
+                    });
+                    self.globals.lock().unwrap().input_devices.insert(sysname, device);
+                }
+                Request::InputRemoved(sysname) => {
+                    if let Some(device) = self.globals.lock().unwrap().input_devices.remove(&sysname) {
+                        debug!("Input device removed: {}", device.name);
+                        events::emit(Event::InputRemoved {
+                            kind: device.kind().to_string(),
+                            device: device.name,
+                            external: device.external,
+                        });
+                    }
                 }
-                Request::Inhibit => {
-                    let _ = self.inhibit_sleep();
+                Request::TogglePresentation => {
+                    let enabled = !self.globals.lock().unwrap().presentation;
+                    self.set_presentation(enabled);
+                }
+                Request::Uninhibit => {
+                    inhibit::release_expired();
+                    self.sync_inhibitor();
+                    self.check_caffeine();
+                }
+                Request::Caffeinate(caffeine) => self.caffeinate(caffeine),
+                Request::Fullscreen(fullscreen) => {
+                    let caffeine = self.globals.lock().unwrap().caffeine.map(|(_, caffeine)| caffeine);
+                    if !fullscreen && caffeine == Some(Caffeine::UntilFullscreenEnds) {
+                        inhibit::release_owner(caffeine::OWNER);
+                        self.sync_inhibitor();
+                        self.check_caffeine();
+                    }
                 }
                 Request::Flush => {
-                    let _ = self.connection.flush();
+                    self.flush();
+                }
+                Request::LockedHint(locked) => {
+                    debug!("Session locked: {}", locked);
+                    let mut globals = self.globals.lock().unwrap();
+                    if locked != globals.session_locked() {
+                        events::emit(if locked { Event::Lock } else { Event::Unlock });
+                    }
+                    globals.set_session_locked(locked);
+                }
+                Request::SessionActive(active) => {
+                    let mut globals = self.globals.lock().unwrap();
+                    if active == globals.session_inactive {
+                        if active {
+                            info!("Session active again, resuming the rules");
+                        } else {
+                            info!("Session inactive, pausing the rules");
+                        }
+                    }
+                    globals.session_inactive = !active;
+                }
+                Request::RemoteDesktop(connected) => {
+                    let mut globals = self.globals.lock().unwrap();
+                    if connected != globals.remote_client {
+                        if connected {
+                            info!("Remote desktop client connected, skipping the skip_when_remote rules");
+                        } else {
+                            info!("Remote desktop client disconnected");
+                        }
+                    }
+                    globals.remote_client = connected;
+                }
+                Request::SetLockedHint(locked) => self.set_locked_hint(locked).await,
+                Request::WaylandLost => {
+                    session = None;
+                    self.connection_lost();
+                }
+                Request::WaylandReconnected(connection) => session = self.reconnected(connection).await,
+                Request::SetPaused(paused) => {
+                    info!("{} the rules", if paused { "Pausing" } else { "Resuming" });
+                    self.globals.lock().unwrap().is_paused = paused;
+                }
+                Request::Snooze { rule, duration } => self.snooze(rule.as_deref(), duration),
+                Request::Scale(factor) => self.scale(factor),
+                Request::SetProfile(profile) => self.set_profile(profile),
+                Request::Ping(reply) => {
+                    let _ = reply.send(());
+                }
+                Request::LockAuth(success) => {
+                    if success {
+                        locker::unlock(&self.globals);
+                        self.set_locked_hint(false).await;
+                    } else if let Some(qh) = &self.qhandle {
+                        locker::auth_failed(&self.globals, qh);
+                    }
+                    self.flush();
+                }
+            }
+            control::state_changed();
+        }
+    }
+
+    /// Postpone the next firing of the rules that didn't fire yet, or of `rule`.
+    fn snooze(&self, rule: Option<&str>, duration: Duration) {
+        let pending: Vec<String> = self
+            .notification_list
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|handle| !handle.is_idled() && rule.is_none_or(|rule| handle.name == rule))
+            .map(|handle| handle.name.clone())
+            .collect();
+        if pending.is_empty() {
+            match rule {
+                Some(rule) => warn!("Can't snooze '{}': no such rule waiting to fire", rule),
+                None => debug!("No rule waiting to fire, nothing to snooze"),
+            }
+            return;
+        }
+        {
+            let mut globals = self.globals.lock().unwrap();
+            for name in &pending {
+                *globals.snoozed.entry(name.clone()).or_default() += duration.as_secs();
+            }
+        }
+        info!("Snoozing {} for {:?}", pending.join(", "), duration);
+        let _ = apply_config(&self.globals, self.qhandle.as_ref(), &self.notification_list, &self.config_path);
+        self.flush();
+    }
+
+    fn scale(&self, factor: f64) {
+        let factor = (factor != 1.0).then_some(factor);
+        {
+            let mut globals = self.globals.lock().unwrap();
+            if globals.manual_scale == factor {
+                return;
+            }
+            globals.manual_scale = factor;
+        }
+        match factor {
+            Some(factor) => info!("Scaling every timeout by {}", factor),
+            None => info!("Restoring the configured timeouts"),
+        }
+        let _ = apply_config(&self.globals, self.qhandle.as_ref(), &self.notification_list, &self.config_path);
+        self.flush();
+    }
+
+    /// Switch to `profile`, kept across restarts.
+    fn set_profile(&self, profile: Option<String>) {
+        {
+            let mut globals = self.globals.lock().unwrap();
+            if globals.profile == profile {
+                return;
+            }
+            globals.profile = profile.clone();
+        }
+        info!("Switching to the {} profile", profile.as_deref().unwrap_or(profile::DEFAULT));
+        if let Err(e) = profile::save(profile.as_deref()) {
+            error!("Failed to save the profile: {}", e);
+        }
+        let _ = apply_config(&self.globals, self.qhandle.as_ref(), &self.notification_list, &self.config_path);
+        self.flush();
+    }
+
+    /// Re-arm the rules when the discharge rate moved their timeouts enough.
+    fn rescale_timeouts(&self) {
+        let wanted = {
+            let mut globals = self.globals.lock().unwrap();
+            let wanted = adaptive::scale(globals.adaptive_timeouts.as_ref(), globals.on_battery, globals.energy_rate);
+            if !adaptive::changed(globals.timeout_scale, wanted) {
+                return;
+            }
+            // Compared against from now on, even while the re-arm waits
+            globals.timeout_scale = wanted;
+            wanted
+        };
+        match wanted {
+            Some(factor) => info!("Discharge rate changed, scaling the battery timeouts by {:.2}", factor),
+            None => info!("Restoring the battery timeouts"),
+        }
+        self.rearm();
+    }
+
+    /// Re-arm the rules for new timeouts, once the session is active again if a rule is idled.
+    fn rearm(&self) {
+        let idled = self.notification_list.lock().unwrap().values().any(|rule| rule.is_idled());
+        if idled {
+            debug!("A rule is idled, re-arming once the session resumes");
+            self.globals.lock().unwrap().rearm_pending = true;
+            return;
+        }
+        let _ = apply_config(&self.globals, self.qhandle.as_ref(), &self.notification_list, &self.config_path);
+        self.flush();
+    }
+
+    /// Re-arm the rules when the battery saver turns on or off.
+    fn check_battery_saver(&self) {
+        let wanted = {
+            let mut globals = self.globals.lock().unwrap();
+            let wanted = battery_saver::scale(globals.battery_saver.as_ref(), globals.on_battery, globals.battery_level);
+            if globals.saver_scale == wanted {
+                return;
+            }
+            // Notified once, even while the re-arm waits
+            globals.saver_scale = wanted;
+            wanted
+        };
+        let notification = match wanted {
+            Some(factor) => {
+                info!("Battery low, scaling every timeout by {}", factor);
+                notify::Notification::new("Battery saver on", "The idle timeouts are shorter until the battery charges")
+            }
+            None => {
+                info!("Battery saver off, restoring the timeouts");
+                notify::Notification::new("Battery saver off", "The idle timeouts are back to normal")
+            }
+        };
+        tokio::spawn(async move {
+            if let Err(e) = notification.send().await {
+                debug!("Failed to send a notification: {}", e);
+            }
+        });
+        self.rearm();
+    }
+
+    /// Hold off suspend until `before_sleep` ran, so the screen is locked before sleeping.
+    async fn take_sleep_inhibitor(&self) {
+        match dbus::sleep_delay_inhibitor().await {
+            Ok(fd) => self.globals.lock().unwrap().sleep_inhibitor = Some(fd),
+            Err(e) => error!("Failed to take the logind sleep inhibitor: {}", e),
+        }
+    }
+
+    async fn prepare_for_sleep(&self) {
+        let before_sleep = {
+            let globals = self.globals.lock().unwrap();
+            globals.hook(&globals.before_sleep, "before_sleep")
+        };
+
+        if let Some(action) = before_sleep {
+            info!("Preparing for sleep, executing: {}", action.command);
+            let timeout = Duration::from_secs(config::BEFORE_SLEEP_TIMEOUT_SEC);
+            let finished = match action.command.strip_prefix('@') {
+                Some(builtin) => {
+                    self.run_builtin(builtin, &action);
+                    if executor::is_dry_run() {
+                        Ok(())
+                    } else {
+                        tokio::time::timeout(timeout, self.wait_for_lock()).await
+                    }
                 }
+                None => tokio::time::timeout(timeout, run_hook(action)).await,
+            };
+            if finished.is_err() {
+                warn!("before_sleep did not finish within {}s, letting the system sleep", config::BEFORE_SLEEP_TIMEOUT_SEC);
             }
         }
-        Ok(())
+
+        self.schedule_wake();
+
+        // Dropping the fd releases the inhibitor
+        debug!("Releasing the sleep inhibitor");
+        self.globals.lock().unwrap().sleep_inhibitor.take();
     }
 
-    fn inhibit_sleep(&self) -> anyhow::Result<()> {
-        let qh = self.qhandle.clone();
-        let connection = self.connection.clone();
-        
+    /// Arm the RTC alarm before sleeping, if a wake schedule is configured.
+    fn schedule_wake(&self) {
+        let mut globals = self.globals.lock().unwrap();
+        let Some(schedule) = globals.wake_schedule.clone() else {
+            return;
+        };
+        match rtc::set_wake_alarm(Duration::from_secs(schedule.interval)) {
+            Ok(wake_at) => {
+                info!("Waking up in {}s for maintenance", schedule.interval);
+                globals.rtc_wake_at = Some(wake_at);
+            }
+            Err(e) => error!("Failed to program the RTC wake alarm: {}", e),
+        }
+    }
+
+    /// Run the maintenance command when the RTC alarm woke us, then go back to sleep
+    /// unless the machine got used meanwhile.
+    fn check_scheduled_wake(&self) {
+        let (wake_at, schedule, maintenance) = {
+            let mut globals = self.globals.lock().unwrap();
+            let command = globals.wake_schedule.as_ref().map(|schedule| schedule.command.clone());
+            let maintenance = globals.hook(&command, "scheduled_wake");
+            (globals.rtc_wake_at.take(), globals.wake_schedule.clone(), maintenance)
+        };
+        let Some(wake_at) = wake_at else {
+            return;
+        };
+
+        if !rtc::is_scheduled_wake(wake_at) {
+            debug!("Woken up before the RTC alarm, cancelling it");
+            if let Err(e) = rtc::clear_wake_alarm() {
+                warn!("Failed to clear the RTC wake alarm: {}", e);
+            }
+            return;
+        }
+
+        let (Some(schedule), Some(maintenance)) = (schedule, maintenance) else {
+            return;
+        };
+        info!("Scheduled wakeup, executing: {}", maintenance.command);
+        let (globals, list) = (self.globals.clone(), self.notification_list.clone());
+        let woke = Instant::now();
         tokio::spawn(async move {
-            if IS_INHIBITED.load(Ordering::SeqCst) { return; }
-            debug!("Inhibiting sleep");
-            IS_INHIBITED.store(true, Ordering::SeqCst);
+            executor::run_and_wait(maintenance).await;
+            if !schedule.resuspend {
+                return;
+            }
+            // Idle for less than since the wakeup, someone sat down meanwhile
+            if rules::session_idle_time(&globals, &list) < woke.elapsed() {
+                info!("Input since the scheduled wakeup, not suspending again");
+                return;
+            }
+            sleep_system(dbus::SleepAction::Suspend);
+        });
+    }
+
+    /// Wait until the built-in lock screen is confirmed by the compositor.
+    async fn wait_for_lock(&self) {
+        loop {
+            let locked = self.globals.lock().unwrap().lock_screen.as_ref().is_some_and(|screen| screen.is_locked());
+            if locked {
+                return;
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    /// Run a configured action, either a built-in or an external command.
+    async fn run_action(&self, action: Action) {
+        match action.command.strip_prefix('@') {
+            // The built-in lock screen reports itself once the compositor confirmed the lock
+            Some(builtin) => {
+                self.run_builtin(builtin, &action);
+                let status = if executor::is_dry_run() { "dry-run" } else { "built-in" };
+                history::record(history::Entry::new(&action.context, &action.command, status.to_string(), Duration::ZERO));
+            }
+            None => {
+                let is_locker = self.globals.lock().unwrap().on_lock.as_ref() == Some(&action.command);
+                // The session is unlocked once the locker exits. One started as a unit
+                // isn't waited for, its unlock is left to logind's `Unlock`
+                if is_locker && !executor::is_dry_run() && action.backend == ExecBackend::Direct {
+                    self.set_locked_hint(true).await;
+                    let tx = self.tx.clone();
+                    tokio::spawn(async move {
+                        executor::run_and_wait(action).await;
+                        debug!("The locker exited, the session is unlocked");
+                        let _ = tx.send(Request::SetLockedHint(false)).await;
+                    });
+                } else {
+                    executor::run(action);
+                }
+            }
+        }
+    }
 
-            let mut inhibitor: Option<ZwpIdleInhibitorV1> = None;
-            if let Some(manager) = INHIBIT_MANAGER.lock().unwrap().as_ref() {
-                let surface = SURFACE.lock().unwrap();
-                if let Some(surface) = surface.as_ref() {
-                    inhibitor = Some(manager.create_inhibitor(surface, &qh.clone(), ()));
-                    let _ = connection.flush();
+    /// Undo what the idle rules did and release everything before exiting: the restore
+    /// commands of the idled rules run, then `on_exit`.
+    async fn shutdown(&self) {
+        let restores: Vec<Action> = {
+            let on_battery = self.globals.lock().unwrap().on_battery;
+            let mut map = self.notification_list.lock().unwrap();
+            let restores = map
+                .values()
+                .filter(|rule| rule.is_idled())
+                .filter_map(|rule| rule.restore.clone().map(|restore| rule.action(restore, "exit", on_battery)))
+                .collect();
+            for rule in map.values() {
+                rule.notification.destroy();
+                if let Some(pending) = &rule.pending_restore {
+                    pending.abort();
                 }
             }
-            sleep(Duration::from_secs(config::TIMEOUT_SEC)).await;
+            map.clear();
+            restores
+        };
+        let on_exit = {
+            let mut globals = self.globals.lock().unwrap();
+            globals.sleep_inhibitor.take();
+            globals.hook(&globals.on_exit, "exit")
+        };
+        // The idle inhibitor goes away with the connection
+        self.flush();
+
+        let commands = async {
+            for action in restores.into_iter().chain(on_exit) {
+                info!("Exiting, executing: {}", action.command);
+                match action.command.strip_prefix('@') {
+                    Some(builtin) => self.run_builtin(builtin, &action),
+                    None => {
+                        executor::run_and_wait(action).await;
+                    }
+                }
+            }
+        };
+        let timeout = Duration::from_secs(config::SHUTDOWN_TIMEOUT_SEC);
+        if tokio::time::timeout(timeout, commands).await.is_err() {
+            warn!("Commands did not finish within {}s, exiting anyway", config::SHUTDOWN_TIMEOUT_SEC);
+        }
+        self.flush();
+    }
+
+    async fn set_locked_hint(&self, locked: bool) {
+        {
+            let mut globals = self.globals.lock().unwrap();
+            if globals.session_locked() == locked {
+                return;
+            }
+            // Don't wait for logind to echo it back, rules may fire meanwhile
+            globals.set_session_locked(locked);
+        }
+        if let Err(e) = dbus::set_locked_hint(locked).await {
+            error!("Failed to set the session LockedHint: {}", e);
+        }
+    }
+
+    /// Actions handled by hypnos itself, written `@name` in the config.
+    /// `action` is the one running the built-in, whose settings `@script` commands get.
+    #[cfg_attr(not(feature = "scripting"), allow(unused_variables))]
+    fn run_builtin(&self, builtin: &str, action: &Action) {
+        debug!("Executing built-in action: @{}", builtin);
+        let (name, args) = builtin.trim().split_once(char::is_whitespace).unwrap_or((builtin.trim(), ""));
+        // Scripts still run, to log what they would start
+        if executor::is_dry_run() && name != "script" {
+            info!("Would run: @{}", builtin);
+            return;
+        }
+        match name {
+            "lock" => {
+                let Some(qh) = &self.qhandle else {
+                    error!("The built-in lock screen needs a Wayland session");
+                    return;
+                };
+                if let Err(e) = locker::lock(&self.globals, qh, &self.tx) {
+                    error!("Failed to lock the session: {}", e);
+                }
+                self.flush();
+            }
+            "suspend" => sleep_system(dbus::SleepAction::Suspend),
+            "hibernate" => sleep_system(dbus::SleepAction::Hibernate),
+            "hybrid-sleep" => sleep_system(dbus::SleepAction::HybridSleep),
+            "suspend-then-hibernate" => sleep_system(dbus::SleepAction::SuspendThenHibernate),
+            "notify" => match notify::Notification::parse(args) {
+                Ok(notification) if notification.actions.is_empty() => {
+                    tokio::spawn(async move {
+                        if let Err(e) = notification.send().await {
+                            error!("Failed to send a notification: {}", e);
+                        }
+                    });
+                }
+                Ok(notification) => {
+                    let tx = self.tx.clone();
+                    // The rule warned about, not the warning itself
+                    let rule = action
+                        .context
+                        .rule
+                        .as_deref()
+                        .map(|rule| rule.strip_suffix(":warning").unwrap_or(rule).to_string());
+                    tokio::spawn(async move {
+                        match notification.send_and_wait().await {
+                            Ok(Some(action)) if action == notify::SNOOZE_ACTION => {
+                                let duration = Duration::from_secs(config::SNOOZE_SEC);
+                                let _ = tx.send(Request::Snooze { rule, duration }).await;
+                            }
+                            Ok(_) => {}
+                            Err(e) => error!("Failed to send a notification: {}", e),
+                        }
+                    });
+                }
+                Err(e) => error!("Invalid @notify action: {}", e),
+            },
+            #[cfg(feature = "scripting")]
+            "script" => {
+                let state = script::ScriptState::new(&self.globals.lock().unwrap());
+                for command in script::run(args, &state, action.context.rule.as_deref()) {
+                    debug!("Script started: {}", command);
+                    match command.strip_prefix('@') {
+                        Some(builtin) if builtin.starts_with("script") => error!("Scripts can't start @script"),
+                        Some(builtin) => self.run_builtin(builtin, action),
+                        None => executor::run(Action { command, ..action.clone() }),
+                    }
+                }
+            }
+            other => error!("Unknown built-in action '@{}'", other),
+        }
+    }
 
-            if let Some(inhibitor) = inhibitor {
+    fn flush(&self) {
+        if let Some(connection) = &self.connection {
+            let _ = connection.flush();
+        }
+    }
+
+    /// Keep the session awake on behalf of `owner`, for at least `duration` from now
+    /// or until released.
+    fn inhibit_sleep(&self, owner: &str, reason: &str, duration: Option<Duration>) {
+        inhibit::extend(owner, reason, duration.map(|duration| Instant::now() + duration));
+        self.sync_inhibitor();
+
+        if let Some(duration) = duration {
+            let tx = self.tx.clone();
+            tokio::spawn(async move {
+                sleep(duration).await;
+                let _ = tx.send(Request::Uninhibit).await;
+            });
+        }
+    }
+
+    /// Hold the compositor's idle inhibitor while an inhibition of every rule is, so
+    /// it's only destroyed with the last one. Those of some rules are checked as they fire.
+    fn sync_inhibitor(&self) {
+        let mut globals = self.globals.lock().unwrap();
+        if !inhibit::inhibits_all() {
+            if let Some(inhibitor) = globals.idle_inhibitor.take() {
+                debug!("Releasing the sleep inhibitor");
                 inhibitor.destroy();
-                let _ = connection.flush();
             }
-            IS_INHIBITED.store(false, Ordering::SeqCst);
+            return;
+        }
+        let Some(qh) = &self.qhandle else {
+            return;
+        };
+        if globals.idle_inhibitor.is_none() {
+            debug!("Inhibiting sleep");
+            let inhibitor = match (&globals.idle_inhibit_manager, &globals.inhibit_surface) {
+                (Some(manager), Some(surface)) => Some(manager.create_inhibitor(surface, qh, ())),
+                _ => None,
+            };
+            globals.idle_inhibitor = inhibitor;
+        }
+    }
+
+    /// Start, replace or stop `hypnos caffeinate`.
+    fn caffeinate(&self, caffeine: Caffeine) {
+        inhibit::release_owner(caffeine::OWNER);
+        self.globals.lock().unwrap().caffeine = None;
+        if caffeine == Caffeine::Off {
+            info!("Caffeine off");
+            self.sync_inhibitor();
+            return;
+        }
+        if caffeine == Caffeine::UntilFullscreenEnds && self.globals.lock().unwrap().fullscreen_toplevels.is_empty() {
+            warn!("No window is fullscreen, not caffeinating");
+            self.sync_inhibitor();
+            return;
+        }
+
+        info!("Caffeine on, {}", caffeine);
+        let reason = format!("caffeinated {}", caffeine);
+        let cookie = inhibit::acquire(caffeine::OWNER, &reason, caffeine.duration().map(|duration| Instant::now() + duration));
+        self.globals.lock().unwrap().caffeine = Some((cookie, caffeine));
+        self.sync_inhibitor();
+        if let Some(duration) = caffeine.duration() {
+            let tx = self.tx.clone();
+            tokio::spawn(async move {
+                sleep(duration).await;
+                let _ = tx.send(Request::Uninhibit).await;
+            });
+        }
+
+        let notification = notify::Notification::new("Caffeine on", &format!("The session stays awake {}", caffeine));
+        tokio::spawn(async move {
+            if let Err(e) = notification.send().await {
+                debug!("Failed to send a notification: {}", e);
+            }
+        });
+    }
+
+    /// Tell once the caffeine's inhibition is gone, expired or released.
+    fn check_caffeine(&self) {
+        let mut globals = self.globals.lock().unwrap();
+        let Some((cookie, _)) = globals.caffeine else {
+            return;
+        };
+        if inhibit::list().iter().any(|inhibitor| inhibitor.cookie == cookie) {
+            return;
+        }
+        globals.caffeine = None;
+        info!("Caffeine wore off");
+        let notification = notify::Notification::new("Caffeine wore off", "The idle rules apply again");
+        tokio::spawn(async move {
+            if let Err(e) = notification.send().await {
+                debug!("Failed to send a notification: {}", e);
+            }
+        });
+    }
+
+    /// Inhibit idle until turned off, confirmed with a notification as it's usually
+    /// toggled from a controller.
+    fn set_presentation(&self, enabled: bool) {
+        info!("Presentation mode {}", if enabled { "on" } else { "off" });
+        self.globals.lock().unwrap().presentation = enabled;
+        if enabled {
+            self.inhibit_sleep("presentation", "presentation mode", None);
+        } else {
+            inhibit::release_owner("presentation");
+            self.sync_inhibitor();
+        }
+
+        let notification = if enabled {
+            notify::Notification::new("Presentation mode on", "The session stays awake until it's turned off")
+        } else {
+            notify::Notification::new("Presentation mode off", "The idle rules apply again")
+        };
+        tokio::spawn(async move {
+            if let Err(e) = notification.send().await {
+                debug!("Failed to send a notification: {}", e);
+            }
         });
-        Ok(())
     }
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    Builder::from_env(Env::default().default_filter_or("info")).init();
     let args = Args::parse();
+    let _logging = logging::init(args.log_format, args.chrome_trace.clone());
 
     match args.command.unwrap_or(Commands::Daemon) {
-        Commands::Install => {
-            systemd::install().await?;
+        Commands::Init => return init::run(args.config).await,
+        Commands::Config { command } => match command {
+            ConfigCommand::Show => return config_cli::show(args.config),
+            ConfigCommand::Edit => return config_cli::edit(args.config),
+        },
+        Commands::Service { system, command } => return service_command(command, system).await,
+        Commands::Install { instance } => {
+            return service_command(ServiceCommand::Install { instance, user: None, wayland_display: None }, false).await
+        }
+        Commands::Enable { instance } => return service_command(ServiceCommand::Enable { instance }, false).await,
+        Commands::Disable { instance } => return service_command(ServiceCommand::Disable { instance }, false).await,
+        Commands::Start { instance } => return service_command(ServiceCommand::Start { instance }, false).await,
+        Commands::Stop { instance } => return service_command(ServiceCommand::Stop { instance }, false).await,
+        Commands::Restart { instance } => return service_command(ServiceCommand::Restart { instance }, false).await,
+        Commands::Status { json, waybar, follow } => {
+            let format = match (json, waybar) {
+                (true, _) => control::StatusFormat::Json,
+                (_, true) => control::StatusFormat::Waybar,
+                _ => control::StatusFormat::Text,
+            };
+            control::status(format, follow).await?;
+            return Ok(());
+        }
+        Commands::Protocols { json } => {
+            protocols::print(json)?;
+            return Ok(());
+        }
+        Commands::Simulate { rule, resume, force } => {
+            control::simulate(&rule, resume, force).await?;
+            return Ok(());
+        }
+        Commands::Snooze { duration, rule } => {
+            control::snooze(duration, rule.as_deref()).await?;
+            return Ok(());
+        }
+        Commands::Scale { factor } => {
+            control::scale(factor).await?;
+            return Ok(());
+        }
+        Commands::Profile { command } => {
+            match command {
+                ProfileCommand::List => control::list_profiles().await?,
+                ProfileCommand::Get => control::get_profile().await?,
+                ProfileCommand::Set { name } => control::set_profile(&name).await?,
+            }
+            return Ok(());
+        }
+        Commands::Caffeinate { preset } => {
+            control::caffeinate(&preset).await?;
             return Ok(());
         }
-        Commands::Enable => {
-            systemd::enable().await?;
+        Commands::IdleTime { ms } => {
+            control::idle_time(ms).await?;
             return Ok(());
         }
-        Commands::Disable => {
-            systemd::disable().await?;
+        Commands::Ping => {
+            control::ping().await?;
             return Ok(());
         }
-        Commands::Start => {
-            systemd::start().await?;
+        Commands::DryRun { enabled } => {
+            control::set_dry_run(enabled).await?;
             return Ok(());
         }
-        Commands::Stop => {
-            systemd::stop().await?;
+        Commands::History { count, json } => {
+            history::print(count, json)?;
             return Ok(());
         }
-        Commands::Restart => {
-            systemd::restart().await?;
+        Commands::Stats { days, format } => {
+            stats::print(days, format)?;
             return Ok(());
         }
         Commands::Daemon => {
-            run_daemon(args.config).await
+            if args.dry_run {
+                info!("Dry run: commands are only logged");
+                executor::set_dry_run(true);
+            }
+            run_daemon(args.config, args.backend).await
         }
     }
 }
 
-async fn run_daemon(config_name: String) -> anyhow::Result<()> {
+/// A panic in any task must not leave the daemon running without idle management:
+/// log it, tell the user and exit, releasing the inhibitors.
+fn install_panic_hook() {
+    std::panic::set_hook(Box::new(|info| {
+        error!("{}\n{}", info, std::backtrace::Backtrace::force_capture());
+        notify::warn_blocking(
+            "hypnos crashed",
+            "Idle management is offline until it restarts",
+            Duration::from_secs(2),
+        );
+        // Exiting releases the inhibitors: the compositor destroys the objects of a
+        // closed connection, logind drops the inhibitor when its fd gets closed
+        std::process::exit(config::PANIC_EXIT_CODE);
+    }));
+}
+
+async fn run_daemon(config_name: String, backend: Option<IdleBackend>) -> anyhow::Result<()> {
+    install_panic_hook();
     let _ = ensure_config_file_exists("config.json");
 
     let (tx, mut rx) = mpsc::channel(32);
 
     let config_path = utils::xdg_config_path(Some(config_name))?;
-    
-    filewatcher_run(&config_path, tx.clone()).await?;
+    let profile = profile::load();
+    // The connections, triggers and watches are set up once from it, with the active
+    // profile, changing them requires a restart
+    let startup = load_profile_config(&config_path, profile.as_deref()).ok();
+
+    #[cfg(feature = "mqtt")]
+    if let Some(mqtt) = startup.as_ref().and_then(|config| config.mqtt.clone()) {
+        tokio::spawn(mqtt::run(mqtt, tx.clone()));
+    }
 
-    let connection = Connection::connect_to_env().unwrap();
-    let event_queue: EventQueue<State> = connection.new_event_queue();
-    let qhandle = event_queue.handle();
+    let connection = match backend {
+        None | Some(IdleBackend::Wayland) => match Connection::connect_to_env() {
+            Ok(connection) => Some(connection),
+            Err(e) if backend.is_none() => {
+                warn!("Not running in a Wayland session: {}", e);
+                None
+            }
+            Err(e) => return Err(e.into()),
+        },
+        Some(_) => None,
+    };
+    let event_queue: Option<EventQueue<State>> = connection.as_ref().map(|c| c.new_event_queue());
+    let qhandle = event_queue.as_ref().map(|q| q.handle());
 
-    let globals = Arc::new(Mutex::new(WaylandGlobals::default()));
+    let globals = Arc::new(Mutex::new(WaylandGlobals {
+        profile,
+        ..Default::default()
+    }));
 
-    let wayland_runner = WaylandRunner::new(
+    let mut wayland_runner = WaylandRunner::new(
         connection.clone(),
         qhandle.clone(),
         tx.clone(),
-        config_path,
+        config_path.clone(),
         globals,
     );
 
-    let udev_handler = UdevHandler::new(tx.clone());
+    let triggers = startup.as_ref().map(|config| config.udev_triggers.clone()).unwrap_or_default();
+    let udev_handler = UdevHandler::new(tx.clone(), wayland_runner.globals.clone(), triggers);
+
+    let _control = match control::serve(
+        wayland_runner.globals.clone(),
+        wayland_runner.notification_list.clone(),
+        tx.clone(),
+    ).await {
+        Ok(conn) => Some(conn),
+        Err(e) => {
+            error!("Failed to export the control interface: {}", e);
+            None
+        }
+    };
+
+    let _screensaver = if startup.as_ref().is_some_and(|config| config.screensaver_server) {
+        match screensaver::serve(
+            wayland_runner.globals.clone(),
+            wayland_runner.notification_list.clone(),
+            tx.clone(),
+        ).await {
+            Ok(conn) => Some(conn),
+            Err(e) => {
+                warn!("Not serving org.freedesktop.ScreenSaver: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let session = match event_queue {
+        Some(event_queue) => match wayland_runner.wayland_run(event_queue).await {
+            Ok(session) => Some(session),
+            Err(e) => {
+                error!("Failed to set up the Wayland connection: {}", e);
+                None
+            }
+        },
+        None => None,
+    };
+
+    let has_idle_protocol = {
+        let globals = wayland_runner.globals.lock().unwrap();
+        globals.notifier.is_some() || globals.kde_idle.is_some()
+    };
+    let picked = backend.is_none();
+    let mut backend = match backend {
+        Some(backend) => backend,
+        None if has_idle_protocol => IdleBackend::Wayland,
+        None if connection.is_none() && cfg!(feature = "x11") && std::env::var_os("DISPLAY").is_some() => IdleBackend::X11,
+        None => {
+            warn!("No idle protocol available, falling back to logind IdleHint");
+            IdleBackend::LogindIdleHint
+        }
+    };
+    info!("Using the {:?} idle backend", backend);
+
+    if backend != IdleBackend::Wayland {
+        let mut result = start_timed_backend(backend, &wayland_runner.globals, tx.clone()).await;
+        // Headless, e.g. a server without any session, the input devices are all there is
+        if let Err(e) = &result {
+            if picked && connection.is_none() && backend == IdleBackend::LogindIdleHint {
+                warn!("No logind IdleHint ({}), falling back to the input devices", e);
+                backend = IdleBackend::Evdev;
+                result = start_timed_backend(backend, &wayland_runner.globals, tx.clone()).await;
+            }
+        }
+        if let Err(e) = result {
+            error!("Failed to start the {:?} idle backend, no rule will ever fire: {}", backend, e);
+        }
+        wayland_runner.globals.lock().unwrap().backend = backend;
+        let _ = tx.send(Request::ReloadConfig).await;
+    } else if !has_idle_protocol {
+        error!("The compositor offers no idle protocol, no rule will ever fire");
+    }
+
+    // Also times the post-lock rules, whatever the backend
+    tokio::spawn(timer::run(
+        wayland_runner.globals.clone(),
+        wayland_runner.notification_list.clone(),
+        tx.clone(),
+    ));
+    tokio::spawn(stats::run(wayland_runner.globals.clone()));
+    tokio::spawn(state_file::run(wayland_runner.globals.clone()));
+    tokio::spawn(schedule::run(
+        wayland_runner.globals.clone(),
+        wayland_runner.notification_list.clone(),
+        tx.clone(),
+    ));
+    tokio::spawn(webhook::run(wayland_runner.globals.clone()));
+    tokio::spawn(remote::run(wayland_runner.globals.clone(), tx.clone()));
+    let units = startup.as_ref().map(|config| config.inhibit_while_units.clone()).unwrap_or_default();
+    if !units.is_empty() {
+        tokio::spawn(systemd::unit_inhibitor(units, tx.clone()));
+    }
+    if let Some(wake_locks) = startup.as_ref().and_then(|config| config.wake_locks.clone()) {
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = wake_locks::run(wake_locks, tx).await {
+                error!("Wake locks stopped: {}", e);
+            }
+        });
+    }
+    for trigger in startup.as_ref().map(|config| config.dbus_triggers.clone()).unwrap_or_default() {
+        let signal = format!("{}.{}", trigger.interface, trigger.member);
+        if let Err(e) = dbus::trigger_watcher(trigger, wayland_runner.globals.clone(), tx.clone()).await {
+            warn!("Can't watch the {} signal: {}", signal, e);
+        }
+    }
+    for watch in startup.as_ref().map(|config| config.inhibit_while_names.clone()).unwrap_or_default() {
+        let name = watch.name.clone();
+        if let Err(e) = dbus::name_inhibitor(watch, tx.clone()).await {
+            warn!("Can't watch the owner of {}: {}", name, e);
+        }
+    }
+    #[cfg(feature = "plugins")]
+    tokio::spawn(plugins::run());
 
-    let _ = wayland_runner.wayland_run(event_queue).await;
+    wayland_runner.take_sleep_inhibitor().await;
 
-    tokio::try_join!(
-        dbus::upower_watcher(tx.clone()),
-        dbus::logind_watcher(tx.clone()),
-        wayland_runner.process_command(&mut rx),
-        udev_handler.monitor()
-    )?;
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    tokio::select! {
+        result = async {
+            tokio::try_join!(
+                // Without a system bus (a container, the tests) the rules just see no
+                // power source and no lock
+                async {
+                    if let Err(e) = dbus::upower_watcher(tx.clone()).await {
+                        warn!("No power source information from UPower: {}", e);
+                    }
+                    anyhow::Ok(())
+                },
+                async {
+                    if let Err(e) = dbus::logind_watcher(tx.clone()).await {
+                        warn!("No session information from logind: {}", e);
+                    }
+                    anyhow::Ok(())
+                },
+                filewatcher_run(&config_path, tx.clone()),
+                wayland_runner.process_command(&mut rx, session),
+                udev_handler.monitor()
+            )
+        } => {
+            result?;
+        }
+        _ = sigterm.recv() => info!("Received SIGTERM, exiting"),
+        _ = sigint.recv() => info!("Received SIGINT, exiting"),
+    }
+    wayland_runner.shutdown().await;
 
     Ok(())
 }
+
+/// Start the idle source of a backend timed by hypnos itself.
+async fn start_timed_backend(backend: IdleBackend, globals: &SharedGlobals, tx: mpsc::Sender<Request>) -> anyhow::Result<()> {
+    match backend {
+        IdleBackend::Wayland => Ok(()),
+        IdleBackend::LogindIdleHint => dbus::logind_idle_watcher(tx.clone()).await,
+        IdleBackend::Evdev => evdev_idle::idle_watcher(globals.clone(), tx.clone()).await,
+        #[cfg(feature = "x11")]
+        IdleBackend::X11 => x11::idle_watcher(tx.clone()).await,
+        #[cfg(not(feature = "x11"))]
+        IdleBackend::X11 => Err(anyhow::anyhow!("hypnos was built without X11 support")),
+    }
+}
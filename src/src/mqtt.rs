@@ -0,0 +1,146 @@
+//! MQTT integration: publishes the daemon events and takes commands, e.g. for Home Assistant.
+
+use tracing::{debug, error, info, warn};
+use rumqttc::{AsyncClient, Event as MqttEvent, LastWill, MqttOptions, Packet, QoS, Transport};
+use serde::{Deserialize, Serialize};
+use std::{fs, path::PathBuf, time::Duration};
+use tokio::sync::{broadcast, mpsc};
+
+use crate::events;
+use crate::types::Request;
+
+/// Wait between reconnection attempts
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MqttConfig {
+    pub host: String,
+    /// Defaults to 1883, or 8883 with TLS
+    #[serde(default)]
+    pub port: Option<u16>,
+    /// Defaults to `hypnos-<hostname>`
+    #[serde(default)]
+    pub client_id: Option<String>,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub tls: bool,
+    /// PEM CA certificate of the broker, the system ones are used when unset
+    #[serde(default)]
+    pub ca_file: Option<PathBuf>,
+    /// Prefix of the topics
+    #[serde(default = "default_topic")]
+    pub topic: String,
+}
+
+fn default_topic() -> String {
+    "hypnos".to_string()
+}
+
+fn options(config: &MqttConfig) -> anyhow::Result<MqttOptions> {
+    let client_id = config.client_id.clone().unwrap_or_else(|| {
+        let hostname = fs::read_to_string("/proc/sys/kernel/hostname").unwrap_or_default();
+        format!("hypnos-{}", hostname.trim())
+    });
+    let port = config.port.unwrap_or(if config.tls { 8883 } else { 1883 });
+
+    let mut options = MqttOptions::new(client_id, &config.host, port);
+    options.set_keep_alive(Duration::from_secs(30));
+    options.set_last_will(LastWill::new(
+        format!("{}/availability", config.topic),
+        "offline",
+        QoS::AtLeastOnce,
+        true,
+    ));
+    if let Some(username) = &config.username {
+        options.set_credentials(username, config.password.clone().unwrap_or_default());
+    }
+    if config.tls {
+        options.set_transport(match &config.ca_file {
+            Some(path) => Transport::tls(fs::read(path)?, None, None),
+            None => Transport::tls_with_default_config(),
+        });
+    }
+    Ok(options)
+}
+
+fn inhibit(duration: Option<Duration>) -> Request {
+    Request::Inhibit {
+        owner: "mqtt".to_string(),
+        reason: "requested over MQTT".to_string(),
+        duration,
+    }
+}
+
+/// Map a payload of the command topic to a request.
+fn parse_command(payload: &str) -> Option<Request> {
+    match payload.trim() {
+        "pause" => Some(Request::SetPaused(true)),
+        "resume" => Some(Request::SetPaused(false)),
+        "inhibit" => Some(inhibit(None)),
+        "uninhibit" => Some(Request::ReleaseInhibit { owner: "mqtt".to_string() }),
+        payload => {
+            let seconds = payload.strip_prefix("inhibit ")?.trim().parse().ok()?;
+            Some(inhibit(Some(Duration::from_secs(seconds))))
+        }
+    }
+}
+
+/// Publish the events under `<topic>/event` and `<topic>/state`, and take
+/// commands on `<topic>/command`, until the daemon exits.
+pub async fn run(config: MqttConfig, tx: mpsc::Sender<Request>) {
+    let options = match options(&config) {
+        Ok(options) => options,
+        Err(e) => {
+            error!("Invalid MQTT configuration: {}", e);
+            return;
+        }
+    };
+    let (client, mut eventloop) = AsyncClient::new(options, 16);
+    let command_topic = format!("{}/command", config.topic);
+    let mut events = events::subscribe();
+
+    loop {
+        tokio::select! {
+            notification = eventloop.poll() => match notification {
+                Ok(MqttEvent::Incoming(Packet::ConnAck(_))) => {
+                    info!("Connected to the MQTT broker {}", config.host);
+                    // Sessions are clean, subscribe again after each reconnection
+                    let _ = client.subscribe(&command_topic, QoS::AtLeastOnce).await;
+                    let _ = client
+                        .publish(format!("{}/availability", config.topic), QoS::AtLeastOnce, true, "online")
+                        .await;
+                }
+                Ok(MqttEvent::Incoming(Packet::Publish(publish))) if publish.topic == command_topic => {
+                    let payload = String::from_utf8_lossy(&publish.payload);
+                    match parse_command(&payload) {
+                        Some(request) => {
+                            debug!("MQTT command: {}", payload);
+                            let _ = tx.send(request).await;
+                        }
+                        None => warn!("Unknown MQTT command '{}'", payload),
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("MQTT connection error: {}", e);
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                }
+            },
+            event = events.recv() => match event {
+                Ok(event) => {
+                    let Ok(payload) = event.to_json() else {
+                        continue;
+                    };
+                    // Queued while disconnected, up to the client capacity
+                    let _ = client.try_publish(format!("{}/event", config.topic), QoS::AtLeastOnce, false, payload);
+                    let _ = client.try_publish(format!("{}/state", config.topic), QoS::AtLeastOnce, true, event.name());
+                }
+                Err(broadcast::error::RecvError::Lagged(count)) => warn!("Dropped {} MQTT events", count),
+                Err(broadcast::error::RecvError::Closed) => return,
+            },
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//! Just enough of libpam to check a password.
+
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::ptr;
+
+/// PAM service, installed as /etc/pam.d/hypnos
+const SERVICE: &str = "hypnos";
+
+const PAM_SUCCESS: c_int = 0;
+const PAM_BUF_ERR: c_int = 5;
+const PAM_CONV_ERR: c_int = 19;
+const PAM_PROMPT_ECHO_OFF: c_int = 1;
+const PAM_PROMPT_ECHO_ON: c_int = 2;
+
+#[repr(C)]
+struct PamMessage {
+    msg_style: c_int,
+    msg: *const c_char,
+}
+
+#[repr(C)]
+struct PamResponse {
+    resp: *mut c_char,
+    resp_retcode: c_int,
+}
+
+#[repr(C)]
+struct PamConv {
+    conv: extern "C" fn(c_int, *mut *const PamMessage, *mut *mut PamResponse, *mut c_void) -> c_int,
+    appdata_ptr: *mut c_void,
+}
+
+#[link(name = "pam")]
+extern "C" {
+    fn pam_start(
+        service_name: *const c_char,
+        user: *const c_char,
+        pam_conversation: *const PamConv,
+        pamh: *mut *mut c_void,
+    ) -> c_int;
+    fn pam_authenticate(pamh: *mut c_void, flags: c_int) -> c_int;
+    fn pam_acct_mgmt(pamh: *mut c_void, flags: c_int) -> c_int;
+    fn pam_end(pamh: *mut c_void, pam_status: c_int) -> c_int;
+}
+
+/// Answers every prompt with the password passed as appdata.
+extern "C" fn conversation(
+    num_msg: c_int,
+    msg: *mut *const PamMessage,
+    resp: *mut *mut PamResponse,
+    appdata_ptr: *mut c_void,
+) -> c_int {
+    if num_msg <= 0 || appdata_ptr.is_null() {
+        return PAM_CONV_ERR;
+    }
+
+    // SAFETY: PAM hands us num_msg messages and owns the responses once we return,
+    // freeing them with free(), hence calloc/strdup.
+    unsafe {
+        let password = &*(appdata_ptr as *const CString);
+        let responses = libc::calloc(num_msg as usize, std::mem::size_of::<PamResponse>()) as *mut PamResponse;
+        if responses.is_null() {
+            return PAM_BUF_ERR;
+        }
+
+        for i in 0..num_msg as usize {
+            let message = *msg.add(i);
+            if matches!((*message).msg_style, PAM_PROMPT_ECHO_OFF | PAM_PROMPT_ECHO_ON) {
+                (*responses.add(i)).resp = libc::strdup(password.as_ptr());
+            }
+        }
+
+        *resp = responses;
+    }
+    PAM_SUCCESS
+}
+
+/// Name of the user running hypnos.
+pub fn current_user() -> Option<String> {
+    // SAFETY: getpwuid returns a pointer to static storage, copied right away
+    unsafe {
+        let passwd = libc::getpwuid(libc::getuid());
+        if passwd.is_null() {
+            return None;
+        }
+        CStr::from_ptr((*passwd).pw_name).to_str().ok().map(str::to_owned)
+    }
+}
+
+/// Check `password` for `user` against the hypnos PAM service. Blocks, sometimes for seconds.
+pub fn authenticate(user: &str, password: &str) -> bool {
+    let (Ok(service), Ok(user), Ok(password)) = (CString::new(SERVICE), CString::new(user), CString::new(password)) else {
+        return false;
+    };
+
+    let conv = PamConv {
+        conv: conversation,
+        appdata_ptr: &password as *const CString as *mut c_void,
+    };
+
+    // SAFETY: every pointer outlives the PAM transaction, which is ended before returning
+    unsafe {
+        let mut pamh: *mut c_void = ptr::null_mut();
+        let mut status = pam_start(service.as_ptr(), user.as_ptr(), &conv, &mut pamh);
+        if status != PAM_SUCCESS {
+            return false;
+        }
+
+        status = pam_authenticate(pamh, 0);
+        if status == PAM_SUCCESS {
+            status = pam_acct_mgmt(pamh, 0);
+        }
+        pam_end(pamh, status);
+
+        status == PAM_SUCCESS
+    }
+}
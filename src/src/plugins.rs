@@ -0,0 +1,146 @@
+//! WASM plugins: custom conditions for the rules, and listeners of the daemon events.
+//!
+//! A plugin is `<config dir>/plugins/<name>.wasm`, a module without imports exporting any of:
+//! - `should_inhibit() -> i32`: non-zero holds off the rules listing the plugin
+//! - `on_event(ptr: i32, len: i32)`: gets each event as JSON, written into its exported
+//!   `memory` at a buffer it returns from `alloc(len: i32) -> i32`
+
+use lazy_static::lazy_static;
+use tracing::{debug, error, warn};
+use std::{collections::HashMap, fs, path::Path, sync::Mutex};
+use tokio::sync::broadcast;
+use wasmtime::{Config, Engine, Instance, Module, Store};
+
+use crate::{events, utils};
+
+/// Instructions a call may run, so a plugin stuck in a loop can't hang the daemon
+const FUEL_PER_CALL: u64 = 10_000_000;
+
+lazy_static! {
+    static ref ENGINE: Engine = {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        Engine::new(&config).expect("Failed to set up the WASM engine")
+    };
+    static ref PLUGINS: Mutex<HashMap<String, Plugin>> = Mutex::new(HashMap::new());
+}
+
+struct Plugin {
+    store: Store<()>,
+    instance: Instance,
+}
+
+impl Plugin {
+    fn load(path: &Path) -> anyhow::Result<Self> {
+        let module = Module::from_file(&ENGINE, path)?;
+        let mut store = Store::new(&ENGINE, ());
+        store.set_fuel(FUEL_PER_CALL)?;
+        // No imports: a plugin only sees what it's given
+        let instance = Instance::new(&mut store, &module, &[])?;
+        Ok(Self { store, instance })
+    }
+
+    /// None when the plugin doesn't export `should_inhibit`.
+    fn should_inhibit(&mut self) -> anyhow::Result<Option<bool>> {
+        let Ok(should_inhibit) = self.instance.get_typed_func::<(), i32>(&mut self.store, "should_inhibit") else {
+            return Ok(None);
+        };
+        self.store.set_fuel(FUEL_PER_CALL)?;
+        Ok(Some(should_inhibit.call(&mut self.store, ())? != 0))
+    }
+
+    fn on_event(&mut self, json: &str) -> anyhow::Result<()> {
+        let Ok(on_event) = self.instance.get_typed_func::<(i32, i32), ()>(&mut self.store, "on_event") else {
+            return Ok(());
+        };
+        let alloc = self.instance.get_typed_func::<i32, i32>(&mut self.store, "alloc")?;
+        let memory = self
+            .instance
+            .get_memory(&mut self.store, "memory")
+            .ok_or_else(|| anyhow::anyhow!("on_event needs an exported memory"))?;
+
+        self.store.set_fuel(FUEL_PER_CALL)?;
+        let len = i32::try_from(json.len())?;
+        let ptr = alloc.call(&mut self.store, len)?;
+        memory.write(&mut self.store, ptr as u32 as usize, json.as_bytes())?;
+        on_event.call(&mut self.store, (ptr, len))?;
+        Ok(())
+    }
+}
+
+/// (Re)load the plugins of the plugins directory, those failing to load are left out.
+pub fn load_all() {
+    let mut plugins = HashMap::new();
+    let dir = match utils::xdg_config_path(None) {
+        Ok(dir) => dir.join("plugins"),
+        Err(e) => {
+            error!("Failed to find the plugins directory: {}", e);
+            return;
+        }
+    };
+
+    if let Ok(entries) = fs::read_dir(&dir) {
+        for path in entries.flatten().map(|entry| entry.path()) {
+            if path.extension().is_none_or(|extension| extension != "wasm") {
+                continue;
+            }
+            let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            match Plugin::load(&path) {
+                Ok(plugin) => {
+                    debug!("Loaded plugin '{}'", name);
+                    plugins.insert(name.to_string(), plugin);
+                }
+                Err(e) => error!("Failed to load plugin {:?}: {}", path, e),
+            }
+        }
+    }
+    *PLUGINS.lock().unwrap() = plugins;
+}
+
+/// Whether one of the plugins `names` holds off idling. Missing and failing plugins don't.
+pub fn should_inhibit(names: &[String]) -> bool {
+    let mut plugins = PLUGINS.lock().unwrap();
+    names.iter().any(|name| match plugins.get_mut(name) {
+        Some(plugin) => match plugin.should_inhibit() {
+            Ok(Some(inhibit)) => inhibit,
+            Ok(None) => {
+                warn!("Plugin '{}' has no should_inhibit", name);
+                false
+            }
+            Err(e) => {
+                error!("Plugin '{}' failed: {}", name, e);
+                false
+            }
+        },
+        None => {
+            warn!("Plugin '{}' is not loaded", name);
+            false
+        }
+    })
+}
+
+/// Pass the events to the plugins.
+pub async fn run() {
+    let mut events = events::subscribe();
+
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(count)) => {
+                warn!("Dropped {} plugin events", count);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        let Ok(json) = event.to_json() else {
+            continue;
+        };
+        for (name, plugin) in PLUGINS.lock().unwrap().iter_mut() {
+            if let Err(e) = plugin.on_event(&json) {
+                error!("Plugin '{}' failed on {}: {}", name, event.name(), e);
+            }
+        }
+    }
+}
@@ -0,0 +1,50 @@
+//! Profiles: named overrides of the configuration, e.g. longer timeouts for
+//! presentations, picked with `hypnos profile set` and kept across restarts.
+
+use serde_json::Value;
+use std::{fs, path::PathBuf};
+use xdg::BaseDirectories;
+
+use crate::config;
+
+const PROFILE_FILE: &str = "profile";
+/// Name standing for the configuration as written
+pub const DEFAULT: &str = "default";
+
+fn profile_path() -> std::io::Result<PathBuf> {
+    BaseDirectories::with_prefix(config::APP_NAME).place_state_file(PROFILE_FILE)
+}
+
+/// The profile picked last, None for the configuration as written.
+pub fn load() -> Option<String> {
+    let profile = fs::read_to_string(profile_path().ok()?).ok()?;
+    Some(profile.trim().to_string()).filter(|profile| !profile.is_empty())
+}
+
+pub fn save(profile: Option<&str>) -> std::io::Result<()> {
+    let path = profile_path()?;
+    match profile {
+        Some(profile) => fs::write(path, profile),
+        None if path.exists() => fs::remove_file(path),
+        None => Ok(()),
+    }
+}
+
+/// Merge `overlay` into `base`: objects key by key, anything else replaced.
+fn merge(base: &mut Value, overlay: &Value) {
+    match (base, overlay) {
+        (Value::Object(base), Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                merge(base.entry(key.clone()).or_insert(Value::Null), value);
+            }
+        }
+        (base, overlay) => *base = overlay.clone(),
+    }
+}
+
+/// Apply the overrides of `profile` to `config`, left as is without such a profile.
+pub fn apply(config: &mut Value, profile: &str) {
+    if let Some(overrides) = config.get("profiles").and_then(|profiles| profiles.get(profile)).cloned() {
+        merge(config, &overrides);
+    }
+}
@@ -0,0 +1,87 @@
+//! `hypnos protocols`: the globals the compositor advertises, for compatibility reports.
+
+use serde::Serialize;
+use wayland_client::{
+    globals::{registry_queue_init, GlobalListContents},
+    protocol::wl_registry,
+    Connection, Dispatch, QueueHandle,
+};
+
+/// The globals hypnos binds: interface, highest version it uses, and what for
+const USED: &[(&str, u32, &str)] = &[
+    ("ext_idle_notifier_v1", 2, "idle notifications, version 2 for ignore_inhibitors"),
+    ("org_kde_kwin_idle", 1, "idle notifications without ext_idle_notifier_v1"),
+    ("wl_seat", 2, "input seats, version 2 for the seat option"),
+    ("zwp_idle_inhibit_manager_v1", 1, "inhibiting idle"),
+    ("wl_compositor", 1, "surfaces of the inhibitor and the lock screen"),
+    ("wl_shm", 1, "lock screen buffers"),
+    ("ext_session_lock_manager_v1", 1, "the built-in lock screen"),
+    ("wl_output", 4, "outputs"),
+    ("zxdg_output_manager_v1", 3, "output names"),
+    ("zwlr_foreign_toplevel_manager_v1", 3, "fullscreen windows, for caffeinate until-fullscreen-ends"),
+];
+
+#[derive(Debug, Serialize)]
+struct GlobalInfo {
+    interface: String,
+    version: u32,
+    /// What hypnos uses it for
+    used_for: Option<&'static str>,
+}
+
+struct Probe;
+
+impl Dispatch<wl_registry::WlRegistry, GlobalListContents> for Probe {
+    fn event(
+        _: &mut Self,
+        _: &wl_registry::WlRegistry,
+        _: wl_registry::Event,
+        _: &GlobalListContents,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+/// Print every global of the compositor, marking those hypnos uses.
+pub fn print(json: bool) -> anyhow::Result<()> {
+    let connection = Connection::connect_to_env()?;
+    let (globals, _queue) = registry_queue_init::<Probe>(&connection)?;
+    let mut list = globals.contents().clone_list();
+    list.sort_by(|a, b| a.interface.cmp(&b.interface));
+
+    if json {
+        let infos: Vec<GlobalInfo> = list
+            .into_iter()
+            .map(|global| GlobalInfo {
+                used_for: USED.iter().find(|(interface, ..)| *interface == global.interface).map(|(.., used_for)| *used_for),
+                interface: global.interface,
+                version: global.version,
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&infos)?);
+        return Ok(());
+    }
+
+    let width = list.iter().map(|global| global.interface.len()).max().unwrap_or(0);
+    for global in &list {
+        match USED.iter().find(|(interface, ..)| *interface == global.interface) {
+            Some((_, wanted, used_for)) => {
+                let outdated = if global.version < *wanted { format!(" (up to v{} used)", wanted) } else { String::new() };
+                println!("* {:<width$}  v{:<3} {}{}", global.interface, global.version, used_for, outdated);
+            }
+            None => println!("  {:<width$}  v{}", global.interface, global.version),
+        }
+    }
+
+    let missing: Vec<&str> = USED
+        .iter()
+        .map(|(interface, ..)| *interface)
+        .filter(|interface| !list.iter().any(|global| global.interface == *interface))
+        .collect();
+    println!("\n* used by hypnos");
+    if !missing.is_empty() {
+        println!("Not advertised: {}", missing.join(", "));
+    }
+    Ok(())
+}
@@ -0,0 +1,112 @@
+//! Remote desktop detection: a VNC/RDP server of the user with a client connected.
+
+use tracing::debug;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, fs, time::Duration};
+use tokio::sync::mpsc;
+
+use crate::types::{Request, SharedGlobals};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// TCP_ESTABLISHED in `/proc/net/tcp`
+const ESTABLISHED: &str = "01";
+
+/// The `remote_desktop` section of the config.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RemoteDesktopConfig {
+    /// Process names of the servers, a session is remote while one of them has a client
+    #[serde(default = "default_servers")]
+    pub servers: Vec<String>,
+}
+
+fn default_servers() -> Vec<String> {
+    ["wayvnc", "krfb", "gnome-remote-desktop-daemon", "weston-rdp", "x11vnc"]
+        .map(String::from)
+        .to_vec()
+}
+
+impl Default for RemoteDesktopConfig {
+    fn default() -> Self {
+        Self { servers: default_servers() }
+    }
+}
+
+/// Inodes of the established TCP connections.
+fn established_sockets() -> HashSet<u64> {
+    let mut inodes = HashSet::new();
+    for table in ["/proc/net/tcp", "/proc/net/tcp6"] {
+        let Ok(content) = fs::read_to_string(table) else {
+            continue;
+        };
+        for line in content.lines().skip(1) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.get(3) == Some(&ESTABLISHED) {
+                if let Some(inode) = fields.get(9).and_then(|inode| inode.parse().ok()) {
+                    inodes.insert(inode);
+                }
+            }
+        }
+    }
+    inodes
+}
+
+/// Whether one of `servers` holds an established connection. Only processes of the
+/// user can be inspected.
+fn client_connected(servers: &[String]) -> bool {
+    let established = established_sockets();
+    if established.is_empty() {
+        return false;
+    }
+    let Ok(processes) = fs::read_dir("/proc") else {
+        return false;
+    };
+    for process in processes.flatten() {
+        let Ok(comm) = fs::read_to_string(process.path().join("comm")) else {
+            continue;
+        };
+        // The kernel truncates process names to 15 bytes
+        let comm = comm.trim_end();
+        if !servers.iter().any(|server| server.get(..15).unwrap_or(server) == comm) {
+            continue;
+        }
+        let Ok(fds) = fs::read_dir(process.path().join("fd")) else {
+            continue;
+        };
+        for fd in fds.flatten() {
+            let Ok(target) = fs::read_link(fd.path()) else {
+                continue;
+            };
+            let inode = target
+                .to_str()
+                .and_then(|target| target.strip_prefix("socket:["))
+                .and_then(|target| target.strip_suffix(']'))
+                .and_then(|inode| inode.parse::<u64>().ok());
+            if inode.is_some_and(|inode| established.contains(&inode)) {
+                debug!("Remote desktop client connected to {}", comm);
+                return true;
+            }
+        }
+    }
+    false
+}
+
+/// Report whether a remote desktop client is connected, on every change.
+pub async fn run(globals: SharedGlobals, tx: mpsc::Sender<Request>) {
+    let mut connected = false;
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+        // Not configured, nothing to scan for
+        let servers = globals.lock().unwrap().remote_desktop.as_ref().map(|config| config.servers.clone());
+        let now_connected = match servers {
+            Some(servers) => tokio::task::spawn_blocking(move || client_connected(&servers)).await.unwrap_or(false),
+            None => false,
+        };
+        if now_connected != connected {
+            connected = now_connected;
+            if tx.send(Request::RemoteDesktop(connected)).await.is_err() {
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,43 @@
+//! Wake the machine from suspend with the RTC alarm, to run maintenance commands.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    time::{Duration, SystemTime},
+};
+
+/// Needs write access, e.g. through a udev rule or running as root
+const WAKEALARM_PATH: &str = "/sys/class/rtc/rtc0/wakealarm";
+
+/// How early a wakeup may happen and still count as ours: resume takes a few seconds
+/// and the RTC only has a 1 second resolution.
+const WAKE_SLACK: Duration = Duration::from_secs(60);
+
+/// The `wake_schedule` section of the config.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct WakeSchedule {
+    /// Seconds after suspending to wake up
+    pub interval: u64,
+    /// Run after the scheduled wakeup
+    pub command: String,
+    /// Suspend again once `command` exited
+    #[serde(default = "crate::default_true")]
+    pub resuspend: bool,
+}
+
+/// Program the RTC to wake the machine in `after`, returns when it will fire.
+pub fn set_wake_alarm(after: Duration) -> std::io::Result<SystemTime> {
+    // A pending alarm has to be cleared before a new one is accepted
+    clear_wake_alarm()?;
+    fs::write(WAKEALARM_PATH, format!("+{}", after.as_secs()))?;
+    Ok(SystemTime::now() + after)
+}
+
+pub fn clear_wake_alarm() -> std::io::Result<()> {
+    fs::write(WAKEALARM_PATH, "0")
+}
+
+/// Whether a resume happening now was most likely caused by the alarm set for `wake_at`.
+pub fn is_scheduled_wake(wake_at: SystemTime) -> bool {
+    SystemTime::now() + WAKE_SLACK >= wake_at
+}
@@ -0,0 +1,323 @@
+use tracing::{debug, info};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+use zbus::zvariant::{OwnedValue, Type, Value};
+
+use hypnos_core::rules::{Conditions, Rule};
+
+use crate::control;
+use crate::countdown;
+use crate::events::{self, Event};
+use crate::inhibit;
+use crate::thermal;
+use crate::types::{NotificationListHandle, Request, SharedGlobals};
+use crate::wayland::IdleNotification;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdleEvent {
+    Idled,
+    Resumed,
+}
+
+/// The commands to run as `rule` fires, None when one of its conditions holds it off.
+fn checked_actions(
+    rule: &Rule,
+    conditions: &Conditions,
+    #[cfg(feature = "scripting")] script_state: &crate::script::ScriptState,
+) -> Option<String> {
+    let actions = rule.idle_actions(conditions)?;
+    #[cfg(feature = "scripting")]
+    if let Some(condition) = &rule.condition {
+        if !crate::script::condition(condition, script_state, &rule.name) {
+            debug!("Skipping idle command '{}' because its condition doesn't hold", rule.actions);
+            return None;
+        }
+    }
+    #[cfg(feature = "plugins")]
+    if crate::plugins::should_inhibit(&rule.plugins) {
+        debug!("Skipping idle command '{}' because a plugin is inhibiting idle", rule.actions);
+        return None;
+    }
+    Some(actions)
+}
+
+/// Common handling of idle/resume events, whichever backend they come from.
+/// `force` runs the rule's commands whatever its conditions, pause included.
+#[tracing::instrument(skip(globals, list, tx))]
+pub fn idle_event(
+    globals: &SharedGlobals,
+    list: &NotificationListHandle,
+    tx: &mpsc::Sender<Request>,
+    uuid: Uuid,
+    event: IdleEvent,
+    force: bool,
+) {
+    if !force && globals.lock().unwrap().is_paused {
+        debug!("System is paused, ignoring idle notification event");
+        return;
+    }
+    // Idling in the background is expected, don't lock or suspend under the other session
+    if !force && event == IdleEvent::Idled && globals.lock().unwrap().session_inactive {
+        debug!("Session is inactive, ignoring idle notification event");
+        return;
+    }
+    
+    let (current_bat_state, battery_level, session_locked, remote_desktop) = {
+        let globals = globals.lock().unwrap();
+        (globals.on_battery, globals.battery_level, globals.session_locked(), globals.remote_client)
+    };
+
+    match event {
+        IdleEvent::Idled => {
+            // Taken before the rules, the globals can't be locked while they are
+            #[cfg(feature = "scripting")]
+            let script_state = crate::script::ScriptState::new(&globals.lock().unwrap());
+            let mut map = list.lock().unwrap();
+
+            if let Some(rule) = map.get_mut(&uuid) {
+                if let Some(pending) = rule.pending_restore.take() {
+                    debug!("Idle again within the resume debounce of '{}', keeping it idle", rule.name);
+                    pending.abort();
+                    return;
+                }
+
+                let actions = if force {
+                    rule.actions.clone()
+                } else {
+                    let conditions = Conditions {
+                        on_battery: current_bat_state,
+                        battery_level,
+                        session_locked,
+                        remote_desktop,
+                        inhibited: inhibit::inhibits(&rule.name, rule.sleeps()),
+                        temperature: thermal::max_temperature(),
+                    };
+                    let Some(actions) = checked_actions(
+                        rule,
+                        &conditions,
+                        #[cfg(feature = "scripting")]
+                        &script_state,
+                    ) else {
+                        return;
+                    };
+                    actions
+                };
+
+                info!(rule = rule.name.as_str(), "Idle reached, executing: {}", actions);
+                rule.idled_since = Some(Instant::now());
+                rule.last_fired = rule.idled_since;
+                let _ = tx.try_send(Request::RunCommand(rule.action(actions, "idled", current_bat_state)));
+
+                // The session went idle when the rule's timeout started
+                let (name, idle_for) = (rule.name.clone(), Duration::from_secs(rule.timeout.max(0) as u64));
+                let warning = rule.warning;
+                drop(map);
+                let mut globals = globals.lock().unwrap();
+                if let (Some(window), Some(pipe)) = (warning, globals.countdown_pipe.clone()) {
+                    tokio::spawn(countdown::run(pipe, window, list.clone(), uuid));
+                }
+                globals.stats.set_idle(true, idle_for);
+                globals.stats.rule_triggered(&name);
+                events::emit(Event::Idle { rule: name });
+            }
+        }
+        IdleEvent::Resumed => {
+            if !force {
+                learn_from_resume(globals, list, tx, uuid);
+            }
+            let mut map = list.lock().unwrap();
+
+            let Some(rule) = map.get_mut(&uuid) else {
+                return;
+            };
+            if (!rule.is_idled() && !force) || rule.pending_restore.is_some() {
+                return;
+            }
+
+            if !force && rule.on_battery && !current_bat_state.unwrap_or(false) {
+                debug!("Skipping restore command because on AC power");
+                rule.idled_since = None;
+                let name = rule.name.clone();
+                drop(map);
+                rule_resumed(globals, list, tx, name, Duration::ZERO);
+                return;
+            }
+
+            let action = rule
+                .restore
+                .clone()
+                .map(|restore_cmd| rule.action(restore_cmd, "resumed", current_bat_state));
+
+            if let Some(debounce) = rule.resume_debounce {
+                debug!("Resumed, waiting {:?} before restoring '{}'", debounce, rule.name);
+                let (globals, list, tx, name) = (globals.clone(), list.clone(), tx.clone(), rule.name.clone());
+                let task = tokio::spawn(async move {
+                    tokio::time::sleep(debounce).await;
+                    if let Some(rule) = list.lock().unwrap().get_mut(&uuid) {
+                        rule.pending_restore = None;
+                        rule.idled_since = None;
+                    }
+                    rule_resumed(&globals, &list, &tx, name, debounce);
+                    if let Some(action) = action {
+                        info!(rule = action.context.rule.as_deref().unwrap_or_default(), "Idle resumed, executing restore command: {}", action.command);
+                        let _ = tx.send(Request::RunCommand(action)).await;
+                    }
+                });
+                rule.pending_restore = Some(task.abort_handle());
+                return;
+            }
+            rule.idled_since = None;
+            let name = rule.name.clone();
+            drop(map);
+            rule_resumed(globals, list, tx, name, Duration::ZERO);
+
+            if let Some(action) = action {
+                info!(rule = action.context.rule.as_deref().unwrap_or_default(), "Idle resumed, executing restore command: {}", action.command);
+                let _ = tx.try_send(Request::RunCommand(action));
+            } else {
+                debug!("No restore command defined for uuid {:?}", uuid);
+            }
+        }
+    }
+}
+
+/// Count a resume right after `uuid`'s rule fired against its timeout.
+fn learn_from_resume(globals: &SharedGlobals, list: &NotificationListHandle, tx: &mpsc::Sender<Request>, uuid: Uuid) {
+    let fired = list
+        .lock()
+        .unwrap()
+        .get(&uuid)
+        .filter(|rule| rule.pending_restore.is_none())
+        .and_then(|rule| Some((rule.name.clone(), rule.idled_since?.elapsed())));
+    let Some((name, fired_ago)) = fired else {
+        return;
+    };
+
+    let mut globals = globals.lock().unwrap();
+    let config = globals.learn_timeouts.clone();
+    if !config.clone().unwrap_or_default().too_soon(fired_ago) {
+        return;
+    }
+    debug!("Rule '{}' resumed {:?} after firing", name, fired_ago);
+    globals.stats.quick_resume(&name);
+    if let Some(config) = config {
+        if globals.learner.quick_resume(&config, &name) {
+            let _ = tx.try_send(Request::ReloadConfig);
+        }
+    }
+}
+
+/// Announce a rule resumed `ago`, the session is active again when no rule is idled.
+fn rule_resumed(globals: &SharedGlobals, list: &NotificationListHandle, tx: &mpsc::Sender<Request>, rule: String, ago: Duration) {
+    // A snooze only holds for one firing, re-arm with the configured timeout
+    if globals.lock().unwrap().snoozed.remove(&rule).is_some() {
+        let _ = tx.try_send(Request::ReloadConfig);
+    }
+    events::emit(Event::Resume { rule });
+    let idle = list.lock().unwrap().values().any(|rule| rule.is_idled());
+    let mut globals_lock = globals.lock().unwrap();
+    globals_lock.stats.set_idle(idle, ago);
+    // The timeouts changed while idle, the rules can be re-armed now
+    if !idle && std::mem::take(&mut globals_lock.rearm_pending) {
+        let _ = tx.try_send(Request::ReloadConfig);
+    }
+    drop(globals_lock);
+    control::state_changed();
+}
+
+/// Follow the activity notification, armed with `after`: idle since that long once it
+/// idles, active again once it resumes.
+pub fn input_activity(globals: &SharedGlobals, event: IdleEvent, after: Duration) {
+    let now = Instant::now();
+    let idle_since = now.checked_sub(after).unwrap_or(now);
+    globals.lock().unwrap().input_idle_since = (event == IdleEvent::Idled).then_some(idle_since);
+    // The countdown to the next rule starts or restarts
+    control::state_changed();
+}
+
+/// How long the session has been idle: the timed backends know it, the Wayland one
+/// follows the input with a short notification. Otherwise it's the longest idled
+/// rule's timeout plus the time since it fired, zero until a rule reaches its timeout.
+pub fn session_idle_time(globals: &SharedGlobals, list: &NotificationListHandle) -> Duration {
+    {
+        let globals = globals.lock().unwrap();
+        if let Some(since) = globals.idle_since {
+            return since.elapsed();
+        }
+        if globals.activity_notification.is_some() {
+            return globals.input_idle_since.map(|since| since.elapsed()).unwrap_or_default();
+        }
+    }
+    list.lock()
+        .unwrap()
+        .values()
+        // Post-lock rules are timed from the lock, not from the last input
+        .filter(|rule| !matches!(rule.notification, IdleNotification::Locked))
+        .filter_map(|rule| Some(rule.idled_since?.elapsed() + Duration::from_secs(rule.timeout.max(0) as u64)))
+        .max()
+        .unwrap_or_default()
+}
+
+/// The rule expected to fire next, and in how many seconds if the session stays idle.
+/// An empty `rule` when none is, e.g. while paused or inhibited.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, Type, Value, OwnedValue)]
+pub struct NextAction {
+    pub rule: String,
+    pub actions: String,
+    pub seconds: u64,
+}
+
+/// The armed rule with the least time left, those held off by an inhibition or their
+/// power source aside. Post-lock rules only start counting once locked, so they don't
+/// count either.
+pub fn next_action(globals: &SharedGlobals, list: &NotificationListHandle) -> Option<NextAction> {
+    let (paused, on_battery, locked) = {
+        let globals = globals.lock().unwrap();
+        (globals.is_paused || globals.session_inactive, globals.on_battery == Some(true), globals.session_locked())
+    };
+    if paused {
+        return None;
+    }
+    let idle = session_idle_time(globals, list);
+    list.lock()
+        .unwrap()
+        .values()
+        .filter(|rule| !rule.is_idled() && !matches!(rule.notification, IdleNotification::Locked))
+        .filter(|rule| !rule.on_battery || on_battery)
+        .filter(|rule| !(rule.skip_when_locked && locked))
+        .filter(|rule| rule.ignore_inhibitors || !inhibit::inhibits(&rule.name, rule.sleeps()))
+        .map(|rule| NextAction {
+            rule: rule.name.clone(),
+            actions: rule.actions.clone(),
+            seconds: Duration::from_secs(rule.timeout.max(0) as u64).saturating_sub(idle).as_secs(),
+        })
+        .min_by_key(|next| next.seconds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::WaylandGlobals;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn idle_time_follows_the_activity_notification() {
+        let activity = Duration::from_secs(1);
+        let globals = Arc::new(Mutex::new(WaylandGlobals {
+            // Stands for the protocol object, only its presence matters
+            activity_notification: Some(IdleNotification::Timer),
+            ..Default::default()
+        }));
+        let list = NotificationListHandle::default();
+        assert_eq!(session_idle_time(&globals, &list), Duration::ZERO);
+
+        input_activity(&globals, IdleEvent::Idled, activity);
+        let idle = session_idle_time(&globals, &list);
+        assert!(idle >= activity && idle < activity * 2, "{:?}", idle);
+
+        input_activity(&globals, IdleEvent::Resumed, activity);
+        assert_eq!(session_idle_time(&globals, &list), Duration::ZERO);
+    }
+}
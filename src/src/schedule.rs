@@ -0,0 +1,152 @@
+//! Commands run at fixed times of the day, from the `schedule` config section.
+//!
+//! Times are checked against the wall clock, so occurrences missed while the machine
+//! was suspended are noticed on wake and run once, late, unless `catch_up` is off.
+
+use tracing::{debug, info};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::mpsc;
+
+use crate::types::{NotificationListHandle, Request, SharedGlobals};
+
+/// How often the clock is checked, also the precision of the schedule
+const TICK: Duration = Duration::from_secs(20);
+/// Later than this, the occurrence was missed, most likely while suspended
+const LATE: Duration = Duration::from_secs(60);
+
+/// `HH:MM`, in local time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeOfDay {
+    hour: u8,
+    minute: u8,
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(time: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("invalid time '{}', expected HH:MM", time);
+        let (hour, minute) = time.split_once(':').ok_or_else(invalid)?;
+        let (hour, minute): (u8, u8) = (hour.parse().map_err(|_| invalid())?, minute.parse().map_err(|_| invalid())?);
+        if hour > 23 || minute > 59 {
+            return Err(invalid());
+        }
+        Ok(Self { hour, minute })
+    }
+}
+
+impl From<TimeOfDay> for String {
+    fn from(time: TimeOfDay) -> Self {
+        format!("{:02}:{:02}", time.hour, time.minute)
+    }
+}
+
+/// An entry of the `schedule` config section.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ScheduledCommand {
+    pub at: TimeOfDay,
+    /// Days of the week, 0 being Sunday, every day when empty
+    #[serde(default)]
+    pub days: Vec<u8>,
+    pub command: String,
+    /// Only run while the session is idle
+    #[serde(default)]
+    pub if_idle: bool,
+    /// Run once on wake when the time passed while suspended
+    #[serde(default = "crate::default_true")]
+    pub catch_up: bool,
+}
+
+impl ScheduledCommand {
+    /// The first occurrence strictly after `after`.
+    fn next_after(&self, after: SystemTime) -> Option<SystemTime> {
+        let after_secs = after.duration_since(UNIX_EPOCH).ok()?.as_secs() as libc::time_t;
+        // SAFETY: localtime_r and mktime only touch the tm we own
+        let today = unsafe {
+            let mut tm: libc::tm = std::mem::zeroed();
+            if libc::localtime_r(&after_secs, &mut tm).is_null() {
+                return None;
+            }
+            tm
+        };
+        // A week and a day covers any set of days, and a skipped DST hour
+        for offset in 0..=7 {
+            let mut tm = today;
+            tm.tm_mday += offset;
+            tm.tm_hour = self.at.hour.into();
+            tm.tm_min = self.at.minute.into();
+            tm.tm_sec = 0;
+            tm.tm_isdst = -1;
+            // SAFETY: as above, mktime normalizes the day overflow
+            let time = unsafe { libc::mktime(&mut tm) };
+            if time <= after_secs || !(self.days.is_empty() || self.days.contains(&(tm.tm_wday as u8))) {
+                continue;
+            }
+            return Some(UNIX_EPOCH + Duration::from_secs(time as u64));
+        }
+        None
+    }
+
+    /// Identifies the entry across config reloads.
+    fn key(&self) -> String {
+        format!("{:02}:{:02} {:?} {}", self.at.hour, self.at.minute, self.days, self.command)
+    }
+}
+
+/// Run the scheduled commands when they are due.
+pub async fn run(globals: SharedGlobals, list: NotificationListHandle, tx: mpsc::Sender<Request>) {
+    let mut next: HashMap<String, SystemTime> = HashMap::new();
+    let mut interval = tokio::time::interval(TICK);
+    loop {
+        interval.tick().await;
+        let now = SystemTime::now();
+        let schedule = globals.lock().unwrap().schedule.clone();
+        // Entries removed from the config are forgotten
+        next.retain(|key, _| schedule.iter().any(|entry| &entry.key() == key));
+
+        for entry in schedule {
+            let key = entry.key();
+            let Some(due) = next.get(&key).copied().or_else(|| entry.next_after(now)) else {
+                continue;
+            };
+            if now < due {
+                next.insert(key, due);
+                continue;
+            }
+            if let Some(following) = entry.next_after(now) {
+                next.insert(key, following);
+            }
+
+            if now.duration_since(due).unwrap_or_default() > LATE {
+                if !entry.catch_up {
+                    info!("Missed '{}' while suspended, skipping it", entry.command);
+                    continue;
+                }
+                info!("Missed '{}' while suspended, running it now", entry.command);
+            }
+            if entry.if_idle {
+                let idle = globals.lock().unwrap().idle_since.is_some()
+                    || list.lock().unwrap().values().any(|rule| rule.is_idled());
+                if !idle {
+                    debug!("Session active, skipping scheduled '{}'", entry.command);
+                    continue;
+                }
+            }
+            let action = {
+                let globals = globals.lock().unwrap();
+                globals.hook(&Some(entry.command.clone()), "schedule")
+            };
+            if let Some(action) = action {
+                info!("Scheduled time reached, executing: {}", action.command);
+                if tx.send(Request::RunCommand(action)).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
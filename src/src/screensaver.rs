@@ -0,0 +1,134 @@
+//! The `org.freedesktop.ScreenSaver` inhibit interface, used by browsers and video
+//! players to keep the session awake, for sessions without a desktop providing it.
+//!
+//! Each inhibition is tied to the client's connection, so apps that crash or exit
+//! without calling `UnInhibit` don't hold idling off forever. The idle time queries are
+//! answered from hypnos' own tracking, the "screensaver" being the lock screen.
+
+use futures::stream::StreamExt;
+use tracing::{debug, info, warn};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::mpsc;
+use zbus::{fdo, interface, message::Header};
+
+use crate::inhibit::{self, Cookie};
+use crate::rules;
+use crate::types::{NotificationListHandle, Request, SharedGlobals};
+
+const BUS_NAME: &str = "org.freedesktop.ScreenSaver";
+/// Apps use either
+const OBJECT_PATHS: [&str; 2] = ["/org/freedesktop/ScreenSaver", "/ScreenSaver"];
+
+/// Unique bus name of the client holding each cookie.
+type Clients = Arc<Mutex<HashMap<Cookie, String>>>;
+
+struct ScreenSaver {
+    clients: Clients,
+    globals: SharedGlobals,
+    list: NotificationListHandle,
+    tx: mpsc::Sender<Request>,
+}
+
+#[interface(name = "org.freedesktop.ScreenSaver")]
+impl ScreenSaver {
+    async fn inhibit(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        application_name: &str,
+        reason_for_inhibit: &str,
+    ) -> u32 {
+        let client = header.sender().map(|sender| sender.to_string()).unwrap_or_default();
+        let cookie = inhibit::acquire(application_name, reason_for_inhibit, None);
+        info!("{} ({}) inhibits idle: {}", application_name, client, reason_for_inhibit);
+        self.clients.lock().unwrap().insert(cookie, client);
+        let _ = self.tx.send(Request::Uninhibit).await;
+        cookie
+    }
+
+    async fn un_inhibit(&self, cookie: u32) {
+        self.clients.lock().unwrap().remove(&cookie);
+        if inhibit::release(cookie) {
+            debug!("Inhibition {} released", cookie);
+            let _ = self.tx.send(Request::Uninhibit).await;
+        }
+    }
+
+    /// Seconds since the last user activity.
+    async fn get_session_idle_time(&self) -> u32 {
+        rules::session_idle_time(&self.globals, &self.list).as_secs() as u32
+    }
+
+    /// Whether the session is locked.
+    async fn get_active(&self) -> bool {
+        self.globals.lock().unwrap().session_locked()
+    }
+
+    /// Seconds the session has been locked, 0 while unlocked.
+    async fn get_active_time(&self) -> u32 {
+        let locked_since = self.globals.lock().unwrap().locked_since;
+        locked_since.map_or(0, |since| since.elapsed().as_secs() as u32)
+    }
+}
+
+/// Serve the interface, if no one else does. The returned connection must be kept alive.
+pub async fn serve(
+    globals: SharedGlobals,
+    list: NotificationListHandle,
+    tx: mpsc::Sender<Request>,
+) -> anyhow::Result<zbus::Connection> {
+    let clients: Clients = Arc::default();
+    let mut builder = zbus::connection::Builder::session()?;
+    for path in OBJECT_PATHS {
+        let iface = ScreenSaver {
+            clients: clients.clone(),
+            globals: globals.clone(),
+            list: list.clone(),
+            tx: tx.clone(),
+        };
+        builder = builder.serve_at(path, iface)?;
+    }
+    let conn = builder.build().await?;
+
+    let reply = conn
+        .request_name_with_flags(BUS_NAME, fdo::RequestNameFlags::DoNotQueue.into())
+        .await?;
+    if !matches!(reply, fdo::RequestNameReply::PrimaryOwner) {
+        anyhow::bail!("{} is already provided, e.g. by the desktop", BUS_NAME);
+    }
+    debug!("Serving {}", BUS_NAME);
+
+    let dbus = fdo::DBusProxy::new(&conn).await?;
+    let mut owner_changes = dbus.receive_name_owner_changed().await?;
+    tokio::spawn(async move {
+        while let Some(signal) = owner_changes.next().await {
+            let Ok(args) = signal.args() else {
+                continue;
+            };
+            // A client's unique name going away is its connection closing
+            if args.new_owner().is_some() {
+                continue;
+            }
+            let name = args.name().to_string();
+            let left: Vec<Cookie> = {
+                let mut clients = clients.lock().unwrap();
+                let left = clients.iter().filter(|(_, client)| **client == name).map(|(cookie, _)| *cookie).collect();
+                clients.retain(|_, client| *client != name);
+                left
+            };
+            if left.is_empty() {
+                continue;
+            }
+            warn!("{} left without releasing its inhibition, releasing it", name);
+            for cookie in left {
+                inhibit::release(cookie);
+            }
+            if tx.send(Request::Uninhibit).await.is_err() {
+                break;
+            }
+        }
+    });
+    Ok(conn)
+}
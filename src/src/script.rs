@@ -0,0 +1,115 @@
+//! Rhai scripts: inline conditions of the rules, and `@script` actions.
+//!
+//! Scripts only get a snapshot of the hypnos state and have no access to files or the
+//! network; actions start commands through `run()`, like the configured ones.
+
+use tracing::{error, info};
+use rhai::{Engine, Scope};
+use std::{cell::RefCell, rc::Rc, time::SystemTime};
+
+use crate::inhibit;
+use crate::thermal;
+use crate::types::WaylandGlobals;
+
+/// Bounds a script so a mistake can't hang the daemon
+const MAX_OPERATIONS: u64 = 100_000;
+
+/// The state scripts can read.
+#[derive(Debug, Clone)]
+pub struct ScriptState {
+    /// Percent, 100 without a battery
+    battery: f64,
+    on_battery: bool,
+    locked: bool,
+    inhibited: bool,
+    /// Seconds, for backends timed by hypnos
+    idle: i64,
+    external_keyboard: bool,
+    external_pointer: bool,
+    /// °C, 0 without any sensor
+    temperature: f64,
+}
+
+impl ScriptState {
+    pub fn new(globals: &WaylandGlobals) -> Self {
+        let external = globals
+            .input_devices
+            .values()
+            .filter(|device| device.external && !globals.input_ignored(&device.info));
+        Self {
+            battery: globals.battery_level.unwrap_or(100.0),
+            on_battery: globals.on_battery.unwrap_or(false),
+            locked: globals.session_locked(),
+            inhibited: inhibit::is_inhibited(),
+            idle: globals.idle_since.map(|since| since.elapsed().as_secs() as i64).unwrap_or(0),
+            external_keyboard: external.clone().any(|device| device.keyboard),
+            external_pointer: external.clone().any(|device| device.pointer),
+            temperature: thermal::max_temperature().unwrap_or(0.0),
+        }
+    }
+
+    /// `rule` is the one the script runs for, empty for hooks.
+    fn scope(&self, rule: Option<&str>) -> Scope<'static> {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|now| now.as_secs() as libc::time_t)
+            .unwrap_or(0);
+        // SAFETY: localtime_r only writes to the tm we own
+        let tm = unsafe {
+            let mut tm: libc::tm = std::mem::zeroed();
+            libc::localtime_r(&now, &mut tm);
+            tm
+        };
+
+        let mut scope = Scope::new();
+        scope.push_constant("battery", self.battery);
+        scope.push_constant("on_battery", self.on_battery);
+        scope.push_constant("locked", self.locked);
+        scope.push_constant("inhibited", self.inhibited);
+        scope.push_constant("idle", self.idle);
+        scope.push_constant("external_keyboard", self.external_keyboard);
+        scope.push_constant("external_pointer", self.external_pointer);
+        scope.push_constant("temperature", self.temperature);
+        scope.push_constant("rule", rule.unwrap_or_default().to_string());
+        scope.push_constant("hour", tm.tm_hour as i64);
+        // 0 is Sunday
+        scope.push_constant("weekday", tm.tm_wday as i64);
+        scope
+    }
+}
+
+fn engine() -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_OPERATIONS);
+    engine.set_max_call_levels(16);
+    engine.set_max_string_size(4096);
+    engine.set_max_array_size(1024);
+    engine.set_max_map_size(1024);
+    engine.disable_symbol("eval");
+    engine.on_print(|text| info!("Script: {}", text));
+    engine
+}
+
+/// Whether a rule's `condition` holds, false when it fails.
+pub fn condition(script: &str, state: &ScriptState, rule: &str) -> bool {
+    match engine().eval_with_scope::<bool>(&mut state.scope(Some(rule)), script) {
+        Ok(holds) => holds,
+        Err(e) => {
+            error!("Condition '{}' failed: {}", script, e);
+            false
+        }
+    }
+}
+
+/// Run an `@script` action, returns the commands it started with `run()`.
+pub fn run(script: &str, state: &ScriptState, rule: Option<&str>) -> Vec<String> {
+    let commands = Rc::new(RefCell::new(Vec::new()));
+    let mut engine = engine();
+    let started = commands.clone();
+    engine.register_fn("run", move |command: &str| started.borrow_mut().push(command.to_string()));
+
+    if let Err(e) = engine.run_with_scope(&mut state.scope(rule), script) {
+        error!("Script action failed: {}", e);
+    }
+    commands.take()
+}
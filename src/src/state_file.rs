@@ -0,0 +1,78 @@
+//! A small state file, $XDG_RUNTIME_DIR/hypnos/state.json, for shell prompts and bars
+//! that would rather read a file than talk DBus. Rewritten whenever the state changes.
+
+use tracing::{debug, error};
+use serde::Serialize;
+use std::{fs, path::PathBuf};
+use tokio::sync::broadcast;
+use xdg::BaseDirectories;
+
+use crate::config;
+use crate::control;
+use crate::events;
+use crate::inhibit;
+use crate::types::SharedGlobals;
+
+const STATE_FILE: &str = "state.json";
+
+#[derive(Debug, Serialize)]
+struct State {
+    paused: bool,
+    inhibited: bool,
+    on_battery: Option<bool>,
+    locked: bool,
+    /// None for the config as written
+    profile: Option<String>,
+    /// As sent to the integrations, with its timestamp
+    last_event: Option<serde_json::Value>,
+}
+
+fn state_path() -> std::io::Result<PathBuf> {
+    BaseDirectories::with_prefix(config::APP_NAME).place_runtime_file(STATE_FILE)
+}
+
+fn write(path: &PathBuf, content: &str) -> std::io::Result<()> {
+    // Write then rename, so readers never see a partial file
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, content)?;
+    fs::rename(tmp, path)
+}
+
+/// Keep the state file up to date.
+pub async fn run(globals: SharedGlobals) -> anyhow::Result<()> {
+    let path = state_path()?;
+    debug!("Writing the state to {:?}", path);
+    let mut changes = control::subscribe();
+    let mut events = events::subscribe();
+    let mut last_event = None;
+    let mut last = String::new();
+    loop {
+        let state = {
+            let globals = globals.lock().unwrap();
+            State {
+                paused: globals.is_paused,
+                inhibited: inhibit::is_inhibited(),
+                on_battery: globals.on_battery,
+                locked: globals.session_locked(),
+                profile: globals.profile.clone(),
+                last_event: last_event.clone(),
+            }
+        };
+        let content = serde_json::to_string(&state)?;
+        if content != last {
+            if let Err(e) = write(&path, &content) {
+                error!("Failed to write {:?}: {}", path, e);
+            }
+            last = content;
+        }
+
+        tokio::select! {
+            changed = changes.changed() => changed?,
+            event = events.recv() => match event {
+                Ok(event) => last_event = event.to_json().ok().and_then(|json| serde_json::from_str(&json).ok()),
+                Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+        }
+    }
+}
@@ -0,0 +1,183 @@
+//! Idle/active time statistics, per day, in $XDG_STATE_HOME/hypnos/stats.json.
+//!
+//! The session counts as idle from the moment the first rule fired until every
+//! rule resumed, i.e. idle periods shorter than the shortest rule timeout are active time.
+
+use tracing::{debug, error};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use xdg::BaseDirectories;
+
+use crate::types::SharedGlobals;
+use crate::{config, utils};
+
+const STATS_FILE: &str = "stats.json";
+/// How often the elapsed time is accounted and saved
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct DayStats {
+    pub idle_secs: u64,
+    pub active_secs: u64,
+    pub idle_episodes: u32,
+    /// Times each rule fired
+    pub triggers: BTreeMap<String, u32>,
+    /// Times each rule was resumed right after firing
+    #[serde(default)]
+    pub quick_resumes: BTreeMap<String, u32>,
+}
+
+/// Keyed by local date, `YYYY-MM-DD`
+pub type Stats = BTreeMap<String, DayStats>;
+
+/// Live accounting, kept in the daemon's globals.
+#[derive(Debug)]
+pub struct Tracker {
+    stats: Stats,
+    idle: bool,
+    /// Time up to here is already accounted
+    accounted_until: Instant,
+}
+
+impl Default for Tracker {
+    fn default() -> Self {
+        Self {
+            stats: load().unwrap_or_default(),
+            idle: false,
+            accounted_until: Instant::now(),
+        }
+    }
+}
+
+impl Tracker {
+    fn today(&mut self) -> &mut DayStats {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        self.stats.entry(utils::format_local_time(timestamp, c"%Y-%m-%d")).or_default()
+    }
+
+    /// Add the time elapsed until `until` to the current state.
+    fn account(&mut self, until: Instant) {
+        let elapsed = until.saturating_duration_since(self.accounted_until).as_secs();
+        // Keep the remainder for the next round
+        self.accounted_until += Duration::from_secs(elapsed);
+        let idle = self.idle;
+        let today = self.today();
+        if idle {
+            today.idle_secs += elapsed;
+        } else {
+            today.active_secs += elapsed;
+        }
+    }
+
+    /// Record a change of the session idle state, which really happened `ago`.
+    pub fn set_idle(&mut self, idle: bool, ago: Duration) {
+        if idle == self.idle {
+            return;
+        }
+        let changed_at = Instant::now().checked_sub(ago).unwrap_or_else(Instant::now);
+        self.account(changed_at.max(self.accounted_until));
+        self.idle = idle;
+        if idle {
+            self.today().idle_episodes += 1;
+        }
+    }
+
+    pub fn rule_triggered(&mut self, rule: &str) {
+        *self.today().triggers.entry(rule.to_string()).or_default() += 1;
+    }
+
+    /// The user came back right after `rule` fired.
+    pub fn quick_resume(&mut self, rule: &str) {
+        *self.today().quick_resumes.entry(rule.to_string()).or_default() += 1;
+    }
+
+    fn save(&mut self) {
+        self.account(Instant::now());
+        if let Err(e) = save(&self.stats) {
+            error!("Failed to save the statistics: {}", e);
+        }
+    }
+}
+
+fn stats_path() -> std::io::Result<PathBuf> {
+    BaseDirectories::with_prefix(config::APP_NAME).place_state_file(STATS_FILE)
+}
+
+fn load() -> anyhow::Result<Stats> {
+    let content = fs::read_to_string(stats_path()?)?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+fn save(stats: &Stats) -> anyhow::Result<()> {
+    let path = stats_path()?;
+    // Write then rename, so a crash can't leave a truncated file
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_string(stats)?)?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
+
+/// Periodically account and persist the statistics.
+pub async fn run(globals: SharedGlobals) -> anyhow::Result<()> {
+    let mut interval = tokio::time::interval(SAVE_INTERVAL);
+    loop {
+        interval.tick().await;
+        debug!("Saving statistics");
+        globals.lock().unwrap().stats.save();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum StatsFormat {
+    Table,
+    Json,
+    Csv,
+}
+
+fn hours(secs: u64) -> String {
+    format!("{}h{:02}", secs / 3600, secs % 3600 / 60)
+}
+
+/// Print the last `days` days of statistics.
+pub fn print(days: usize, format: StatsFormat) -> anyhow::Result<()> {
+    let stats = load().unwrap_or_default();
+    let skip = stats.len().saturating_sub(days);
+    let stats: Stats = stats.into_iter().skip(skip).collect();
+
+    match format {
+        StatsFormat::Json => println!("{}", serde_json::to_string(&stats)?),
+        StatsFormat::Csv => {
+            println!("date,idle_secs,active_secs,idle_episodes,triggers");
+            for (date, day) in &stats {
+                let triggers: Vec<String> = day.triggers.iter().map(|(rule, count)| format!("{}={}", rule, count)).collect();
+                println!("{},{},{},{},\"{}\"", date, day.idle_secs, day.active_secs, day.idle_episodes, triggers.join(";"));
+            }
+        }
+        StatsFormat::Table => {
+            if stats.is_empty() {
+                println!("No statistics yet");
+            }
+            for (date, day) in &stats {
+                println!(
+                    "{}  active {:>6}  idle {:>6}  {:>3} idle episodes",
+                    date,
+                    hours(day.active_secs),
+                    hours(day.idle_secs),
+                    day.idle_episodes
+                );
+                for (rule, count) in &day.triggers {
+                    match day.quick_resumes.get(rule) {
+                        Some(quick) => println!("    {:<16} {:>4}x  {} resumed right away", rule, count, quick),
+                        None => println!("    {:<16} {:>4}x", rule, count),
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -1,10 +1,28 @@
-use std::fs;
-use log::{error, info, warn};
-use zbus::{proxy, Connection, Result};
+use futures::stream::StreamExt;
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    ffi::CString,
+    fs,
+    path::{Path, PathBuf},
+};
+use tracing::{debug, error, info, warn};
+use serde::Serialize;
+use tokio::sync::mpsc;
+use zbus::{message, proxy, Connection, MatchRule, MessageStream, Result};
 use zbus::zvariant::OwnedObjectPath;
 
+use crate::{config, utils};
+use crate::inhibit::{self, Cookie, Scope};
+use crate::types::Request;
+
 const SERVICE_TEMPLATE: &str = include_str!("../res/hypnos.service.in");
 const SERVICE_NAME: &str = "hypnos.service";
+/// Template of the instanced units, `hypnos@<config>.service` runs with `<config>.json`
+const INSTANCE_TEMPLATE: &str = include_str!("../res/hypnos@.service.in");
+const INSTANCE_SERVICE_NAME: &str = "hypnos@.service";
+/// Template of the system units, for both the main service and the instances
+const SYSTEM_TEMPLATE: &str = include_str!("../res/hypnos-system.service.in");
+const SYSTEM_UNIT_DIR: &str = "/etc/systemd/system";
 
 #[proxy(
     interface = "org.freedesktop.systemd1.Manager",
@@ -21,6 +39,15 @@ trait SystemdManager {
     fn disable_unit_files(&self, files: &[&str], runtime: bool) -> Result<Vec<(String, String, String)>>;
     fn get_unit_file_state(&self, file: &str) -> Result<String>;
     fn get_unit(&self, name: &str) -> Result<OwnedObjectPath>;
+    /// Needed for the managers to emit their signals
+    fn subscribe(&self) -> Result<()>;
+    // Signature: (asas) -> a(ssssssouso)
+    #[allow(clippy::type_complexity)]
+    fn list_units_by_patterns(
+        &self,
+        states: &[&str],
+        patterns: &[&str],
+    ) -> Result<Vec<(String, String, String, String, String, String, OwnedObjectPath, u32, String, OwnedObjectPath)>>;
 }
 
 #[proxy(
@@ -30,29 +57,108 @@ trait SystemdManager {
 trait Unit {
     #[zbus(property)]
     fn active_state(&self) -> Result<String>;
+    #[zbus(property)]
+    fn sub_state(&self) -> Result<String>;
+    #[zbus(property)]
+    fn load_state(&self) -> Result<String>;
+    /// Microseconds since the epoch
+    #[zbus(property)]
+    fn active_enter_timestamp(&self) -> Result<u64>;
+}
+
+#[proxy(
+    interface = "org.freedesktop.systemd1.Service",
+    default_service = "org.freedesktop.systemd1"
+)]
+trait Service {
+    #[zbus(property, name = "ExecMainPID")]
+    fn exec_main_pid(&self) -> Result<u32>;
+    /// Bytes, u64::MAX when unknown
+    #[zbus(property)]
+    fn memory_current(&self) -> Result<u64>;
+    #[zbus(property, name = "NRestarts")]
+    fn n_restarts(&self) -> Result<u32>;
 }
 
-/// Helper to get the proxy connection to the Session Bus (User Systemd)
-async fn get_manager() -> Result<SystemdManagerProxy<'static>> {
-    let connection = Connection::session().await?;
+#[derive(Debug, Serialize)]
+pub struct ServiceStatus {
+    pub unit: String,
+    pub load_state: String,
+    pub active_state: String,
+    pub sub_state: String,
+    /// Seconds since the epoch
+    pub active_since: Option<u64>,
+    pub main_pid: Option<u32>,
+    /// Bytes
+    pub memory: Option<u64>,
+    pub restarts: u32,
+    pub enabled: bool,
+}
+
+/// The service to manage, as given on the command line.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Target<'a> {
+    /// `hypnos@<instance>.service` instead of `hypnos.service`
+    pub instance: Option<&'a str>,
+    /// A unit of the system manager, for greeters and kiosks, instead of the user one
+    pub system: bool,
+    /// User the system service runs as, `$SUDO_USER` by default
+    pub user: Option<&'a str>,
+    /// Wayland socket of the user's session, for the system service
+    pub wayland_display: Option<&'a str>,
+}
+
+async fn connection(system: bool) -> Result<Connection> {
+    if system {
+        Connection::system().await
+    } else {
+        Connection::session().await
+    }
+}
+
+/// Helper to get the proxy connection to the Session Bus (User Systemd), or the System Bus
+async fn get_manager(system: bool) -> Result<SystemdManagerProxy<'static>> {
+    let connection = connection(system).await?;
     SystemdManagerProxy::new(&connection).await
 }
 
-fn check_service_installed() -> bool {
-    let xdg_dirs = xdg::BaseDirectories::new();
-    if let Some(config_home) = xdg_dirs.get_config_home() {
-        let service_path = config_home.join("systemd/user").join(SERVICE_NAME);
-        service_path.exists()
+fn unit_dir(system: bool) -> PathBuf {
+    if system {
+        PathBuf::from(SYSTEM_UNIT_DIR)
     } else {
-        false
+        let xdg_dirs = xdg::BaseDirectories::new();
+        xdg_dirs.get_config_home().unwrap().join("systemd/user")
     }
 }
 
-async fn is_running() -> anyhow::Result<bool> {
-    let connection = Connection::session().await?;
+/// Name of the unit, `hypnos@<instance>.service` for an instance.
+pub fn unit_name(instance: Option<&str>) -> String {
+    match instance {
+        Some(instance) => format!("hypnos@{}.service", instance),
+        None => SERVICE_NAME.to_string(),
+    }
+}
+
+/// The unit file backing the unit: the template for an instance.
+fn unit_file(instance: Option<&str>) -> &'static str {
+    match instance {
+        Some(_) => INSTANCE_SERVICE_NAME,
+        None => SERVICE_NAME,
+    }
+}
+
+fn check_service_installed(target: Target<'_>) -> bool {
+    unit_dir(target.system).join(unit_file(target.instance)).exists()
+}
+
+async fn is_running(target: Target<'_>) -> anyhow::Result<bool> {
+    let connection = connection(target.system).await?;
     let manager = SystemdManagerProxy::new(&connection).await?;
     
-    let unit_path = manager.get_unit(SERVICE_NAME).await?;
+    // Units are only loaded once used
+    let Ok(unit_path) = manager.get_unit(&unit_name(target.instance)).await else {
+        return Ok(false);
+    };
     
     let unit = UnitProxy::builder(&connection)
         .path(unit_path)?
@@ -65,89 +171,103 @@ async fn is_running() -> anyhow::Result<bool> {
     Ok(state == "active")
 }
 
-pub async fn is_enabled() -> anyhow::Result<bool> {
-    let manager = get_manager().await?;
-    let state = manager.get_unit_file_state(SERVICE_NAME).await?;
+pub async fn is_enabled(target: Target<'_>) -> anyhow::Result<bool> {
+    let manager = get_manager(target.system).await?;
+    let state = manager.get_unit_file_state(&unit_name(target.instance)).await?;
     
     // States that imply enabled: "enabled", "enabled-runtime", "linked", "linked-runtime", "static"
     // For a simple check, "enabled" is usually sufficient for installed services.
     Ok(state == "enabled")
 }
 
-pub async fn start() -> anyhow::Result<()> {
-    let manager = get_manager().await?;
+pub async fn start(target: Target<'_>) -> anyhow::Result<()> {
+    let name = unit_name(target.instance);
+    let manager = get_manager(target.system).await?;
 
-    if !check_service_installed() {
+    if !check_service_installed(target) {
         warn!("Service is not installed. Installing now...");
-        install().await?;
+        install(target).await?;
     }
 
-    if is_running().await? {
+    if is_running(target).await? {
         info!("Service is already running.");
         return Ok(());
     }
 
-    info!("Starting {}...", SERVICE_NAME);
+    info!("Starting {}...", name);
     // Mode "replace" will start it if stopped, or restart if running (conceptually)
     // usually we use "replace" for start
-    manager.start_unit(SERVICE_NAME, "replace").await?;
+    manager.start_unit(&name, "replace").await?;
     info!("Service started successfully.");
     Ok(())
 }
 
-pub async fn stop() -> anyhow::Result<()> {
-    let manager = get_manager().await?;
+pub async fn stop(target: Target<'_>) -> anyhow::Result<()> {
+    let name = unit_name(target.instance);
+    let manager = get_manager(target.system).await?;
 
-    if !is_running().await? {
+    if !is_running(target).await? {
         info!("Service is not running.");
         return Ok(());
     }
 
-    info!("Stopping {}...", SERVICE_NAME);
-    manager.stop_unit(SERVICE_NAME, "replace").await?;
+    info!("Stopping {}...", name);
+    manager.stop_unit(&name, "replace").await?;
     info!("Service stopped.");
     Ok(())
 }
 
-pub async fn restart() -> anyhow::Result<()> {
-    let manager = get_manager().await?;
+pub async fn restart(target: Target<'_>) -> anyhow::Result<()> {
+    let name = unit_name(target.instance);
+    let manager = get_manager(target.system).await?;
 
-    if !check_service_installed() {
+    if !check_service_installed(target) {
         warn!("Service is not installed. Installing now...");
-        install().await?;
+        install(target).await?;
     }
 
-    info!("Restarting {}...", SERVICE_NAME);
-    manager.restart_unit(SERVICE_NAME, "replace").await?;
+    info!("Restarting {}...", name);
+    manager.restart_unit(&name, "replace").await?;
     info!("Service restarted.");
     Ok(())
 }
 
-pub async fn install() -> anyhow::Result<()> {
+pub async fn install(target: Target<'_>) -> anyhow::Result<()> {
+    let name = unit_file(target.instance);
 
-    if check_service_installed() {
-        info!("Service {} already installed...", SERVICE_NAME);
+    if check_service_installed(target) {
+        info!("Service {} already installed...", name);
         return Ok(());
     } else {
 
-        let xdg_dirs = xdg::BaseDirectories::new();
-        
-        let config_home = xdg_dirs.get_config_home().unwrap();
-        let systemd_dir = config_home.join("systemd/user");
+        let systemd_dir = unit_dir(target.system);
         fs::create_dir_all(&systemd_dir)?;
-        let service_path = systemd_dir.join(SERVICE_NAME);
+        let service_path = systemd_dir.join(name);
 
         let current_exe = std::env::current_exe()?;
         let exe_str = current_exe.to_str().unwrap_or("hypnos");
 
-        let content = SERVICE_TEMPLATE.replace("@BIN_PATH@", exe_str);
-        let home_dir = std::env::var("HOME").unwrap();
-        let content = content.replace("@HOME@", &home_dir);
+        let content = if target.system {
+            write_session_dropin(&systemd_dir.join(format!("{}.d", name)), target)?;
+            let (description, config) = match target.instance {
+                Some(_) => ("%i", "%i.json"),
+                None => ("system", "config.json"),
+            };
+            SYSTEM_TEMPLATE
+                .replace("@BIN_PATH@", exe_str)
+                .replace("@DESCRIPTION@", description)
+                .replace("@CONFIG@", config)
+        } else {
+            let template = if target.instance.is_some() { INSTANCE_TEMPLATE } else { SERVICE_TEMPLATE };
+            let content = template.replace("@BIN_PATH@", exe_str);
+            let home_dir = std::env::var("HOME").unwrap();
+            content.replace("@HOME@", &home_dir)
+        };
 
         fs::write(&service_path, content)?;
         info!("Wrote service file to {:?}", service_path);
 
-        let manager = get_manager().await?;
+        let manager = get_manager(target.system).await?;
         manager.reload().await?;
         info!("Systemd daemon reloaded.");
 
@@ -155,44 +275,293 @@ pub async fn install() -> anyhow::Result<()> {
     }
 }
 
-pub async fn enable() -> anyhow::Result<()> {
-    let manager = get_manager().await?;
+pub async fn enable(target: Target<'_>) -> anyhow::Result<()> {
+    let name = unit_name(target.instance);
+    let manager = get_manager(target.system).await?;
 
-    if !check_service_installed() {
+    if !check_service_installed(target) {
         warn!("Service is not installed. Installing now...");
-        install().await?;
+        install(target).await?;
     }
 
-    if !is_enabled().await? {
-        match manager.enable_unit_files(&[SERVICE_NAME], false, true).await {
+    if !is_enabled(target).await? {
+        match manager.enable_unit_files(&[name.as_str()], false, true).await {
             Ok(_) => info!("Service enabled."),
             Err(e) => error!("Failed to enable service: {}", e),
         }
         manager.reload().await?;
         return Ok(());
     } else {
-        info!("Service {} is already enabled.", SERVICE_NAME);
+        info!("Service {} is already enabled.", name);
         return Ok(());
     }
 }
 
-pub async fn disable() -> anyhow::Result<()> {
-    let manager = get_manager().await?;
+pub async fn disable(target: Target<'_>) -> anyhow::Result<()> {
+    let name = unit_name(target.instance);
+    let manager = get_manager(target.system).await?;
 
-    if !check_service_installed() {
+    if !check_service_installed(target) {
         warn!("Service is not installed. Installing now...");
-        install().await?;
+        install(target).await?;
     }
 
-    if is_enabled().await? {
-        match manager.disable_unit_files(&[SERVICE_NAME], false).await {
+    if is_enabled(target).await? {
+        match manager.disable_unit_files(&[name.as_str()], false).await {
             Ok(_) => info!("Service disabled."),
             Err(e) => error!("Failed to disable service: {}", e),
         }
         manager.reload().await?;
         return Ok(());
     } else {
-        info!("Service {} is already disabled.", SERVICE_NAME);
+        info!("Service {} is already disabled.", name);
         return Ok(());
     }
-}
\ No newline at end of file
+}
+
+async fn service_status(target: Target<'_>) -> anyhow::Result<ServiceStatus> {
+    let connection = connection(target.system).await?;
+    let manager = SystemdManagerProxy::new(&connection).await?;
+    let name = unit_name(target.instance);
+
+    // LoadUnit would load it just to answer, an unloaded unit is simply not running
+    let Ok(unit_path) = manager.get_unit(&name).await else {
+        return Ok(ServiceStatus {
+            load_state: if check_service_installed(target) { "installed" } else { "not-found" }.to_string(),
+            active_state: "inactive".to_string(),
+            sub_state: "dead".to_string(),
+            active_since: None,
+            main_pid: None,
+            memory: None,
+            restarts: 0,
+            enabled: is_enabled(target).await.unwrap_or(false),
+            unit: name,
+        });
+    };
+    let unit = UnitProxy::builder(&connection).path(unit_path.clone())?.build().await?;
+    let service = ServiceProxy::builder(&connection).path(unit_path)?.build().await?;
+
+    let active_since = unit.active_enter_timestamp().await?;
+    let main_pid = service.exec_main_pid().await?;
+    let memory = service.memory_current().await?;
+    Ok(ServiceStatus {
+        load_state: unit.load_state().await?,
+        active_state: unit.active_state().await?,
+        sub_state: unit.sub_state().await?,
+        active_since: (active_since != 0).then_some(active_since / 1_000_000),
+        main_pid: (main_pid != 0).then_some(main_pid),
+        memory: (memory != u64::MAX).then_some(memory),
+        restarts: service.n_restarts().await?,
+        enabled: is_enabled(target).await.unwrap_or(false),
+        unit: name,
+    })
+}
+
+/// Print the state of the service, as reported by systemd.
+pub async fn status(target: Target<'_>, json: bool) -> anyhow::Result<()> {
+    let status = service_status(target).await?;
+    if json {
+        println!("{}", serde_json::to_string(&status)?);
+        return Ok(());
+    }
+
+    let since = status
+        .active_since
+        .filter(|_| status.active_state == "active")
+        .map(|since| format!(" since {}", utils::format_local_time(since, c"%Y-%m-%d %H:%M:%S")))
+        .unwrap_or_default();
+    println!("Unit:     {} ({})", status.unit, status.load_state);
+    println!("Enabled:  {}", if status.enabled { "yes" } else { "no" });
+    println!("State:    {} ({}){}", status.active_state, status.sub_state, since);
+    if let Some(pid) = status.main_pid {
+        println!("PID:      {}", pid);
+    }
+    if let Some(memory) = status.memory {
+        println!("Memory:   {:.1} MiB", memory as f64 / (1024.0 * 1024.0));
+    }
+    println!("Restarts: {}", status.restarts);
+    Ok(())
+}
+
+/// Run the system service as the session user, with access to their Wayland and D-Bus sockets.
+fn write_session_dropin(dropin_dir: &Path, target: Target<'_>) -> anyhow::Result<()> {
+    let sudo_user = std::env::var("SUDO_USER").ok();
+    let Some(user) = target.user.or(sudo_user.as_deref()) else {
+        anyhow::bail!("No user to run the system service as, pass --user");
+    };
+    let name = CString::new(user)?;
+    // SAFETY: getpwnam returns null or a pointer to a static entry, read right away
+    let uid = unsafe {
+        let passwd = libc::getpwnam(name.as_ptr());
+        if passwd.is_null() {
+            anyhow::bail!("Unknown user '{}'", user);
+        }
+        (*passwd).pw_uid
+    };
+    let runtime_dir = format!("/run/user/{}", uid);
+
+    let content = format!(
+        "[Service]\nUser={}\nEnvironment=XDG_RUNTIME_DIR={}\nEnvironment=WAYLAND_DISPLAY={}\nEnvironment=DBUS_SESSION_BUS_ADDRESS=unix:path={}/bus\n",
+        user,
+        runtime_dir,
+        target.wayland_display.unwrap_or("wayland-1"),
+        runtime_dir,
+    );
+    fs::create_dir_all(dropin_dir)?;
+    let path = dropin_dir.join("session.conf");
+    fs::write(&path, content)?;
+    info!("Wrote {:?}, running as {}", path, user);
+    Ok(())
+}
+
+/// Whether instances other than `instance` are still enabled, i.e. need the template.
+fn other_instances_enabled(systemd_dir: &Path, instance: &str) -> bool {
+    let own = unit_name(Some(instance));
+    let Ok(entries) = fs::read_dir(systemd_dir) else {
+        return false;
+    };
+    // Enabled units are symlinked from the `.wants` directories
+    entries
+        .flatten()
+        .filter(|entry| entry.file_name().to_string_lossy().ends_with(".wants"))
+        .filter_map(|entry| fs::read_dir(entry.path()).ok())
+        .flat_map(|links| links.flatten())
+        .any(|link| {
+            let name = link.file_name().to_string_lossy().into_owned();
+            name.starts_with("hypnos@") && name != own
+        })
+}
+
+/// Stop and disable the service, then delete its unit file. With `purge`, its
+/// configuration file and, for the main service, the history and statistics go too.
+pub async fn uninstall(target: Target<'_>, purge: bool) -> anyhow::Result<()> {
+    let name = unit_name(target.instance);
+    let manager = get_manager(target.system).await?;
+
+    if is_running(target).await? {
+        info!("Stopping {}...", name);
+        manager.stop_unit(&name, "replace").await?;
+    }
+    if is_enabled(target).await.unwrap_or(false) {
+        manager.disable_unit_files(&[name.as_str()], false).await?;
+        info!("Service {} disabled.", name);
+    }
+
+    let systemd_dir = unit_dir(target.system);
+    let service_path = systemd_dir.join(unit_file(target.instance));
+    let keep_template = target.instance.is_some_and(|instance| other_instances_enabled(&systemd_dir, instance));
+    if keep_template {
+        info!("Keeping {:?}, other instances use it", service_path);
+    } else if service_path.exists() {
+        fs::remove_file(&service_path)?;
+        info!("Removed {:?}", service_path);
+        let dropin_dir = systemd_dir.join(format!("{}.d", unit_file(target.instance)));
+        if target.system && dropin_dir.exists() {
+            fs::remove_dir_all(&dropin_dir)?;
+            info!("Removed {:?}", dropin_dir);
+        }
+    }
+    manager.reload().await?;
+    info!("Systemd daemon reloaded.");
+
+    if purge && target.system {
+        warn!("Not purging, the configuration belongs to the user the service ran as");
+    } else if purge {
+        let config_file = match target.instance {
+            Some(instance) => format!("{}.json", instance),
+            None => "config.json".to_string(),
+        };
+        let hypnos_dirs = xdg::BaseDirectories::with_prefix(config::APP_NAME);
+        if let Some(path) = hypnos_dirs.find_config_file(&config_file) {
+            fs::remove_file(&path)?;
+            info!("Removed {:?}", path);
+        }
+        if target.instance.is_none() {
+            if let Some(state_dir) = hypnos_dirs.get_state_home().filter(|dir| dir.exists()) {
+                fs::remove_dir_all(&state_dir)?;
+                info!("Removed {:?}", state_dir);
+            }
+        }
+        // Only once empty, other instances may keep their configuration there
+        if let Some(config_dir) = hypnos_dirs.get_config_home() {
+            let _ = fs::remove_dir(config_dir);
+        }
+    }
+    info!("Service {} uninstalled.", name);
+    Ok(())
+}
+
+/// Owner of the inhibitions of `inhibit_while_units`
+const UNITS_OWNER: &str = "systemd";
+/// The states of a unit that's running
+const RUNNING_STATES: [&str; 4] = ["active", "activating", "deactivating", "reloading"];
+
+/// Hold the sleep rules off while units matching `patterns` (globs like
+/// `podman-*.scope`) run, on the system manager and the user's.
+pub async fn unit_inhibitor(patterns: Vec<String>, tx: mpsc::Sender<Request>) {
+    let (system, user) = tokio::join!(
+        watch_units(true, &patterns, tx.clone()),
+        watch_units(false, &patterns, tx.clone()),
+    );
+    for (manager, result) in [("system", system), ("user", user)] {
+        if let Err(e) = result {
+            warn!("Not watching the {} units: {}", manager, e);
+        }
+    }
+}
+
+async fn watch_units(system: bool, patterns: &[String], tx: mpsc::Sender<Request>) -> anyhow::Result<()> {
+    let manager = get_manager(system).await?;
+    manager.subscribe().await?;
+    // Any unit changing state, they're listed again to find out which
+    let rule = MatchRule::builder()
+        .msg_type(message::Type::Signal)
+        .sender("org.freedesktop.systemd1")?
+        .interface("org.freedesktop.DBus.Properties")?
+        .member("PropertiesChanged")?
+        .arg(0, "org.freedesktop.systemd1.Unit")?
+        .build();
+    let mut changes = MessageStream::for_match_rule(rule, manager.inner().connection(), None).await?;
+    let patterns: Vec<&str> = patterns.iter().map(String::as_str).collect();
+    debug!("Watching the {} units matching {:?}", if system { "system" } else { "user" }, patterns);
+
+    let mut held: HashMap<String, Cookie> = HashMap::new();
+    loop {
+        let running: Vec<String> = match manager.list_units_by_patterns(&RUNNING_STATES, &patterns).await {
+            Ok(units) => units.into_iter().map(|unit| unit.0).collect(),
+            Err(e) => {
+                // Not knowing when they stop, don't keep the rules off forever
+                for cookie in held.values() {
+                    inhibit::release(*cookie);
+                }
+                let _ = tx.send(Request::Uninhibit).await;
+                return Err(e.into());
+            }
+        };
+        let mut changed = false;
+        held.retain(|unit, cookie| {
+            let keep = running.contains(unit);
+            if !keep {
+                info!("{} stopped, the sleep rules apply again", unit);
+                inhibit::release(*cookie);
+                changed = true;
+            }
+            keep
+        });
+        for unit in running {
+            if let Entry::Vacant(entry) = held.entry(unit) {
+                info!("{} is running, holding the sleep rules off", entry.key());
+                let reason = format!("{} is running", entry.key());
+                entry.insert(inhibit::acquire_for(UNITS_OWNER, &reason, Scope::Sleep, None));
+                changed = true;
+            }
+        }
+        if changed && tx.send(Request::Uninhibit).await.is_err() {
+            return Ok(());
+        }
+
+        if changes.next().await.is_none() {
+            return Ok(());
+        }
+    }
+}
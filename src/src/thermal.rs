@@ -0,0 +1,25 @@
+//! Temperature of the machine, from the kernel's thermal zones or, lacking them, hwmon.
+
+use std::{fs, path::Path};
+
+/// The hottest sensor, in °C, None without any.
+pub fn max_temperature() -> Option<f64> {
+    hottest("/sys/class/thermal", |name| name.starts_with("thermal_zone"), "temp")
+        .or_else(|| hottest("/sys/class/hwmon", |name| name.starts_with("hwmon"), "temp1_input"))
+}
+
+/// The highest of the `file` readings, in millidegrees, of the devices in `class`.
+fn hottest(class: &str, wanted: impl Fn(&str) -> bool, file: &str) -> Option<f64> {
+    fs::read_dir(class)
+        .ok()?
+        .flatten()
+        .filter(|entry| entry.file_name().to_str().is_some_and(&wanted))
+        .filter_map(|entry| read_millidegrees(&entry.path().join(file)))
+        .reduce(f64::max)
+}
+
+fn read_millidegrees(path: &Path) -> Option<f64> {
+    let millidegrees: i64 = fs::read_to_string(path).ok()?.trim().parse().ok()?;
+    // Disabled or broken sensors report nonsense, e.g. -274000
+    (millidegrees > 0).then(|| millidegrees as f64 / 1000.0)
+}
@@ -0,0 +1,69 @@
+use std::{collections::HashSet, time::Duration};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::rules::{self, IdleEvent};
+use crate::types::{NotificationListHandle, Request, SharedGlobals};
+use crate::wayland::IdleNotification;
+
+/// How often the idle duration is checked against the rules' timeouts
+const TICK: Duration = Duration::from_secs(5);
+
+/// Drive the rules from `idle_since`, for backends that only tell us since when the
+/// session is idle instead of notifying each timeout, and post-lock rules from `locked_since`
+/// or the last input after it, whichever is later.
+pub async fn run(
+    globals: SharedGlobals,
+    list: NotificationListHandle,
+    tx: mpsc::Sender<Request>,
+) -> anyhow::Result<()> {
+    let mut fired: HashSet<Uuid> = HashSet::new();
+    let mut interval = tokio::time::interval(TICK);
+
+    loop {
+        interval.tick().await;
+
+        let (idle_for, locked_for) = {
+            let globals = globals.lock().unwrap();
+            (
+                globals.idle_since.map(|since| since.elapsed()),
+                globals.locked_since.map(|since| since.elapsed()),
+            )
+        };
+        // Typing at the lock screen resumes the post-lock rules and starts them over
+        let session_idle = rules::session_idle_time(&globals, &list);
+        let locked_idle_for = locked_for.map(|locked_for| locked_for.min(session_idle));
+
+        let mut events = Vec::new();
+        {
+            let map = list.lock().unwrap();
+            // Rules are recreated with new uuids on reload
+            fired.retain(|uuid| map.contains_key(uuid));
+
+            for (uuid, rule) in map.iter() {
+                let elapsed = match rule.notification {
+                    IdleNotification::Timer => idle_for,
+                    IdleNotification::Locked => locked_idle_for,
+                    _ => continue,
+                };
+
+                let timeout = Duration::from_secs(rule.timeout.max(0) as u64);
+                let is_idle = elapsed.is_some_and(|elapsed| elapsed >= timeout);
+
+                if is_idle && !fired.contains(uuid) {
+                    events.push((*uuid, IdleEvent::Idled));
+                } else if !is_idle && fired.contains(uuid) {
+                    events.push((*uuid, IdleEvent::Resumed));
+                }
+            }
+        }
+
+        for (uuid, event) in events {
+            match event {
+                IdleEvent::Idled => fired.insert(uuid),
+                IdleEvent::Resumed => fired.remove(&uuid),
+            };
+            rules::idle_event(&globals, &list, &tx, uuid, event, false);
+        }
+    }
+}
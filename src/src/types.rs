@@ -1,46 +1,286 @@
 use std::{
-    collections::HashMap, path::PathBuf, sync::{Arc, Mutex}
+    collections::{HashMap, HashSet}, ops::{Deref, DerefMut}, path::PathBuf, sync::{Arc, Mutex}, time::{Duration, Instant, SystemTime}
 };
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
 
 use uuid::Uuid;
-use crate::wayland::Output;
-use wayland_client::{protocol::wl_seat, QueueHandle};
-use wayland_protocols::ext::idle_notify::v1::client::{
-    ext_idle_notification_v1, ext_idle_notifier_v1,
+use crate::executor::{Action, ActionContext, Concurrency, ExecBackend, ResourceLimits, Sandbox};
+use hypnos_core::rules::Rule;
+use crate::adaptive::AdaptiveTimeouts;
+use crate::battery_saver::BatterySaver;
+use crate::caffeine::Caffeine;
+use crate::inhibit::Cookie;
+use crate::learn::{LearnConfig, Learner};
+use crate::joystick_handler::{DeviceInfo, DeviceMatch, GamepadConfig};
+use crate::locker::{LockScreen, LockScreenConfig};
+use crate::remote::RemoteDesktopConfig;
+use crate::rtc::WakeSchedule;
+use crate::schedule::ScheduledCommand;
+use crate::stats;
+use crate::udev_handler::InputDevice;
+use crate::wayland::{IdleNotification, Output, Seat};
+use crate::webhook::Webhook;
+use wayland_client::{backend::ObjectId, protocol::{wl_compositor, wl_seat, wl_shm, wl_surface}, Connection, QueueHandle};
+use wayland_protocols::{
+    ext::{
+        idle_notify::v1::client::ext_idle_notifier_v1,
+        session_lock::v1::client::ext_session_lock_manager_v1,
+    },
+    wp::idle_inhibit::zv1::client::{zwp_idle_inhibit_manager_v1, zwp_idle_inhibitor_v1},
+    xdg::xdg_output::zv1::client::zxdg_output_manager_v1,
 };
+use wayland_protocols_plasma::idle::client::org_kde_kwin_idle;
+use wayland_protocols_wlr::foreign_toplevel::v1::client::zwlr_foreign_toplevel_manager_v1;
 
 #[derive(Debug)]
 pub enum Request {
     ReloadConfig,
-    RunCommand(String),
+    RunCommand(Action),
     DbEvent(String), 
     OnBattery(bool),
+    /// Charge of the system battery, in percent
+    BatteryLevel(f64),
+    /// Power drawn from the batteries, in watts
+    EnergyRate(f64),
+    /// Since when the session is idle, from backends timed by hypnos
+    IdleSince(Option<Instant>),
     Flush,
-    Inhibit,
+    /// A controller is in use, inhibit idle for the `gamepad` quiet period
+    GamepadActivity,
+    /// Inhibit idle on behalf of `owner` for `duration`, `inhibit_duration` when None,
+    /// until released when zero
+    Inhibit { owner: String, reason: String, duration: Option<Duration> },
+    /// End the inhibitions of `owner`, even those held until released
+    ReleaseInhibit { owner: String },
+    /// Turn presentation mode, idle inhibited until turned off, on or off
+    TogglePresentation,
+    /// A keyboard or pointing device appeared, by sysname
+    InputAdded(String, InputDevice),
+    InputRemoved(String),
+    /// Result of a password check from the built-in lock screen
+    LockAuth(bool),
+    /// logind's LockedHint for our session
+    LockedHint(bool),
+    /// logind's Active for our session, false while another session is in the foreground
+    SessionActive(bool),
+    /// Whether a VNC/RDP client is connected to the session
+    RemoteDesktop(bool),
+    /// Report a lock/unlock done by hypnos to logind
+    SetLockedHint(bool),
+    /// Pause or resume the rules, until the next config reload
+    SetPaused(bool),
+    /// Postpone the next firing of `rule`, of every rule that didn't fire yet when None
+    Snooze { rule: Option<String>, duration: Duration },
+    /// Some inhibition may have expired, or was acquired or released by a client
+    Uninhibit,
+    /// Switch to a profile, None for the config as written
+    SetProfile(Option<String>),
+    /// Multiply every rule's timeout, 1 to reset
+    Scale(f64),
+    /// Start or stop `hypnos caffeinate`
+    Caffeinate(Caffeine),
+    /// Whether any window is fullscreen
+    Fullscreen(bool),
+    /// Answered by the event loop, showing it's not stuck
+    Ping(oneshot::Sender<()>),
+    /// The Wayland connection died, e.g. the compositor restarted
+    WaylandLost,
+    /// Connected to the compositor again, after `WaylandLost`
+    WaylandReconnected(Connection),
 }
 
-pub type NotificationListHandle =
-    Arc<Mutex<HashMap<Uuid, (String, Option<String>, bool, ext_idle_notification_v1::ExtIdleNotificationV1)>>>;
+/// An armed rule, keyed by the uuid of its idle notification.
+#[derive(Debug)]
+pub struct RuleHandle {
+    pub rule: Rule,
+    pub notification: IdleNotification,
+}
+
+impl Deref for RuleHandle {
+    type Target = Rule;
+
+    fn deref(&self) -> &Rule {
+        &self.rule
+    }
+}
+
+impl DerefMut for RuleHandle {
+    fn deref_mut(&mut self) -> &mut Rule {
+        &mut self.rule
+    }
+}
+
+pub type NotificationListHandle = Arc<Mutex<HashMap<Uuid, RuleHandle>>>;
+
+/// Where idle/resume events come from.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum IdleBackend {
+    /// Per-rule notifications from the compositor
+    #[default]
+    Wayland,
+    /// XScreenSaver idle time, rules are timed by hypnos itself
+    X11,
+    /// logind's session IdleHint, rules are timed by hypnos itself
+    #[value(name = "logind")]
+    LogindIdleHint,
+    /// Input events read from `/dev/input`, rules are timed by hypnos itself
+    Evdev,
+}
 
 #[derive(Debug, Default)]
 pub struct WaylandGlobals {
+    pub backend: IdleBackend,
+    /// When the session became idle, for backends timed by hypnos
+    pub idle_since: Option<Instant>,
+    /// Tells about the windows, for `caffeinate until-fullscreen-ends`
+    pub toplevel_manager: Option<zwlr_foreign_toplevel_manager_v1::ZwlrForeignToplevelManagerV1>,
+    pub fullscreen_toplevels: HashSet<ObjectId>,
+    /// The running `hypnos caffeinate`, with its inhibition
+    pub caffeine: Option<(Cookie, Caffeine)>,
+    /// Short notification following input activity, with the Wayland backend
+    pub activity_notification: Option<IdleNotification>,
+    /// When the last input happened, None while active, from `activity_notification`
+    pub input_idle_since: Option<Instant>,
+    pub seats: HashMap<u32, Seat>,
+    /// The seat picked from `seats` according to the config
     pub seat: Option<wl_seat::WlSeat>,
+    pub outputs: HashMap<u32, Output>,
     pub notifier: Option<ext_idle_notifier_v1::ExtIdleNotifierV1>,
+    /// Fallback for compositors without `ext_idle_notifier_v1`
+    pub kde_idle: Option<org_kde_kwin_idle::OrgKdeKwinIdle>,
     pub on_battery: Option<bool>,
+    pub battery_level: Option<f64>,
+    /// Watts, from UPower
+    pub energy_rate: Option<f64>,
+    pub adaptive_timeouts: Option<AdaptiveTimeouts>,
+    /// Factor the `on_battery` rules were armed with, None when unscaled
+    pub timeout_scale: Option<f64>,
+    pub battery_saver: Option<BatterySaver>,
+    /// Factor every rule was armed with for the battery saver, None while it's off
+    pub saver_scale: Option<f64>,
+    /// A rescale waiting for every rule to resume, re-arming an idled one drops its restore
+    pub rearm_pending: bool,
+    /// Factor of every rule's timeout from `hypnos scale`, None at 1
+    pub manual_scale: Option<f64>,
     pub restore_cmd: Option<String>,
     pub is_paused: bool,
+    /// Another session (user switch, text console) is in the foreground, rules don't fire
+    pub session_inactive: bool,
+    /// Detection of remote desktop clients, off when unset
+    pub remote_desktop: Option<RemoteDesktopConfig>,
+    /// A VNC/RDP client is connected, `skip_when_remote` rules don't fire
+    pub remote_client: bool,
+    pub compositor: Option<wl_compositor::WlCompositor>,
+    pub shm: Option<wl_shm::WlShm>,
+    pub idle_inhibit_manager: Option<zwp_idle_inhibit_manager_v1::ZwpIdleInhibitManagerV1>,
+    /// Surface the idle inhibitor is attached to
+    pub inhibit_surface: Option<wl_surface::WlSurface>,
+    /// Held while any inhibition of the `inhibit` registry is
+    pub idle_inhibitor: Option<zwp_idle_inhibitor_v1::ZwpIdleInhibitorV1>,
+    /// Length of `Request::Inhibit` without one, zero until released
+    pub inhibit_duration: Option<Duration>,
+    /// Picked with `hypnos profile set`, None for the config as written
+    pub profile: Option<String>,
+    /// Those of the config
+    pub profiles: Vec<String>,
+    /// Presentation mode, an inhibition is held until it's turned off
+    pub presentation: bool,
+    /// Keyboards and pointing devices, by sysname
+    pub input_devices: HashMap<String, InputDevice>,
+    /// Input devices whose events aren't activity, nor count in the conditions
+    pub ignored_inputs: Vec<DeviceMatch>,
+    pub gamepad: GamepadConfig,
+    pub session_lock_manager: Option<ext_session_lock_manager_v1::ExtSessionLockManagerV1>,
+    /// The built-in lock screen, while the session is locked by it
+    pub lock_screen: Option<LockScreen>,
+    pub lock_config: LockScreenConfig,
+    pub before_sleep: Option<String>,
+    pub after_resume: Option<String>,
+    pub on_lock: Option<String>,
+    pub on_unlock: Option<String>,
+    /// The `on_battery` hook, named apart from the power source
+    pub on_battery_cmd: Option<String>,
+    pub on_ac_cmd: Option<String>,
+    pub on_start: Option<String>,
+    pub on_exit: Option<String>,
+    /// The config was applied and the rules armed at least once
+    pub rules_armed: bool,
+    /// What the last applied config had ignored, already notified
+    pub config_problems: Vec<String>,
+    /// Since when logind considers the session locked
+    pub locked_since: Option<Instant>,
+    pub wake_schedule: Option<WakeSchedule>,
+    pub schedule: Vec<ScheduledCommand>,
+    /// Default of the rules' `shell`, also used for hooks
+    pub shell: bool,
+    /// Default of the rules' `command_timeout`, also used for hooks
+    pub command_timeout: Option<Duration>,
+    /// Default of the rules' `exec_backend`, also used for hooks
+    pub exec_backend: ExecBackend,
+    /// Default of the rules' `sandbox`, also used for hooks
+    pub sandbox: Option<Sandbox>,
+    /// Default of the rules' `retries`, also used for hooks
+    pub retries: u32,
+    /// When the RTC alarm set before sleeping fires
+    pub rtc_wake_at: Option<SystemTime>,
+    /// logind delay inhibitor for sleep, released once `before_sleep` ran
+    pub sleep_inhibitor: Option<zbus::zvariant::OwnedFd>,
+    pub stats: stats::Tracker,
+    /// Extend the timeouts of rules resumed right after firing
+    pub learn_timeouts: Option<LearnConfig>,
+    pub learner: Learner,
+    /// Seconds added to rules' timeouts until they fire once
+    pub snoozed: HashMap<String, u64>,
+    pub webhooks: Vec<Webhook>,
+    /// Where the warnings count down to, from `countdown_pipe`
+    pub countdown_pipe: Option<PathBuf>,
 }
 pub type SharedGlobals = Arc<Mutex<WaylandGlobals>>;
 
+impl WaylandGlobals {
+    /// A configured hook command, run with the global shell setting.
+    pub fn hook(&self, command: &Option<String>, event: &'static str) -> Option<Action> {
+        command.clone().map(|command| Action {
+            command,
+            shell: self.shell,
+            concurrency: Concurrency::Parallel,
+            timeout: self.command_timeout,
+            backend: self.exec_backend,
+            limits: ResourceLimits::default(),
+            sandbox: self.sandbox.clone(),
+            retries: self.retries,
+            context: ActionContext {
+                event,
+                on_battery: self.on_battery,
+                idle_duration: self.idle_since.map(|since| since.elapsed()),
+                ..Default::default()
+            },
+        })
+    }
+
+    /// Whether `ignored_inputs` picks the device.
+    pub fn input_ignored(&self, info: &DeviceInfo) -> bool {
+        self.ignored_inputs.iter().any(|device| device.matches(info))
+    }
+
+    pub fn session_locked(&self) -> bool {
+        self.locked_since.is_some()
+    }
+
+    /// Record a lock state change, keeping the original lock time while still locked.
+    pub fn set_session_locked(&mut self, locked: bool) {
+        if locked != self.session_locked() {
+            self.locked_since = locked.then(Instant::now);
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct State {
     pub(crate) globals: SharedGlobals,
-    pub(crate) wl_seat: Option<wl_seat::WlSeat>,
     pub(crate) qh: QueueHandle<State>,
-    pub(crate) idle_notifier: Option<ext_idle_notifier_v1::ExtIdleNotifierV1>,
     pub(crate) notification_list: NotificationListHandle,
     pub(crate) tx: mpsc::Sender<Request>,
     pub(crate) config_path: PathBuf,
-    pub(crate) outputs: HashMap<u32, Output>,
+    pub(crate) xdg_output_manager: Option<zxdg_output_manager_v1::ZxdgOutputManagerV1>,
 }
\ No newline at end of file
@@ -1,5 +1,5 @@
-use log::debug;
-use std::collections::HashMap;
+use tracing::{debug, info, warn};
+use std::{collections::HashMap, path::PathBuf};
 use tokio::{
     io::unix::AsyncFd,
     sync::{mpsc, Mutex},
@@ -7,31 +7,109 @@ use tokio::{
 };
 use udev::{EventType, MonitorBuilder};
 
-use crate::joystick_handler::JoystickHandler;
-use crate::types::Request;
+use crate::joystick_handler::{DeviceInfo, JoystickHandler};
+use crate::types::{Request, SharedGlobals};
+use crate::udev_triggers::UdevTrigger;
+
+/// A keyboard or pointing device.
+#[derive(Debug, Clone)]
+pub struct InputDevice {
+    pub name: String,
+    pub keyboard: bool,
+    /// Mouse, touchpad or trackball
+    pub pointer: bool,
+    /// On USB or Bluetooth, unlike a laptop's own keyboard and touchpad
+    pub external: bool,
+    /// For `ignored_inputs`
+    pub info: DeviceInfo,
+}
+
+impl InputDevice {
+    fn from_udev(device: &udev::Device) -> Option<Self> {
+        let flag = |name: &str| device.property_value(name).is_some_and(|value| value == "1");
+        let keyboard = flag("ID_INPUT_KEYBOARD");
+        let pointer = flag("ID_INPUT_MOUSE") || flag("ID_INPUT_TOUCHPAD");
+        if !keyboard && !pointer || !device.sysname().to_str().is_some_and(|name| name.starts_with("event")) {
+            return None;
+        }
+        // The name is on the parent `inputN` device, quoted
+        let name = device
+            .parent()
+            .and_then(|parent| parent.property_value("NAME").map(|name| name.to_string_lossy().trim_matches('"').to_string()))
+            .unwrap_or_else(|| device.sysname().to_string_lossy().into_owned());
+        let external = matches!(device.property_value("ID_BUS").and_then(|bus| bus.to_str()), Some("usb" | "bluetooth"));
+        let id = |name: &str| {
+            device
+                .property_value(name)
+                .and_then(|id| id.to_str())
+                .and_then(|id| u16::from_str_radix(id, 16).ok())
+                .unwrap_or(0)
+        };
+        // The event node, then its by-id and by-path links
+        let mut paths: Vec<PathBuf> = device.devnode().map(PathBuf::from).into_iter().collect();
+        if let Some(links) = device.property_value("DEVLINKS").and_then(|links| links.to_str()) {
+            paths.extend(links.split_whitespace().map(PathBuf::from));
+        }
+        let info = DeviceInfo { name: name.clone(), vendor: id("ID_VENDOR_ID"), product: id("ID_MODEL_ID"), paths };
+        Some(Self { name, keyboard, pointer, external, info })
+    }
+
+    pub fn kind(&self) -> &'static str {
+        if self.keyboard { "keyboard" } else { "pointer" }
+    }
+}
 
 pub struct UdevHandler {
     joysticks: Mutex<HashMap<String, JoinHandle<anyhow::Result<()>>>>,
     tx: mpsc::Sender<Request>,
+    globals: SharedGlobals,
+    /// Power source last reported from the `power_supply` devices
+    on_battery: Mutex<Option<bool>>,
+    /// Set up once, changing them requires a restart
+    triggers: Vec<UdevTrigger>,
 }
 
 impl UdevHandler {
-    pub fn new(tx: mpsc::Sender<Request>) -> Self {
+    pub fn new(tx: mpsc::Sender<Request>, globals: SharedGlobals, triggers: Vec<UdevTrigger>) -> Self {
         Self {
             joysticks: Mutex::new(HashMap::new()),
             tx,
+            globals,
+            on_battery: Mutex::new(None),
+            triggers,
+        }
+    }
+
+    /// Run the commands of the `udev_triggers` matching the event.
+    async fn run_triggers(&self, device: &udev::Device) {
+        for trigger in self.triggers.iter().filter(|trigger| trigger.matches_device(device)) {
+            let action = {
+                let globals = self.globals.lock().unwrap();
+                globals.hook(&Some(trigger.command.clone()), "udev")
+            };
+            if let Some(action) = action {
+                info!("udev {} event on {}, executing: {}", trigger.subsystem, device.sysname().to_string_lossy(), action.command);
+                let _ = self.tx.send(Request::RunCommand(action)).await;
+            }
         }
     }
 
     async fn joystick_add(&self, joystick: udev::Device) -> anyhow::Result<()> {
         if let Some(sysname) = self.get_joystick_sysname(joystick) {
             debug!("Added joystick {}", sysname.clone());
-            let joystick_handler = JoystickHandler::new(sysname.clone(), self.tx.clone());
+            let joystick_handler = JoystickHandler::new(sysname.clone(), self.tx.clone(), self.globals.clone());
             let mut joysticks = self.joysticks.lock().await;
-            joysticks.insert(
-                sysname.clone(),
-                tokio::spawn(async move { joystick_handler.js_handler().await }),
-            );
+            let task = tokio::spawn(async move {
+                let result = joystick_handler.js_handler().await;
+                if let Err(e) = &result {
+                    warn!("Cannot read joystick {}: {}", joystick_handler.syspath(), e);
+                }
+                result
+            });
+            // Seen both while enumerating and from the monitor
+            if let Some(previous) = joysticks.insert(sysname, task) {
+                previous.abort();
+            }
         }
         Ok(())
     }
@@ -39,10 +117,9 @@ impl UdevHandler {
     async fn joystick_remove(&self, joystick: udev::Device) -> anyhow::Result<()> {
         if let Some(sysname) = self.get_joystick_sysname(joystick) {
             debug!("Removed joystick {}", sysname.clone());
-            let mut joysticks = self.joysticks.lock().await;
-            let task = joysticks.get(&sysname).unwrap();
-            task.abort();
-            joysticks.remove(&sysname);
+            if let Some(task) = self.joysticks.lock().await.remove(&sysname) {
+                task.abort();
+            }
         }
         Ok(())
     }
@@ -105,6 +182,31 @@ impl UdevHandler {
         }
     }
 
+    /// Report the keyboards and pointing devices, for the rules' conditions and the events.
+    async fn update_input_device(&self, event_type: EventType, device: udev::Device) {
+        let Some(input) = InputDevice::from_udev(&device) else {
+            return;
+        };
+        let sysname = device.sysname().to_string_lossy().into_owned();
+        let request = match event_type {
+            EventType::Add => Request::InputAdded(sysname, input),
+            EventType::Remove => Request::InputRemoved(sysname),
+            _ => return,
+        };
+        let _ = self.tx.send(request).await;
+    }
+
+    async fn iterate_input_devices(&self) -> anyhow::Result<()> {
+        let mut enumerator = udev::Enumerator::new()?;
+        enumerator.match_subsystem("input")?;
+        enumerator.match_sysname("event*")?;
+
+        for device in enumerator.scan_devices()? {
+            self.update_input_device(EventType::Add, device).await;
+        }
+        Ok(())
+    }
+
     async fn iterate_joystick_devices(&self) -> anyhow::Result<()> {
         let mut enumerator = udev::Enumerator::new()?;
         let _ = enumerator.match_subsystem("input");
@@ -118,10 +220,51 @@ impl UdevHandler {
         Ok(())
     }
 
+    /// Report the power source from the external supplies (`Mains`, or `USB` for USB-C
+    /// chargers), faster than UPower and without it. Nothing is reported on machines
+    /// without any.
+    async fn update_power_source(&self) -> anyhow::Result<()> {
+        let mut enumerator = udev::Enumerator::new()?;
+        enumerator.match_subsystem("power_supply")?;
+
+        let mut supplies = 0;
+        let mut online = false;
+        for device in enumerator.scan_devices()? {
+            let is_external = matches!(
+                device.property_value("POWER_SUPPLY_TYPE").and_then(|kind| kind.to_str()),
+                Some("Mains" | "USB")
+            );
+            if is_external {
+                supplies += 1;
+                online |= device.property_value("POWER_SUPPLY_ONLINE").is_some_and(|value| value == "1");
+            }
+        }
+        if supplies == 0 {
+            return Ok(());
+        }
+
+        let on_battery = !online;
+        let mut last = self.on_battery.lock().await;
+        if *last != Some(on_battery) {
+            debug!("udev: {}", if on_battery { "on battery" } else { "on AC" });
+            *last = Some(on_battery);
+            let _ = self.tx.send(Request::OnBattery(on_battery)).await;
+        }
+        Ok(())
+    }
+
     pub async fn monitor(&self) -> anyhow::Result<()> {
-        let socket = MonitorBuilder::new()?.match_subsystem("input")?.listen()?;
+        let mut builder = MonitorBuilder::new()?
+            .match_subsystem("input")?
+            .match_subsystem("power_supply")?;
+        for trigger in &self.triggers {
+            builder = builder.match_subsystem(&trigger.subsystem)?;
+        }
+        let socket = builder.listen()?;
 
         let _ = self.iterate_joystick_devices().await;
+        let _ = self.iterate_input_devices().await;
+        let _ = self.update_power_source().await;
         let async_socket = AsyncFd::new(socket)?;
 
         loop {
@@ -130,8 +273,15 @@ impl UdevHandler {
                     let mut guard = result?;
 
                     while let Some(event) = async_socket.get_ref().iter().next() {
-                        if let Some(_sysname) = self.get_joystick_sysname(event.clone()) {
-                            self.update_device(event.event_type(), event.device()).await;
+                        self.run_triggers(&event.device()).await;
+                        if event.subsystem().is_some_and(|subsystem| subsystem == "power_supply") {
+                            let _ = self.update_power_source().await;
+                        } else if event.subsystem().is_some_and(|subsystem| subsystem == "input") {
+                            if let Some(_sysname) = self.get_joystick_sysname(event.clone()) {
+                                self.update_device(event.event_type(), event.device()).await;
+                            } else {
+                                self.update_input_device(event.event_type(), event.device()).await;
+                            }
                         }
                     }
 
@@ -0,0 +1,177 @@
+//! Commands run on udev events, from the `udev_triggers` config section, e.g. locking
+//! the session when a security key is unplugged.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// A value pattern where `*` stands for any run of characters, split once at load.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(from = "String", into = "String")]
+pub struct Glob {
+    /// The literal parts between the `*`s
+    parts: Vec<String>,
+}
+
+impl From<String> for Glob {
+    fn from(pattern: String) -> Self {
+        Self {
+            parts: pattern.split('*').map(String::from).collect(),
+        }
+    }
+}
+
+impl From<Glob> for String {
+    fn from(glob: Glob) -> Self {
+        glob.parts.join("*")
+    }
+}
+
+impl Glob {
+    pub fn matches(&self, value: &str) -> bool {
+        let (first, rest) = self.parts.split_first().expect("split yields at least one part");
+        let Some(mut remaining) = value.strip_prefix(first.as_str()) else {
+            return false;
+        };
+        let Some((last, middle)) = rest.split_last() else {
+            // No `*` at all
+            return remaining.is_empty();
+        };
+        for part in middle {
+            match remaining.find(part.as_str()) {
+                Some(index) => remaining = &remaining[index + part.len()..],
+                None => return false,
+            }
+        }
+        remaining.ends_with(last.as_str())
+    }
+}
+
+/// A udev event mapped to a command.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct UdevTrigger {
+    pub subsystem: String,
+    /// `add`, `remove`, `change`, `bind`... any when unset
+    #[serde(default)]
+    pub action: Option<String>,
+    /// Device properties, as shown by `udevadm info`, still known on removal
+    #[serde(default)]
+    pub properties: BTreeMap<String, Glob>,
+    /// sysfs attributes, gone by the time a device is removed
+    #[serde(default)]
+    pub attributes: BTreeMap<String, Glob>,
+    pub command: String,
+}
+
+impl UdevTrigger {
+    /// Whether an event matches, the device's properties and attributes being looked
+    /// up by name.
+    pub fn matches(
+        &self,
+        subsystem: &str,
+        action: &str,
+        property: impl Fn(&str) -> Option<String>,
+        attribute: impl Fn(&str) -> Option<String>,
+    ) -> bool {
+        self.subsystem == subsystem
+            && self.action.as_deref().is_none_or(|wanted| wanted == action)
+            && self
+                .properties
+                .iter()
+                .all(|(name, glob)| property(name).is_some_and(|value| glob.matches(&value)))
+            && self
+                .attributes
+                .iter()
+                .all(|(name, glob)| attribute(name).is_some_and(|value| glob.matches(&value)))
+    }
+
+    /// Check an event from the udev monitor.
+    pub fn matches_device(&self, device: &udev::Device) -> bool {
+        let text = |value: Option<&std::ffi::OsStr>| value.map(|value| value.to_string_lossy().into_owned());
+        self.matches(
+            &text(device.subsystem()).unwrap_or_default(),
+            &text(device.action()).unwrap_or_default(),
+            |name| text(device.property_value(name)),
+            // sysfs attributes usually end with a newline
+            |name| text(device.attribute_value(name)).map(|value| value.trim_end().to_string()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn glob(pattern: &str) -> Glob {
+        Glob::from(pattern.to_string())
+    }
+
+    fn trigger(json: &str) -> UdevTrigger {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn lookup(values: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let values: HashMap<String, String> = values.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        move |name| values.get(name).cloned()
+    }
+
+    #[test]
+    fn glob_without_wildcard_is_exact() {
+        assert!(glob("1050").matches("1050"));
+        assert!(!glob("1050").matches("10500"));
+        assert!(!glob("1050").matches("105"));
+        assert!(glob("").matches(""));
+        assert!(!glob("").matches("a"));
+    }
+
+    #[test]
+    fn glob_wildcards() {
+        assert!(glob("*").matches(""));
+        assert!(glob("*").matches("anything"));
+        assert!(glob("Yubi*").matches("YubiKey OTP+FIDO+CCID"));
+        assert!(glob("*FIDO*").matches("YubiKey OTP+FIDO+CCID"));
+        assert!(glob("*CCID").matches("YubiKey OTP+FIDO+CCID"));
+        assert!(glob("Y*O*D").matches("YubiKey OTP+FIDO+CCID"));
+        assert!(!glob("*FIDO").matches("YubiKey OTP+FIDO+CCID"));
+        assert!(!glob("ab*ba").matches("aba"));
+    }
+
+    #[test]
+    fn subsystem_and_action() {
+        let trigger = trigger(r#"{ "subsystem": "usb", "action": "remove", "command": "@lock" }"#);
+        let none = lookup(&[]);
+        assert!(trigger.matches("usb", "remove", &none, &none));
+        assert!(!trigger.matches("usb", "add", &none, &none));
+        assert!(!trigger.matches("input", "remove", &none, &none));
+
+        let any_action = UdevTrigger { action: None, ..trigger };
+        assert!(any_action.matches("usb", "add", &none, &none));
+    }
+
+    #[test]
+    fn property_filters() {
+        let trigger = trigger(
+            r#"{
+                "subsystem": "usb",
+                "properties": { "ID_VENDOR_ID": "1050", "ID_MODEL": "YubiKey*" },
+                "command": "@lock"
+            }"#,
+        );
+        let none = lookup(&[]);
+        let yubikey = lookup(&[("ID_VENDOR_ID", "1050"), ("ID_MODEL", "YubiKey_OTP+FIDO"), ("DEVTYPE", "usb_device")]);
+        let other = lookup(&[("ID_VENDOR_ID", "046d"), ("ID_MODEL", "YubiKey_OTP+FIDO")]);
+        let partial = lookup(&[("ID_VENDOR_ID", "1050")]);
+        assert!(trigger.matches("usb", "remove", &yubikey, &none));
+        assert!(!trigger.matches("usb", "remove", &other, &none));
+        assert!(!trigger.matches("usb", "remove", &partial, &none));
+    }
+
+    #[test]
+    fn attribute_filters() {
+        let trigger = trigger(r#"{ "subsystem": "usb", "attributes": { "manufacturer": "Yubico" }, "command": "@lock" }"#);
+        let none = lookup(&[]);
+        assert!(trigger.matches("usb", "add", &none, lookup(&[("manufacturer", "Yubico")])));
+        assert!(!trigger.matches("usb", "add", &none, lookup(&[("manufacturer", "Logitech")])));
+        assert!(!trigger.matches("usb", "add", lookup(&[("manufacturer", "Yubico")]), &none));
+    }
+}
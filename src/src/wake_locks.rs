@@ -0,0 +1,93 @@
+//! Wake locks as files: while a file is in the directory, idling is held off with its
+//! name as the reason. For scripts, `touch` before the work and `rm` after.
+
+use futures::stream::StreamExt;
+use inotify::{Inotify, WatchMask};
+use tracing::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fs, path::PathBuf};
+use tokio::sync::mpsc;
+use xdg::BaseDirectories;
+
+use crate::config;
+use crate::inhibit::{self, Cookie, Scope};
+use crate::types::Request;
+
+const OWNER: &str = "wake-lock";
+const DEFAULT_DIR: &str = "inhibitors";
+
+/// The `wake_locks` section of the config.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct WakeLockConfig {
+    /// Defaults to $XDG_RUNTIME_DIR/hypnos/inhibitors
+    #[serde(default)]
+    pub dir: Option<PathBuf>,
+    /// Names of the rules held off, every rule when empty
+    #[serde(default)]
+    pub rules: Vec<String>,
+}
+
+fn default_dir() -> std::io::Result<PathBuf> {
+    BaseDirectories::with_prefix(config::APP_NAME).create_runtime_directory(DEFAULT_DIR)
+}
+
+/// Names of the files in `dir`, dotfiles aside so editors' and `mktemp`'s don't count.
+fn wake_locks(dir: &PathBuf) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .filter(|name| !name.starts_with('.'))
+        .collect()
+}
+
+/// Hold an inhibition per file in the directory, until it's removed.
+pub async fn run(config: WakeLockConfig, tx: mpsc::Sender<Request>) -> anyhow::Result<()> {
+    let dir = match config.dir {
+        Some(dir) => {
+            fs::create_dir_all(&dir)?;
+            dir
+        }
+        None => default_dir()?,
+    };
+    let inotify = Inotify::init()?;
+    inotify.watches().add(
+        &dir,
+        WatchMask::CREATE | WatchMask::DELETE | WatchMask::MOVED_TO | WatchMask::MOVED_FROM,
+    )?;
+    let mut events = inotify.into_event_stream([0; 1024])?;
+    debug!("Watching the wake locks in {:?}", dir);
+
+    let mut held: HashMap<String, Cookie> = HashMap::new();
+    loop {
+        let present = wake_locks(&dir);
+        let mut changed = false;
+        held.retain(|name, cookie| {
+            let keep = present.contains(name);
+            if !keep {
+                info!("Wake lock '{}' removed", name);
+                inhibit::release(*cookie);
+                changed = true;
+            }
+            keep
+        });
+        for name in present {
+            if !held.contains_key(&name) {
+                info!("Wake lock '{}' taken", name);
+                held.insert(name.clone(), inhibit::acquire_for(OWNER, &name, Scope::rules(&config.rules), None));
+                changed = true;
+            }
+        }
+        if changed && tx.send(Request::Uninhibit).await.is_err() {
+            return Ok(());
+        }
+
+        match events.next().await {
+            Some(Ok(_)) => {}
+            Some(Err(e)) => warn!("Failed to read the wake lock changes: {}", e),
+            None => return Ok(()),
+        }
+    }
+}
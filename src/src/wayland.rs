@@ -1,29 +1,75 @@
-use log::{debug, info};
+use tracing::{debug, info};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
 use uuid::Uuid;
 use wayland_client::{
+    backend::ObjectId,
     protocol::{
-        wl_compositor, wl_output, wl_registry, wl_seat,
+        wl_compositor, wl_output, wl_registry, wl_seat, wl_shm,
         wl_surface::{self},
     },
-    Connection, Dispatch, QueueHandle,
+    event_created_child, Connection, Dispatch, Proxy, QueueHandle,
 };
 use wayland_protocols::{
-    ext::idle_notify::v1::client::{ext_idle_notification_v1, ext_idle_notifier_v1},
+    ext::{
+        idle_notify::v1::client::{ext_idle_notification_v1, ext_idle_notifier_v1},
+        session_lock::v1::client::ext_session_lock_manager_v1,
+    },
     wp::idle_inhibit::zv1::client::{
         zwp_idle_inhibit_manager_v1,
         zwp_idle_inhibitor_v1::{self},
     },
-    xdg::activation::v1::client::{xdg_activation_token_v1, xdg_activation_v1},
+    xdg::{
+        activation::v1::client::{xdg_activation_token_v1, xdg_activation_v1},
+        xdg_output::zv1::client::{zxdg_output_manager_v1, zxdg_output_v1},
+    },
 };
 
-use crate::{apply_config, types::{State, Request}, INHIBIT_MANAGER, SURFACE};
+use wayland_protocols_plasma::idle::client::{org_kde_kwin_idle, org_kde_kwin_idle_timeout};
+use wayland_protocols_wlr::foreign_toplevel::v1::client::{
+    zwlr_foreign_toplevel_handle_v1, zwlr_foreign_toplevel_manager_v1,
+};
+
+use crate::{
+    apply_config,
+    locker,
+    rules::{self, IdleEvent},
+    types::{Request, State, WaylandGlobals},
+};
+
+/// Of the notification tracking input activity, apart from the rules
+pub const ACTIVITY_UUID: Uuid = Uuid::nil();
+/// Precision of the tracked idle time
+const ACTIVITY_TIMEOUT_MS: u32 = 1000;
 
 #[derive(Debug)]
-#[allow(dead_code)]
 pub struct Output {
-    reg_name: u32,
-    wl_output: wl_output::WlOutput,
-    name: Option<String>,
+    pub reg_name: u32,
+    pub wl_output: wl_output::WlOutput,
+    pub xdg_output: Option<zxdg_output_v1::ZxdgOutputV1>,
+    pub info: OutputInfo,
+}
+
+/// What the compositor told us about an output, from `wl_output` and `xdg_output`.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct OutputInfo {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub make: Option<String>,
+    pub model: Option<String>,
+    pub width: i32,
+    pub height: i32,
+    /// Refresh rate in mHz
+    pub refresh: i32,
+    pub scale: i32,
+}
+
+impl Output {
+    fn bind_xdg_output(&mut self, manager: &zxdg_output_manager_v1::ZxdgOutputManagerV1, qh: &QueueHandle<State>) {
+        if self.xdg_output.is_none() {
+            self.xdg_output = Some(manager.get_xdg_output(&self.wl_output, qh, self.reg_name));
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -40,39 +86,37 @@ impl Dispatch<wl_registry::WlRegistry, ()> for State {
         _: &Connection,
         qh: &QueueHandle<Self>,
     ) {
-        if let wl_registry::Event::Global {
-            name, interface, ..
-        } = event
-        {
-            match &interface[..] {
+        match event {
+            wl_registry::Event::Global {
+                name, interface, version
+            } => match &interface[..] {
                 "wl_seat" => {
-                    let wl_seat = registry.bind::<wl_seat::WlSeat, _, _>(name, 1, qh, ());
-                    state.wl_seat = Some(wl_seat.clone());
-                    state.globals.lock().unwrap().seat = Some(wl_seat.clone());
+                    // Seat names only arrive from version 2, wait for them before picking one
+                    let wl_seat = registry.bind::<wl_seat::WlSeat, _, _>(name, version.min(2), qh, name);
                     debug!("wl_seat: {:?}", name);
-                    if state.idle_notifier.is_some() {
-                         let _ = apply_config(
-                            &state.globals,
-                            &state.qh,
-                            &state.notification_list,
-                            &state.config_path
-                         );
+                    state.globals.lock().unwrap().seats.insert(name, Seat { wl_seat: wl_seat.clone(), name: None });
+                    if version < 2 && state.has_idle_protocol() {
+                        state.reapply_config();
                     }
                 }
                 "ext_idle_notifier_v1" => {
                     let idle_notifier = registry
-                        .bind::<ext_idle_notifier_v1::ExtIdleNotifierV1, _, _>(name, 1, qh, ());
+                        .bind::<ext_idle_notifier_v1::ExtIdleNotifierV1, _, _>(name, version.min(2), qh, ());
 
-                    debug!("ext_idle_notifier_v1: {:?}", name);
-                    state.idle_notifier = Some(idle_notifier.clone());
+                    debug!("ext_idle_notifier_v1: {:?} (version {})", name, idle_notifier.version());
                     state.globals.lock().unwrap().notifier = Some(idle_notifier.clone());
-                    if state.wl_seat.is_some() {
-                        let _ = apply_config(
-                            &state.globals,
-                            &state.qh,
-                            &state.notification_list,
-                            &state.config_path
-                        );
+                    if !state.globals.lock().unwrap().seats.is_empty() {
+                        state.reapply_config();
+                    }
+                }
+                "org_kde_kwin_idle" => {
+                    // Only used when the compositor has no ext_idle_notifier_v1
+                    let kde_idle = registry.bind::<org_kde_kwin_idle::OrgKdeKwinIdle, _, _>(name, 1, qh, ());
+
+                    debug!("org_kde_kwin_idle: {:?}", name);
+                    state.globals.lock().unwrap().kde_idle = Some(kde_idle);
+                    if !state.globals.lock().unwrap().seats.is_empty() {
+                        state.reapply_config();
                     }
                 }
                 "xdg_activation_v1" => {
@@ -86,30 +130,202 @@ impl Dispatch<wl_registry::WlRegistry, ()> for State {
                 }
                 "zwp_idle_inhibit_manager_v1" => {
                     let inhibit_manager = registry.bind::<zwp_idle_inhibit_manager_v1::ZwpIdleInhibitManagerV1, _, _>(name, 1, qh, ());
-                    *INHIBIT_MANAGER.lock().unwrap() = Some(inhibit_manager);
+                    state.globals.lock().unwrap().idle_inhibit_manager = Some(inhibit_manager);
                 }
                 "wl_compositor" => {
                     let compositor = registry.bind::<wl_compositor::WlCompositor, _, _>(name, 1, qh, ());
                     let surface = compositor.create_surface(qh, ());
-                    *SURFACE.lock().unwrap() = Some(surface);
+                    let mut globals = state.globals.lock().unwrap();
+                    globals.inhibit_surface = Some(surface);
+                    globals.compositor = Some(compositor);
+                }
+                "wl_shm" => {
+                    let shm = registry.bind::<wl_shm::WlShm, _, _>(name, 1, qh, ());
+                    state.globals.lock().unwrap().shm = Some(shm);
+                }
+                "ext_session_lock_manager_v1" => {
+                    let manager = registry.bind::<ext_session_lock_manager_v1::ExtSessionLockManagerV1, _, _>(name, 1, qh, ());
+                    state.globals.lock().unwrap().session_lock_manager = Some(manager);
                 }
                 "wl_output" => {
-                    let wl_output = registry.bind::<wl_output::WlOutput, _, _>(name, 1, qh, ());
-                    let output = Output {
+                    let wl_output = registry.bind::<wl_output::WlOutput, _, _>(name, version.min(4), qh, name);
+                    let mut output = Output {
                         reg_name: name,
                         wl_output,
-                        name: None,
+                        xdg_output: None,
+                        info: OutputInfo {
+                            scale: 1,
+                            ..Default::default()
+                        },
                     };
-                    state.outputs.insert(name, output);
+                    if let Some(manager) = &state.xdg_output_manager {
+                        output.bind_xdg_output(manager, qh);
+                    }
+                    state.globals.lock().unwrap().outputs.insert(name, output);
+                    locker::output_added(&state.globals, name, qh);
+                    reapply_idle_state(state);
+                }
+                "zwlr_foreign_toplevel_manager_v1" => {
+                    let manager = registry
+                        .bind::<zwlr_foreign_toplevel_manager_v1::ZwlrForeignToplevelManagerV1, _, _>(name, version.min(3), qh, ());
+                    state.globals.lock().unwrap().toplevel_manager = Some(manager);
+                }
+                "zxdg_output_manager_v1" => {
+                    let manager = registry.bind::<zxdg_output_manager_v1::ZxdgOutputManagerV1, _, _>(name, version.min(3), qh, ());
+                    for output in state.globals.lock().unwrap().outputs.values_mut() {
+                        output.bind_xdg_output(&manager, qh);
+                    }
+                    state.xdg_output_manager = Some(manager);
                 }
                 _ => {}
+            },
+            wl_registry::Event::GlobalRemove { name } => {
+                locker::output_removed(&state.globals, name);
+                let removed_seat = state.globals.lock().unwrap().seats.remove(&name);
+                if let Some(seat) = removed_seat {
+                    debug!("wl_seat removed: {:?} ({:?})", name, seat.name);
+                    if seat.wl_seat.version() >= 5 {
+                        seat.wl_seat.release();
+                    }
+                    state.reapply_config();
+                }
+                if let Some(output) = state.globals.lock().unwrap().outputs.remove(&name) {
+                    debug!("wl_output removed: {:?} ({:?})", output.reg_name, output.info.name);
+                    if let Some(xdg_output) = output.xdg_output {
+                        xdg_output.destroy();
+                    }
+                    if output.wl_output.version() >= 3 {
+                        output.wl_output.release();
+                    }
+                }
             }
+            _ => {}
+        }
+    }
+}
+
+/// An idle notification from whichever idle protocol the compositor offers.
+#[derive(Debug)]
+pub enum IdleNotification {
+    Ext(ext_idle_notification_v1::ExtIdleNotificationV1),
+    Kde(org_kde_kwin_idle_timeout::OrgKdeKwinIdleTimeout),
+    /// No protocol object, the rule is timed by hypnos (see `timer`)
+    Timer,
+    /// Timed by hypnos from when the session got locked
+    Locked,
+}
+
+impl IdleNotification {
+    pub fn destroy(&self) {
+        match self {
+            IdleNotification::Ext(notification) => notification.destroy(),
+            IdleNotification::Kde(timeout) => timeout.release(),
+            IdleNotification::Timer | IdleNotification::Locked => {}
         }
     }
 }
 
-impl Dispatch<wl_seat::WlSeat, ()> for State {
-    fn event(_: &mut Self, _: &wl_seat::WlSeat, _: wl_seat::Event, _: &(), _: &Connection, _qh: &QueueHandle<Self>) {}
+/// A `wl_seat` global and the name the compositor gave it.
+#[derive(Debug)]
+pub struct Seat {
+    pub wl_seat: wl_seat::WlSeat,
+    pub name: Option<String>,
+}
+
+/// (Re-)arm the short notification telling when the last input happened, for the
+/// idle time queries.
+pub fn track_activity(globals: &mut WaylandGlobals, qh: &QueueHandle<State>) {
+    if let Some(notification) = globals.activity_notification.take() {
+        notification.destroy();
+    }
+    globals.input_idle_since = None;
+    let Some(seat) = &globals.seat else {
+        return;
+    };
+    let ctx = NotificationContext { uuid: ACTIVITY_UUID };
+    globals.activity_notification = match (&globals.notifier, &globals.kde_idle) {
+        // Version 2 tells about input alone, whatever the inhibitors
+        (Some(notifier), _) if notifier.version() >= 2 => Some(IdleNotification::Ext(
+            notifier.get_input_idle_notification(ACTIVITY_TIMEOUT_MS, seat, qh, ctx),
+        )),
+        (Some(notifier), _) => Some(IdleNotification::Ext(notifier.get_idle_notification(ACTIVITY_TIMEOUT_MS, seat, qh, ctx))),
+        (None, Some(kde_idle)) => Some(IdleNotification::Kde(kde_idle.get_idle_timeout(seat, ACTIVITY_TIMEOUT_MS, qh, ctx))),
+        (None, None) => None,
+    };
+}
+
+impl State {
+    fn idle_event(&self, ctx: &NotificationContext, event: IdleEvent) {
+        if ctx.uuid == ACTIVITY_UUID {
+            rules::input_activity(&self.globals, event, Duration::from_millis(ACTIVITY_TIMEOUT_MS.into()));
+            return;
+        }
+        rules::idle_event(&self.globals, &self.notification_list, &self.tx, ctx.uuid, event, false);
+    }
+
+    /// Track which windows are fullscreen, telling the event loop when the first one
+    /// appears or the last one goes.
+    fn fullscreen_changed(&self, toplevel: ObjectId, fullscreen: bool) {
+        let mut globals = self.globals.lock().unwrap();
+        let before = !globals.fullscreen_toplevels.is_empty();
+        if fullscreen {
+            globals.fullscreen_toplevels.insert(toplevel);
+        } else {
+            globals.fullscreen_toplevels.remove(&toplevel);
+        }
+        let after = !globals.fullscreen_toplevels.is_empty();
+        if before != after {
+            debug!("Fullscreen window: {}", after);
+            let _ = self.tx.try_send(Request::Fullscreen(after));
+        }
+    }
+
+    fn has_idle_protocol(&self) -> bool {
+        let globals = self.globals.lock().unwrap();
+        globals.notifier.is_some() || globals.kde_idle.is_some()
+    }
+
+    fn reapply_config(&self) {
+        let _ = apply_config(
+            &self.globals,
+            Some(&self.qh),
+            &self.notification_list,
+            &self.config_path,
+        );
+    }
+}
+
+/// Re-run the actions of the idled rules opting in with `reapply_on_output`, so an
+/// output plugged in while idle ends up in the same state (dpms, gamma...) as the
+/// others. Only those, running a locker or a suspend again would do harm.
+fn reapply_idle_state(state: &State) {
+    let map = state.notification_list.lock().unwrap();
+    let on_battery = state.globals.lock().unwrap().on_battery;
+    for rule in map.values().filter(|rule| rule.is_idled() && rule.reapply_on_output) {
+        info!("Output added while idle, re-applying rule '{}'", rule.name);
+        let _ = state.tx.try_send(Request::RunCommand(rule.action(rule.actions.clone(), "output_added", on_battery)));
+    }
+}
+
+impl Dispatch<wl_seat::WlSeat, u32> for State {
+    fn event(
+        state: &mut Self,
+        _: &wl_seat::WlSeat,
+        event: wl_seat::Event,
+        reg_name: &u32,
+        _: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        if let wl_seat::Event::Name { name } = event {
+            debug!("wl_seat {} is named '{}'", reg_name, name);
+            if let Some(seat) = state.globals.lock().unwrap().seats.get_mut(reg_name) {
+                seat.name = Some(name);
+            }
+            if state.has_idle_protocol() {
+                state.reapply_config();
+            }
+        }
+    }
 }
 impl Dispatch<zwp_idle_inhibitor_v1::ZwpIdleInhibitorV1, ()> for State {
     fn event(_: &mut Self, _: &zwp_idle_inhibitor_v1::ZwpIdleInhibitorV1, _: zwp_idle_inhibitor_v1::Event, _: &(), _: &Connection, _qh: &QueueHandle<Self>) {}
@@ -129,8 +345,70 @@ impl Dispatch<wl_compositor::WlCompositor, ()> for State {
 impl Dispatch<wl_surface::WlSurface, ()> for State {
     fn event(_: &mut Self, _: &wl_surface::WlSurface, _: wl_surface::Event, _: &(), _: &Connection, _qh: &QueueHandle<Self>) {}
 }
-impl Dispatch<wl_output::WlOutput, ()> for State {
-     fn event(_state: &mut Self, _output: &wl_output::WlOutput, _event: wl_output::Event, _: &(), _: &Connection, _qh: &QueueHandle<Self>) {}
+impl Dispatch<wl_output::WlOutput, u32> for State {
+    fn event(
+        state: &mut Self,
+        _output: &wl_output::WlOutput,
+        event: wl_output::Event,
+        reg_name: &u32,
+        _: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        let mut globals = state.globals.lock().unwrap();
+        let Some(output) = globals.outputs.get_mut(reg_name) else {
+            return;
+        };
+        let info = &mut output.info;
+
+        match event {
+            wl_output::Event::Geometry { make, model, .. } => {
+                info.make = Some(make);
+                info.model = Some(model);
+            }
+            wl_output::Event::Mode { flags, width, height, refresh } => {
+                let is_current = matches!(flags, wayland_client::WEnum::Value(f) if f.contains(wl_output::Mode::Current));
+                if is_current {
+                    info.width = width;
+                    info.height = height;
+                    info.refresh = refresh;
+                }
+            }
+            wl_output::Event::Scale { factor } => info.scale = factor,
+            wl_output::Event::Name { name } => info.name = Some(name),
+            wl_output::Event::Description { description } => info.description = Some(description),
+            wl_output::Event::Done => debug!("wl_output {} updated: {:?}", reg_name, info),
+            _ => {}
+        }
+    }
+}
+impl Dispatch<zxdg_output_manager_v1::ZxdgOutputManagerV1, ()> for State {
+    fn event(_: &mut Self, _: &zxdg_output_manager_v1::ZxdgOutputManagerV1, _: zxdg_output_manager_v1::Event, _: &(), _: &Connection, _qh: &QueueHandle<Self>) {}
+}
+impl Dispatch<zxdg_output_v1::ZxdgOutputV1, u32> for State {
+    fn event(
+        state: &mut Self,
+        _xdg_output: &zxdg_output_v1::ZxdgOutputV1,
+        event: zxdg_output_v1::Event,
+        reg_name: &u32,
+        _: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        let mut globals = state.globals.lock().unwrap();
+        let Some(output) = globals.outputs.get_mut(reg_name) else {
+            return;
+        };
+
+        // wl_output v4 already sends these, only fill in what is missing
+        match event {
+            zxdg_output_v1::Event::Name { name } => {
+                output.info.name.get_or_insert(name);
+            }
+            zxdg_output_v1::Event::Description { description } => {
+                output.info.description.get_or_insert(description);
+            }
+            _ => {}
+        }
+    }
 }
 impl Dispatch<ext_idle_notifier_v1::ExtIdleNotifierV1, ()> for State {
     fn event(_: &mut Self, _: &ext_idle_notifier_v1::ExtIdleNotifierV1, _: ext_idle_notifier_v1::Event, _: &(), _: &Connection, _qh: &QueueHandle<Self>) {}
@@ -147,47 +425,74 @@ impl Dispatch<ext_idle_notification_v1::ExtIdleNotificationV1, NotificationConte
     ) {
         debug!("Idle Notification event: {:?} uuid: {:?}", event, ctx.uuid);
 
-        if state.globals.lock().unwrap().is_paused {
-            debug!("System is paused, ignoring idle notification event");
-            return;
-        }
-        
         match event {
-            ext_idle_notification_v1::Event::Idled => {
-                let map = state.notification_list.lock().unwrap();
-
-                if let Some((command, _, req_battery, _)) = map.get(&ctx.uuid) {
+            ext_idle_notification_v1::Event::Idled => state.idle_event(ctx, IdleEvent::Idled),
+            ext_idle_notification_v1::Event::Resumed => state.idle_event(ctx, IdleEvent::Resumed),
+            _ => {}
+        }
+    }
+}
 
-                    let current_bat_state = state.globals.lock().unwrap().on_battery;
+impl Dispatch<zwlr_foreign_toplevel_manager_v1::ZwlrForeignToplevelManagerV1, ()> for State {
+    fn event(
+        _: &mut Self,
+        _: &zwlr_foreign_toplevel_manager_v1::ZwlrForeignToplevelManagerV1,
+        _: zwlr_foreign_toplevel_manager_v1::Event,
+        _: &(),
+        _: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+    }
 
-                    if *req_battery && !current_bat_state.unwrap_or(false) {
-                        debug!("Skipping idle command '{}' because on AC power", command);
-                        return;
-                    }
+    event_created_child!(State, zwlr_foreign_toplevel_manager_v1::ZwlrForeignToplevelManagerV1, [
+        zwlr_foreign_toplevel_manager_v1::EVT_TOPLEVEL_OPCODE => (zwlr_foreign_toplevel_handle_v1::ZwlrForeignToplevelHandleV1, ()),
+    ]);
+}
 
-                    info!("Idle reached, executing: {}", command);
-                    let _ = state.tx.try_send(Request::RunCommand(command.clone()));
-                }
+impl Dispatch<zwlr_foreign_toplevel_handle_v1::ZwlrForeignToplevelHandleV1, ()> for State {
+    fn event(
+        state: &mut Self,
+        toplevel: &zwlr_foreign_toplevel_handle_v1::ZwlrForeignToplevelHandleV1,
+        event: zwlr_foreign_toplevel_handle_v1::Event,
+        _: &(),
+        _: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        let fullscreen = match event {
+            // An array of native-endian u32 states
+            zwlr_foreign_toplevel_handle_v1::Event::State { state: states } => states
+                .chunks_exact(4)
+                .map(|value| u32::from_ne_bytes([value[0], value[1], value[2], value[3]]))
+                .any(|value| value == zwlr_foreign_toplevel_handle_v1::State::Fullscreen as u32),
+            zwlr_foreign_toplevel_handle_v1::Event::Closed => {
+                toplevel.destroy();
+                false
             }
-            ext_idle_notification_v1::Event::Resumed => {
-                let map = state.notification_list.lock().unwrap();
-
-                if let Some((_, Some(restore_cmd), req_battery, _)) = map.get(&ctx.uuid) {
+            _ => return,
+        };
+        state.fullscreen_changed(toplevel.id(), fullscreen);
+    }
+}
 
-                    let current_bat_state = state.globals.lock().unwrap().on_battery;
+impl Dispatch<org_kde_kwin_idle::OrgKdeKwinIdle, ()> for State {
+    fn event(_: &mut Self, _: &org_kde_kwin_idle::OrgKdeKwinIdle, _: org_kde_kwin_idle::Event, _: &(), _: &Connection, _qh: &QueueHandle<Self>) {}
+}
 
-                    if *req_battery && !current_bat_state.unwrap_or(false) {
-                        debug!("Skipping restore command because on AC power");
-                        return;
-                    }
+impl Dispatch<org_kde_kwin_idle_timeout::OrgKdeKwinIdleTimeout, NotificationContext> for State {
+    fn event(
+        state: &mut Self,
+        _idle_timeout: &org_kde_kwin_idle_timeout::OrgKdeKwinIdleTimeout,
+        event: org_kde_kwin_idle_timeout::Event,
+        ctx: &NotificationContext,
+        _: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        debug!("KWin idle timeout event: {:?} uuid: {:?}", event, ctx.uuid);
 
-                    info!("Idle resumed, executing restore command: {}", restore_cmd);
-                    let _ = state.tx.try_send(Request::RunCommand(restore_cmd.clone()));
-                } else {
-                    debug!("No restore command defined for uuid {:?}", ctx.uuid);
-                }
-            }
+        match event {
+            org_kde_kwin_idle_timeout::Event::Idle => state.idle_event(ctx, IdleEvent::Idled),
+            org_kde_kwin_idle_timeout::Event::Resumed => state.idle_event(ctx, IdleEvent::Resumed),
             _ => {}
         }
     }
-}
\ No newline at end of file
+}
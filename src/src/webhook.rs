@@ -0,0 +1,116 @@
+//! HTTP webhooks: POST the daemon events as JSON to configured URLs.
+
+use tracing::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, time::Duration};
+use tokio::sync::broadcast;
+
+use crate::events::{self, Event};
+use crate::types::SharedGlobals;
+
+/// Delay before the first retry, doubled after each attempt
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Webhook {
+    pub url: String,
+    /// Only these events are sent, all of them when unset
+    #[serde(default)]
+    pub events: Option<Vec<String>>,
+    /// Only these rules' `idle` and `resume` events are sent, all of them when unset
+    #[serde(default)]
+    pub rules: Option<Vec<String>>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Seconds before a request is given up
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    /// Further attempts when a request fails
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+}
+
+fn default_timeout() -> u64 {
+    10
+}
+
+fn default_retries() -> u32 {
+    3
+}
+
+impl Webhook {
+    fn wants(&self, event: &Event) -> bool {
+        let event_matches = self.events.as_ref().is_none_or(|events| events.iter().any(|name| name == event.name()));
+        let rule_matches = match (&self.rules, event.rule()) {
+            (Some(rules), Some(rule)) => rules.iter().any(|name| name == rule),
+            _ => true,
+        };
+        event_matches && rule_matches
+    }
+
+    async fn post(&self, client: &reqwest::Client, body: String) -> anyhow::Result<()> {
+        let mut request = client
+            .post(&self.url)
+            .timeout(Duration::from_secs(self.timeout))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body);
+        for (name, value) in &self.headers {
+            request = request.header(name, value);
+        }
+        request.send().await?.error_for_status()?;
+        Ok(())
+    }
+
+    async fn deliver(self, client: reqwest::Client, body: String) {
+        let mut delay = RETRY_DELAY;
+        for attempt in 0..=self.retries {
+            match self.post(&client, body.clone()).await {
+                Ok(()) => {
+                    debug!("Webhook {} delivered", self.url);
+                    return;
+                }
+                Err(e) if attempt < self.retries => {
+                    debug!("Webhook {} failed ({}), retrying in {:?}", self.url, e, delay);
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                Err(e) => warn!("Webhook {} failed: {}", self.url, e),
+            }
+        }
+    }
+}
+
+/// Send the events to the webhooks configured at the time, each delivery in its own task.
+pub async fn run(globals: SharedGlobals) {
+    let client = reqwest::Client::new();
+    let mut events = events::subscribe();
+
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(count)) => {
+                warn!("Dropped {} webhook events", count);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+
+        let webhooks: Vec<Webhook> = globals
+            .lock()
+            .unwrap()
+            .webhooks
+            .iter()
+            .filter(|webhook| webhook.wants(&event))
+            .cloned()
+            .collect();
+        if webhooks.is_empty() {
+            continue;
+        }
+        let Ok(body) = event.to_json() else {
+            continue;
+        };
+        for webhook in webhooks {
+            tokio::spawn(webhook.deliver(client.clone(), body.clone()));
+        }
+    }
+}
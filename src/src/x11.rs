@@ -0,0 +1,36 @@
+use tracing::{debug, error};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use x11rb::{connection::Connection, protocol::screensaver::ConnectionExt};
+
+use crate::types::Request;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Poll the XScreenSaver extension for the time since the last user input.
+pub async fn idle_watcher(tx: mpsc::Sender<Request>) -> anyhow::Result<()> {
+    let (conn, screen_num) = x11rb::connect(None)?;
+    let root = conn.setup().roots[screen_num].root;
+
+    let version = conn.screensaver_query_version(1, 1)?.reply()?;
+    debug!("XScreenSaver extension {}.{}", version.server_major_version, version.server_minor_version);
+
+    tokio::task::spawn_blocking(move || loop {
+        match conn.screensaver_query_info(root).map(|cookie| cookie.reply()) {
+            Ok(Ok(info)) => {
+                let idle_for = Duration::from_millis(info.ms_since_user_input.into());
+                let since = Instant::now().checked_sub(idle_for).unwrap_or_else(Instant::now);
+                if tx.blocking_send(Request::IdleSince(Some(since))).is_err() {
+                    break;
+                }
+            }
+            Ok(Err(e)) => error!("XScreenSaver query failed: {}", e),
+            Err(e) => {
+                error!("Lost the X11 connection: {}", e);
+                break;
+            }
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    });
+    Ok(())
+}
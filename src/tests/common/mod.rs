@@ -0,0 +1,306 @@
+//! An in-process Wayland compositor offering a seat and ext-idle-notify, and a hypnos
+//! daemon connected to it.
+
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::{mpsc, Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use tempfile::TempDir;
+use wayland_protocols::ext::idle_notify::v1::server::{ext_idle_notification_v1, ext_idle_notifier_v1};
+use wayland_server::{
+    backend::{ClientData, ClientId},
+    protocol::wl_seat,
+    Client, DataInit, Dispatch, Display, DisplayHandle, GlobalDispatch, ListeningSocket, New, Resource,
+};
+
+const SOCKET_NAME: &str = "wayland-hypnos-test";
+/// How often the compositor dispatches its clients
+const TICK: Duration = Duration::from_millis(5);
+/// How long `wait_for` waits for the daemon
+const WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Of the notification hypnos arms to follow the input, apart from the rules
+pub const ACTIVITY_TIMEOUT: Duration = Duration::from_secs(1);
+
+enum Request {
+    Idle(u32),
+    Resume(u32),
+    Stop,
+}
+
+struct Notification {
+    /// Milliseconds
+    timeout: u32,
+    resource: ext_idle_notification_v1::ExtIdleNotificationV1,
+    idled: bool,
+}
+
+#[derive(Default)]
+struct MockState {
+    notifications: Vec<Notification>,
+    /// Timeouts the user is idle past, so notifications created meanwhile get `idled` too,
+    /// as the daemon re-arms its rules whenever it learns about a seat
+    idle: HashSet<u32>,
+    /// Timeouts of `notifications`, shared with the test
+    timeouts: Arc<Mutex<Vec<u32>>>,
+}
+
+impl MockState {
+    fn sync_timeouts(&self) {
+        let mut timeouts: Vec<u32> = self.notifications.iter().map(|notification| notification.timeout).collect();
+        timeouts.sort();
+        *self.timeouts.lock().unwrap() = timeouts;
+    }
+
+    fn forget(&mut self, resource: &ext_idle_notification_v1::ExtIdleNotificationV1) {
+        self.notifications.retain(|notification| notification.resource != *resource);
+        self.sync_timeouts();
+    }
+
+    /// Send `idled` and `resumed` to match `idle`.
+    fn update_idle(&mut self) {
+        for notification in &mut self.notifications {
+            let idle = self.idle.contains(&notification.timeout);
+            if idle && !notification.idled {
+                notification.resource.idled();
+            } else if !idle && notification.idled {
+                notification.resource.resumed();
+            }
+            notification.idled = idle;
+        }
+    }
+}
+
+struct ClientState;
+
+impl ClientData for ClientState {}
+
+impl GlobalDispatch<wl_seat::WlSeat, ()> for MockState {
+    fn bind(
+        _: &mut Self,
+        _: &DisplayHandle,
+        _: &Client,
+        resource: New<wl_seat::WlSeat>,
+        _: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        let seat = data_init.init(resource, ());
+        seat.capabilities(wl_seat::Capability::Pointer | wl_seat::Capability::Keyboard);
+        if seat.version() >= 2 {
+            seat.name("seat0".to_string());
+        }
+    }
+}
+
+impl Dispatch<wl_seat::WlSeat, ()> for MockState {
+    fn request(
+        _: &mut Self,
+        _: &Client,
+        _: &wl_seat::WlSeat,
+        _: wl_seat::Request,
+        _: &(),
+        _: &DisplayHandle,
+        _: &mut DataInit<'_, Self>,
+    ) {
+    }
+}
+
+impl GlobalDispatch<ext_idle_notifier_v1::ExtIdleNotifierV1, ()> for MockState {
+    fn bind(
+        _: &mut Self,
+        _: &DisplayHandle,
+        _: &Client,
+        resource: New<ext_idle_notifier_v1::ExtIdleNotifierV1>,
+        _: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        data_init.init(resource, ());
+    }
+}
+
+impl Dispatch<ext_idle_notifier_v1::ExtIdleNotifierV1, ()> for MockState {
+    fn request(
+        state: &mut Self,
+        _: &Client,
+        _: &ext_idle_notifier_v1::ExtIdleNotifierV1,
+        request: ext_idle_notifier_v1::Request,
+        _: &(),
+        _: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        let (id, timeout) = match request {
+            ext_idle_notifier_v1::Request::GetIdleNotification { id, timeout, .. } => (id, timeout),
+            ext_idle_notifier_v1::Request::GetInputIdleNotification { id, timeout, .. } => (id, timeout),
+            _ => return,
+        };
+        state.notifications.push(Notification { timeout, resource: data_init.init(id, ()), idled: false });
+        state.sync_timeouts();
+    }
+}
+
+impl Dispatch<ext_idle_notification_v1::ExtIdleNotificationV1, ()> for MockState {
+    fn request(
+        state: &mut Self,
+        _: &Client,
+        notification: &ext_idle_notification_v1::ExtIdleNotificationV1,
+        request: ext_idle_notification_v1::Request,
+        _: &(),
+        _: &DisplayHandle,
+        _: &mut DataInit<'_, Self>,
+    ) {
+        if let ext_idle_notification_v1::Request::Destroy = request {
+            state.forget(notification);
+        }
+    }
+
+    fn destroyed(
+        state: &mut Self,
+        _: ClientId,
+        notification: &ext_idle_notification_v1::ExtIdleNotificationV1,
+        _: &(),
+    ) {
+        state.forget(notification);
+    }
+}
+
+/// A compositor whose idle notifications only fire on demand.
+pub struct MockCompositor {
+    pub runtime_dir: TempDir,
+    timeouts: Arc<Mutex<Vec<u32>>>,
+    commands: mpsc::Sender<Request>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MockCompositor {
+    pub fn start() -> Self {
+        let runtime_dir = tempfile::tempdir().unwrap();
+        let socket = ListeningSocket::bind_absolute(runtime_dir.path().join(SOCKET_NAME)).unwrap();
+        let (commands, rx) = mpsc::channel();
+
+        let mut display: Display<MockState> = Display::new().unwrap();
+        display.handle().create_global::<MockState, wl_seat::WlSeat, ()>(2, ());
+        display.handle().create_global::<MockState, ext_idle_notifier_v1::ExtIdleNotifierV1, ()>(2, ());
+        let mut state = MockState::default();
+        let timeouts = state.timeouts.clone();
+
+        let thread = thread::spawn(move || loop {
+            if let Some(stream) = socket.accept().unwrap() {
+                display.handle().insert_client(stream, Arc::new(ClientState)).unwrap();
+            }
+            display.dispatch_clients(&mut state).unwrap();
+            while let Ok(request) = rx.try_recv() {
+                match request {
+                    Request::Idle(timeout) => state.idle.insert(timeout),
+                    Request::Resume(timeout) => state.idle.remove(&timeout),
+                    Request::Stop => return,
+                };
+            }
+            state.update_idle();
+            display.flush_clients().unwrap();
+            thread::sleep(TICK);
+        });
+
+        Self { runtime_dir, timeouts, commands, thread: Some(thread) }
+    }
+
+    /// Timeouts of the rules' armed idle notifications, in milliseconds.
+    pub fn timeouts(&self) -> Vec<u32> {
+        let activity = ACTIVITY_TIMEOUT.as_millis() as u32;
+        self.timeouts.lock().unwrap().iter().copied().filter(|timeout| *timeout != activity).collect()
+    }
+
+    /// Whether hypnos armed its notification following the input.
+    pub fn tracks_activity(&self) -> bool {
+        self.timeouts.lock().unwrap().contains(&(ACTIVITY_TIMEOUT.as_millis() as u32))
+    }
+
+    /// Send `idled` to the notifications armed with `timeout`, as if the user was away that long,
+    /// until `resume`.
+    pub fn idle(&self, timeout: Duration) {
+        self.commands.send(Request::Idle(timeout.as_millis() as u32)).unwrap();
+    }
+
+    pub fn resume(&self, timeout: Duration) {
+        self.commands.send(Request::Resume(timeout.as_millis() as u32)).unwrap();
+    }
+}
+
+impl Drop for MockCompositor {
+    fn drop(&mut self) {
+        let _ = self.commands.send(Request::Stop);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// A hypnos daemon connected to a `MockCompositor`, with its own config and state.
+pub struct Daemon {
+    dir: TempDir,
+    child: Child,
+}
+
+impl Daemon {
+    pub fn start(compositor: &MockCompositor, config: &str) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("config/hypnos")).unwrap();
+        fs::write(config_path(dir.path()), config).unwrap();
+
+        // No buses, the daemon must not touch the user's session or the system
+        let no_bus = format!("unix:path={}", dir.path().join("no-bus").display());
+        let child = Command::new(env!("CARGO_BIN_EXE_hypnos"))
+            .arg("daemon")
+            .env("XDG_RUNTIME_DIR", compositor.runtime_dir.path())
+            .env("WAYLAND_DISPLAY", SOCKET_NAME)
+            .env("XDG_CONFIG_HOME", dir.path().join("config"))
+            .env("XDG_STATE_HOME", dir.path().join("state"))
+            .env("DBUS_SESSION_BUS_ADDRESS", &no_bus)
+            .env("DBUS_SYSTEM_BUS_ADDRESS", &no_bus)
+            .env_remove("DISPLAY")
+            .env("RUST_LOG", "debug")
+            .stdout(Stdio::null())
+            .stderr(fs::File::create(dir.path().join("daemon.log")).unwrap())
+            .spawn()
+            .unwrap();
+        Self { dir, child }
+    }
+
+    /// Replace the config, the daemon reloads it on its own.
+    pub fn write_config(&self, config: &str) {
+        fs::write(config_path(self.dir.path()), config).unwrap();
+    }
+
+    /// Wait until `condition` holds, failing with the daemon's log when it doesn't in time.
+    pub fn wait_for(&mut self, what: &str, condition: impl Fn() -> bool) {
+        let start = Instant::now();
+        while !condition() {
+            if let Ok(Some(status)) = self.child.try_wait() {
+                panic!("hypnos exited ({}) waiting for {}\n{}", status, what, self.log());
+            }
+            if start.elapsed() > WAIT_TIMEOUT {
+                panic!("Timed out waiting for {}\n{}", what, self.log());
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    fn log(&self) -> String {
+        fs::read_to_string(self.dir.path().join("daemon.log")).unwrap_or_default()
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn config_path(dir: &Path) -> PathBuf {
+    dir.join("config/hypnos/config.json")
+}
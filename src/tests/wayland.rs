@@ -0,0 +1,108 @@
+//! The daemon against a mock compositor: rules get armed, fire, restore and follow reloads.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{Daemon, MockCompositor};
+
+fn config(rules: &str) -> String {
+    format!(r#"{{ "enabled": true, "rules": {{ {} }} }}"#, rules)
+}
+
+#[test]
+fn rules_are_armed() {
+    let compositor = MockCompositor::start();
+    let mut daemon = Daemon::start(
+        &compositor,
+        &config(
+            r#"
+            "dim": { "timeout": 60, "actions": "true" },
+            "suspend": { "timeout": 300, "actions": "true" },
+            "off": { "timeout": 30, "actions": "true", "enabled": false }
+            "#,
+        ),
+    );
+
+    daemon.wait_for("the rules to be armed", || compositor.timeouts() == [60_000, 300_000]);
+    daemon.wait_for("the input to be followed", || compositor.tracks_activity());
+}
+
+#[test]
+fn rule_fires_and_restores() {
+    let compositor = MockCompositor::start();
+    let dir = tempfile::tempdir().unwrap();
+    let idled = dir.path().join("idled");
+    let resumed = dir.path().join("resumed");
+    let mut daemon = Daemon::start(
+        &compositor,
+        &config(&format!(
+            r#""dim": {{ "timeout": 60, "actions": "touch {}", "restore": "touch {}" }}"#,
+            idled.display(),
+            resumed.display()
+        )),
+    );
+    daemon.wait_for("the rule to be armed", || compositor.timeouts() == [60_000]);
+
+    compositor.idle(Duration::from_secs(60));
+    daemon.wait_for("the idle action", || idled.exists());
+    assert!(!resumed.exists());
+
+    compositor.resume(Duration::from_secs(60));
+    daemon.wait_for("the restore action", || resumed.exists());
+}
+
+#[test]
+fn other_rules_do_not_fire() {
+    let compositor = MockCompositor::start();
+    let dir = tempfile::tempdir().unwrap();
+    let dimmed = dir.path().join("dimmed");
+    let suspended = dir.path().join("suspended");
+    let mut daemon = Daemon::start(
+        &compositor,
+        &config(&format!(
+            r#""dim": {{ "timeout": 60, "actions": "touch {}" }},
+            "suspend": {{ "timeout": 300, "actions": "touch {}" }}"#,
+            dimmed.display(),
+            suspended.display()
+        )),
+    );
+    daemon.wait_for("the rules to be armed", || compositor.timeouts() == [60_000, 300_000]);
+
+    compositor.idle(Duration::from_secs(60));
+    daemon.wait_for("the dim action", || dimmed.exists());
+    assert!(!suspended.exists());
+}
+
+#[test]
+fn reload_rearms_rules() {
+    let compositor = MockCompositor::start();
+    let mut daemon = Daemon::start(&compositor, &config(r#""dim": { "timeout": 60, "actions": "true" }"#));
+    daemon.wait_for("the rule to be armed", || compositor.timeouts() == [60_000]);
+
+    daemon.write_config(&config(
+        r#"
+        "dim": { "timeout": 30, "actions": "true" },
+        "lock": { "timeout": 120, "actions": "true" }
+        "#,
+    ));
+    daemon.wait_for("the new rules to be armed", || compositor.timeouts() == [30_000, 120_000]);
+
+    daemon.write_config(&config(r#""dim": { "timeout": 30, "actions": "true", "enabled": false }"#));
+    daemon.wait_for("the disabled rule to be disarmed", || compositor.timeouts().is_empty());
+}
+
+#[test]
+fn reloaded_rule_fires() {
+    let compositor = MockCompositor::start();
+    let dir = tempfile::tempdir().unwrap();
+    let fired = dir.path().join("fired");
+    let mut daemon = Daemon::start(&compositor, &config(r#""dim": { "timeout": 60, "actions": "true" }"#));
+    daemon.wait_for("the rule to be armed", || compositor.timeouts() == [60_000]);
+
+    daemon.write_config(&config(&format!(r#""dim": {{ "timeout": 90, "actions": "touch {}" }}"#, fired.display())));
+    daemon.wait_for("the reloaded rule to be armed", || compositor.timeouts() == [90_000]);
+
+    compositor.idle(Duration::from_secs(90));
+    daemon.wait_for("the reloaded action", || fired.exists());
+}